@@ -57,4 +57,8 @@ pub enum EncodingError {
     /// Error encoding the given flow id, the flow id is too large.
     #[error("The verification flow id length can't be converted into a u16: {0}")]
     FlowId(#[from] std::num::TryFromIntError),
+    /// Error encoding the rendered QR code into an image format.
+    #[cfg(feature = "decode_image")]
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
 }
@@ -141,6 +141,42 @@ mod test {
         assert_eq!(result, third_result);
     }
 
+    #[test]
+    #[cfg(feature = "decode_image")]
+    fn render_png_cycle() {
+        let image = Cursor::new(VERIFICATION);
+        let image = image::load(image, ImageFormat::Png).unwrap();
+        let result = QrVerificationData::from_image(image).unwrap();
+
+        let png = result.to_png().unwrap();
+        let image = image::load(Cursor::new(png), ImageFormat::Png).unwrap();
+        let second_result = QrVerificationData::from_image(image).unwrap();
+
+        assert_eq!(result, second_result);
+    }
+
+    #[test]
+    fn render_text_formats() {
+        let data = b"MATRIX\
+                   \x02\x02\x00\x07\
+                   FLOW_ID\
+                   AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                   BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                   SHARED_SECRET";
+
+        let result = QrVerificationData::from_bytes(data).unwrap();
+        let width = result.to_qr_code().unwrap().width();
+
+        let svg = result.to_svg().unwrap();
+        assert!(svg.contains("<svg"));
+
+        let unicode = result.to_unicode().unwrap();
+        // Two rows of modules fit into a single line, the quiet zone adds
+        // four modules to each side.
+        assert_eq!(unicode.lines().count(), (width + 8 + 1) / 2);
+        assert!(unicode.lines().all(|l| l.chars().count() == width + 8));
+    }
+
     #[test]
     #[cfg(feature = "decode_image")]
     fn decode_invalid_qr() {
@@ -19,8 +19,11 @@ use std::{
 
 use byteorder::{BigEndian, ReadBytesExt};
 #[cfg(feature = "decode_image")]
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Luma};
-use qrcode::QrCode;
+use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, ImageOutputFormat, Luma};
+use qrcode::{
+    render::{svg, unicode},
+    QrCode,
+};
 use ruma_identifiers::EventId;
 
 #[cfg(feature = "decode_image")]
@@ -226,6 +229,83 @@ impl QrVerificationData {
         }
     }
 
+    /// Render the `QrVerificationData` as a QR code in the SVG format.
+    ///
+    /// The encoding can fail if the identity keys that should be encoded are
+    /// not valid base64.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{QrVerificationData, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// let data = b"MATRIX\
+    ///              \x02\x02\x00\x07\
+    ///              FLOW_ID\
+    ///              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    ///              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    ///              SHARED_SECRET";
+    ///
+    /// let result = QrVerificationData::from_bytes(data)?;
+    /// let svg = result.to_svg().unwrap();
+    ///
+    /// assert!(svg.contains("<svg"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_svg(&self) -> Result<String, EncodingError> {
+        Ok(self.to_qr_code()?.render::<svg::Color>().min_dimensions(200, 200).build())
+    }
+
+    /// Render the `QrVerificationData` as a QR code and encode it as a PNG
+    /// image.
+    ///
+    /// The encoding can fail if the identity keys that should be encoded are
+    /// not valid base64.
+    #[cfg(feature = "decode_image")]
+    pub fn to_png(&self) -> Result<Vec<u8>, EncodingError> {
+        let image = self.to_qr_code()?.render::<Luma<u8>>().build();
+        let mut bytes = Vec::new();
+
+        DynamicImage::ImageLuma8(image).write_to(&mut bytes, ImageOutputFormat::Png)?;
+
+        Ok(bytes)
+    }
+
+    /// Render the `QrVerificationData` as a QR code made out of Unicode
+    /// half-block characters, each line of the string containing two rows of
+    /// the QR code.
+    ///
+    /// The colors are inverted so the code can be scanned when it's printed
+    /// in a terminal that uses light text on a dark background.
+    ///
+    /// The encoding can fail if the identity keys that should be encoded are
+    /// not valid base64.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{QrVerificationData, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// let data = b"MATRIX\
+    ///              \x02\x02\x00\x07\
+    ///              FLOW_ID\
+    ///              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    ///              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    ///              SHARED_SECRET";
+    ///
+    /// let result = QrVerificationData::from_bytes(data)?;
+    /// println!("{}", result.to_unicode().unwrap());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_unicode(&self) -> Result<String, EncodingError> {
+        Ok(self
+            .to_qr_code()?
+            .render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build())
+    }
+
     /// Decode the byte slice containing the decoded QR code data.
     ///
     /// The format is defined in the [spec].
@@ -24,27 +24,30 @@ use std::{
     sync::Arc,
 };
 
+#[cfg(feature = "encryption")]
+use matrix_sdk_common::uuid::Uuid;
 use matrix_sdk_common::{
     deserialized_responses::{
         AmbiguityChanges, JoinedRoom, LeftRoom, MemberEvent, MembersResponse, Rooms,
         StrippedMemberEvent, SyncResponse, SyncRoomEvent, Timeline,
     },
     instant::Instant,
-    locks::RwLock,
+    locks::{Mutex, RwLock},
 };
 #[cfg(feature = "encryption")]
-use matrix_sdk_common::{locks::Mutex, uuid::Uuid};
-#[cfg(feature = "encryption")]
 use matrix_sdk_crypto::{
     store::{CryptoStore, CryptoStoreError},
     Device, EncryptionSettings, IncomingResponse, MegolmError, OlmError, OlmMachine,
-    OutgoingRequest, ToDeviceRequest, UserDevices,
+    OutgoingRequest, ShareStrategy, ToDeviceRequest, UserDevices,
 };
 #[cfg(feature = "encryption")]
 use ruma::{
     api::client::r0::keys::claim_keys::Request as KeysClaimRequest,
     events::{
-        room::{encrypted::RoomEncryptedEventContent, history_visibility::HistoryVisibility},
+        room::{
+            encrypted::RoomEncryptedEventContent, history_visibility::HistoryVisibility,
+            message::MessageType,
+        },
         AnyMessageEventContent, AnySyncMessageEvent,
     },
     DeviceId,
@@ -83,6 +86,10 @@ pub struct BaseClient {
     session: Arc<RwLock<Option<Session>>>,
     /// The current sync token that should be used for the next sync call.
     pub(crate) sync_token: Arc<RwLock<Option<Token>>>,
+    /// Lock making sure that only one sync response, or other update of the
+    /// room infos, is processed at a time, otherwise the updates might
+    /// overwrite each other.
+    sync_lock: Arc<Mutex<()>>,
     /// Database
     store: Store,
     #[cfg(feature = "encryption")]
@@ -93,6 +100,20 @@ pub struct BaseClient {
     store_path: Arc<Option<PathBuf>>,
     #[cfg(feature = "sled_cryptostore")]
     store_passphrase: Arc<Option<Zeroizing<String>>>,
+    /// The crypto store that shares the database with the state store, it
+    /// needs to be re-keyed together with the state store.
+    #[cfg(all(
+        feature = "encryption",
+        feature = "sled_state_store",
+        feature = "sled_cryptostore"
+    ))]
+    sled_crypto_store: Option<matrix_sdk_crypto::store::SledStore>,
+    #[cfg(feature = "encryption")]
+    trust_on_first_use: bool,
+    #[cfg(feature = "encryption")]
+    room_key_sharing_strategy: ShareStrategy,
+    #[cfg(feature = "encryption")]
+    room_key_retention_policy: RoomKeyRetentionPolicy,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -105,6 +126,29 @@ impl fmt::Debug for BaseClient {
     }
 }
 
+/// Policy deciding when the room keys of a room we aren't part of anymore get
+/// deleted.
+///
+/// Deleted room keys can't be used to decrypt the history of the room anymore,
+/// unless they are received again, e.g. from a key backup.
+#[cfg(feature = "encryption")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomKeyRetentionPolicy {
+    /// Never delete room keys, this is the default.
+    KeepAll,
+    /// Delete the room keys of a room once it gets forgotten.
+    DeleteOnForget,
+    /// Delete the room keys of a room as soon as we leave it.
+    DeleteOnLeave,
+}
+
+#[cfg(feature = "encryption")]
+impl Default for RoomKeyRetentionPolicy {
+    fn default() -> Self {
+        Self::KeepAll
+    }
+}
+
 /// Configuration for the creation of the `BaseClient`.
 ///
 /// # Example
@@ -122,6 +166,12 @@ pub struct BaseClientConfig {
     crypto_store: Option<Box<dyn CryptoStore>>,
     store_path: Option<PathBuf>,
     passphrase: Option<Zeroizing<String>>,
+    #[cfg(feature = "encryption")]
+    trust_on_first_use: bool,
+    #[cfg(feature = "encryption")]
+    room_key_sharing_strategy: ShareStrategy,
+    #[cfg(feature = "encryption")]
+    room_key_retention_policy: RoomKeyRetentionPolicy,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -174,6 +224,35 @@ impl BaseClientConfig {
         self.passphrase = Some(Zeroizing::new(passphrase));
         self
     }
+
+    /// Pin the master key of user identities the first time we see them.
+    ///
+    /// See [`OlmMachine::set_trust_on_first_use()`] for more info.
+    #[cfg(feature = "encryption")]
+    pub fn trust_on_first_use(mut self) -> Self {
+        self.trust_on_first_use = true;
+        self
+    }
+
+    /// Set the strategy that decides which devices of the room members should
+    /// receive our room keys.
+    ///
+    /// This is used for all rooms that don't have a strategy set using
+    /// [`BaseClient::set_room_key_sharing_strategy()`], defaults to
+    /// [`ShareStrategy::AllDevices`].
+    #[cfg(feature = "encryption")]
+    pub fn room_key_sharing_strategy(mut self, strategy: ShareStrategy) -> Self {
+        self.room_key_sharing_strategy = strategy;
+        self
+    }
+
+    /// Set the policy that decides when the room keys of rooms that we left
+    /// get deleted, defaults to [`RoomKeyRetentionPolicy::KeepAll`].
+    #[cfg(feature = "encryption")]
+    pub fn room_key_retention_policy(mut self, policy: RoomKeyRetentionPolicy) -> Self {
+        self.room_key_retention_policy = policy;
+        self
+    }
 }
 
 impl BaseClient {
@@ -209,16 +288,28 @@ impl BaseClient {
         #[cfg(not(feature = "sled_state_store"))]
         let stores = Store::open_memory_store();
 
-        #[cfg(all(feature = "encryption", feature = "sled_state_store"))]
-        let crypto_store = if config.crypto_store.is_none() {
-            #[cfg(feature = "sled_cryptostore")]
-            let store: Option<Box<dyn CryptoStore>> = Some(Box::new(
+        #[cfg(all(
+            feature = "encryption",
+            feature = "sled_state_store",
+            feature = "sled_cryptostore"
+        ))]
+        let sled_crypto_store = if config.crypto_store.is_none() {
+            Some(
                 matrix_sdk_crypto::store::SledStore::open_with_database(
                     stores.1,
                     config.passphrase.as_deref().map(|p| p.as_str()),
                 )
                 .map_err(OlmError::Store)?,
-            ));
+            )
+        } else {
+            None
+        };
+
+        #[cfg(all(feature = "encryption", feature = "sled_state_store"))]
+        let crypto_store = if config.crypto_store.is_none() {
+            #[cfg(feature = "sled_cryptostore")]
+            let store: Option<Box<dyn CryptoStore>> =
+                sled_crypto_store.clone().map(|s| Box::new(s) as Box<dyn CryptoStore>);
             #[cfg(not(feature = "sled_cryptostore"))]
             let store = config.crypto_store;
 
@@ -237,6 +328,7 @@ impl BaseClient {
         Ok(BaseClient {
             session: store.session.clone(),
             sync_token: store.sync_token.clone(),
+            sync_lock: Default::default(),
             store,
             #[cfg(feature = "encryption")]
             olm: Mutex::new(None).into(),
@@ -246,6 +338,18 @@ impl BaseClient {
             store_path: config.store_path.into(),
             #[cfg(feature = "sled_cryptostore")]
             store_passphrase: config.passphrase.into(),
+            #[cfg(all(
+                feature = "encryption",
+                feature = "sled_state_store",
+                feature = "sled_cryptostore"
+            ))]
+            sled_crypto_store,
+            #[cfg(feature = "encryption")]
+            trust_on_first_use: config.trust_on_first_use,
+            #[cfg(feature = "encryption")]
+            room_key_sharing_strategy: config.room_key_sharing_strategy,
+            #[cfg(feature = "encryption")]
+            room_key_retention_policy: config.room_key_retention_policy,
         })
     }
 
@@ -260,6 +364,87 @@ impl BaseClient {
         &self.store
     }
 
+    /// Change the passphrase that is used to encrypt the state and crypto
+    /// stores.
+    ///
+    /// Both stores share a database, they are re-encrypted one after the
+    /// other. A marker is persisted before the stores get re-encrypted, if the
+    /// process is interrupted the stores need to be opened using the new
+    /// passphrase, which finishes the change.
+    ///
+    /// Passing `None` as the old passphrase encrypts previously unencrypted
+    /// stores, passing `None` as the new passphrase removes the encryption of
+    /// the stores.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the stores.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the stores from
+    /// now on.
+    #[cfg(feature = "sled_state_store")]
+    pub async fn change_store_passphrase(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+    ) -> Result<()> {
+        if old_passphrase.is_none() && new_passphrase.is_none() {
+            return Ok(());
+        }
+
+        self.rekey_stores(old_passphrase, new_passphrase, false).await
+    }
+
+    /// Re-encrypt all the data in the state and crypto stores under freshly
+    /// generated keys.
+    ///
+    /// This should be used if the keys of the stores might have been
+    /// compromised, the stores stay protected by the same passphrase. If the
+    /// process is interrupted the re-keying is finished the next time the
+    /// stores are opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that protects the stores.
+    #[cfg(feature = "sled_state_store")]
+    pub async fn rekey_store(&self, passphrase: &str) -> Result<()> {
+        self.rekey_stores(Some(passphrase), Some(passphrase), true).await
+    }
+
+    #[cfg(feature = "sled_state_store")]
+    async fn rekey_stores(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+        new_key: bool,
+    ) -> Result<()> {
+        let state_store = self.store.sled_store();
+
+        // The markers of both stores are persisted atomically, so a re-keying
+        // that gets interrupted is finished for both stores once they are
+        // opened again.
+        let mut batch = sled::Batch::default();
+        state_store.begin_rekey(&mut batch, old_passphrase, new_passphrase, new_key)?;
+
+        #[cfg(all(feature = "encryption", feature = "sled_cryptostore"))]
+        if let Some(crypto_store) = &self.sled_crypto_store {
+            crypto_store
+                .begin_rekey(&mut batch, old_passphrase, new_passphrase, new_key)
+                .await
+                .map_err(OlmError::Store)?;
+        }
+
+        state_store.apply_rekey_batch(batch).await?;
+        state_store.finish_rekey(new_passphrase).await?;
+
+        #[cfg(all(feature = "encryption", feature = "sled_cryptostore"))]
+        if let Some(crypto_store) = &self.sled_crypto_store {
+            crypto_store.finish_rekey(new_passphrase).await.map_err(OlmError::Store)?;
+        }
+
+        Ok(())
+    }
+
     /// Is the client logged in.
     pub async fn logged_in(&self) -> bool {
         // TODO turn this into a atomic bool so this method doesn't need to be
@@ -332,6 +517,10 @@ impl BaseClient {
             } else {
                 *olm = Some(OlmMachine::new(&session.user_id, &session.device_id));
             }
+
+            if let Some(olm) = &*olm {
+                olm.set_trust_on_first_use(self.trust_on_first_use);
+            }
         }
 
         *self.session.write().await = Some(session);
@@ -415,13 +604,56 @@ impl BaseClient {
                             encrypted,
                         )) => {
                             if let Some(olm) = self.olm_machine().await {
-                                if let Ok(decrypted) =
-                                    olm.decrypt_room_event(encrypted, room_id).await
-                                {
-                                    event = decrypted;
+                                match olm.decrypt_room_event(encrypted, room_id).await {
+                                    Ok(decrypted) => event = decrypted,
+                                    Err(e) => {
+                                        event.unable_to_decrypt_info = olm
+                                            .unable_to_decrypt_info(encrypted, room_id, &e)
+                                            .await
+                                            .unwrap_or_else(|e| {
+                                                warn!(
+                                                    error = ?e,
+                                                    "Couldn't get the decryption failure info"
+                                                );
+                                                None
+                                            });
+                                    }
                                 }
                             }
                         }
+                        #[cfg(feature = "encryption")]
+                        AnySyncRoomEvent::Message(message) if is_verification_event(message) => {
+                            // Encrypted verification events reach the
+                            // verification machine once they get decrypted.
+                            // In-room verifications only happen in the DM
+                            // with the other user, ignore any other room.
+                            let sender = message.sender();
+                            let in_dm = sender == room.own_user_id()
+                                || room.direct_target().as_deref() == Some(sender);
+
+                            if in_dm {
+                                if let Some(olm) = self.olm_machine().await {
+                                    let message =
+                                        message.clone().into_full_event(room_id.to_owned());
+
+                                    if let Err(e) =
+                                        olm.receive_unencrypted_verification_event(&message).await
+                                    {
+                                        warn!(
+                                            room_id = room_id.as_str(),
+                                            error = ?e,
+                                            "Error handling an in-room verification event"
+                                        );
+                                    }
+                                }
+                            } else {
+                                trace!(
+                                    room_id = room_id.as_str(),
+                                    sender = sender.as_str(),
+                                    "Ignoring a verification event outside of a DM"
+                                );
+                            }
+                        }
                         // TODO if there is redacted state save the room id,
                         // event type and state key, add a method to get the
                         // requests that are needed to be called to heal this
@@ -663,6 +895,8 @@ impl BaseClient {
             ..
         } = response;
 
+        let _sync_lock = self.sync_lock.lock().await;
+
         // The server might respond multiple times with the same sync token, in
         // that case we already received this response and there's nothing to
         // do.
@@ -812,6 +1046,13 @@ impl BaseClient {
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
 
+            #[cfg(feature = "encryption")]
+            if self.room_key_retention_policy == RoomKeyRetentionPolicy::DeleteOnLeave {
+                if let Some(o) = self.olm_machine().await {
+                    o.delete_room_keys(&room_id).await?;
+                }
+            }
+
             changes.add_room(room_info);
             new_rooms
                 .leave
@@ -909,6 +1150,8 @@ impl BaseClient {
             .collect();
         let mut ambiguity_cache = AmbiguityCache::new(self.store.clone());
 
+        let _sync_lock = self.sync_lock.lock().await;
+
         if let Some(room) = self.store.get_room(room_id) {
             let mut room_info = room.clone_info();
             room_info.mark_members_synced();
@@ -1061,7 +1304,51 @@ impl BaseClient {
         }
     }
 
+    /// Get the strategy that decides which devices of the room members should
+    /// receive our room keys for the given room.
+    #[cfg(feature = "encryption")]
+    pub fn room_key_sharing_strategy(&self, room_id: &RoomId) -> ShareStrategy {
+        self.get_room(room_id)
+            .and_then(|r| r.room_key_sharing_strategy())
+            .unwrap_or(self.room_key_sharing_strategy)
+    }
+
+    /// Set the strategy that decides which devices of the room members should
+    /// receive our room keys for the given room.
+    ///
+    /// This overrides the strategy that was set using
+    /// [`BaseClientConfig::room_key_sharing_strategy()`] for this room. The
+    /// strategy is persisted in the state store, it does nothing if we don't
+    /// know about the room.
+    #[cfg(feature = "encryption")]
+    pub async fn set_room_key_sharing_strategy(
+        &self,
+        room_id: &RoomId,
+        strategy: ShareStrategy,
+    ) -> Result<()> {
+        // A sync that is processed concurrently would overwrite the room info
+        // with the copy it took before the strategy was set.
+        let _sync_lock = self.sync_lock.lock().await;
+
+        if let Some(room) = self.store.get_room(room_id) {
+            let mut room_info = room.clone_info();
+            room_info.set_room_key_sharing_strategy(strategy);
+
+            let mut changes = StateChanges::default();
+            changes.add_room(room_info.clone());
+
+            self.store.save_changes(&changes).await?;
+            room.update_summary(room_info);
+        }
+
+        Ok(())
+    }
+
     /// Get a to-device request that will share a group session for a room.
+    ///
+    /// Fails with [`OlmError::UnverifiedDevices`] if the sharing strategy of
+    /// the room is [`ShareStrategy::ErrorOnUnverifiedDevices`] and the room
+    /// contains unverified devices.
     #[cfg(feature = "encryption")]
     pub async fn share_group_session(&self, room_id: &RoomId) -> Result<Vec<Arc<ToDeviceRequest>>> {
         let olm = self.olm.lock().await;
@@ -1085,7 +1372,8 @@ impl BaseClient {
                 };
 
                 let settings = settings.ok_or(MegolmError::EncryptionNotEnabled)?;
-                let settings = EncryptionSettings::new(settings, history_visibility);
+                let mut settings = EncryptionSettings::new(settings, history_visibility);
+                settings.sharing_strategy = self.room_key_sharing_strategy(room_id);
 
                 Ok(o.share_group_session(room_id, members.map(Deref::deref), settings).await?)
             }
@@ -1135,6 +1423,26 @@ impl BaseClient {
         }
     }
 
+    /// Notify the client that the given room was forgotten.
+    ///
+    /// This deletes the room keys of the room if the
+    /// [`RoomKeyRetentionPolicy`] of the client asks for it.
+    ///
+    /// Returns the number of room keys that were deleted.
+    #[cfg(feature = "encryption")]
+    pub async fn receive_forgotten_room(&self, room_id: &RoomId) -> Result<usize> {
+        if self.room_key_retention_policy == RoomKeyRetentionPolicy::KeepAll {
+            return Ok(0);
+        }
+
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.delete_room_keys(room_id).await?),
+            None => Ok(0),
+        }
+    }
+
     /// Get a specific device of a user.
     ///
     /// # Arguments
@@ -1363,5 +1671,16 @@ impl BaseClient {
     }
 }
 
+/// Is the given room event part of an in-room verification flow.
+#[cfg(feature = "encryption")]
+fn is_verification_event(event: &AnySyncMessageEvent) -> bool {
+    match event {
+        AnySyncMessageEvent::RoomMessage(m) => {
+            matches!(m.content.msgtype, MessageType::VerificationRequest(_))
+        }
+        e => e.content().event_type().starts_with("m.key.verification."),
+    }
+}
+
 #[cfg(test)]
 mod test {}
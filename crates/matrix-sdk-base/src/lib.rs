@@ -39,6 +39,8 @@ mod rooms;
 mod session;
 mod store;
 
+#[cfg(feature = "encryption")]
+pub use client::RoomKeyRetentionPolicy;
 pub use client::{BaseClient, BaseClientConfig};
 #[cfg(feature = "encryption")]
 pub use matrix_sdk_crypto as crypto;
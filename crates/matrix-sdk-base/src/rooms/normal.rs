@@ -18,6 +18,8 @@ use std::{
 };
 
 use futures_util::stream::{self, StreamExt};
+#[cfg(feature = "encryption")]
+use matrix_sdk_crypto::ShareStrategy;
 use ruma::{
     api::client::r0::sync::sync_events::RoomSummary as RumaSummary,
     events::{
@@ -95,6 +97,8 @@ impl Room {
             members_synced: false,
             last_prev_batch: None,
             base_info: BaseRoomInfo::new(),
+            #[cfg(feature = "encryption")]
+            room_key_sharing_strategy: None,
         };
 
         Self::restore(own_user_id, store, room_info)
@@ -212,6 +216,13 @@ impl Room {
         self.inner.read().unwrap().base_info.history_visibility.clone()
     }
 
+    /// Get the strategy that was set to decide which devices receive our room
+    /// keys for this room, if any.
+    #[cfg(feature = "encryption")]
+    pub fn room_key_sharing_strategy(&self) -> Option<ShareStrategy> {
+        self.inner.read().unwrap().room_key_sharing_strategy
+    }
+
     /// Is the room considered to be public.
     pub fn is_public(&self) -> bool {
         matches!(self.join_rule(), JoinRule::Public)
@@ -492,6 +503,11 @@ pub struct RoomInfo {
     /// Base room info which holds some basic event contents important for the
     /// room state.
     pub base_info: BaseRoomInfo,
+    /// The strategy deciding which devices receive our room keys for this
+    /// room, overriding the default strategy of the client.
+    #[cfg(feature = "encryption")]
+    #[serde(default)]
+    pub room_key_sharing_strategy: Option<ShareStrategy>,
 }
 
 impl RoomInfo {
@@ -515,6 +531,11 @@ impl RoomInfo {
         self.members_synced = false;
     }
 
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_room_key_sharing_strategy(&mut self, strategy: ShareStrategy) {
+        self.room_key_sharing_strategy = Some(strategy);
+    }
+
     pub(crate) fn set_prev_batch(&mut self, prev_batch: Option<&str>) -> bool {
         if self.last_prev_batch.as_deref() != prev_batch {
             self.last_prev_batch = prev_batch.map(|p| p.to_string());
@@ -83,6 +83,18 @@ pub enum StoreError {
     #[cfg(feature = "sled_state_store")]
     #[error(transparent)]
     Task(#[from] tokio::task::JoinError),
+    /// The database was created by a newer version of the library and uses a
+    /// schema that this version doesn't know about.
+    #[error(
+        "The database uses schema version {version}, the latest supported version is \
+         {max_supported}"
+    )]
+    UnsupportedDatabaseVersion {
+        /// The schema version of the database.
+        version: u8,
+        /// The latest schema version this version of the library supports.
+        max_supported: u8,
+    },
 }
 
 /// A `StateStore` specific result type.
@@ -322,6 +334,8 @@ pub trait StateStore: AsyncTraitDeps {
 #[derive(Debug, Clone)]
 pub struct Store {
     inner: Arc<dyn StateStore>,
+    #[cfg(feature = "sled_state_store")]
+    sled_store: SledStore,
     pub(crate) session: Arc<RwLock<Option<Session>>>,
     pub(crate) sync_token: Arc<RwLock<Option<String>>>,
     rooms: Arc<DashMap<Box<RoomId>, Room>>,
@@ -329,6 +343,7 @@ pub struct Store {
 }
 
 impl Store {
+    #[cfg(not(feature = "sled_state_store"))]
     fn new(inner: Box<dyn StateStore>) -> Self {
         Self {
             inner: inner.into(),
@@ -339,6 +354,18 @@ impl Store {
         }
     }
 
+    #[cfg(feature = "sled_state_store")]
+    fn new(sled_store: SledStore) -> Self {
+        Self {
+            inner: Arc::new(sled_store.clone()),
+            sled_store,
+            session: Default::default(),
+            sync_token: Default::default(),
+            rooms: Default::default(),
+            stripped_rooms: Default::default(),
+        }
+    }
+
     pub(crate) async fn restore_session(&self, session: Session) -> Result<()> {
         for info in self.inner.get_room_infos().await? {
             let room = Room::restore(&session.user_id, self.inner.clone(), info);
@@ -381,14 +408,23 @@ impl Store {
             SledStore::open_with_path(path)?
         };
 
-        Ok((Self::new(Box::new(inner.clone())), inner.inner))
+        let db = inner.inner.clone();
+
+        Ok((Self::new(inner), db))
     }
 
     #[cfg(feature = "sled_state_store")]
     pub(crate) fn open_temporary() -> Result<(Self, Db)> {
         let inner = SledStore::open()?;
+        let db = inner.inner.clone();
+
+        Ok((Self::new(inner), db))
+    }
 
-        Ok((Self::new(Box::new(inner.clone())), inner.inner))
+    /// Get the Sled store that backs this store.
+    #[cfg(feature = "sled_state_store")]
+    pub(crate) fn sled_store(&self) -> &SledStore {
+        &self.sled_store
     }
 
     /// Get all the rooms this store knows about.
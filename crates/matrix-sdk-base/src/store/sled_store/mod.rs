@@ -18,13 +18,11 @@ use std::{
     collections::BTreeSet,
     convert::{TryFrom, TryInto},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, RwLock},
     time::Instant,
 };
 
-use futures_core::stream::Stream;
-use futures_util::stream::{self, TryStreamExt};
-use matrix_sdk_common::async_trait;
+use matrix_sdk_common::{async_trait, locks::RwLock as AsyncRwLock};
 use ruma::{
     events::{
         presence::PresenceEvent,
@@ -39,10 +37,11 @@ use ruma::{
 use serde::{Deserialize, Serialize};
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
-    Config, Db, Transactional, Tree,
+    Batch, Config, Db, Transactional, Tree,
 };
 use tokio::task::spawn_blocking;
 use tracing::info;
+use zeroize::Zeroizing;
 
 use self::store_key::{EncryptedEvent, StoreKey};
 use super::{Result, RoomInfo, StateChanges, StateStore, StoreError};
@@ -57,6 +56,23 @@ pub enum DatabaseType {
     Encrypted(store_key::EncryptedStoreKey),
 }
 
+/// Marker that is persisted while the store gets re-keyed, it allows an
+/// interrupted re-keying to be finished the next time the store is opened.
+#[derive(Debug, Serialize, Deserialize)]
+struct RekeyMarker {
+    /// The store key the events are currently encrypted with, encrypted using
+    /// the new passphrase. An empty passphrase is used if the store won't be
+    /// encrypted anymore. `None` if the store currently isn't encrypted.
+    old_key: Option<store_key::EncryptedStoreKey>,
+    /// The store key the events will be encrypted with, encrypted using the
+    /// new passphrase. `None` if the store keeps its store key or won't be
+    /// encrypted anymore.
+    ///
+    /// Either this or `old_key` is set, so the new passphrase can always be
+    /// checked when an interrupted re-keying is finished.
+    next_key: Option<store_key::EncryptedStoreKey>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SerializationError {
     #[error(transparent)]
@@ -88,6 +104,13 @@ impl From<SerializationError> for StoreError {
 }
 
 const ENCODE_SEPARATOR: u8 = 0xff;
+const REKEY_MARKER: &str = "state_store_rekey";
+const DATABASE_VERSION: u8 = 1;
+
+/// The migrations of the database schema, the migration at index `n`
+/// upgrades the database from version `n` to version `n + 1`.
+const MIGRATIONS: [fn(&SledStore) -> Result<()>; DATABASE_VERSION as usize] =
+    [SledStore::migrate_to_v1];
 
 trait EncodeKey {
     fn encode(&self) -> Vec<u8>;
@@ -170,7 +193,11 @@ pub fn decode_key_value(key: &[u8], position: usize) -> Option<String> {
 pub struct SledStore {
     path: Option<PathBuf>,
     pub(crate) inner: Db,
-    store_key: Arc<Option<StoreKey>>,
+    store_key: Arc<RwLock<Option<StoreKey>>>,
+    /// Held for reading while the store is accessed and for writing while the
+    /// store is re-encrypted, so nothing gets encrypted or decrypted using an
+    /// outdated key.
+    rekey_lock: Arc<AsyncRwLock<()>>,
     session: Tree,
     account_data: Tree,
     members: Tree,
@@ -228,10 +255,11 @@ impl SledStore {
 
         let custom = db.open_tree("custom")?;
 
-        Ok(Self {
+        let database = Self {
             path,
             inner: db,
-            store_key: store_key.into(),
+            store_key: RwLock::new(store_key).into(),
+            rekey_lock: AsyncRwLock::new(()).into(),
             session,
             account_data,
             members,
@@ -250,7 +278,63 @@ impl SledStore {
             room_event_receipts,
             media,
             custom,
-        })
+        };
+
+        database.upgrade()?;
+
+        Ok(database)
+    }
+
+    /// Get the schema version of the database.
+    ///
+    /// Databases that were created before the schema version was recorded
+    /// are treated as version `0`.
+    fn database_version(&self) -> Result<u8> {
+        Ok(self
+            .inner
+            .get("store_version".encode())?
+            .and_then(|v| v.first().copied())
+            .unwrap_or_default())
+    }
+
+    fn set_database_version(&self, version: u8) -> Result<()> {
+        self.inner.insert("store_version".encode(), version.to_be_bytes().as_ref())?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    /// Bring the database up to the latest schema version.
+    ///
+    /// Every migration upgrades the schema by exactly one version, the new
+    /// version is recorded after each step so an interrupted upgrade resumes
+    /// where it stopped the next time the store is opened.
+    fn upgrade(&self) -> Result<()> {
+        let version = self.database_version()?;
+
+        if version > DATABASE_VERSION {
+            return Err(StoreError::UnsupportedDatabaseVersion {
+                version,
+                max_supported: DATABASE_VERSION,
+            });
+        }
+
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version.into()) {
+            let to = from as u8 + 1;
+
+            info!(from, to, "Migrating the Sled state store");
+
+            migration(self)?;
+            self.set_database_version(to)?;
+        }
+
+        Ok(())
+    }
+
+    fn migrate_to_v1(&self) -> Result<()> {
+        // Version 1 only started to record the schema version, the layout of
+        // the trees didn't change.
+        Ok(())
     }
 
     pub fn open() -> Result<Self> {
@@ -263,6 +347,10 @@ impl SledStore {
         let path = path.as_ref().join("matrix-sdk-state");
         let db = Config::new().temporary(false).path(&path).open()?;
 
+        if let Some(marker) = Self::rekey_marker(&db)? {
+            return SledStore::open_interrupted_rekey(db, path, marker, Some(passphrase));
+        }
+
         let store_key: Option<DatabaseType> = db
             .get("store_key".encode())?
             .map(|k| serde_json::from_slice(&k).map_err(StoreError::Json))
@@ -290,11 +378,342 @@ impl SledStore {
         let path = path.as_ref().join("matrix-sdk-state");
         let db = Config::new().temporary(false).path(&path).open()?;
 
+        if let Some(marker) = Self::rekey_marker(&db)? {
+            return SledStore::open_interrupted_rekey(db, path, marker, None);
+        }
+
         SledStore::open_helper(db, Some(path), None)
     }
 
-    fn serialize_event(&self, event: &impl Serialize) -> Result<Vec<u8>, SerializationError> {
-        if let Some(key) = &*self.store_key {
+    /// Open a store whose re-keying was interrupted and finish the re-keying.
+    ///
+    /// Such a store needs to be opened using the new passphrase, the store key
+    /// the events are still encrypted with is restored from the marker.
+    fn open_interrupted_rekey(
+        db: Db,
+        path: PathBuf,
+        mut marker: RekeyMarker,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        info!("Finishing an interrupted re-keying of the Sled state store");
+
+        let store_key = match marker.old_key.take() {
+            Some(key) => Some(
+                StoreKey::import(passphrase.unwrap_or_default(), key)
+                    .map_err(|_| StoreError::StoreLocked)?,
+            ),
+            // The store wasn't encrypted, the new store key gets checked
+            // against the passphrase once the re-keying is finished.
+            None if marker.next_key.is_some() => None,
+            None => return Err(StoreError::StoreLocked),
+        };
+
+        let store = SledStore::open_helper(db, Some(path), store_key)?;
+        store.finish_rekey_helper(marker, passphrase)?;
+        store.inner.flush()?;
+
+        Ok(store)
+    }
+
+    /// Change the passphrase that is used to encrypt the store.
+    ///
+    /// If the store is encrypted and stays encrypted only the store key gets
+    /// encrypted using the new passphrase. Encrypting a previously
+    /// unencrypted store, or removing the encryption of an encrypted store,
+    /// re-encrypts all the events in the store.
+    ///
+    /// If the process is interrupted the store needs to be opened using the
+    /// new passphrase, which finishes the change.
+    ///
+    /// The crypto store might share the database with the state store, in that
+    /// case use `BaseClient::change_store_passphrase()` which changes the
+    /// passphrase of both stores.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the store,
+    /// `None` if the store isn't encrypted.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the store from
+    /// now on, `None` if the store shouldn't be encrypted anymore.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+    ) -> Result<()> {
+        if old_passphrase.is_none() && new_passphrase.is_none() {
+            return self.unlock(None).map(|_| ());
+        }
+
+        let mut batch = Batch::default();
+        self.begin_rekey(&mut batch, old_passphrase, new_passphrase, false)?;
+        self.apply_rekey_batch(batch).await?;
+
+        self.finish_rekey(new_passphrase).await
+    }
+
+    /// Re-encrypt all the events in the store under a freshly generated store
+    /// key.
+    ///
+    /// This should be used if the store key might have been compromised,
+    /// unlike [`SledStore::change_passphrase()`] this replaces the store key
+    /// itself. If the process is interrupted the re-keying is finished the
+    /// next time the store is opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that protects the store, the new store
+    /// key will be encrypted using the same passphrase.
+    pub async fn rekey(&self, passphrase: &str) -> Result<()> {
+        let mut batch = Batch::default();
+        self.begin_rekey(&mut batch, Some(passphrase), Some(passphrase), true)?;
+        self.apply_rekey_batch(batch).await?;
+
+        self.finish_rekey(Some(passphrase)).await
+    }
+
+    fn rekey_marker(db: &Db) -> Result<Option<RekeyMarker>> {
+        Ok(db.get(REKEY_MARKER.encode())?.map(|m| serde_json::from_slice(&m)).transpose()?)
+    }
+
+    /// Start to re-key the store.
+    ///
+    /// A marker containing the current store key, encrypted using the new
+    /// passphrase, is added to the given batch. After the batch has been
+    /// applied the re-keying needs to be finished using
+    /// [`SledStore::finish_rekey()`], if the process gets interrupted before
+    /// that the re-keying is finished the next time the store is opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The batch the marker should be added to.
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the store.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the store from
+    /// now on.
+    ///
+    /// * `new_key` - Should a new store key be generated even if the store
+    /// stays encrypted.
+    pub(crate) fn begin_rekey(
+        &self,
+        batch: &mut Batch,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+        new_key: bool,
+    ) -> Result<()> {
+        let old_key = self.unlock(old_passphrase)?;
+
+        let next_key = match new_passphrase {
+            Some(passphrase) if new_key || old_key.is_none() => Some(
+                StoreKey::new()
+                    .and_then(|k| k.export(passphrase))
+                    .map_err::<StoreError, _>(|e| e.into())?,
+            ),
+            _ => None,
+        };
+
+        let old_key = old_key
+            .map(|k| k.export(new_passphrase.unwrap_or_default()))
+            .transpose()
+            .map_err::<StoreError, _>(|e| e.into())?;
+
+        batch
+            .insert(REKEY_MARKER.encode(), serde_json::to_vec(&RekeyMarker { old_key, next_key })?);
+
+        Ok(())
+    }
+
+    /// Atomically apply a batch containing re-keying markers.
+    pub(crate) async fn apply_rekey_batch(&self, batch: Batch) -> Result<()> {
+        self.inner.apply_batch(batch)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Finish a re-keying that was started using
+    /// [`SledStore::begin_rekey()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should protect the store from now
+    /// on.
+    pub(crate) async fn finish_rekey(&self, passphrase: Option<&str>) -> Result<()> {
+        let _lock = self.rekey_lock.write().await;
+
+        if let Some(marker) = Self::rekey_marker(&self.inner)? {
+            let store = self.clone();
+            let passphrase = passphrase.map(|p| Zeroizing::new(p.to_owned()));
+
+            // Re-encrypting the store rewrites every event, don't block the
+            // executor while doing so.
+            spawn_blocking(move || {
+                store.finish_rekey_helper(marker, passphrase.as_deref().map(|p| p.as_str()))
+            })
+            .await??;
+
+            self.inner.flush_async().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt the store key using the new passphrase, or re-encrypt all the
+    /// events in the store if the store key needs to change, and remove the
+    /// re-keying marker.
+    ///
+    /// Returns [`StoreError::StoreLocked`] if the passphrase isn't the new
+    /// passphrase of the re-keying.
+    ///
+    /// The caller needs to hold the `rekey_lock` for writing.
+    fn finish_rekey_helper(&self, marker: RekeyMarker, passphrase: Option<&str>) -> Result<()> {
+        match (passphrase, marker.next_key) {
+            (Some(passphrase), Some(next_key)) => {
+                let store_key =
+                    StoreKey::import(passphrase, next_key).map_err(|_| StoreError::StoreLocked)?;
+                let encrypted_key = DatabaseType::Encrypted(
+                    store_key.export(passphrase).map_err::<StoreError, _>(|e| e.into())?,
+                );
+
+                self.reencrypt(Some(store_key), encrypted_key)
+            }
+            (Some(passphrase), None) => {
+                // The store key stays the same, it only needs to be encrypted
+                // using the new passphrase.
+                let encrypted_key = self
+                    .store_key
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|k| k.export(passphrase))
+                    .transpose()
+                    .map_err::<StoreError, _>(|e| e.into())?
+                    .ok_or(StoreError::StoreLocked)?;
+
+                let mut batch = Batch::default();
+                batch.insert(
+                    "store_key".encode(),
+                    serde_json::to_vec(&DatabaseType::Encrypted(encrypted_key))?,
+                );
+                batch.remove(REKEY_MARKER.encode());
+
+                Ok(self.inner.apply_batch(batch)?)
+            }
+            (None, None) => self.reencrypt(None, DatabaseType::Unencrypted),
+            (None, Some(_)) => Err(StoreError::StoreLocked),
+        }
+    }
+
+    /// Decrypt the store key that is stored in the database using the given
+    /// passphrase.
+    ///
+    /// Returns `None` if the store isn't encrypted.
+    fn unlock(&self, passphrase: Option<&str>) -> Result<Option<StoreKey>> {
+        let database_type: Option<DatabaseType> = self
+            .inner
+            .get("store_key".encode())?
+            .map(|k| serde_json::from_slice(&k).map_err(StoreError::Json))
+            .transpose()?;
+
+        match (database_type, passphrase) {
+            (Some(DatabaseType::Encrypted(key)), Some(passphrase)) => {
+                Ok(Some(StoreKey::import(passphrase, key).map_err(|_| StoreError::StoreLocked)?))
+            }
+            (Some(DatabaseType::Encrypted(_)), None) => Err(StoreError::StoreLocked),
+            (_, Some(_)) => Err(StoreError::UnencryptedStore),
+            (_, None) => Ok(None),
+        }
+    }
+
+    /// Re-encrypt all the events in the store using the given store key and
+    /// remove the re-keying marker.
+    ///
+    /// The caller needs to hold the `rekey_lock` for writing and flush the
+    /// database afterwards.
+    fn reencrypt(&self, store_key: Option<StoreKey>, database_type: DatabaseType) -> Result<()> {
+        let trees = [
+            &self.account_data,
+            &self.members,
+            &self.profiles,
+            &self.display_names,
+            &self.room_info,
+            &self.room_state,
+            &self.room_account_data,
+            &self.stripped_room_info,
+            &self.stripped_room_state,
+            &self.stripped_members,
+            &self.presence,
+            &self.room_user_receipts,
+            &self.room_event_receipts,
+        ];
+
+        let events = {
+            let old_key = self.store_key.read().unwrap();
+
+            trees
+                .iter()
+                .map(|tree| {
+                    tree.iter()
+                        .map(|entry| {
+                            let (key, event) = entry?;
+                            let event: serde_json::Value =
+                                Self::deserialize_with_key(old_key.as_ref(), &event)?;
+
+                            Ok((key, Self::serialize_with_key(store_key.as_ref(), &event)?))
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        let database_type = serde_json::to_vec(&database_type)?;
+
+        let ret: Result<(), TransactionError<SerializationError>> = (
+            &*self.inner,
+            trees[0],
+            trees[1],
+            trees[2],
+            trees[3],
+            trees[4],
+            trees[5],
+            trees[6],
+            trees[7],
+            trees[8],
+            trees[9],
+            trees[10],
+            trees[11],
+            trees[12],
+        )
+            .transaction(
+                |(inner, t0, t1, t2, t3, t4, t5, t6, t7, t8, t9, t10, t11, t12)| {
+                    let trees = [t0, t1, t2, t3, t4, t5, t6, t7, t8, t9, t10, t11, t12];
+
+                    for (tree, events) in trees.iter().zip(&events) {
+                        for (key, event) in events {
+                            tree.insert(key.clone(), event.as_slice())?;
+                        }
+                    }
+
+                    inner.insert("store_key".encode(), database_type.as_slice())?;
+                    inner.remove(REKEY_MARKER.encode())?;
+
+                    Ok(())
+                },
+            );
+
+        ret?;
+
+        *self.store_key.write().unwrap() = store_key;
+
+        Ok(())
+    }
+
+    fn serialize_with_key(
+        key: Option<&StoreKey>,
+        event: &impl Serialize,
+    ) -> Result<Vec<u8>, SerializationError> {
+        if let Some(key) = key {
             let encrypted = key.encrypt(event)?;
             Ok(serde_json::to_vec(&encrypted)?)
         } else {
@@ -302,11 +721,11 @@ impl SledStore {
         }
     }
 
-    fn deserialize_event<T: for<'b> Deserialize<'b>>(
-        &self,
+    fn deserialize_with_key<T: for<'b> Deserialize<'b>>(
+        key: Option<&StoreKey>,
         event: &[u8],
     ) -> Result<T, SerializationError> {
-        if let Some(key) = &*self.store_key {
+        if let Some(key) = key {
             let encrypted: EncryptedEvent = serde_json::from_slice(event)?;
             Ok(key.decrypt(encrypted)?)
         } else {
@@ -314,6 +733,17 @@ impl SledStore {
         }
     }
 
+    fn serialize_event(&self, event: &impl Serialize) -> Result<Vec<u8>, SerializationError> {
+        Self::serialize_with_key(self.store_key.read().unwrap().as_ref(), event)
+    }
+
+    fn deserialize_event<T: for<'b> Deserialize<'b>>(
+        &self,
+        event: &[u8],
+    ) -> Result<T, SerializationError> {
+        Self::deserialize_with_key(self.store_key.read().unwrap().as_ref(), event)
+    }
+
     pub async fn save_filter(&self, filter_name: &str, filter_id: &str) -> Result<()> {
         self.session.insert(("filter", filter_name).encode(), filter_id)?;
 
@@ -632,10 +1062,7 @@ impl SledStore {
         .await?
     }
 
-    pub async fn get_user_ids_stream(
-        &self,
-        room_id: &RoomId,
-    ) -> Result<impl Stream<Item = Result<Box<UserId>>>> {
+    pub async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
         let decode = |key: &[u8]| -> Result<Box<UserId>> {
             let mut iter = key.split(|c| c == &ENCODE_SEPARATOR);
             // Our key is a the room id separated from the user id by a null
@@ -650,65 +1077,56 @@ impl SledStore {
         let members = self.members.clone();
         let key = room_id.encode();
 
-        spawn_blocking(move || stream::iter(members.scan_prefix(key).map(move |u| decode(&u?.0))))
-            .await
-            .map_err(Into::into)
+        spawn_blocking(move || members.scan_prefix(key).map(|u| decode(&u?.0)).collect()).await?
     }
 
-    pub async fn get_invited_user_ids(
-        &self,
-        room_id: &RoomId,
-    ) -> Result<impl Stream<Item = Result<Box<UserId>>>> {
+    pub async fn get_invited_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
         let db = self.clone();
         let key = room_id.encode();
         spawn_blocking(move || {
-            stream::iter(db.invited_user_ids.scan_prefix(key).map(|u| {
-                Box::<UserId>::try_from(String::from_utf8_lossy(&u?.1).to_string())
-                    .map_err(StoreError::Identifier)
-            }))
+            db.invited_user_ids
+                .scan_prefix(key)
+                .map(|u| {
+                    Box::<UserId>::try_from(String::from_utf8_lossy(&u?.1).to_string())
+                        .map_err(StoreError::Identifier)
+                })
+                .collect()
         })
-        .await
-        .map_err(Into::into)
+        .await?
     }
 
-    pub async fn get_joined_user_ids(
-        &self,
-        room_id: &RoomId,
-    ) -> Result<impl Stream<Item = Result<Box<UserId>>>> {
+    pub async fn get_joined_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
         let db = self.clone();
         let key = room_id.encode();
         spawn_blocking(move || {
-            stream::iter(db.joined_user_ids.scan_prefix(key).map(|u| {
-                Box::<UserId>::try_from(String::from_utf8_lossy(&u?.1).to_string())
-                    .map_err(StoreError::Identifier)
-            }))
+            db.joined_user_ids
+                .scan_prefix(key)
+                .map(|u| {
+                    Box::<UserId>::try_from(String::from_utf8_lossy(&u?.1).to_string())
+                        .map_err(StoreError::Identifier)
+                })
+                .collect()
         })
-        .await
-        .map_err(Into::into)
+        .await?
     }
 
-    pub async fn get_room_infos(&self) -> Result<impl Stream<Item = Result<RoomInfo>>> {
+    pub async fn get_room_infos(&self) -> Result<Vec<RoomInfo>> {
         let db = self.clone();
         spawn_blocking(move || {
-            stream::iter(
-                db.room_info.iter().map(move |r| db.deserialize_event(&r?.1).map_err(|e| e.into())),
-            )
+            db.room_info.iter().map(|r| db.deserialize_event(&r?.1).map_err(Into::into)).collect()
         })
-        .await
-        .map_err(Into::into)
+        .await?
     }
 
-    pub async fn get_stripped_room_infos(&self) -> Result<impl Stream<Item = Result<RoomInfo>>> {
+    pub async fn get_stripped_room_infos(&self) -> Result<Vec<RoomInfo>> {
         let db = self.clone();
         spawn_blocking(move || {
-            stream::iter(
-                db.stripped_room_info
-                    .iter()
-                    .map(move |r| db.deserialize_event(&r?.1).map_err(|e| e.into())),
-            )
+            db.stripped_room_info
+                .iter()
+                .map(|r| db.deserialize_event(&r?.1).map_err(Into::into))
+                .collect()
         })
-        .await
-        .map_err(Into::into)
+        .await?
     }
 
     pub async fn get_users_with_display_name(
@@ -851,22 +1269,32 @@ impl SledStore {
 #[async_trait]
 impl StateStore for SledStore {
     async fn save_filter(&self, filter_name: &str, filter_id: &str) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         self.save_filter(filter_name, filter_id).await
     }
 
     async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         self.save_changes(changes).await
     }
 
     async fn get_filter(&self, filter_id: &str) -> Result<Option<String>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_filter(filter_id).await
     }
 
     async fn get_sync_token(&self) -> Result<Option<String>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_sync_token().await
     }
 
     async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<Raw<PresenceEvent>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_presence_event(user_id).await
     }
 
@@ -876,6 +1304,8 @@ impl StateStore for SledStore {
         event_type: EventType,
         state_key: &str,
     ) -> Result<Option<Raw<AnySyncStateEvent>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_state_event(room_id, event_type, state_key).await
     }
 
@@ -884,6 +1314,8 @@ impl StateStore for SledStore {
         room_id: &RoomId,
         event_type: EventType,
     ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_state_events(room_id, event_type).await
     }
 
@@ -892,6 +1324,8 @@ impl StateStore for SledStore {
         room_id: &RoomId,
         user_id: &UserId,
     ) -> Result<Option<RoomMemberEventContent>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_profile(room_id, user_id).await
     }
 
@@ -900,27 +1334,39 @@ impl StateStore for SledStore {
         room_id: &RoomId,
         state_key: &UserId,
     ) -> Result<Option<MemberEvent>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_member_event(room_id, state_key).await
     }
 
     async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
-        self.get_user_ids_stream(room_id).await?.try_collect().await
+        let _lock = self.rekey_lock.read().await;
+
+        self.get_user_ids(room_id).await
     }
 
     async fn get_invited_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
-        self.get_invited_user_ids(room_id).await?.try_collect().await
+        let _lock = self.rekey_lock.read().await;
+
+        self.get_invited_user_ids(room_id).await
     }
 
     async fn get_joined_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
-        self.get_joined_user_ids(room_id).await?.try_collect().await
+        let _lock = self.rekey_lock.read().await;
+
+        self.get_joined_user_ids(room_id).await
     }
 
     async fn get_room_infos(&self) -> Result<Vec<RoomInfo>> {
-        self.get_room_infos().await?.try_collect().await
+        let _lock = self.rekey_lock.read().await;
+
+        self.get_room_infos().await
     }
 
     async fn get_stripped_room_infos(&self) -> Result<Vec<RoomInfo>> {
-        self.get_stripped_room_infos().await?.try_collect().await
+        let _lock = self.rekey_lock.read().await;
+
+        self.get_stripped_room_infos().await
     }
 
     async fn get_users_with_display_name(
@@ -928,6 +1374,8 @@ impl StateStore for SledStore {
         room_id: &RoomId,
         display_name: &str,
     ) -> Result<BTreeSet<Box<UserId>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_users_with_display_name(room_id, display_name).await
     }
 
@@ -935,6 +1383,8 @@ impl StateStore for SledStore {
         &self,
         event_type: EventType,
     ) -> Result<Option<Raw<AnyGlobalAccountDataEvent>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_account_data_event(event_type).await
     }
 
@@ -943,6 +1393,8 @@ impl StateStore for SledStore {
         room_id: &RoomId,
         event_type: EventType,
     ) -> Result<Option<Raw<AnyRoomAccountDataEvent>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_room_account_data_event(room_id, event_type).await
     }
 
@@ -952,6 +1404,8 @@ impl StateStore for SledStore {
         receipt_type: ReceiptType,
         user_id: &UserId,
     ) -> Result<Option<(Box<EventId>, Receipt)>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_user_room_receipt_event(room_id, receipt_type, user_id).await
     }
 
@@ -961,30 +1415,44 @@ impl StateStore for SledStore {
         receipt_type: ReceiptType,
         event_id: &EventId,
     ) -> Result<Vec<(Box<UserId>, Receipt)>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_event_room_receipt_events(room_id, receipt_type, event_id).await
     }
 
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_custom_value(key).await
     }
 
     async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.set_custom_value(key, value).await
     }
 
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         self.add_media_content(request, data).await
     }
 
     async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.get_media_content(request).await
     }
 
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         self.remove_media_content(request).await
     }
 
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         self.remove_media_content_for_uri(uri).await
     }
 }
@@ -1010,7 +1478,7 @@ mod test {
     };
     use serde_json::json;
 
-    use super::{Result, SledStore, StateChanges};
+    use super::{Batch, EncodeKey, Result, SledStore, StateChanges, StoreError, DATABASE_VERSION};
     use crate::{
         deserialized_responses::MemberEvent,
         media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
@@ -1049,6 +1517,24 @@ mod test {
         }
     }
 
+    #[async_test]
+    async fn test_database_version() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let store = SledStore::open_helper(db.clone(), None, None).unwrap();
+        assert_eq!(store.database_version().unwrap(), DATABASE_VERSION);
+        drop(store);
+
+        // Pretend the database was written by a newer version of the library.
+        db.insert("store_version".encode(), (DATABASE_VERSION + 1).to_be_bytes().as_ref()).unwrap();
+
+        assert!(matches!(
+            SledStore::open_helper(db, None, None),
+            Err(StoreError::UnsupportedDatabaseVersion { version, max_supported })
+                if version == DATABASE_VERSION + 1 && max_supported == DATABASE_VERSION
+        ));
+    }
+
     #[async_test]
     async fn test_member_saving() {
         let store = SledStore::open().unwrap();
@@ -1070,6 +1556,129 @@ mod test {
         assert!(!members.is_empty())
     }
 
+    #[async_test]
+    async fn test_change_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_path(dir.path()).unwrap();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned(), membership_event());
+        store.save_changes(&changes).await.unwrap();
+
+        store.change_passphrase(None, Some("secret")).await.unwrap();
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+
+        store.change_passphrase(Some("secret"), Some("new secret")).await.unwrap();
+        assert!(matches!(
+            store.change_passphrase(Some("secret"), None).await,
+            Err(StoreError::StoreLocked)
+        ));
+
+        store.rekey("new secret").await.unwrap();
+        drop(store);
+
+        assert!(matches!(
+            SledStore::open_with_passphrase(dir.path(), "secret"),
+            Err(StoreError::StoreLocked)
+        ));
+
+        let store = SledStore::open_with_passphrase(dir.path(), "new secret").unwrap();
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+
+        store.change_passphrase(Some("new secret"), None).await.unwrap();
+        drop(store);
+
+        assert!(matches!(
+            SledStore::open_with_passphrase(dir.path(), "new secret"),
+            Err(StoreError::UnencryptedStore)
+        ));
+
+        let store = SledStore::open_with_path(dir.path()).unwrap();
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn test_interrupted_rekey() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_passphrase(dir.path(), "secret").unwrap();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned(), membership_event());
+        store.save_changes(&changes).await.unwrap();
+
+        // Only start the re-keying, as if the process got interrupted.
+        let mut batch = Batch::default();
+        store.begin_rekey(&mut batch, Some("secret"), Some("new secret"), true).unwrap();
+        store.apply_rekey_batch(batch).await.unwrap();
+        drop(store);
+
+        assert!(matches!(
+            SledStore::open_with_passphrase(dir.path(), "secret"),
+            Err(StoreError::StoreLocked)
+        ));
+
+        let store = SledStore::open_with_passphrase(dir.path(), "new secret").unwrap();
+        assert!(SledStore::rekey_marker(&store.inner).unwrap().is_none());
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), "new secret").unwrap();
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn test_interrupted_encryption_of_an_unencrypted_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_path(dir.path()).unwrap();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned(), membership_event());
+        store.save_changes(&changes).await.unwrap();
+
+        // Only start the re-keying, as if the process got interrupted.
+        let mut batch = Batch::default();
+        store.begin_rekey(&mut batch, None, Some("secret"), false).unwrap();
+        store.apply_rekey_batch(batch).await.unwrap();
+        drop(store);
+
+        assert!(matches!(SledStore::open_with_path(dir.path()), Err(StoreError::StoreLocked)));
+        assert!(matches!(
+            SledStore::open_with_passphrase(dir.path(), "wrong secret"),
+            Err(StoreError::StoreLocked)
+        ));
+
+        let store = SledStore::open_with_passphrase(dir.path(), "secret").unwrap();
+        assert!(SledStore::rekey_marker(&store.inner).unwrap().is_none());
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+        drop(store);
+
+        assert!(matches!(
+            SledStore::open_with_passphrase(dir.path(), "wrong secret"),
+            Err(StoreError::StoreLocked)
+        ));
+
+        let store = SledStore::open_with_passphrase(dir.path(), "secret").unwrap();
+        assert!(store.get_member_event(room_id, user_id).await.unwrap().is_some());
+    }
+
     #[async_test]
     async fn test_power_level_saving() {
         let store = SledStore::open().unwrap();
@@ -86,6 +86,43 @@ pub struct EncryptionInfo {
     pub verification_state: VerificationState,
 }
 
+/// The reason why an event couldn't be decrypted.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum UnableToDecryptReason {
+    /// We don't have the room key that was used to encrypt the event.
+    MissingSession,
+    /// The sender withheld the room key that was used to encrypt the event
+    /// from us.
+    Withheld {
+        /// The `m.room_key.withheld` code the sender gave us, e.g.
+        /// `m.unverified`.
+        code: String,
+    },
+    /// We have the room key, but only starting from a later message index
+    /// than the one of the event.
+    UnknownMessageIndex,
+    /// The Olm library failed to decrypt the event, e.g. because the message
+    /// was tampered with.
+    OlmFailure,
+    /// The event couldn't be decrypted for another reason, e.g. because it was
+    /// malformed or replayed.
+    Other,
+}
+
+/// Struct containing information on why an event couldn't be decrypted.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UnableToDecryptInfo {
+    /// The ID of the room key that was used to encrypt the event.
+    pub session_id: String,
+    /// The curve25519 key of the device that created the room key.
+    pub sender_key: String,
+    /// The reason why the event couldn't be decrypted.
+    pub reason: UnableToDecryptReason,
+    /// Did we request the room key from our other devices without receiving
+    /// it yet.
+    pub key_request_pending: bool,
+}
+
 /// A customized version of a room event coming from a sync that holds optional
 /// encryption info.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -95,11 +132,15 @@ pub struct SyncRoomEvent {
     /// The encryption info about the event. Will be `None` if the event was not
     /// encrypted.
     pub encryption_info: Option<EncryptionInfo>,
+    /// Information on why the event couldn't be decrypted. Will be `None` if
+    /// the event was not encrypted or was decrypted successfully.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unable_to_decrypt_info: Option<UnableToDecryptInfo>,
 }
 
 impl From<Raw<AnySyncRoomEvent>> for SyncRoomEvent {
     fn from(inner: Raw<AnySyncRoomEvent>) -> Self {
-        Self { encryption_info: None, event: inner }
+        Self { encryption_info: None, unable_to_decrypt_info: None, event: inner }
     }
 }
 
@@ -142,6 +183,9 @@ pub struct RoomEvent {
     /// The encryption info about the event. Will be `None` if the event was not
     /// encrypted.
     pub encryption_info: Option<EncryptionInfo>,
+    /// Information on why the event couldn't be decrypted. Will be `None` if
+    /// the event was not encrypted or was decrypted successfully.
+    pub unable_to_decrypt_info: Option<UnableToDecryptInfo>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -17,7 +17,6 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use olm_rs::pk::OlmPkEncryption;
 use ruma::{
     api::client::r0::backup::{KeyBackupData, KeyBackupDataInit, SessionDataInit},
     DeviceKeyId, UserId,
@@ -25,7 +24,7 @@ use ruma::{
 use zeroize::Zeroizing;
 
 use super::recovery::DecodeError;
-use crate::olm::InboundGroupSession;
+use crate::olm::{InboundGroupSession, RawPkEncryption};
 
 #[derive(Debug)]
 struct InnerBackupKey {
@@ -109,7 +108,7 @@ impl MegolmV1BackupKey {
     }
 
     pub(crate) async fn encrypt(&self, session: InboundGroupSession) -> KeyBackupData {
-        let pk = OlmPkEncryption::new(&self.to_base64());
+        let pk = RawPkEncryption::from_base64(&self.to_base64());
 
         // It's ok to truncate here, there's a semantic difference only between
         // 0 and 1+ anyways.
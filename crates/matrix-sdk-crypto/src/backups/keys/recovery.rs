@@ -23,17 +23,16 @@ use aes_gcm::{
     Aes256Gcm,
 };
 use bs58;
-use olm_rs::{
-    errors::OlmPkDecryptionError,
-    pk::{OlmPkDecryption, PkMessage},
-};
 use rand::{thread_rng, Error as RandomError, Fill};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use zeroize::{Zeroize, Zeroizing};
 
 use super::MegolmV1BackupKey;
-use crate::utilities::{decode_url_safe, encode, encode_url_safe};
+use crate::{
+    olm::{OlmPkDecryptionError, PkMessage, RawPkDecryption},
+    utilities::{decode_url_safe, encode, encode_url_safe},
+};
 
 const NONCE_SIZE: usize = 12;
 
@@ -223,16 +222,14 @@ impl RecoveryKey {
         bs58::encode(bytes.as_slice()).with_alphabet(bs58::Alphabet::BITCOIN).into_string()
     }
 
-    fn get_pk_decrytpion(&self) -> OlmPkDecryption {
-        OlmPkDecryption::from_bytes(self.inner.as_ref())
-            .expect("Can't generate a libom PkDecryption object from our private key")
+    fn get_pk_decrytpion(&self) -> RawPkDecryption {
+        RawPkDecryption::from_bytes(&self.inner)
     }
 
     /// Extract the megolm.v1 public key from this `RecoveryKey`.
     pub fn megolm_v1_public_key(&self) -> MegolmV1BackupKey {
         let pk = self.get_pk_decrytpion();
-        let public_key = MegolmV1BackupKey::new(pk.public_key(), None);
-        public_key
+        MegolmV1BackupKey::new(&pk.public_key(), None)
     }
 
     /// Export this [`RecoveryKey`] as an encrypted pickle that can be safely
@@ -299,7 +296,7 @@ impl RecoveryKey {
         ephemeral_key: String,
         ciphertext: String,
     ) -> Result<String, OlmPkDecryptionError> {
-        let message = PkMessage::new(mac, ephemeral_key, ciphertext);
+        let message = PkMessage { ciphertext, mac, ephemeral_key };
         let pk = self.get_pk_decrytpion();
 
         pk.decrypt(message)
@@ -308,7 +305,10 @@ impl RecoveryKey {
 
 #[cfg(test)]
 mod test {
+    use matches::assert_matches;
+
     use super::{DecodeError, RecoveryKey};
+    use crate::olm::{OlmPkDecryptionError, RawPkEncryption};
 
     const TEST_KEY: [u8; 32] = [
         0x77, 0x07, 0x6D, 0x0A, 0x73, 0x18, 0xA5, 0x7D, 0x3C, 0x16, 0xC1, 0x72, 0x51, 0xB2, 0x66,
@@ -351,4 +351,52 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn megolm_v1_public_key() {
+        let key = RecoveryKey::from_bytes(TEST_KEY);
+
+        assert_eq!(
+            key.megolm_v1_public_key().to_base64(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo",
+            "The public key doesn't match the curve25519 public key of the test key"
+        );
+    }
+
+    #[test]
+    fn decrypt_v1() {
+        let key = RecoveryKey::from_bytes(TEST_KEY);
+
+        let decrypted = key
+            .decrypt_v1(
+                "zpzU6BkZcNI".to_owned(),
+                "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08".to_owned(),
+                "8SsGt8JRdhUvYg+Oo1//8m6owWbrapJ8Gq3n2Es10No".to_owned(),
+            )
+            .expect("Can't decrypt the test message");
+        assert_eq!(decrypted, "It's a secret to everybody");
+
+        assert_matches!(
+            key.decrypt_v1(
+                "AAAAAAAAAAA".to_owned(),
+                "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08".to_owned(),
+                "8SsGt8JRdhUvYg+Oo1//8m6owWbrapJ8Gq3n2Es10No".to_owned(),
+            ),
+            Err(OlmPkDecryptionError::Mac)
+        );
+    }
+
+    #[test]
+    fn encryption_roundtrip() {
+        let key = RecoveryKey::new().expect("Can't create a new recovery key");
+        let plaintext = "It's a secret to everybody";
+
+        let message = RawPkEncryption::from_base64(&key.megolm_v1_public_key().to_base64())
+            .encrypt(plaintext);
+
+        let decrypted = key
+            .decrypt_v1(message.mac, message.ephemeral_key, message.ciphertext)
+            .expect("Can't decrypt the message");
+        assert_eq!(decrypted, plaintext);
+    }
 }
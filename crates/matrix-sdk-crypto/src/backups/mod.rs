@@ -30,23 +30,25 @@ use std::{
 
 use matrix_sdk_common::{locks::RwLock, uuid::Uuid};
 use ruma::{
-    api::client::r0::backup::RoomKeyBackup, serde::Raw, DeviceKeyAlgorithm, DeviceKeyId, RoomId,
-    UserId,
+    api::client::r0::backup::{KeyBackupData, RoomKeyBackup},
+    serde::Raw,
+    DeviceKeyAlgorithm, DeviceKeyId, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tracing::{debug, info, instrument, trace, warn};
+use zeroize::Zeroizing;
 
 use crate::{
-    olm::{Account, InboundGroupSession},
+    olm::{Account, BackedUpRoomKey, ExportedRoomKey, InboundGroupSession},
     store::{BackupKeys, Changes, RoomKeyCounts, Store},
-    CryptoStoreError, KeysBackupRequest, OutgoingRequest,
+    CryptoStoreError, KeysBackupRequest, OutgoingRequest, RoomKeyImportResult,
 };
 
 mod keys;
 
+pub use crate::olm::OlmPkDecryptionError;
 pub use keys::{DecodeError, MegolmV1BackupKey, PickledRecoveryKey, RecoveryKey};
-pub use olm_rs::errors::OlmPkDecryptionError;
 
 /// A state machine that handles backing up room keys.
 ///
@@ -105,6 +107,12 @@ impl BackupMachine {
         self.backup_key.read().await.as_ref().map(|b| b.backup_version().is_some()).unwrap_or(false)
     }
 
+    /// Get the version of the backup we're currently backing up room keys to,
+    /// if backups are enabled.
+    pub async fn backup_version(&self) -> Option<String> {
+        self.backup_key.read().await.as_ref().and_then(|k| k.backup_version())
+    }
+
     /// Verify some backup auth data that we downloaded from the server.
     ///
     /// The auth data should be fetched from the server using the
@@ -238,6 +246,121 @@ impl BackupMachine {
         self.store.load_backup_keys().await
     }
 
+    /// Restore room keys from a server-side key backup.
+    ///
+    /// The room keys need to be downloaded from the server using one of the
+    /// [`/room_keys/keys`] endpoints, either the whole backup, the room keys of
+    /// a single room, or a single room key. The room keys are decrypted using
+    /// the given [`RecoveryKey`] and imported into the store, they will be
+    /// marked as backed up.
+    ///
+    /// Room keys that fail to be decrypted, e.g. because they were encrypted
+    /// for a different backup key, are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key of the backup the room keys were
+    /// downloaded from.
+    ///
+    /// * `backup` - The downloaded room keys, a map from the room id to the
+    /// backed up room keys of the room.
+    ///
+    /// * `progress_listener` - A closure that will be called with the index of
+    /// the room key that is being decrypted and the total number of room keys
+    /// that are part of the backup.
+    ///
+    /// [`/room_keys/keys`]: https://spec.matrix.org/unstable/client-server-api/#get_matrixclientv3room_keyskeys
+    #[instrument(skip(self, recovery_key, backup, progress_listener))]
+    pub async fn import_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        backup: BTreeMap<Box<RoomId>, RoomKeyBackup>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult, CryptoStoreError> {
+        let total_count = backup.values().map(|r| r.sessions.len()).sum();
+        let mut decrypted_keys = Vec::new();
+        let mut index = 0;
+
+        for (room_id, room_backup) in backup {
+            for (session_id, key_data) in room_backup.sessions {
+                if let Some(key) =
+                    Self::decrypt_room_key(recovery_key, &room_id, session_id, key_data)
+                {
+                    decrypted_keys.push(key);
+                }
+
+                progress_listener(index, total_count);
+                index += 1;
+            }
+        }
+
+        debug!(
+            total_count,
+            decrypted_count = decrypted_keys.len(),
+            "Decrypted room keys from the backup"
+        );
+
+        let result = self.store.import_room_keys(decrypted_keys, true, |_, _| {}).await?;
+
+        Ok(RoomKeyImportResult::new(result.imported_count, total_count, result.keys))
+    }
+
+    fn decrypt_room_key(
+        recovery_key: &RecoveryKey,
+        room_id: &RoomId,
+        session_id: String,
+        key_data: Raw<KeyBackupData>,
+    ) -> Option<ExportedRoomKey> {
+        let key_data = match key_data.deserialize() {
+            Ok(k) => k,
+            Err(e) => {
+                warn!(
+                    room_id = room_id.as_str(),
+                    session_id = session_id.as_str(),
+                    error =? e,
+                    "Backed up room key has an invalid format"
+                );
+                return None;
+            }
+        };
+
+        let session_data = key_data.session_data;
+
+        let decrypted = match recovery_key.decrypt_v1(
+            session_data.mac,
+            session_data.ephemeral,
+            session_data.ciphertext,
+        ) {
+            Ok(d) => Zeroizing::new(d),
+            Err(e) => {
+                warn!(
+                    room_id = room_id.as_str(),
+                    session_id = session_id.as_str(),
+                    error =? e,
+                    "Couldn't decrypt a backed up room key"
+                );
+                return None;
+            }
+        };
+
+        match serde_json::from_str::<BackedUpRoomKey>(&decrypted) {
+            Ok(room_key) => Some(ExportedRoomKey::from_backed_up_room_key(
+                room_id.to_owned(),
+                session_id,
+                room_key,
+            )),
+            Err(e) => {
+                warn!(
+                    room_id = room_id.as_str(),
+                    session_id = session_id.as_str(),
+                    error =? e,
+                    "Decrypted a backed up room key but it has an invalid format"
+                );
+                None
+            }
+        }
+    }
+
     /// Encrypt a batch of room keys and return a request that needs to be sent
     /// out to backup the room keys.
     pub async fn backup(&self) -> Result<Option<OutgoingRequest>, CryptoStoreError> {
@@ -392,7 +515,7 @@ mod test {
     use ruma::{device_id, room_id, user_id, DeviceId, RoomId, UserId};
 
     use super::RecoveryKey;
-    use crate::{OlmError, OlmMachine};
+    use crate::{OlmError, OlmMachine, OutgoingRequests};
 
     fn alice_id() -> &'static UserId {
         user_id!("@alice:example.org")
@@ -461,6 +584,58 @@ mod test {
         Ok(())
     }
 
+    #[async_test]
+    async fn backup_restoring() -> Result<(), OlmError> {
+        let machine = OlmMachine::new(alice_id(), alice_device_id());
+        let backup_machine = machine.backup_machine();
+
+        machine.create_outbound_group_session_with_defaults(room_id()).await?;
+        machine.create_outbound_group_session_with_defaults(room_id2()).await?;
+
+        let recovery_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let backup_key = recovery_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+
+        backup_machine.enable_backup_v1(backup_key).await?;
+
+        let request =
+            backup_machine.backup().await?.expect("Created a backup request successfully");
+
+        let backup = match request.request() {
+            OutgoingRequests::KeysBackup(r) => r.rooms.clone(),
+            r => panic!("Expected a keys backup request, got {:?}", r),
+        };
+
+        let new_machine = OlmMachine::new(alice_id(), device_id!("NEWDEVICE"));
+        let new_backup_machine = new_machine.backup_machine();
+
+        let result =
+            new_backup_machine.import_backup(&recovery_key, backup.clone(), |_, _| {}).await?;
+
+        assert_eq!(result.total_count, 2, "The backup contained two room keys");
+        assert_eq!(result.imported_count, 2, "Both room keys have been imported");
+        assert!(result.keys.contains_key(room_id()));
+        assert!(result.keys.contains_key(room_id2()));
+
+        let counts = new_backup_machine.store.inbound_group_session_counts().await?;
+        assert_eq!(counts.total, 2, "Two room keys need to exist in the store");
+        assert_eq!(counts.backed_up, 2, "Restored room keys are marked as backed up");
+
+        let result =
+            new_backup_machine.import_backup(&recovery_key, backup.clone(), |_, _| {}).await?;
+        assert_eq!(result.imported_count, 0, "Restoring the same backup imports nothing new");
+
+        let wrong_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let other_machine = OlmMachine::new(alice_id(), device_id!("OTHERDEVICE"));
+
+        let result =
+            other_machine.backup_machine().import_backup(&wrong_key, backup, |_, _| {}).await?;
+        assert_eq!(result.total_count, 2);
+        assert_eq!(result.imported_count, 0, "A wrong recovery key can't decrypt the backup");
+
+        Ok(())
+    }
+
     #[async_test]
     async fn memory_store_backups() -> Result<(), OlmError> {
         let machine = OlmMachine::new(alice_id(), alice_device_id());
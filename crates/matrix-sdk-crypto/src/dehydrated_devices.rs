@@ -0,0 +1,179 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for [dehydrated devices][msc3814].
+//!
+//! A dehydrated device is an Olm account that is pickled, encrypted with a
+//! pickle key, and stored on the server. Other devices keep sending room keys
+//! to the dehydrated device while all of our real devices are offline. The
+//! next time we log in, the dehydrated device can be rehydrated and the room
+//! keys it received get imported into the store of the new device.
+//!
+//! The dehydrated device is never claimed, it stays a separate device of our
+//! account until it's replaced by a new dehydrated device.
+//!
+//! This module doesn't do any network IO, the requests this module produces
+//! need to be sent out, and the dehydrated device and its to-device events
+//! need to be fetched, by the user of this crate.
+//!
+//! [msc3814]: https://github.com/matrix-org/matrix-doc/pull/3814
+
+use std::collections::BTreeMap;
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use ruma::{
+    encryption::{DeviceKeys, OneTimeKey},
+    serde::Raw,
+    DeviceId, DeviceKeyId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+use thiserror::Error;
+
+use crate::{
+    olm::{OlmAccountError, PickledAccount, PicklingMode, ReadOnlyAccount},
+    store::CryptoStoreError,
+    OlmError,
+};
+
+/// The name of the only dehydrated device algorithm we support.
+///
+/// The device data contains a libolm account pickle that is encrypted with
+/// the pickle key.
+pub const DEHYDRATION_ALGORITHM: &str = "org.matrix.msc2697.v1.olm.libolm_pickle";
+
+/// Error type for the creation and rehydration of dehydrated devices.
+#[derive(Debug, Error)]
+pub enum DehydrationError {
+    /// The dehydrated device uses an algorithm we don't support.
+    #[error("The dehydrated device uses an unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    /// The pickled account couldn't be restored, most likely the wrong pickle
+    /// key was used.
+    #[error("The dehydrated device couldn't be unpickled: {0}")]
+    Pickle(#[from] OlmAccountError),
+    /// The to-device events of the dehydrated device couldn't be handled.
+    #[error(transparent)]
+    Olm(#[from] OlmError),
+    /// The room keys couldn't be exported from the dehydrated device or
+    /// imported into our store.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// The data of a dehydrated device, as it is stored on the server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DehydratedDeviceData {
+    /// The algorithm that was used to create the dehydrated device, should be
+    /// [`DEHYDRATION_ALGORITHM`].
+    pub algorithm: String,
+    /// The encrypted pickle of the Olm account of the dehydrated device.
+    pub account: String,
+}
+
+/// The length of the device id we generate for dehydrated devices.
+const DEVICE_ID_LENGTH: usize = 10;
+
+/// Request to store a dehydrated device on the server.
+///
+/// This should be sent out using the `PUT /dehydrated_device` endpoint, it
+/// replaces the dehydrated device that was previously stored on the server.
+#[derive(Clone, Debug)]
+pub struct DehydratedDeviceRequest {
+    /// The device id of the dehydrated device, the device id is chosen by us.
+    pub device_id: Box<DeviceId>,
+    /// The display name of the dehydrated device.
+    pub initial_device_display_name: String,
+    /// The data of the dehydrated device.
+    pub device_data: DehydratedDeviceData,
+    /// The signed identity keys of the dehydrated device.
+    pub device_keys: Raw<DeviceKeys>,
+    /// The signed one-time keys of the dehydrated device.
+    pub one_time_keys: BTreeMap<Box<DeviceKeyId>, Raw<OneTimeKey>>,
+}
+
+/// A freshly created dehydrated device that still needs to be uploaded using
+/// the request [`request()`](Self::request) returns.
+#[derive(Debug)]
+pub struct DehydratedDevice {
+    request: DehydratedDeviceRequest,
+}
+
+impl DehydratedDevice {
+    pub(crate) async fn new(user_id: &UserId, pickle_key: &[u8], display_name: &str) -> Self {
+        let device_id: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(DEVICE_ID_LENGTH)
+            .map(char::from)
+            .collect();
+        let device_id: Box<DeviceId> = device_id.into();
+
+        let account = ReadOnlyAccount::new(user_id, &device_id);
+
+        // The one-time keys need to be part of the pickle, otherwise the
+        // rehydrated account won't be able to create Olm sessions.
+        let _ = account.generate_one_time_keys().await;
+
+        let device_keys = account.device_keys().await;
+        let device_keys =
+            Raw::from_json(to_raw_value(&device_keys).expect("Couldn't serialize device keys"));
+        let one_time_keys = account.signed_one_time_keys_helper().await.unwrap_or_default();
+
+        let pickle = account.pickle(PicklingMode::Encrypted { key: pickle_key.to_vec() }).await;
+
+        let request = DehydratedDeviceRequest {
+            device_id,
+            initial_device_display_name: display_name.to_owned(),
+            device_data: DehydratedDeviceData {
+                algorithm: DEHYDRATION_ALGORITHM.to_owned(),
+                account: pickle.pickle.as_str().to_owned(),
+            },
+            device_keys,
+            one_time_keys,
+        };
+
+        Self { request }
+    }
+
+    /// Get the request that stores the dehydrated device on the server.
+    pub fn request(&self) -> &DehydratedDeviceRequest {
+        &self.request
+    }
+}
+
+impl DehydratedDeviceData {
+    pub(crate) fn rehydrate(
+        self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        pickle_key: &[u8],
+    ) -> Result<ReadOnlyAccount, DehydrationError> {
+        if self.algorithm != DEHYDRATION_ALGORITHM {
+            return Err(DehydrationError::UnsupportedAlgorithm(self.algorithm));
+        }
+
+        let pickle = PickledAccount {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            pickle: self.account.into(),
+            shared: true,
+            uploaded_signed_key_count: 0,
+        };
+
+        Ok(ReadOnlyAccount::from_pickle(
+            pickle,
+            PicklingMode::Encrypted { key: pickle_key.to_vec() },
+        )?)
+    }
+}
@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use olm_rs::errors::{OlmGroupSessionError, OlmSessionError};
-use ruma::{identifiers::Error as IdentifierError, DeviceId, RoomId, UserId};
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{identifiers::Error as IdentifierError, DeviceId, EventId, RoomId, UserId};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
 use super::store::CryptoStoreError;
+use crate::{
+    olm::{OlmGroupSessionError, OlmSessionError},
+    withheld::WithheldCode,
+};
 
 pub type OlmResult<T> = Result<T, OlmError>;
 pub type MegolmResult<T> = Result<T, MegolmError>;
@@ -64,6 +69,15 @@ pub enum OlmError {
             have a valid Olm session with us"
     )]
     MissingSession,
+
+    /// Sharing a room key failed because some of the devices in the room
+    /// aren't verified and the [`ShareStrategy`] doesn't allow this.
+    ///
+    /// The devices are grouped by the user ID of their owner.
+    ///
+    /// [`ShareStrategy`]: crate::ShareStrategy
+    #[error("sharing the room key failed because the room contains unverified devices: {0:?}")]
+    UnverifiedDevices(BTreeMap<Box<UserId>, BTreeSet<Box<DeviceId>>>),
 }
 
 /// Error representing a failure during a group encryption operation.
@@ -82,10 +96,23 @@ pub enum MegolmError {
     #[error("decryption failed because the room key is missing")]
     MissingRoomKey,
 
+    /// Decryption failed because the sender withheld the room key that was
+    /// used to encrypt the event from us.
+    #[error("decryption failed because the room key was withheld: {0}")]
+    Withheld(WithheldCode),
+
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm group session operation {0}")]
     OlmGroupSession(#[from] OlmGroupSessionError),
 
+    /// A Megolm message got replayed, the message index of the room key was
+    /// already used to decrypt a different event.
+    #[error(
+        "decryption failed because the message index {1} of the room key was already used by \
+         the event {0}"
+    )]
+    ReplayedMessage(Box<EventId>, u32),
+
     /// The room where a group session should be shared is not encrypted.
     #[error("The room where a group session should be shared is not encrypted")]
     EncryptionNotEnabled,
@@ -15,6 +15,8 @@
 use std::{
     collections::BTreeMap,
     io::{Error as IoError, ErrorKind, Read},
+    pin::Pin,
+    task::{Context, Poll},
 };
 
 use aes::{
@@ -22,6 +24,7 @@ use aes::{
     Aes256, Aes256Ctr,
 };
 use base64::DecodeError;
+use futures_util::{io::AsyncRead, ready};
 use getrandom::getrandom;
 use ruma::events::room::{EncryptedFile, JsonWebKey, JsonWebKeyInit};
 use serde::{Deserialize, Serialize};
@@ -35,29 +38,36 @@ const IV_SIZE: usize = 16;
 const KEY_SIZE: usize = 32;
 const VERSION: &str = "v2";
 
-/// A wrapper that transparently encrypts anything that implements `Read` as an
-/// Matrix attachment.
-pub struct AttachmentDecryptor<'a, R: 'a + Read> {
-    inner: &'a mut R,
+/// The decryption state that is shared between the blocking and the async
+/// attachment decryptor.
+struct DecryptionState {
     expected_hash: Vec<u8>,
     sha: Sha256,
     aes: Aes256Ctr,
 }
 
-impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentDecryptor<'a, R> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("AttachmentDecryptor")
-            .field("inner", &self.inner)
-            .field("expected_hash", &self.expected_hash)
-            .finish()
-    }
-}
+impl DecryptionState {
+    fn new(info: MediaEncryptionInfo) -> Result<Self, DecryptorError> {
+        if info.version != VERSION {
+            return Err(DecryptorError::UnknownVersion);
+        }
 
-impl<'a, R: Read> Read for AttachmentDecryptor<'a, R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read_bytes = self.inner.read(buf)?;
+        let hash = decode(info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?)?;
+        let key = Zeroizing::from(decode_url_safe(info.web_key.k)?);
+        let iv = decode(info.iv)?;
+        let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
+
+        let sha = Sha256::default();
+        let aes = Aes256::new_from_slice(&key).map_err(|_| DecryptorError::KeyNonceLength)?;
+        let aes = Aes256Ctr::from_block_cipher(aes, &iv);
 
-        if read_bytes == 0 {
+        Ok(Self { expected_hash: hash, sha, aes })
+    }
+
+    /// Decrypt a chunk of data that was read from the inner reader, a chunk of
+    /// size zero signals the end of the data and checks the hash.
+    fn decrypt(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
             let hash = self.sha.finalize_reset();
 
             if hash.as_slice() == self.expected_hash.as_slice() {
@@ -66,14 +76,37 @@ impl<'a, R: Read> Read for AttachmentDecryptor<'a, R> {
                 Err(IoError::new(ErrorKind::Other, "Hash mismatch while decrypting"))
             }
         } else {
-            self.sha.update(&buf[0..read_bytes]);
-            self.aes.apply_keystream(&mut buf[0..read_bytes]);
+            self.sha.update(&*buf);
+            self.aes.apply_keystream(buf);
 
-            Ok(read_bytes)
+            Ok(buf.len())
         }
     }
 }
 
+/// A wrapper that transparently encrypts anything that implements `Read` as an
+/// Matrix attachment.
+pub struct AttachmentDecryptor<'a, R: 'a + Read> {
+    inner: &'a mut R,
+    state: DecryptionState,
+}
+
+impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentDecryptor<'a, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentDecryptor")
+            .field("inner", &self.inner)
+            .field("expected_hash", &self.state.expected_hash)
+            .finish()
+    }
+}
+
+impl<'a, R: Read> Read for AttachmentDecryptor<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_bytes = self.inner.read(buf)?;
+        self.state.decrypt(&mut buf[0..read_bytes])
+    }
+}
+
 /// Error type for attachment decryption.
 #[derive(Error, Debug)]
 pub enum DecryptorError {
@@ -127,27 +160,86 @@ impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
         input: &'a mut R,
         info: MediaEncryptionInfo,
     ) -> Result<AttachmentDecryptor<'a, R>, DecryptorError> {
-        if info.version != VERSION {
-            return Err(DecryptorError::UnknownVersion);
-        }
+        Ok(AttachmentDecryptor { inner: input, state: DecryptionState::new(info)? })
+    }
+}
 
-        let hash = decode(info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?)?;
-        let key = Zeroizing::from(decode_url_safe(info.web_key.k)?);
-        let iv = decode(info.iv)?;
-        let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
+/// A wrapper that transparently decrypts anything that implements `AsyncRead`
+/// as a Matrix attachment.
+///
+/// This is the non-blocking counterpart of the [`AttachmentDecryptor`], the
+/// hash of the encrypted data is checked once the inner reader reaches the end
+/// of its data.
+pub struct AsyncAttachmentDecryptor<R: AsyncRead + Unpin> {
+    inner: R,
+    state: DecryptionState,
+}
 
-        let sha = Sha256::default();
-        let aes = Aes256::new_from_slice(&key).map_err(|_| DecryptorError::KeyNonceLength)?;
-        let aes = Aes256Ctr::from_block_cipher(aes, &iv);
+impl<R: AsyncRead + Unpin + std::fmt::Debug> std::fmt::Debug for AsyncAttachmentDecryptor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncAttachmentDecryptor")
+            .field("inner", &self.inner)
+            .field("expected_hash", &self.state.expected_hash)
+            .finish()
+    }
+}
 
-        Ok(AttachmentDecryptor { inner: input, expected_hash: hash, sha, aes })
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncAttachmentDecryptor<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let read_bytes = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        Poll::Ready(this.state.decrypt(&mut buf[0..read_bytes]))
     }
 }
 
-/// A wrapper that transparently encrypts anything that implements `Read`.
-pub struct AttachmentEncryptor<'a, R: Read + 'a> {
-    finished: bool,
-    inner: &'a mut R,
+impl<R: AsyncRead + Unpin> AsyncAttachmentDecryptor<R> {
+    /// Wrap the given async reader decrypting all the data we read from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The `AsyncRead` implementor that should be wrapped and
+    /// decrypted.
+    ///
+    /// * `info` - The encryption info that is necessary to decrypt data from
+    /// the reader.
+    ///
+    /// # Examples
+    /// ```
+    /// # use futures::{executor::block_on, io::{AsyncReadExt, Cursor}};
+    /// # use matrix_sdk_crypto::{AsyncAttachmentEncryptor, AsyncAttachmentDecryptor};
+    /// # block_on(async {
+    /// let data = "Hello world".to_owned();
+    /// let mut encryptor = AsyncAttachmentEncryptor::new(Cursor::new(data.clone()));
+    ///
+    /// let mut encrypted = Vec::new();
+    /// encryptor.read_to_end(&mut encrypted).await.unwrap();
+    /// let info = encryptor.finish();
+    ///
+    /// let mut decryptor = AsyncAttachmentDecryptor::new(Cursor::new(encrypted), info).unwrap();
+    /// let mut decrypted_data = Vec::new();
+    /// decryptor.read_to_end(&mut decrypted_data).await.unwrap();
+    ///
+    /// assert_eq!(data.as_bytes(), decrypted_data);
+    /// # });
+    /// ```
+    pub fn new(reader: R, info: MediaEncryptionInfo) -> Result<Self, DecryptorError> {
+        Ok(Self { inner: reader, state: DecryptionState::new(info)? })
+    }
+
+    /// Consume the decryptor and get back the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// The encryption state that is shared between the blocking and the async
+/// attachment encryptor.
+struct EncryptionState {
     web_key: JsonWebKey,
     iv: String,
     hashes: BTreeMap<String, String>,
@@ -155,6 +247,67 @@ pub struct AttachmentEncryptor<'a, R: Read + 'a> {
     sha: Sha256,
 }
 
+impl EncryptionState {
+    fn new() -> Self {
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        let mut iv = Zeroizing::new([0u8; IV_SIZE]);
+
+        getrandom(&mut *key).expect("Can't generate randomness");
+        // Only populate the first 8 bytes with randomness, the rest is 0
+        // initialized for the counter.
+        getrandom(&mut iv[0..8]).expect("Can't generate randomness");
+
+        let web_key = JsonWebKey::from(JsonWebKeyInit {
+            kty: "oct".to_owned(),
+            key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+            alg: "A256CTR".to_owned(),
+            k: encode_url_safe(&*key),
+            ext: true,
+        });
+        let encoded_iv = encode(&*iv);
+        let iv = GenericArray::from_slice(&*iv);
+        let key = GenericArray::from_slice(&*key);
+
+        let aes = Aes256::new(key);
+        let aes = Aes256Ctr::from_block_cipher(aes, iv);
+
+        Self { iv: encoded_iv, web_key, hashes: BTreeMap::new(), aes, sha: Sha256::default() }
+    }
+
+    /// Encrypt a chunk of data that was read from the inner reader, a chunk of
+    /// size zero signals the end of the data and stores the final hash.
+    fn encrypt(&mut self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            let hash = self.sha.finalize_reset();
+            self.hashes.entry("sha256".to_owned()).or_insert_with(|| encode(hash));
+        } else {
+            self.aes.apply_keystream(buf);
+            self.sha.update(&*buf);
+        }
+
+        buf.len()
+    }
+
+    fn finish(mut self) -> MediaEncryptionInfo {
+        let hash = self.sha.finalize();
+        self.hashes.entry("sha256".to_owned()).or_insert_with(|| encode(hash));
+
+        MediaEncryptionInfo {
+            version: VERSION.to_string(),
+            hashes: self.hashes,
+            iv: self.iv,
+            web_key: self.web_key,
+        }
+    }
+}
+
+/// A wrapper that transparently encrypts anything that implements `Read`.
+pub struct AttachmentEncryptor<'a, R: Read + 'a> {
+    finished: bool,
+    inner: &'a mut R,
+    state: EncryptionState,
+}
+
 impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentEncryptor<'a, R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AttachmentEncryptor")
@@ -167,17 +320,7 @@ impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentEncryptor
 impl<'a, R: Read + 'a> Read for AttachmentEncryptor<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let read_bytes = self.inner.read(buf)?;
-
-        if read_bytes == 0 {
-            let hash = self.sha.finalize_reset();
-            self.hashes.entry("sha256".to_owned()).or_insert_with(|| encode(hash));
-            Ok(0)
-        } else {
-            self.aes.apply_keystream(&mut buf[0..read_bytes]);
-            self.sha.update(&buf[0..read_bytes]);
-
-            Ok(read_bytes)
-        }
+        Ok(self.state.encrypt(&mut buf[0..read_bytes]))
     }
 }
 
@@ -211,50 +354,81 @@ impl<'a, R: Read + 'a> AttachmentEncryptor<'a, R> {
     /// let key = encryptor.finish();
     /// ```
     pub fn new(reader: &'a mut R) -> Self {
-        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
-        let mut iv = Zeroizing::new([0u8; IV_SIZE]);
+        AttachmentEncryptor { finished: false, inner: reader, state: EncryptionState::new() }
+    }
 
-        getrandom(&mut *key).expect("Can't generate randomness");
-        // Only populate the first 8 bytes with randomness, the rest is 0
-        // initialized for the counter.
-        getrandom(&mut iv[0..8]).expect("Can't generate randomness");
+    /// Consume the encryptor and get the encryption key.
+    pub fn finish(self) -> MediaEncryptionInfo {
+        self.state.finish()
+    }
+}
 
-        let web_key = JsonWebKey::from(JsonWebKeyInit {
-            kty: "oct".to_owned(),
-            key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
-            alg: "A256CTR".to_owned(),
-            k: encode_url_safe(&*key),
-            ext: true,
-        });
-        let encoded_iv = encode(&*iv);
-        let iv = GenericArray::from_slice(&*iv);
-        let key = GenericArray::from_slice(&*key);
+/// A wrapper that transparently encrypts anything that implements `AsyncRead`.
+///
+/// This is the non-blocking counterpart of the [`AttachmentEncryptor`], the
+/// SHA-256 hash of the encrypted data is calculated while the data is being
+/// read, so the reader can be streamed out without buffering it.
+pub struct AsyncAttachmentEncryptor<R: AsyncRead + Unpin> {
+    inner: R,
+    state: EncryptionState,
+}
 
-        let aes = Aes256::new(key);
-        let aes = Aes256Ctr::from_block_cipher(aes, iv);
+impl<R: AsyncRead + Unpin + std::fmt::Debug> std::fmt::Debug for AsyncAttachmentEncryptor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncAttachmentEncryptor").field("inner", &self.inner).finish()
+    }
+}
 
-        AttachmentEncryptor {
-            finished: false,
-            inner: reader,
-            iv: encoded_iv,
-            web_key,
-            hashes: BTreeMap::new(),
-            aes,
-            sha: Sha256::default(),
-        }
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncAttachmentEncryptor<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let read_bytes = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        Poll::Ready(Ok(this.state.encrypt(&mut buf[0..read_bytes])))
     }
+}
 
-    /// Consume the encryptor and get the encryption key.
-    pub fn finish(mut self) -> MediaEncryptionInfo {
-        let hash = self.sha.finalize();
-        self.hashes.entry("sha256".to_owned()).or_insert_with(|| encode(hash));
+impl<R: AsyncRead + Unpin> AsyncAttachmentEncryptor<R> {
+    /// Wrap the given async reader encrypting all the data we read from it.
+    ///
+    /// After all the data has been read, a call to
+    /// [`finish()`](#method.finish) is necessary to get the decryption key for
+    /// the data.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The `AsyncRead` implementor that should be wrapped and
+    /// encrypted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if we can't generate enough random data to create a fresh
+    /// encryption key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use futures::{executor::block_on, io::{AsyncReadExt, Cursor}};
+    /// # use matrix_sdk_crypto::AsyncAttachmentEncryptor;
+    /// # block_on(async {
+    /// let data = "Hello world".to_owned();
+    /// let mut encryptor = AsyncAttachmentEncryptor::new(Cursor::new(data));
+    ///
+    /// let mut encrypted = Vec::new();
+    /// encryptor.read_to_end(&mut encrypted).await.unwrap();
+    /// let key = encryptor.finish();
+    /// # });
+    /// ```
+    pub fn new(reader: R) -> Self {
+        Self { inner: reader, state: EncryptionState::new() }
+    }
 
-        MediaEncryptionInfo {
-            version: VERSION.to_string(),
-            hashes: self.hashes,
-            iv: self.iv,
-            web_key: self.web_key,
-        }
+    /// Consume the encryptor and get the encryption key.
+    pub fn finish(self) -> MediaEncryptionInfo {
+        self.state.finish()
     }
 }
 
@@ -283,9 +457,14 @@ impl From<EncryptedFile> for MediaEncryptionInfo {
 mod test {
     use std::io::{Cursor, Read};
 
+    use futures::io::{AsyncReadExt, Cursor as AsyncCursor};
+    use matrix_sdk_test::async_test;
     use serde_json::json;
 
-    use super::{AttachmentDecryptor, AttachmentEncryptor, MediaEncryptionInfo};
+    use super::{
+        AsyncAttachmentDecryptor, AsyncAttachmentEncryptor, AttachmentDecryptor,
+        AttachmentEncryptor, MediaEncryptionInfo,
+    };
 
     const EXAMPLE_DATA: &[u8] = &[
         179, 154, 118, 127, 186, 127, 110, 33, 203, 33, 33, 134, 67, 100, 173, 46, 235, 27, 215,
@@ -359,4 +538,46 @@ mod test {
 
         assert!(decryptor.read_to_end(&mut decrypted_data).is_err())
     }
+
+    #[async_test]
+    async fn async_encrypt_decrypt_cycle() {
+        let data = "Hello world".to_owned();
+        let mut encryptor = AsyncAttachmentEncryptor::new(AsyncCursor::new(data.clone()));
+
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).await.unwrap();
+        let key = encryptor.finish();
+        assert_ne!(encrypted.as_slice(), data.as_bytes());
+
+        // The async encryptor must produce data the blocking decryptor
+        // understands.
+        let mut cursor = Cursor::new(encrypted.clone());
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, key).unwrap();
+        let mut decrypted_data = Vec::new();
+        decryptor.read_to_end(&mut decrypted_data).unwrap();
+
+        assert_eq!(data.as_bytes(), decrypted_data);
+    }
+
+    #[async_test]
+    async fn async_real_decrypt() {
+        let mut decryptor =
+            AsyncAttachmentDecryptor::new(AsyncCursor::new(EXAMPLE_DATA.to_vec()), example_key())
+                .unwrap();
+        let mut decrypted_data = Vec::new();
+
+        decryptor.read_to_end(&mut decrypted_data).await.unwrap();
+        let decrypted = String::from_utf8(decrypted_data).unwrap();
+
+        assert_eq!("It's a secret to everybody", decrypted);
+    }
+
+    #[async_test]
+    async fn async_decrypt_invalid_hash() {
+        let mut decryptor =
+            AsyncAttachmentDecryptor::new(AsyncCursor::new("fake message"), example_key()).unwrap();
+        let mut decrypted_data = Vec::new();
+
+        assert!(decryptor.read_to_end(&mut decrypted_data).await.is_err())
+    }
 }
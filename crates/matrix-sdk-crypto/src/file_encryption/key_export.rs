@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    mem,
+};
 
 use aes::{
     cipher::{generic_array::GenericArray, FromBlockCipher, NewBlockCipher, StreamCipher},
@@ -28,6 +32,7 @@ use thiserror::Error;
 
 use crate::{
     olm::ExportedRoomKey,
+    store::CryptoStoreError,
     utilities::{decode, encode, DecodeError},
 };
 
@@ -36,6 +41,8 @@ const IV_SIZE: usize = 16;
 const MAC_SIZE: usize = 32;
 const KEY_SIZE: usize = 32;
 const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 1 + SALT_SIZE + IV_SIZE + 4;
+const LINE_LENGTH: usize = 96;
 
 const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
 const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
@@ -64,6 +71,12 @@ pub enum KeyExportError {
     /// The key export doesn't all the required fields.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// The decrypted key export isn't a list of room keys.
+    #[error("The decrypted key export isn't a list of room keys.")]
+    InvalidPayload,
+    /// The room keys couldn't be loaded from or saved to the crypto store.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
 }
 
 /// Try to decrypt a reader into a list of exported room keys.
@@ -147,6 +160,536 @@ pub fn encrypt_key_export(
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
+/// Streaming encryptor for room key exports.
+///
+/// Room keys are encrypted and written out one at a time, only a single room
+/// key needs to be held in memory. The output uses the same format as
+/// [`encrypt_key_export()`] and can be decrypted using
+/// [`decrypt_key_export()`] or a [`KeyExportReader`].
+///
+/// # Examples
+/// ```no_run
+/// # use std::fs::File;
+/// # use matrix_sdk_crypto::{OlmMachine, KeyExportWriter};
+/// # use ruma::{device_id, user_id};
+/// # use futures::executor::block_on;
+/// # let alice = user_id!("@alice:example.org");
+/// # let machine = OlmMachine::new(&alice, device_id!("DEVICEID"));
+/// # block_on(async {
+/// let file = File::create("/home/example/e2e-keys.txt").unwrap();
+/// let mut writer = KeyExportWriter::new(file, "1234", 100_000).unwrap();
+/// machine.export_keys_streaming(&mut writer, |_| true).await.unwrap();
+/// writer.finish().unwrap();
+/// # });
+/// ```
+pub struct KeyExportWriter<W: Write> {
+    inner: W,
+    aes: Aes256Ctr,
+    hmac: Hmac<Sha256>,
+    unencoded: Vec<u8>,
+    line_length: usize,
+    key_count: usize,
+}
+
+impl<W: Write + std::fmt::Debug> std::fmt::Debug for KeyExportWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExportWriter")
+            .field("inner", &self.inner)
+            .field("key_count", &self.key_count)
+            .finish()
+    }
+}
+
+impl<W: Write> KeyExportWriter<W> {
+    /// Create a new `KeyExportWriter` and write out the header of the key
+    /// export.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The writer that the encrypted key export will be written
+    /// to.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// exported room keys.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation, see [`encrypt_key_export()`] for more info.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely.
+    pub fn new(mut inner: W, passphrase: &str, rounds: u32) -> Result<Self, KeyExportError> {
+        let mut salt = [0u8; SALT_SIZE];
+        let mut iv = [0u8; IV_SIZE];
+
+        getrandom(&mut salt).expect("Can't generate randomness");
+        getrandom(&mut iv).expect("Can't generate randomness");
+
+        let mut iv = u128::from_be_bytes(iv);
+        iv &= !(1 << 63);
+        let iv = iv.to_be_bytes();
+
+        let (aes, hmac) = derive_keys(passphrase, &salt, &iv, rounds);
+
+        inner.write_all(HEADER.as_bytes())?;
+        inner.write_all(b"\n")?;
+
+        let mut writer =
+            Self { inner, aes, hmac, unencoded: Vec::new(), line_length: 0, key_count: 0 };
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend(&VERSION.to_be_bytes());
+        header.extend(&salt);
+        header.extend(&iv);
+        header.extend(&rounds.to_be_bytes());
+
+        writer.hmac.update(&header);
+        writer.write_encoded(&header)?;
+
+        Ok(writer)
+    }
+
+    /// Encrypt and write out a single room key.
+    pub fn write_key(&mut self, key: &ExportedRoomKey) -> Result<(), KeyExportError> {
+        let mut plaintext = if self.key_count == 0 { b"[".to_vec() } else { b",".to_vec() };
+        serde_json::to_writer(&mut plaintext, key)?;
+
+        self.write_plaintext(plaintext)?;
+        self.key_count += 1;
+
+        Ok(())
+    }
+
+    /// The number of room keys that were written out so far.
+    pub fn key_count(&self) -> usize {
+        self.key_count
+    }
+
+    /// Finish the key export by writing out the MAC and the footer.
+    ///
+    /// Returns the inner writer.
+    pub fn finish(mut self) -> Result<W, KeyExportError> {
+        let end = if self.key_count == 0 { b"[]".to_vec() } else { b"]".to_vec() };
+        self.write_plaintext(end)?;
+
+        let mac = self.hmac.clone().finalize().into_bytes();
+        self.write_encoded(&mac)?;
+
+        let rest = encode(mem::take(&mut self.unencoded));
+        self.write_wrapped(&rest)?;
+
+        if self.line_length > 0 {
+            self.inner.write_all(b"\n")?;
+        }
+
+        self.inner.write_all(FOOTER.as_bytes())?;
+        self.inner.write_all(b"\n")?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+
+    fn write_plaintext(&mut self, mut plaintext: Vec<u8>) -> Result<(), KeyExportError> {
+        self.aes.apply_keystream(&mut plaintext);
+        self.hmac.update(&plaintext);
+        self.write_encoded(&plaintext)
+    }
+
+    /// Base64 encode and write out the given bytes, bytes that don't fill up a
+    /// whole base64 block are kept until more data arrives.
+    fn write_encoded(&mut self, data: &[u8]) -> Result<(), KeyExportError> {
+        self.unencoded.extend_from_slice(data);
+
+        let length = self.unencoded.len() - self.unencoded.len() % 3;
+
+        if length > 0 {
+            let encoded = encode(&self.unencoded[..length]);
+            self.unencoded.drain(..length);
+            self.write_wrapped(&encoded)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_wrapped(&mut self, mut encoded: &str) -> Result<(), KeyExportError> {
+        while !encoded.is_empty() {
+            let split = (LINE_LENGTH - self.line_length).min(encoded.len());
+            let (line, rest) = encoded.split_at(split);
+
+            self.inner.write_all(line.as_bytes())?;
+            self.line_length += line.len();
+
+            if self.line_length == LINE_LENGTH {
+                self.inner.write_all(b"\n")?;
+                self.line_length = 0;
+            }
+
+            encoded = rest;
+        }
+
+        Ok(())
+    }
+}
+
+/// Streaming decryptor for room key exports.
+///
+/// The MAC of the key export is checked when the reader is created, the room
+/// keys are afterwards decrypted and deserialized one at a time while iterating
+/// over the reader. Since the key export needs to be read twice the underlying
+/// reader needs to implement [`Seek`].
+///
+/// # Examples
+/// ```no_run
+/// # use std::fs::File;
+/// # use matrix_sdk_crypto::{OlmMachine, KeyExportReader};
+/// # use ruma::{device_id, user_id};
+/// # use futures::executor::block_on;
+/// # let alice = user_id!("@alice:example.org");
+/// # let machine = OlmMachine::new(&alice, device_id!("DEVICEID"));
+/// # block_on(async {
+/// let file = File::open("/home/example/e2e-keys.txt").unwrap();
+/// let reader = KeyExportReader::new(file, "1234").unwrap();
+/// machine.import_keys_streaming(reader, false, |_| {}).await.unwrap();
+/// # });
+/// ```
+pub struct KeyExportReader<R: Read + Seek> {
+    payload: ArmoredPayload<BufReader<R>>,
+    aes: Aes256Ctr,
+    pending: Vec<u8>,
+    remaining: usize,
+    splitter: ArraySplitter,
+    keys: VecDeque<Vec<u8>>,
+    finished: bool,
+}
+
+impl<R: Read + Seek + std::fmt::Debug> std::fmt::Debug for KeyExportReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExportReader")
+            .field("inner", &self.payload.inner)
+            .field("remaining", &self.remaining)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> KeyExportReader<R> {
+    /// Create a new `KeyExportReader` and check the MAC of the key export.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The reader containing the encrypted key export.
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt the exported
+    /// keys.
+    pub fn new(mut inner: R, passphrase: &str) -> Result<Self, KeyExportError> {
+        let start = inner.stream_position()?;
+
+        let (aes, total_length) = {
+            let mut payload = ArmoredPayload::new(BufReader::new(&mut inner));
+            let mut buffer = Vec::new();
+
+            while buffer.len() < HEADER_SIZE {
+                let chunk = payload.next_chunk()?.ok_or_else(unexpected_eof)?;
+                buffer.extend(chunk);
+            }
+
+            let (salt, iv, rounds) = parse_header(&buffer[..HEADER_SIZE])?;
+            let (aes, mut hmac) = derive_keys(passphrase, &salt, &iv, rounds);
+
+            // The last bytes of the payload are the MAC, so only bytes that
+            // can't be part of it get authenticated.
+            let mut total_length = buffer.len();
+
+            loop {
+                if buffer.len() > MAC_SIZE {
+                    let length = buffer.len() - MAC_SIZE;
+                    hmac.update(&buffer[..length]);
+                    buffer.drain(..length);
+                }
+
+                match payload.next_chunk()? {
+                    Some(chunk) => {
+                        total_length += chunk.len();
+                        buffer.extend(chunk);
+                    }
+                    None => break,
+                }
+            }
+
+            if total_length < HEADER_SIZE + MAC_SIZE {
+                return Err(unexpected_eof().into());
+            }
+
+            hmac.verify(&buffer).map_err(|_| KeyExportError::InvalidMac)?;
+
+            (aes, total_length)
+        };
+
+        inner.seek(SeekFrom::Start(start))?;
+
+        let mut payload = ArmoredPayload::new(BufReader::new(inner));
+        let mut pending = Vec::new();
+
+        while pending.len() < HEADER_SIZE {
+            let chunk = payload.next_chunk()?.ok_or_else(unexpected_eof)?;
+            pending.extend(chunk);
+        }
+
+        pending.drain(..HEADER_SIZE);
+
+        Ok(Self {
+            payload,
+            aes,
+            pending,
+            remaining: total_length - HEADER_SIZE - MAC_SIZE,
+            splitter: ArraySplitter::default(),
+            keys: VecDeque::new(),
+            finished: false,
+        })
+    }
+
+    fn next_plaintext(&mut self) -> Result<Option<Vec<u8>>, KeyExportError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        let mut chunk = if self.pending.is_empty() {
+            self.payload.next_chunk()?.ok_or_else(unexpected_eof)?
+        } else {
+            mem::take(&mut self.pending)
+        };
+
+        chunk.truncate(self.remaining);
+        self.remaining -= chunk.len();
+        self.aes.apply_keystream(&mut chunk);
+
+        Ok(Some(chunk))
+    }
+}
+
+impl<R: Read + Seek> Iterator for KeyExportReader<R> {
+    type Item = Result<ExportedRoomKey, KeyExportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(key) = self.keys.pop_front() {
+                return Some(serde_json::from_slice(&key).map_err(KeyExportError::from));
+            }
+
+            if self.finished {
+                return None;
+            }
+
+            let result = match self.next_plaintext() {
+                Ok(Some(chunk)) => self.splitter.feed(&chunk, &mut self.keys),
+                Ok(None) => {
+                    self.finished = true;
+
+                    if self.splitter.is_done() {
+                        Ok(())
+                    } else {
+                        Err(KeyExportError::InvalidPayload)
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Reader for the ASCII armored payload of a key export, yields the base64
+/// decoded payload line by line.
+struct ArmoredPayload<R: BufRead> {
+    inner: R,
+    line: String,
+    encoded: Vec<u8>,
+    header_found: bool,
+    footer_found: bool,
+}
+
+impl<R: BufRead> ArmoredPayload<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: String::new(),
+            encoded: Vec::new(),
+            header_found: false,
+            footer_found: false,
+        }
+    }
+
+    /// Get the decoded bytes of the next line, returns `None` once the footer
+    /// has been reached.
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, KeyExportError> {
+        loop {
+            if self.footer_found {
+                return Ok(None);
+            }
+
+            self.line.clear();
+
+            if self.inner.read_line(&mut self.line)? == 0 {
+                return Err(KeyExportError::InvalidHeaders);
+            }
+
+            let line = self.line.trim();
+
+            if !self.header_found {
+                if line == HEADER {
+                    self.header_found = true;
+                } else if !line.is_empty() {
+                    return Err(KeyExportError::InvalidHeaders);
+                }
+            } else if line == FOOTER {
+                self.footer_found = true;
+                return Ok(Some(decode(mem::take(&mut self.encoded))?));
+            } else {
+                self.encoded.extend(line.bytes().filter(|b| *b != b'='));
+
+                let length = self.encoded.len() - self.encoded.len() % 4;
+                let decoded = decode(&self.encoded[..length])?;
+                self.encoded.drain(..length);
+
+                return Ok(Some(decoded));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SplitterState {
+    Start,
+    FirstElement,
+    NextElement,
+    Element,
+    AfterElement,
+    End,
+}
+
+/// Splits a JSON array of objects into the serialized objects without having
+/// to hold the whole array in memory.
+#[derive(Debug)]
+struct ArraySplitter {
+    state: SplitterState,
+    element: Vec<u8>,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Default for ArraySplitter {
+    fn default() -> Self {
+        Self {
+            state: SplitterState::Start,
+            element: Vec::new(),
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+}
+
+impl ArraySplitter {
+    fn feed(
+        &mut self,
+        data: &[u8],
+        elements: &mut VecDeque<Vec<u8>>,
+    ) -> Result<(), KeyExportError> {
+        for &byte in data {
+            match self.state {
+                SplitterState::Element => {
+                    self.element.push(byte);
+
+                    if self.in_string {
+                        if self.escaped {
+                            self.escaped = false;
+                        } else if byte == b'\\' {
+                            self.escaped = true;
+                        } else if byte == b'"' {
+                            self.in_string = false;
+                        }
+                    } else {
+                        match byte {
+                            b'"' => self.in_string = true,
+                            b'{' | b'[' => self.depth += 1,
+                            b'}' | b']' => {
+                                self.depth -= 1;
+
+                                if self.depth == 0 {
+                                    elements.push_back(mem::take(&mut self.element));
+                                    self.state = SplitterState::AfterElement;
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                _ if byte.is_ascii_whitespace() => (),
+                SplitterState::Start if byte == b'[' => self.state = SplitterState::FirstElement,
+                SplitterState::FirstElement if byte == b']' => self.state = SplitterState::End,
+                SplitterState::FirstElement | SplitterState::NextElement if byte == b'{' => {
+                    self.element.push(byte);
+                    self.depth = 1;
+                    self.state = SplitterState::Element;
+                }
+                SplitterState::AfterElement if byte == b',' => {
+                    self.state = SplitterState::NextElement
+                }
+                SplitterState::AfterElement if byte == b']' => self.state = SplitterState::End,
+                _ => return Err(KeyExportError::InvalidPayload),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_done(&self) -> bool {
+        self.state == SplitterState::End
+    }
+}
+
+fn unexpected_eof() -> std::io::Error {
+    ErrorKind::UnexpectedEof.into()
+}
+
+fn parse_header(header: &[u8]) -> Result<([u8; SALT_SIZE], [u8; IV_SIZE], u32), KeyExportError> {
+    let mut header = Cursor::new(header);
+
+    let mut salt = [0u8; SALT_SIZE];
+    let mut iv = [0u8; IV_SIZE];
+
+    let version = header.read_u8()?;
+
+    if version != VERSION {
+        return Err(KeyExportError::UnsupportedVersion);
+    }
+
+    header.read_exact(&mut salt)?;
+    header.read_exact(&mut iv)?;
+    let rounds = header.read_u32::<BigEndian>()?;
+
+    Ok((salt, iv, rounds))
+}
+
+fn derive_keys(passphrase: &str, salt: &[u8], iv: &[u8], rounds: u32) -> (Aes256Ctr, Hmac<Sha256>) {
+    let mut derived_keys = [0u8; KEY_SIZE * 2];
+
+    pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, rounds, &mut derived_keys);
+    let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+
+    let aes = Aes256::new(GenericArray::from_slice(key));
+    let aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(iv));
+    let hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create an HMAC object");
+
+    (aes, hmac)
+}
+
 fn encrypt_helper(plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
     let mut salt = [0u8; SALT_SIZE];
     let mut iv = [0u8; IV_SIZE];
@@ -245,7 +788,10 @@ mod test {
     use proptest::prelude::*;
     use ruma::room_id;
 
-    use super::{decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export};
+    use super::{
+        decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export,
+        KeyExportError, KeyExportReader, KeyExportWriter, LINE_LENGTH,
+    };
     use crate::{error::OlmResult, machine::test::get_prepared_machine, RoomKeyImportResult};
 
     const PASSPHRASE: &str = "1234";
@@ -376,4 +922,70 @@ mod test {
         let imported = decrypt_key_export(reader, PASSPHRASE).expect("Can't decrypt key export");
         assert!(!imported.is_empty())
     }
+
+    #[test]
+    fn test_real_streaming_decrypt() {
+        let expected = decrypt_key_export(Cursor::new(TEST_EXPORT), PASSPHRASE).unwrap();
+
+        let reader = KeyExportReader::new(Cursor::new(TEST_EXPORT), PASSPHRASE)
+            .expect("Can't decrypt key export");
+        let imported: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(expected, imported);
+
+        let result = KeyExportReader::new(Cursor::new(TEST_EXPORT), "wrong passphrase");
+        assert!(matches!(result, Err(KeyExportError::InvalidMac)));
+    }
+
+    #[async_test]
+    async fn test_streaming_export_cycle() -> OlmResult<()> {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        for _ in 0..5 {
+            machine.create_outbound_group_session_with_defaults(room_id).await?;
+        }
+
+        let mut export = machine.export_keys(|_| true).await?;
+        export.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+
+        let mut writer = KeyExportWriter::new(Vec::new(), PASSPHRASE, 1).unwrap();
+        assert_eq!(machine.export_keys_streaming(&mut writer, |_| true).await.unwrap(), 5);
+        assert_eq!(writer.key_count(), 5);
+
+        let encrypted = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert!(encrypted.lines().all(|l| l.len() <= LINE_LENGTH));
+
+        let mut decrypted = decrypt_key_export(Cursor::new(&encrypted), PASSPHRASE).unwrap();
+        decrypted.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        assert_eq!(export, decrypted);
+
+        let reader = KeyExportReader::new(Cursor::new(&encrypted), PASSPHRASE).unwrap();
+        let mut streamed: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        streamed.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        assert_eq!(export, streamed);
+
+        let (other_machine, _) = get_prepared_machine().await;
+        let reader = KeyExportReader::new(Cursor::new(&encrypted), PASSPHRASE).unwrap();
+        let result = other_machine.import_keys_streaming(reader, false, |_| {}).await.unwrap();
+
+        assert_eq!(result.imported_count, 5);
+        assert_eq!(result.total_count, 5);
+
+        let reader = KeyExportReader::new(Cursor::new(&encrypted), PASSPHRASE).unwrap();
+        let result = other_machine.import_keys_streaming(reader, false, |_| {}).await.unwrap();
+
+        assert_eq!(result, RoomKeyImportResult::new(0, 5, BTreeMap::new()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_empty_export() {
+        let writer = KeyExportWriter::new(Vec::new(), PASSPHRASE, 1).unwrap();
+        let encrypted = writer.finish().unwrap();
+
+        assert!(decrypt_key_export(Cursor::new(&encrypted), PASSPHRASE).unwrap().is_empty());
+        assert_eq!(KeyExportReader::new(Cursor::new(&encrypted), PASSPHRASE).unwrap().count(), 0);
+    }
 }
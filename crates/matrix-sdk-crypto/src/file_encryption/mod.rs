@@ -2,6 +2,9 @@ mod attachments;
 mod key_export;
 
 pub use attachments::{
-    AttachmentDecryptor, AttachmentEncryptor, DecryptorError, MediaEncryptionInfo,
+    AsyncAttachmentDecryptor, AsyncAttachmentEncryptor, AttachmentDecryptor, AttachmentEncryptor,
+    DecryptorError, MediaEncryptionInfo,
+};
+pub use key_export::{
+    decrypt_key_export, encrypt_key_export, KeyExportError, KeyExportReader, KeyExportWriter,
 };
-pub use key_export::{decrypt_key_export, encrypt_key_export, KeyExportError};
@@ -23,7 +23,7 @@
 use std::{collections::BTreeMap, sync::Arc};
 
 use dashmap::{mapref::entry::Entry, DashMap, DashSet};
-use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::{instant::Duration, locks::RwLock, uuid::Uuid};
 use ruma::{
     api::client::r0::keys::claim_keys::Request as KeysClaimRequest,
     events::{
@@ -40,17 +40,22 @@ use ruma::{
         },
         AnyToDeviceEvent, AnyToDeviceEventContent,
     },
-    DeviceId, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId, UserId,
+    DeviceId, DeviceKeyAlgorithm, EventEncryptionAlgorithm, MilliSecondsSinceUnixEpoch, RoomId,
+    UserId,
 };
 use tracing::{debug, info, trace, warn};
 
-use super::{GossipRequest, KeyForwardDecision, RequestEvent, RequestInfo, SecretInfo, WaitQueue};
+use super::{
+    DefaultKeyForwardingPolicy, GossipRequest, KeyForwardDecision, KeyForwardRequest,
+    KeyForwardingPolicy, RequestEvent, RequestInfo, SecretInfo, WaitQueue,
+};
 use crate::{
     error::{OlmError, OlmResult},
     olm::{InboundGroupSession, Session, ShareState},
     requests::{OutgoingRequest, ToDeviceRequest},
     session_manager::GroupSessionCache,
     store::{Changes, CryptoStoreError, SecretImportError, Store},
+    withheld::{withheld_request, RoomKeyWithheldEventContent, WithheldCode},
     Device,
 };
 
@@ -64,9 +69,14 @@ pub(crate) struct GossipMachine {
     incoming_key_requests: Arc<DashMap<RequestInfo, RequestEvent>>,
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<Box<UserId>, DashSet<Box<DeviceId>>>>,
+    key_forwarding_policy: Arc<RwLock<Arc<dyn KeyForwardingPolicy>>>,
 }
 
 impl GossipMachine {
+    /// The time after which we don't expect an answer to a key request that
+    /// was sent out anymore.
+    const KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 24);
+
     pub fn new(
         user_id: Arc<UserId>,
         device_id: Arc<DeviceId>,
@@ -83,9 +93,16 @@ impl GossipMachine {
             incoming_key_requests: Default::default(),
             wait_queue: WaitQueue::new(),
             users_for_key_claim,
+            key_forwarding_policy: Arc::new(RwLock::new(Arc::new(DefaultKeyForwardingPolicy))),
         }
     }
 
+    /// Replace the policy that decides if room keys get forwarded to devices
+    /// requesting them.
+    pub async fn set_key_forwarding_policy(&self, policy: Arc<dyn KeyForwardingPolicy>) {
+        *self.key_forwarding_policy.write().await = policy;
+    }
+
     /// Load stored outgoing requests that were not yet sent out.
     async fn load_outgoing_requests(&self) -> Result<Vec<OutgoingRequest>, CryptoStoreError> {
         Ok(self
@@ -358,6 +375,16 @@ impl GossipMachine {
                 room_id = key_info.room_id.as_str(),
                 "Received a room key request for an unknown inbound group session",
             );
+
+            if self.should_send_unavailable(event, key_info).await? {
+                self.withhold_key(
+                    &event.sender,
+                    &event.content.requesting_device_id,
+                    key_info,
+                    WithheldCode::Unavailable,
+                );
+            }
+
             return Ok(None);
         };
 
@@ -381,6 +408,14 @@ impl GossipMachine {
                             reason =? e,
                             "Received a key request that we won't serve",
                         );
+
+                        let code = if let KeyForwardDecision::UntrustedDevice = e {
+                            WithheldCode::Unverified
+                        } else {
+                            WithheldCode::Unauthorised
+                        };
+
+                        self.withhold_key(device.user_id(), device.device_id(), key_info, code);
                     }
 
                     Ok(None)
@@ -425,6 +460,64 @@ impl GossipMachine {
         }
     }
 
+    /// Check if we should tell the requesting device that we don't have the
+    /// requested room key.
+    ///
+    /// We only do so for our own devices and for devices we shared the room
+    /// key with, otherwise anybody could probe which room keys we have.
+    async fn should_send_unavailable(
+        &self,
+        event: &ToDeviceRoomKeyRequestEvent,
+        key_info: &RequestedKeyInfo,
+    ) -> OlmResult<bool> {
+        if event.sender == self.user_id() {
+            return Ok(true);
+        }
+
+        let outbound_session = self
+            .outbound_group_sessions
+            .get_with_id(&key_info.room_id, &key_info.session_id)
+            .await
+            .ok()
+            .flatten();
+
+        let outbound_session = if let Some(s) = outbound_session {
+            s
+        } else {
+            return Ok(false);
+        };
+
+        let device =
+            self.store.get_device(&event.sender, &event.content.requesting_device_id).await?;
+
+        Ok(device.map_or(false, |d| {
+            !matches!(outbound_session.is_shared_with(&d), ShareState::NotShared)
+        }))
+    }
+
+    /// Tell the requesting device that we won't forward the requested room
+    /// key to it.
+    fn withhold_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_info: &RequestedKeyInfo,
+        code: WithheldCode,
+    ) {
+        let content = RoomKeyWithheldEventContent::new(
+            code,
+            &key_info.room_id,
+            &key_info.session_id,
+            self.store.account().identity_keys().curve25519(),
+        );
+
+        let request = withheld_request([(user_id, device_id, content)]);
+
+        let request =
+            OutgoingRequest { request_id: request.txn_id, request: Arc::new(request.into()) };
+        self.outgoing_requests.insert(request.request_id, request);
+    }
+
     async fn share_secret(
         &self,
         device: &Device,
@@ -470,19 +563,8 @@ impl GossipMachine {
 
     /// Check if it's ok to share a session with the given device.
     ///
-    /// The logic for this is currently as follows:
-    ///
-    /// * Share the session in full, starting from the earliest known index, if
-    /// the requesting device is our own, trusted (verified) device.
-    ///
-    /// * For other requesting devices, share only a limited session and only if
-    /// we originally shared with that device because it was present when the
-    /// message was initially sent. By limited, we mean that the session will
-    /// not be shared in full, but only from the message index at that moment.
-    /// Since this information is recorded in the outbound session, we need to
-    /// have it for this to work.
-    ///
-    /// * In all other cases, refuse to share the session.
+    /// The decision is delegated to the configured [`KeyForwardingPolicy`],
+    /// [`DefaultKeyForwardingPolicy`] describes the logic we use by default.
     ///
     /// # Arguments
     ///
@@ -512,27 +594,20 @@ impl GossipMachine {
             .ok()
             .flatten();
 
-        // If this is our own, verified device, we share the entire session from the
-        // earliest known index.
-        if device.user_id() == self.user_id() && device.verified() {
-            Ok(None)
-        // Otherwise, if the records show we previously shared with this device,
-        // we'll reshare the session from the index we previously shared
-        // at. For this, we need an outbound session because this
-        // information is recorded there.
-        } else if let Some(outbound) = outbound_session {
-            match outbound.is_shared_with(device) {
-                ShareState::Shared(message_index) => Ok(Some(message_index)),
-                ShareState::SharedButChangedSenderKey => Err(KeyForwardDecision::ChangedSenderKey),
-                ShareState::NotShared => Err(KeyForwardDecision::OutboundSessionNotShared),
-            }
-        // Otherwise, there's not enough info to decide if we can safely share
-        // the session.
-        } else if device.user_id() == self.user_id() {
-            Err(KeyForwardDecision::UntrustedDevice)
-        } else {
-            Err(KeyForwardDecision::MissingOutboundSession)
-        }
+        let history_visibility = outbound_session
+            .as_ref()
+            .map(|o| &o.settings().history_visibility)
+            .or_else(|| session.history_visibility());
+
+        let request = KeyForwardRequest {
+            own_user_id: self.user_id(),
+            device,
+            session,
+            history_visibility,
+            share_state: outbound_session.as_ref().map(|o| o.is_shared_with(device)),
+        };
+
+        self.key_forwarding_policy.read().await.should_forward(&request)
     }
 
     /// Check if it's ok, or rather if it makes sense to automatically request
@@ -636,6 +711,7 @@ impl GossipMachine {
             request_id: Uuid::new_v4(),
             info: key_info,
             sent_out: false,
+            creation_time: MilliSecondsSinceUnixEpoch::now(),
         };
 
         let outgoing_request = request.to_request(self.device_id());
@@ -680,6 +756,39 @@ impl GossipMachine {
         })
     }
 
+    /// Check if we requested the key with the given session id and are still
+    /// waiting for it to arrive.
+    ///
+    /// Requests that weren't sent out yet, or that were sent out so long ago
+    /// that we don't expect an answer anymore, don't count as pending.
+    ///
+    /// # Arguments
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `sender_key` - The curve25519 key of the sender that owns the key.
+    ///
+    /// * `session_id` - The id that uniquely identifies the session.
+    pub async fn is_key_request_pending(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<bool, CryptoStoreError> {
+        let key_info = RequestedKeyInfo::new(
+            EventEncryptionAlgorithm::MegolmV1AesSha2,
+            room_id.to_owned(),
+            sender_key.to_owned(),
+            session_id.to_owned(),
+        )
+        .into();
+
+        Ok(self
+            .store
+            .get_secret_request_by_info(&key_info)
+            .await?
+            .map_or(false, |r| r.sent_out && r.elapsed() < Self::KEY_REQUEST_TIMEOUT))
+    }
+
     /// Save an outgoing key info.
     async fn save_outgoing_key_info(&self, info: GossipRequest) -> Result<(), CryptoStoreError> {
         let mut changes = Changes::default();
@@ -752,6 +861,43 @@ impl GossipMachine {
         Ok(())
     }
 
+    /// Remove all the outgoing secret requests that are older than the given
+    /// maximal age.
+    ///
+    /// Requests that were already sent out will get cancelled.
+    ///
+    /// Returns the number of requests that were removed.
+    pub async fn expire_outgoing_requests(
+        &self,
+        max_age: Duration,
+    ) -> Result<usize, CryptoStoreError> {
+        let mut requests = self.store.get_unsent_secret_requests().await?;
+        requests.extend(self.store.get_sent_secret_requests().await?);
+
+        let mut expired = 0;
+
+        for request in requests.into_iter().filter(|r| r.elapsed() >= max_age) {
+            trace!(
+                recipient = request.request_recipient.as_str(),
+                request_type = request.request_type(),
+                request_id = request.request_id.to_string().as_str(),
+                "Expiring an outgoing secret request"
+            );
+
+            self.outgoing_requests.remove(&request.request_id);
+            self.delete_key_info(&request).await?;
+
+            if request.sent_out {
+                let cancellation = request.to_cancellation(self.device_id());
+                self.outgoing_requests.insert(cancellation.request_id, cancellation);
+            }
+
+            expired += 1;
+        }
+
+        Ok(expired)
+    }
+
     pub async fn receive_secret(
         &self,
         sender_key: &str,
@@ -937,29 +1083,33 @@ mod test {
 
     use dashmap::DashMap;
     use matches::assert_matches;
-    use matrix_sdk_common::locks::Mutex;
+    use matrix_sdk_common::{instant::Duration, locks::Mutex};
     use matrix_sdk_test::async_test;
     use ruma::{
         device_id,
         events::{
             forwarded_room_key::ToDeviceForwardedRoomKeyEventContent,
-            room::encrypted::ToDeviceRoomEncryptedEventContent,
+            room::{
+                encrypted::ToDeviceRoomEncryptedEventContent, history_visibility::HistoryVisibility,
+            },
             room_key_request::ToDeviceRoomKeyRequestEventContent,
             secret::request::{RequestAction, SecretName, ToDeviceSecretRequestEventContent},
             AnyToDeviceEvent, ToDeviceEvent,
         },
         room_id,
         to_device::DeviceIdOrAllDevices,
-        user_id, DeviceId, DeviceKeyAlgorithm, RoomId, UserId,
+        uint, user_id, DeviceId, DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch, RoomId, UserId,
     };
 
     use super::{GossipMachine, KeyForwardDecision};
     use crate::{
+        gossiping::{KeyForwardRequest, KeyForwardingPolicy, NeverForwardPolicy},
         identities::{LocalTrust, ReadOnlyDevice},
         olm::{Account, PrivateCrossSigningIdentity, ReadOnlyAccount},
         session_manager::GroupSessionCache,
         store::{Changes, CryptoStore, MemoryStore, Store},
         verification::VerificationMachine,
+        withheld::{RoomKeyWithheldEventContent, WithheldCode, WITHHELD_EVENT_TYPE},
         OutgoingRequests,
     };
 
@@ -1120,6 +1270,98 @@ mod test {
         assert!(machine.outgoing_to_device_requests().await.unwrap().is_empty());
     }
 
+    #[async_test]
+    async fn key_request_pending() {
+        let machine = get_machine().await;
+        let account = account();
+
+        let (_, session) =
+            account.create_group_session_pair_with_defaults(room_id()).await.unwrap();
+        assert!(!machine
+            .is_key_request_pending(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap());
+
+        machine
+            .create_outgoing_key_request(
+                session.room_id(),
+                &session.sender_key,
+                session.session_id(),
+            )
+            .await
+            .unwrap();
+
+        // The request wasn't sent out yet.
+        assert!(!machine
+            .is_key_request_pending(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap());
+
+        let requests = machine.outgoing_to_device_requests().await.unwrap();
+        let request = requests.get(0).unwrap();
+        machine.mark_outgoing_request_as_sent(request.request_id).await.unwrap();
+
+        assert!(machine
+            .is_key_request_pending(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap());
+
+        let mut stored =
+            machine.store.get_outgoing_secret_requests(request.request_id).await.unwrap().unwrap();
+        stored.creation_time = MilliSecondsSinceUnixEpoch(uint!(0));
+        machine.save_outgoing_key_info(stored).await.unwrap();
+
+        // Nobody is going to answer a request that old anymore.
+        assert!(!machine
+            .is_key_request_pending(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap());
+    }
+
+    #[async_test]
+    async fn expire_key_requests() {
+        let machine = get_machine().await;
+        let account = account();
+        let second_account = alice_2_account();
+        let alice_device = ReadOnlyDevice::from_account(&second_account).await;
+
+        alice_device.set_trust_state(LocalTrust::Verified);
+        machine.store.save_devices(&[alice_device]).await.unwrap();
+
+        let (_, session) =
+            account.create_group_session_pair_with_defaults(room_id()).await.unwrap();
+
+        machine
+            .create_outgoing_key_request(
+                session.room_id(),
+                &session.sender_key,
+                session.session_id(),
+            )
+            .await
+            .unwrap();
+
+        let requests = machine.outgoing_to_device_requests().await.unwrap();
+        let request = requests.get(0).unwrap();
+        machine.mark_outgoing_request_as_sent(request.request_id).await.unwrap();
+
+        assert_eq!(machine.expire_outgoing_requests(Duration::from_secs(3600)).await.unwrap(), 0);
+        assert!(machine
+            .is_key_request_pending(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap());
+
+        assert_eq!(machine.expire_outgoing_requests(Duration::from_secs(0)).await.unwrap(), 1);
+        assert!(!machine
+            .is_key_request_pending(session.room_id(), &session.sender_key, session.session_id())
+            .await
+            .unwrap());
+
+        // The expired request was already sent out, so a cancellation is queued.
+        let requests = machine.outgoing_to_device_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_ne!(requests[0].request_id, request.request_id);
+    }
+
     #[async_test]
     async fn receive_forwarded_key() {
         let machine = get_machine().await;
@@ -1335,6 +1577,50 @@ mod test {
         assert_matches!(machine.should_share_key(&own_device, &other_inbound).await, Ok(None));
     }
 
+    #[async_test]
+    async fn key_forwarding_policy() {
+        let machine = get_machine().await;
+        let account = account();
+
+        let own_device =
+            machine.store.get_device(alice_id(), alice2_device_id()).await.unwrap().unwrap();
+        own_device.set_trust_state(LocalTrust::Verified);
+
+        let (_, inbound) =
+            account.create_group_session_pair_with_defaults(room_id()).await.unwrap();
+
+        assert_matches!(machine.should_share_key(&own_device, &inbound).await, Ok(None));
+
+        machine.set_key_forwarding_policy(Arc::new(NeverForwardPolicy)).await;
+
+        assert_matches!(
+            machine.should_share_key(&own_device, &inbound).await,
+            Err(KeyForwardDecision::RefusedByPolicy)
+        );
+
+        #[derive(Debug)]
+        struct SharedHistoryPolicy;
+
+        impl KeyForwardingPolicy for SharedHistoryPolicy {
+            fn should_forward(
+                &self,
+                request: &KeyForwardRequest<'_>,
+            ) -> Result<Option<u32>, KeyForwardDecision> {
+                if request.history_visibility == Some(&HistoryVisibility::Shared) {
+                    Ok(Some(request.session.first_known_index()))
+                } else {
+                    Err(KeyForwardDecision::RefusedByPolicy)
+                }
+            }
+        }
+
+        machine.set_key_forwarding_policy(Arc::new(SharedHistoryPolicy)).await;
+
+        // Sessions we create ourselves remember the history visibility of the
+        // room, the default one is shared.
+        assert_matches!(machine.should_share_key(&own_device, &inbound).await, Ok(Some(0)));
+    }
+
     #[async_test]
     async fn key_share_cycle() {
         let alice_machine = get_machine().await;
@@ -1541,6 +1827,83 @@ mod test {
         assert!(!alice_machine.outgoing_requests.is_empty());
     }
 
+    #[async_test]
+    async fn unknown_room_key_requests_get_a_withheld_notice() {
+        let alice_machine = get_machine().await;
+        let bob_machine = bob_machine();
+        let bob_account = bob_account();
+
+        let second_account = alice_2_account();
+        let alice_device = ReadOnlyDevice::from_account(&second_account).await;
+
+        // We need a trusted device, otherwise we won't request keys
+        alice_device.set_trust_state(LocalTrust::Verified);
+        alice_machine.store.save_devices(&[alice_device]).await.unwrap();
+
+        let (group_session, _) =
+            bob_account.create_group_session_pair_with_defaults(room_id()).await.unwrap();
+
+        // Alice requests a room key bob doesn't have.
+        alice_machine
+            .create_outgoing_key_request(
+                room_id(),
+                bob_account.identity_keys.curve25519(),
+                group_session.session_id(),
+            )
+            .await
+            .unwrap();
+
+        let requests = alice_machine.outgoing_to_device_requests().await.unwrap();
+        let content = requests[0]
+            .request
+            .to_device()
+            .unwrap()
+            .messages
+            .get(alice_id())
+            .unwrap()
+            .get(&DeviceIdOrAllDevices::AllDevices)
+            .unwrap();
+        let content: ToDeviceRoomKeyRequestEventContent = content.deserialize_as().unwrap();
+        let event = ToDeviceEvent { sender: alice_id().to_owned(), content };
+
+        // Bob never shared the room key with Alice, so he doesn't tell her that
+        // he doesn't have it.
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+        assert!(bob_machine.outgoing_to_device_requests().await.unwrap().is_empty());
+
+        let alice_device = ReadOnlyDevice::from_account(&account()).await;
+        bob_machine.store.save_devices(&[alice_device.clone()]).await.unwrap();
+        group_session
+            .mark_shared_with(
+                alice_device.user_id(),
+                alice_device.device_id(),
+                alice_device.get_key(DeviceKeyAlgorithm::Curve25519).unwrap(),
+            )
+            .await;
+        bob_machine.outbound_group_sessions.insert(group_session.clone());
+
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+
+        let requests = bob_machine.outgoing_to_device_requests().await.unwrap();
+        let request = requests[0].request.to_device().unwrap();
+
+        assert_eq!(request.event_type.as_str(), WITHHELD_EVENT_TYPE);
+
+        let content: RoomKeyWithheldEventContent = request
+            .messages
+            .get(alice_id())
+            .unwrap()
+            .get(&DeviceIdOrAllDevices::DeviceId(alice_device_id().to_owned()))
+            .unwrap()
+            .deserialize_as()
+            .unwrap();
+
+        assert_eq!(content.code, WithheldCode::Unavailable);
+        assert_eq!(content.session_id.as_deref(), Some(group_session.session_id()));
+    }
+
     #[async_test]
     async fn key_share_cycle_without_session() {
         let alice_machine = get_machine().await;
@@ -13,12 +13,16 @@
 // limitations under the License.
 
 mod machine;
+mod policy;
 
 use std::sync::Arc;
 
 use dashmap::{DashMap, DashSet};
 pub(crate) use machine::GossipMachine;
-use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::{instant::Duration, uuid::Uuid};
+pub use policy::{
+    DefaultKeyForwardingPolicy, KeyForwardRequest, KeyForwardingPolicy, NeverForwardPolicy,
+};
 use ruma::{
     events::{
         room_key_request::{
@@ -32,7 +36,7 @@ use ruma::{
         AnyToDeviceEventContent,
     },
     to_device::DeviceIdOrAllDevices,
-    DeviceId, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, UserId,
 };
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -61,6 +65,10 @@ pub enum KeyForwardDecision {
     /// accidentally or maliciously changed their curve25519 sender key.
     #[error("the device has changed their curve25519 sender key")]
     ChangedSenderKey,
+    /// The configured [`KeyForwardingPolicy`] doesn't allow the key to be
+    /// forwarded to the device.
+    #[error("the key forwarding policy doesn't allow the key to be forwarded")]
+    RefusedByPolicy,
 }
 
 /// A struct describing an outgoing key request.
@@ -74,6 +82,9 @@ pub struct GossipRequest {
     pub info: SecretInfo,
     /// Has the request been sent out.
     pub sent_out: bool,
+    /// The point in time when the request was created.
+    #[serde(default = "MilliSecondsSinceUnixEpoch::now")]
+    pub creation_time: MilliSecondsSinceUnixEpoch,
 }
 
 /// An enum over the various secret request types we can have.
@@ -105,9 +116,18 @@ impl GossipRequest {
             request_id: Uuid::new_v4(),
             info: secret_name.into(),
             sent_out: false,
+            creation_time: MilliSecondsSinceUnixEpoch::now(),
         }
     }
 
+    /// The time that passed since the request was created.
+    pub(crate) fn elapsed(&self) -> Duration {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let then: u64 = self.creation_time.get().into();
+
+        Duration::from_millis(now.saturating_sub(then))
+    }
+
     fn request_type(&self) -> &str {
         match &self.info {
             SecretInfo::KeyRequest(_) => "m.room_key_request",
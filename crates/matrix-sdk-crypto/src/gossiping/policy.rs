@@ -0,0 +1,149 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Debug;
+
+use ruma::{events::room::history_visibility::HistoryVisibility, UserId};
+
+use super::KeyForwardDecision;
+use crate::{
+    olm::{InboundGroupSession, ShareState},
+    Device,
+};
+
+/// Information about an incoming room key request, used by a
+/// [`KeyForwardingPolicy`] to decide if the room key should be forwarded.
+#[derive(Debug)]
+pub struct KeyForwardRequest<'a> {
+    /// Our own user id.
+    pub own_user_id: &'a UserId,
+    /// The device that requested the room key.
+    pub device: &'a Device,
+    /// The room key that was requested.
+    pub session: &'a InboundGroupSession,
+    /// The history visibility of the room the room key belongs to, if known.
+    pub history_visibility: Option<&'a HistoryVisibility>,
+    /// Did we share the room key with the requesting device when we sent it
+    /// out.
+    ///
+    /// This is `None` if we don't have the outbound group session of the room
+    /// key, e.g. because it was rotated or because someone else created it.
+    pub share_state: Option<ShareState>,
+}
+
+/// A policy deciding if a requested room key should be forwarded to the
+/// requesting device.
+///
+/// The policy can be set using
+/// [`OlmMachine::set_key_forwarding_policy()`](crate::OlmMachine::set_key_forwarding_policy),
+/// by default the [`DefaultKeyForwardingPolicy`] is used.
+///
+/// # Examples
+///
+/// A policy that additionally forwards room keys to verified devices of other
+/// users if the room has a shared history.
+///
+/// ```
+/// # use matrix_sdk_crypto::{
+/// #     DefaultKeyForwardingPolicy, KeyForwardDecision, KeyForwardRequest,
+/// #     KeyForwardingPolicy,
+/// # };
+/// # use ruma::events::room::history_visibility::HistoryVisibility;
+/// #[derive(Debug)]
+/// struct SharedHistoryPolicy;
+///
+/// impl KeyForwardingPolicy for SharedHistoryPolicy {
+///     fn should_forward(
+///         &self,
+///         request: &KeyForwardRequest<'_>,
+///     ) -> Result<Option<u32>, KeyForwardDecision> {
+///         if request.history_visibility == Some(&HistoryVisibility::Shared)
+///             && request.device.verified()
+///         {
+///             Ok(None)
+///         } else {
+///             DefaultKeyForwardingPolicy.should_forward(request)
+///         }
+///     }
+/// }
+/// ```
+pub trait KeyForwardingPolicy: Debug + Send + Sync {
+    /// Decide if the requested room key should be forwarded.
+    ///
+    /// # Return value
+    ///
+    /// - `Ok(None)`: Forward the entire session, starting with the earliest
+    ///   known index.
+    /// - `Ok(Some(i))`: Forward the session, but only starting from index i.
+    /// - `Err(x)`: *Refuse* to forward the session. `x` is the reason for the
+    ///   refusal.
+    fn should_forward(
+        &self,
+        request: &KeyForwardRequest<'_>,
+    ) -> Result<Option<u32>, KeyForwardDecision>;
+}
+
+/// The default key forwarding policy.
+///
+/// * Forward the session in full, starting from the earliest known index, if
+/// the requesting device is our own, trusted (verified) device.
+///
+/// * For other requesting devices, forward only a limited session and only if
+/// we originally shared with that device because it was present when the
+/// message was initially sent. By limited, we mean that the session will not
+/// be shared in full, but only from the message index at that moment.
+///
+/// * In all other cases, refuse to forward the session.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultKeyForwardingPolicy;
+
+impl KeyForwardingPolicy for DefaultKeyForwardingPolicy {
+    fn should_forward(
+        &self,
+        request: &KeyForwardRequest<'_>,
+    ) -> Result<Option<u32>, KeyForwardDecision> {
+        let is_own_device = request.device.user_id() == request.own_user_id;
+
+        // If this is our own, verified device, we share the entire session from the
+        // earliest known index.
+        if is_own_device && request.device.verified() {
+            Ok(None)
+        // Otherwise, if the records show we previously shared with this device,
+        // we'll reshare the session from the index we previously shared
+        // at.
+        } else if let Some(share_state) = request.share_state {
+            match share_state {
+                ShareState::Shared(message_index) => Ok(Some(message_index)),
+                ShareState::SharedButChangedSenderKey => Err(KeyForwardDecision::ChangedSenderKey),
+                ShareState::NotShared => Err(KeyForwardDecision::OutboundSessionNotShared),
+            }
+        // Otherwise, there's not enough info to decide if we can safely share
+        // the session.
+        } else if is_own_device {
+            Err(KeyForwardDecision::UntrustedDevice)
+        } else {
+            Err(KeyForwardDecision::MissingOutboundSession)
+        }
+    }
+}
+
+/// A key forwarding policy that never forwards any room keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NeverForwardPolicy;
+
+impl KeyForwardingPolicy for NeverForwardPolicy {
+    fn should_forward(&self, _: &KeyForwardRequest<'_>) -> Result<Option<u32>, KeyForwardDecision> {
+        Err(KeyForwardDecision::RefusedByPolicy)
+    }
+}
@@ -44,8 +44,8 @@ use crate::{
     identities::{ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities},
     olm::{InboundGroupSession, Session, Utility},
     store::{Changes, CryptoStore, DeviceChanges, Result as StoreResult},
-    verification::VerificationMachine,
-    OutgoingVerificationRequest, Sas, ToDeviceRequest, VerificationRequest,
+    verification::{VerificationMachine, DEFAULT_SAS_METHODS},
+    OutgoingVerificationRequest, Sas, SasMethod, ToDeviceRequest, VerificationRequest,
 };
 #[cfg(test)]
 use crate::{OlmMachine, ReadOnlyAccount};
@@ -64,6 +64,14 @@ pub struct ReadOnlyDevice {
         deserialize_with = "local_trust_deserializer"
     )]
     trust_state: Arc<Atomic<LocalTrust>>,
+    /// Did we already tell the device that we can't share room keys with it
+    /// because we don't have an Olm session with it.
+    #[serde(
+        default,
+        serialize_with = "atomic_bool_serializer",
+        deserialize_with = "atomic_bool_deserializer"
+    )]
+    withheld_code_sent: Arc<AtomicBool>,
 }
 
 impl std::fmt::Debug for ReadOnlyDevice {
@@ -129,7 +137,25 @@ impl Device {
     ///
     /// [`request_verification()`]: #method.request_verification
     pub async fn start_verification(&self) -> StoreResult<(Sas, ToDeviceRequest)> {
-        let (sas, request) = self.verification_machine.start_sas(self.inner.clone()).await?;
+        self.start_verification_with_methods(DEFAULT_SAS_METHODS).await
+    }
+
+    /// Start a interactive verification with this `Device` that only offers
+    /// the given short auth string methods.
+    ///
+    /// The decimal method is always offered, since the spec requires every
+    /// client to support it.
+    ///
+    /// This method has been deprecated in the spec and the
+    /// [`request_verification()`] method should be used instead.
+    ///
+    /// [`request_verification()`]: #method.request_verification
+    pub async fn start_verification_with_methods(
+        &self,
+        methods: &[SasMethod],
+    ) -> StoreResult<(Sas, ToDeviceRequest)> {
+        let (sas, request) =
+            self.verification_machine.start_sas(self.inner.clone(), methods).await?;
 
         if let OutgoingVerificationRequest::ToDevice(r) = request {
             Ok((sas, r))
@@ -199,6 +225,23 @@ impl Device {
         self.inner.is_cross_signing_trusted(&self.own_identity, &self.device_owner_identity)
     }
 
+    /// Is this device signed by the identity of its owner, while the identity
+    /// is verified.
+    ///
+    /// Unlike [`Device::is_cross_signing_trusted()`], this doesn't require our
+    /// own identity to be verified if the device belongs to another user.
+    pub fn is_signed_by_verified_identity(&self) -> bool {
+        self.inner.is_signed_by_verified_identity(&self.own_identity, &self.device_owner_identity)
+    }
+
+    /// Is this device signed by the identity of its owner, while the identity
+    /// is either verified or [pinned].
+    ///
+    /// [pinned]: crate::IdentityTrustState::Pinned
+    pub fn is_signed_by_pinned_identity(&self) -> bool {
+        self.inner.is_signed_by_pinned_identity(&self.own_identity, &self.device_owner_identity)
+    }
+
     /// Manually verify this device.
     ///
     /// This method will attempt to sign the device using our private cross
@@ -380,6 +423,7 @@ impl ReadOnlyDevice {
             inner: device_keys.into(),
             trust_state: Arc::new(Atomic::new(trust_state)),
             deleted: Arc::new(AtomicBool::new(false)),
+            withheld_code_sent: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -485,6 +529,47 @@ impl ReadOnlyDevice {
         })
     }
 
+    pub(crate) fn is_signed_by_verified_identity(
+        &self,
+        own_identity: &Option<ReadOnlyOwnUserIdentity>,
+        device_owner: &Option<ReadOnlyUserIdentities>,
+    ) -> bool {
+        self.is_signed_by_owner_identity(own_identity, device_owner, false)
+    }
+
+    pub(crate) fn is_signed_by_pinned_identity(
+        &self,
+        own_identity: &Option<ReadOnlyOwnUserIdentity>,
+        device_owner: &Option<ReadOnlyUserIdentities>,
+    ) -> bool {
+        self.is_signed_by_owner_identity(own_identity, device_owner, true)
+    }
+
+    /// Is the device signed by the identity of its owner, while the identity is
+    /// verified or, if `allow_pinned` is set, pinned.
+    fn is_signed_by_owner_identity(
+        &self,
+        own_identity: &Option<ReadOnlyOwnUserIdentity>,
+        device_owner: &Option<ReadOnlyUserIdentities>,
+        allow_pinned: bool,
+    ) -> bool {
+        match device_owner {
+            Some(ReadOnlyUserIdentities::Own(identity)) => {
+                (identity.is_verified() || (allow_pinned && identity.is_pinned()))
+                    && identity.is_device_signed(self).is_ok()
+            }
+            Some(ReadOnlyUserIdentities::Other(identity)) => {
+                let verified = own_identity
+                    .as_ref()
+                    .map_or(false, |own| own.is_identity_signed(identity).is_ok());
+
+                (verified || (allow_pinned && identity.is_pinned()))
+                    && identity.is_device_signed(self).is_ok()
+            }
+            None => false,
+        }
+    }
+
     pub(crate) async fn encrypt(
         &self,
         store: &dyn CryptoStore,
@@ -572,6 +657,18 @@ impl ReadOnlyDevice {
         self.deleted.store(true, Ordering::Relaxed);
     }
 
+    /// Was the device already sent a `m.no_olm` withheld code.
+    ///
+    /// The code is only sent once per device, not once per room key.
+    pub(crate) fn was_withheld_code_sent(&self) -> bool {
+        self.withheld_code_sent.load(Ordering::Relaxed)
+    }
+
+    /// Remember that the device was sent a `m.no_olm` withheld code.
+    pub(crate) fn mark_withheld_code_as_sent(&self) {
+        self.withheld_code_sent.store(true, Ordering::Relaxed);
+    }
+
     #[cfg(test)]
     pub async fn from_machine(machine: &OlmMachine) -> ReadOnlyDevice {
         ReadOnlyDevice::from_account(machine.account()).await
@@ -592,6 +689,7 @@ impl TryFrom<&DeviceKeys> for ReadOnlyDevice {
             inner: device_keys.clone().into(),
             deleted: Arc::new(AtomicBool::new(false)),
             trust_state: Arc::new(Atomic::new(LocalTrust::Unset)),
+            withheld_code_sent: Arc::new(AtomicBool::new(false)),
         };
 
         device.verify_device_keys(device_keys)?;
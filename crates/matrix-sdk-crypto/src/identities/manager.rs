@@ -16,14 +16,17 @@ use std::{
     collections::{BTreeMap, BTreeSet, HashSet},
     convert::TryFrom,
     ops::Deref,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use futures_util::future::join_all;
 use matrix_sdk_common::executor::spawn;
 use ruma::{
     api::client::r0::keys::get_keys::Response as KeysQueryResponse, encryption::DeviceKeys,
-    serde::Raw, DeviceId, UserId,
+    serde::Raw, DeviceId, DeviceKeyAlgorithm, UserId,
 };
 use tracing::{debug, info, trace, warn};
 
@@ -50,19 +53,56 @@ pub(crate) struct IdentityManager {
     user_id: Arc<UserId>,
     device_id: Arc<DeviceId>,
     store: Store,
+    trust_on_first_use: Arc<AtomicBool>,
 }
 
 impl IdentityManager {
     const MAX_KEY_QUERY_USERS: usize = 250;
 
     pub fn new(user_id: Arc<UserId>, device_id: Arc<DeviceId>, store: Store) -> Self {
-        IdentityManager { user_id, device_id, store }
+        IdentityManager { user_id, device_id, store, trust_on_first_use: Default::default() }
     }
 
     fn user_id(&self) -> &UserId {
         &self.user_id
     }
 
+    /// Enable or disable the pinning of master keys the first time we see a
+    /// user identity.
+    pub fn set_trust_on_first_use(&self, enabled: bool) {
+        self.trust_on_first_use.store(enabled, Ordering::SeqCst)
+    }
+
+    /// Pin the first master key we saw for the given identity, unless we
+    /// already pinned one.
+    ///
+    /// `stored_master_key` is the master key the identity had before the
+    /// current key query, if we knew about the identity. A changed master key
+    /// must not be pinned, otherwise the change would go unnoticed.
+    fn pin_on_first_use(
+        identity: &mut ReadOnlyUserIdentities,
+        stored_master_key: Option<MasterPubkey>,
+    ) {
+        match identity.pinned_master_key() {
+            None => {
+                trace!(
+                    user_id = identity.user_id().as_str(),
+                    "Pinning the master key of a user identity"
+                );
+                let master_key =
+                    stored_master_key.unwrap_or_else(|| identity.master_key().to_owned());
+                identity.pin_master_key(master_key);
+            }
+            Some(pinned) if pinned != identity.master_key() => {
+                warn!(
+                    user_id = identity.user_id().as_str(),
+                    "The master key of a user identity doesn't match the pinned master key"
+                );
+            }
+            Some(_) => (),
+        }
+    }
+
     /// Receive a successful keys query response.
     ///
     /// Returns a list of devices newly discovered devices and devices that
@@ -85,10 +125,23 @@ impl IdentityManager {
         let devices = self.handle_devices_from_key_query(response.device_keys.clone()).await?;
         let (identities, cross_signing_identity) = self.handle_cross_singing_keys(response).await?;
 
+        let mut deleted_sessions = Vec::new();
+
+        // Olm sessions with deleted devices can't be used anymore, remove them
+        // together with the devices.
+        for device in &devices.deleted {
+            if let Some(sender_key) = device.get_key(DeviceKeyAlgorithm::Curve25519) {
+                if let Some(sessions) = self.store.get_sessions(sender_key).await? {
+                    deleted_sessions.extend(sessions.lock().await.iter().cloned());
+                }
+            }
+        }
+
         let changes = Changes {
             identities: identities.clone(),
             devices: devices.clone(),
             private_identity: cross_signing_identity,
+            deleted_sessions,
             ..Default::default()
         };
 
@@ -325,6 +378,9 @@ impl IdentityManager {
         let mut changes = IdentityChanges::default();
         let mut changed_identity = None;
 
+        let own_identity =
+            self.store.get_user_identity(self.user_id()).await?.and_then(|i| i.into_own());
+
         // TODO this is a bit chunky, refactor this into smaller methods.
 
         for (user_id, master_key) in &response.master_keys {
@@ -345,7 +401,11 @@ impl IdentityManager {
                         continue;
                     };
 
-                    let result = if let Some(mut i) = self.store.get_user_identity(user_id).await? {
+                    let stored_identity = self.store.get_user_identity(user_id).await?;
+                    let stored_master_key =
+                        stored_identity.as_ref().map(|i| i.master_key().to_owned());
+
+                    let result = if let Some(mut i) = stored_identity {
                         match &mut i {
                             ReadOnlyUserIdentities::Own(ref mut identity) => {
                                 let user_signing = if let Some(s) = response
@@ -368,6 +428,17 @@ impl IdentityManager {
                                     .map(|_| (i, false))
                             }
                             ReadOnlyUserIdentities::Other(ref mut identity) => {
+                                // Identities we verified before we started to
+                                // remember verifications aren't marked yet, do
+                                // so before the update might replace the
+                                // verified master key.
+                                if own_identity
+                                    .as_ref()
+                                    .map_or(false, |o| o.is_identity_signed(identity).is_ok())
+                                {
+                                    identity.mark_as_previously_verified();
+                                }
+
                                 identity.update(master_key, self_signing).map(|_| (i, false))
                             }
                         }
@@ -412,7 +483,11 @@ impl IdentityManager {
                     };
 
                     match result {
-                        Ok((i, new)) => {
+                        Ok((mut i, new)) => {
+                            if self.trust_on_first_use.load(Ordering::SeqCst) {
+                                Self::pin_on_first_use(&mut i, stored_master_key);
+                            }
+
                             if let Some(identity) = i.own() {
                                 let private_identity = self.store.private_identity();
                                 let private_identity = private_identity.lock().await;
@@ -454,9 +529,49 @@ impl IdentityManager {
             }
         }
 
+        self.check_verification_violations(&changes).await?;
+
         Ok((changes, changed_identity))
     }
 
+    /// Remember which of the given identities we verified, and warn about the
+    /// ones that changed since we verified them.
+    async fn check_verification_violations(&self, changes: &IdentityChanges) -> StoreResult<()> {
+        let own_identity = if let Some(identity) =
+            changes.new.iter().chain(changes.changed.iter()).find_map(|i| i.own())
+        {
+            Some(identity.clone())
+        } else {
+            self.store.get_user_identity(self.user_id()).await?.and_then(|i| i.into_own())
+        };
+
+        let own_identity = if let Some(identity) = own_identity {
+            identity
+        } else {
+            return Ok(());
+        };
+
+        if own_identity.has_verification_violation() {
+            warn!(
+                user_id = own_identity.user_id().as_str(),
+                "Our own user identity changed since we verified it"
+            );
+        }
+
+        for identity in changes.new.iter().chain(changes.changed.iter()).filter_map(|i| i.other()) {
+            if own_identity.is_identity_signed(identity).is_ok() {
+                identity.mark_as_previously_verified();
+            } else if identity.was_previously_verified() {
+                warn!(
+                    user_id = identity.user_id().as_str(),
+                    "The user identity changed since we verified it"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get a key query request if one is needed.
     ///
     /// Returns a key query request if the client should query E2E keys,
@@ -550,10 +665,10 @@ pub(crate) mod test {
     use serde_json::json;
 
     use crate::{
-        identities::IdentityManager,
+        identities::{IdentityManager, IdentityTrustState, ReadOnlyUserIdentity},
         machine::test::response_from_file,
         olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
-        store::{CryptoStore, MemoryStore, Store},
+        store::{Changes, CryptoStore, IdentityChanges, MemoryStore, Store},
         verification::VerificationMachine,
     };
 
@@ -741,6 +856,61 @@ pub(crate) mod test {
             .expect("Can't parse the keys upload response")
     }
 
+    /// Get a keys query response containing the public part of the given
+    /// cross signing identity of the other user.
+    ///
+    /// If `signed_by` is given, the master key of the identity will be signed
+    /// by the user-signing key of that identity.
+    async fn other_identity_key_query(
+        identity: &PrivateCrossSigningIdentity,
+        signed_by: Option<&PrivateCrossSigningIdentity>,
+    ) -> KeyQueryResponse {
+        let keys = identity.as_upload_request().await;
+        let master_key = keys.master_key.unwrap();
+        let self_signing_key = keys.self_signing_key.unwrap();
+
+        let mut master_key_json = serde_json::to_value(&master_key).unwrap();
+
+        if let Some(signer) = signed_by {
+            let public_identity =
+                ReadOnlyUserIdentity::new(master_key.into(), self_signing_key.clone().into())
+                    .unwrap();
+            let request = signer.sign_user(&public_identity).await.unwrap();
+            let signed_master_key =
+                request.signed_keys[other_user_id()].values().next().unwrap().clone();
+
+            master_key_json["signatures"][signer.user_id().as_str()] =
+                signed_master_key["signatures"][signer.user_id().as_str()].clone();
+        }
+
+        let data = response_from_file(&json!({
+            "device_keys": {},
+            "failures": {},
+            "master_keys": { "@example2:localhost": master_key_json },
+            "self_signing_keys": { "@example2:localhost": self_signing_key },
+            "user_signing_keys": {}
+        }));
+
+        KeyQueryResponse::try_from_http_response(data).expect("Can't parse the keys query response")
+    }
+
+    /// Create a manager whose own user identity is verified.
+    async fn manager_with_own_identity() -> (IdentityManager, PrivateCrossSigningIdentity) {
+        let manager = manager();
+        let own_identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+
+        let changes = Changes {
+            identities: IdentityChanges {
+                new: vec![own_identity.to_public_identity().await.unwrap().into()],
+                changed: vec![],
+            },
+            ..Default::default()
+        };
+        manager.store.save_changes(changes).await.unwrap();
+
+        (manager, own_identity)
+    }
+
     #[async_test]
     async fn test_manager_creation() {
         let manager = manager();
@@ -795,6 +965,156 @@ pub(crate) mod test {
         assert!(identity.is_device_signed(&device).is_ok())
     }
 
+    #[async_test]
+    async fn trust_on_first_use() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        let identity = manager.store.get_user_identity(other_user).await.unwrap().unwrap();
+        assert!(!identity.is_pinned());
+
+        let device =
+            manager.store.get_device(other_user, device_id!("SKISMLNIMH")).await.unwrap().unwrap();
+        assert!(!device.is_signed_by_pinned_identity());
+
+        manager.set_trust_on_first_use(true);
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        let identity = manager.store.get_user_identity(other_user).await.unwrap().unwrap();
+        assert!(identity.is_pinned());
+        assert_eq!(identity.pinned_master_key(), Some(identity.master_key()));
+
+        let device =
+            manager.store.get_device(other_user, device_id!("SKISMLNIMH")).await.unwrap().unwrap();
+        assert!(!device.verified());
+        assert!(device.is_signed_by_pinned_identity());
+    }
+
+    #[async_test]
+    async fn changed_master_key_is_a_verification_violation() {
+        let (manager, own_identity) = manager_with_own_identity().await;
+        let other_user = other_user_id();
+
+        let first_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&first_identity, Some(&own_identity)).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert!(identity.verified());
+        assert!(identity.was_previously_verified());
+        assert!(!identity.has_verification_violation());
+
+        // The master key of the user changes and nobody verified the new one.
+        let second_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&second_identity, None).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert!(!identity.verified());
+        assert!(identity.has_verification_violation());
+
+        identity.withdraw_verification().await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        assert!(!identity.other().unwrap().has_verification_violation());
+    }
+
+    #[async_test]
+    async fn identities_verified_before_violations_were_tracked() {
+        let (manager, own_identity) = manager_with_own_identity().await;
+        let other_user = other_user_id();
+
+        // The identity was verified and stored before we remembered
+        // verifications, so it isn't marked as previously verified.
+        let first_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&first_identity, Some(&own_identity)).await;
+        let master_key = response.master_keys[other_user].deserialize().unwrap();
+        let self_signing_key = response.self_signing_keys[other_user].deserialize().unwrap();
+        let identity =
+            ReadOnlyUserIdentity::new(master_key.into(), self_signing_key.into()).unwrap();
+        assert!(!identity.was_previously_verified());
+
+        let changes = Changes {
+            identities: IdentityChanges { new: vec![identity.into()], changed: vec![] },
+            ..Default::default()
+        };
+        manager.store.save_changes(changes).await.unwrap();
+
+        let second_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&second_identity, None).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        assert!(identity.other().unwrap().has_verification_violation());
+    }
+
+    #[async_test]
+    async fn trust_on_first_use_mismatch() {
+        let manager = manager();
+        let other_user = other_user_id();
+        manager.set_trust_on_first_use(true);
+
+        let first_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&first_identity, None).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert_eq!(identity.trust_state(), IdentityTrustState::Pinned);
+        let pinned_master_key = identity.master_key().to_owned();
+
+        // The master key changes, the new one must not be pinned.
+        let second_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&second_identity, None).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert_ne!(identity.master_key(), &pinned_master_key);
+        assert_eq!(identity.pinned_master_key(), Some(&pinned_master_key));
+        assert_eq!(identity.trust_state(), IdentityTrustState::Untrusted);
+
+        // Accepting the new master key pins it.
+        identity.pin_current_master_key().await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert_eq!(identity.pinned_master_key(), Some(identity.master_key()));
+        assert_eq!(identity.trust_state(), IdentityTrustState::Pinned);
+    }
+
+    #[async_test]
+    async fn trust_on_first_use_for_known_identities() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        // We saw the identity before trust on first use was enabled.
+        let first_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&first_identity, None).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_user_identity(other_user).await.unwrap().unwrap();
+        let first_master_key = identity.master_key().to_owned();
+        assert!(identity.pinned_master_key().is_none());
+
+        manager.set_trust_on_first_use(true);
+
+        // The master key changed in the first key query after trust on first
+        // use was enabled, the master key we saw first gets pinned.
+        let second_identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let response = other_identity_key_query(&second_identity, None).await;
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let identity = manager.store.get_identity(other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert_eq!(identity.pinned_master_key(), Some(&first_master_key));
+        assert_eq!(identity.trust_state(), IdentityTrustState::Untrusted);
+    }
+
     #[async_test]
     async fn no_tracked_users_key_query_request() {
         let manager = manager();
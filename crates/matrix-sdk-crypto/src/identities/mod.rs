@@ -42,6 +42,7 @@
 //! `/keys/query` API call.
 pub(crate) mod device;
 mod manager;
+mod trust_report;
 pub(crate) mod user;
 
 use std::sync::{
@@ -52,9 +53,12 @@ use std::sync::{
 pub use device::{Device, LocalTrust, ReadOnlyDevice, UserDevices};
 pub(crate) use manager::IdentityManager;
 use serde::{Deserialize, Deserializer, Serializer};
+pub(crate) use trust_report::TrustReportCache;
+pub use trust_report::{RoomTrustReport, ShieldState, UserTrustReport};
 pub use user::{
-    MasterPubkey, OwnUserIdentity, ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities,
-    ReadOnlyUserIdentity, SelfSigningPubkey, UserIdentities, UserIdentity, UserSigningPubkey,
+    IdentityTrustState, MasterPubkey, OwnUserIdentity, ReadOnlyOwnUserIdentity,
+    ReadOnlyUserIdentities, ReadOnlyUserIdentity, SelfSigningPubkey, UserIdentities, UserIdentity,
+    UserSigningPubkey,
 };
 
 // These methods are only here because Serialize and Deserialize don't seem to
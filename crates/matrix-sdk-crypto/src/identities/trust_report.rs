@@ -0,0 +1,209 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use dashmap::DashMap;
+use ruma::UserId;
+
+use super::IdentityTrustState;
+use crate::store::{DeviceChanges, IdentityChanges};
+
+/// The shield that should be displayed for a user or a room, summarizing how
+/// much the devices that will receive our room keys can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShieldState {
+    /// All the devices are verified.
+    Green,
+    /// Some devices aren't verified, or there are no devices at all.
+    Grey,
+    /// Something is wrong, a device is blacklisted or a verified user has
+    /// devices that aren't verified.
+    Red,
+}
+
+/// A summary of the trust state of a single user and of their devices.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserTrustReport {
+    /// The trust state of the cross signing identity of the user, `None` if
+    /// the user doesn't have a cross signing identity.
+    pub identity_state: Option<IdentityTrustState>,
+    /// The number of devices that are verified, either locally or using cross
+    /// signing.
+    pub verified_devices: usize,
+    /// The number of devices that aren't verified.
+    pub unverified_devices: usize,
+    /// The number of devices that were locally blacklisted.
+    pub blacklisted_devices: usize,
+}
+
+impl UserTrustReport {
+    /// Get the shield state of this user.
+    pub fn shield_state(&self) -> ShieldState {
+        let identity_verified = self.identity_state == Some(IdentityTrustState::Verified);
+
+        if self.blacklisted_devices > 0 || (identity_verified && self.unverified_devices > 0) {
+            ShieldState::Red
+        } else if self.unverified_devices == 0 && self.verified_devices > 0 {
+            ShieldState::Green
+        } else {
+            ShieldState::Grey
+        }
+    }
+}
+
+/// A summary of the trust state of all the members of a room.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomTrustReport {
+    /// The trust reports of the room members, keyed by their user id.
+    pub members: BTreeMap<Box<UserId>, UserTrustReport>,
+}
+
+impl RoomTrustReport {
+    /// Get the shield state of the room.
+    ///
+    /// The room gets a red shield if any of its members has a red shield and a
+    /// green one only if all of its members have a green shield.
+    ///
+    /// Members without any devices, e.g. our own user if we don't have other
+    /// devices, won't receive any room keys and don't influence the shield.
+    pub fn shield_state(&self) -> ShieldState {
+        self.members
+            .values()
+            .filter(|m| m.verified_devices + m.unverified_devices + m.blacklisted_devices > 0)
+            .map(|m| m.shield_state())
+            .max()
+            .unwrap_or(ShieldState::Grey)
+    }
+
+    /// Are all the devices of all the room members verified.
+    pub fn contains_only_verified_devices(&self) -> bool {
+        self.members.values().all(|m| m.unverified_devices == 0 && m.blacklisted_devices == 0)
+    }
+}
+
+/// A cached trust report of a user together with the generation of the
+/// report, the generation is bumped every time the report gets invalidated.
+#[derive(Debug, Default)]
+struct CacheEntry {
+    generation: u64,
+    report: Option<UserTrustReport>,
+}
+
+impl CacheEntry {
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        self.report = None;
+    }
+}
+
+/// A cache for the user trust reports, which gets invalidated as identity and
+/// device changes get saved in the store.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrustReportCache {
+    reports: Arc<DashMap<Box<UserId>, CacheEntry>>,
+}
+
+impl TrustReportCache {
+    pub fn get(&self, user_id: &UserId) -> Option<UserTrustReport> {
+        self.reports.get(user_id).and_then(|e| e.report.clone())
+    }
+
+    /// Get the current generation of the report of the given user.
+    ///
+    /// This needs to be fetched before the report is computed and passed to
+    /// [`TrustReportCache::insert()`].
+    pub fn generation(&self, user_id: &UserId) -> u64 {
+        self.reports.entry(user_id.to_owned()).or_default().generation
+    }
+
+    /// Cache the given report, unless the report of the user got invalidated
+    /// since the given generation was fetched.
+    pub fn insert(&self, user_id: &UserId, generation: u64, report: UserTrustReport) {
+        if let Some(mut entry) = self.reports.get_mut(user_id) {
+            if entry.generation == generation {
+                entry.report = Some(report);
+            }
+        }
+    }
+
+    /// Invalidate the reports of all the users the given changes touch.
+    ///
+    /// This needs to be called after the changes were saved in the store,
+    /// otherwise a report that was computed using the old state of the store
+    /// could be cached.
+    pub fn apply_changes(
+        &self,
+        own_user_id: &UserId,
+        identities: &IdentityChanges,
+        devices: &DeviceChanges,
+    ) {
+        let identities = identities.new.iter().chain(&identities.changed);
+
+        // The trust of every device that is verified using cross signing
+        // depends on our own identity.
+        if identities.clone().any(|i| i.user_id() == own_user_id) {
+            self.reports.iter_mut().for_each(|mut e| e.invalidate());
+        } else {
+            for identity in identities {
+                self.invalidate(identity.user_id());
+            }
+        }
+
+        for device in devices.new.iter().chain(&devices.changed).chain(&devices.deleted) {
+            self.invalidate(device.user_id());
+        }
+    }
+
+    fn invalidate(&self, user_id: &UserId) {
+        self.reports.entry(user_id.to_owned()).or_default().invalidate();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::user_id;
+
+    use super::{TrustReportCache, UserTrustReport};
+    use crate::{
+        identities::device::test::get_device,
+        store::{DeviceChanges, IdentityChanges},
+    };
+
+    #[test]
+    fn stale_reports_are_not_cached() {
+        let cache = TrustReportCache::default();
+        let own_user_id = user_id!("@alice:localhost");
+        let device = get_device();
+        let user_id = device.user_id();
+
+        let generation = cache.generation(user_id);
+        let report = UserTrustReport { unverified_devices: 1, ..Default::default() };
+        cache.insert(user_id, generation, report.clone());
+        assert_eq!(cache.get(user_id), Some(report.clone()));
+
+        // The device gets changed while a report is being computed.
+        let generation = cache.generation(user_id);
+        let devices = DeviceChanges { changed: vec![device.clone()], ..Default::default() };
+        cache.apply_changes(own_user_id, &IdentityChanges::default(), &devices);
+        assert!(cache.get(user_id).is_none());
+
+        cache.insert(user_id, generation, report.clone());
+        assert!(cache.get(user_id).is_none());
+
+        let generation = cache.generation(user_id);
+        cache.insert(user_id, generation, report.clone());
+        assert_eq!(cache.get(user_id), Some(report));
+    }
+}
@@ -43,6 +43,21 @@ use crate::{
     CryptoStoreError, OutgoingVerificationRequest, ReadOnlyDevice, VerificationRequest,
 };
 
+/// The trust state of a user identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityTrustState {
+    /// The identity has been verified.
+    Verified,
+    /// The identity hasn't been verified, but its master key matches the one
+    /// we pinned when we first saw the identity.
+    ///
+    /// Master keys are only pinned if trust on first use is enabled, see
+    /// [`OlmMachine::set_trust_on_first_use()`](crate::OlmMachine::set_trust_on_first_use).
+    Pinned,
+    /// The identity is neither verified nor pinned.
+    Untrusted,
+}
+
 /// Enum over the different user identity types we can have.
 #[derive(Debug, Clone)]
 pub enum UserIdentities {
@@ -161,6 +176,39 @@ impl OwnUserIdentity {
         })
     }
 
+    /// Acknowledge that our identity changed without being verified again.
+    ///
+    /// This forgets that we previously verified our identity, which resolves
+    /// the [verification violation].
+    ///
+    /// [verification violation]: ReadOnlyOwnUserIdentity::has_verification_violation
+    pub async fn withdraw_verification(&self) -> Result<(), CryptoStoreError> {
+        self.inner.withdraw_verification();
+
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![self.inner.clone().into()], new: vec![] },
+            ..Default::default()
+        };
+
+        self.verification_machine.store.save_changes(changes).await
+    }
+
+    /// Pin the current master key of our identity.
+    ///
+    /// This can be used to accept a master key that doesn't match the one
+    /// pinned on first use, without verifying the identity.
+    pub async fn pin_current_master_key(&self) -> Result<(), CryptoStoreError> {
+        let mut identity = self.inner.clone();
+        identity.pin();
+
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![identity.into()], new: vec![] },
+            ..Default::default()
+        };
+
+        self.verification_machine.store.save_changes(changes).await
+    }
+
     async fn request_verification_helper(
         &self,
         methods: Option<Vec<VerificationMethod>>,
@@ -214,6 +262,64 @@ impl UserIdentity {
             .unwrap_or(false)
     }
 
+    /// Get the trust state of this user identity.
+    pub fn trust_state(&self) -> IdentityTrustState {
+        if self.verified() {
+            IdentityTrustState::Verified
+        } else if self.inner.is_pinned() {
+            IdentityTrustState::Pinned
+        } else {
+            IdentityTrustState::Untrusted
+        }
+    }
+
+    /// Pin the current master key of this user.
+    ///
+    /// This can be used to accept a master key that doesn't match the one
+    /// pinned on first use, without verifying the user.
+    pub async fn pin_current_master_key(&self) -> Result<(), CryptoStoreError> {
+        let mut identity = self.inner.clone();
+        identity.pin();
+
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![identity.into()], new: vec![] },
+            ..Default::default()
+        };
+
+        self.verification_machine.store.save_changes(changes).await
+    }
+
+    /// Has the identity of this user changed since we last verified it.
+    ///
+    /// This is the case if we previously verified the user, but the master key
+    /// of the user changed and the new identity isn't verified yet. This should
+    /// be surfaced to the user, since the change might mean that the account
+    /// was taken over.
+    ///
+    /// The violation can be resolved by verifying the user again, or
+    /// acknowledged using [`UserIdentity::withdraw_verification()`].
+    pub fn has_verification_violation(&self) -> bool {
+        self.inner.was_previously_verified() && !self.verified()
+    }
+
+    /// Acknowledge that the identity of this user changed without being
+    /// verified again.
+    ///
+    /// This forgets that we previously verified the user, which resolves the
+    /// [verification violation].
+    ///
+    /// [verification violation]: UserIdentity::has_verification_violation
+    pub async fn withdraw_verification(&self) -> Result<(), CryptoStoreError> {
+        self.inner.withdraw_verification();
+
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![self.inner.clone().into()], new: vec![] },
+            ..Default::default()
+        };
+
+        self.verification_machine.store.save_changes(changes).await
+    }
+
     /// Manually verify this user.
     ///
     /// This method will attempt to sign the user identity using our private
@@ -652,6 +758,31 @@ impl ReadOnlyUserIdentities {
         }
     }
 
+    /// Get the master key we pinned for this identity, if any.
+    pub fn pinned_master_key(&self) -> Option<&MasterPubkey> {
+        match self {
+            ReadOnlyUserIdentities::Own(i) => i.pinned_master_key(),
+            ReadOnlyUserIdentities::Other(i) => i.pinned_master_key(),
+        }
+    }
+
+    /// Does the master key of the identity match the pinned one.
+    pub fn is_pinned(&self) -> bool {
+        match self {
+            ReadOnlyUserIdentities::Own(i) => i.is_pinned(),
+            ReadOnlyUserIdentities::Other(i) => i.is_pinned(),
+        }
+    }
+
+    /// Pin the given master key, which should be the first master key we saw
+    /// for the identity.
+    pub(crate) fn pin_master_key(&mut self, master_key: MasterPubkey) {
+        match self {
+            ReadOnlyUserIdentities::Own(i) => i.pinned_master_key = Some(master_key),
+            ReadOnlyUserIdentities::Other(i) => i.pinned_master_key = Some(master_key),
+        }
+    }
+
     /// Destructure the enum into an `ReadOnlyOwnUserIdentity` if it's of the
     /// correct type.
     pub fn own(&self) -> Option<&ReadOnlyOwnUserIdentity> {
@@ -694,6 +825,14 @@ pub struct ReadOnlyUserIdentity {
     user_id: Arc<UserId>,
     pub(crate) master_key: MasterPubkey,
     self_signing_key: SelfSigningPubkey,
+    #[serde(
+        default,
+        serialize_with = "atomic_bool_serializer",
+        deserialize_with = "atomic_bool_deserializer"
+    )]
+    previously_verified: Arc<AtomicBool>,
+    #[serde(default)]
+    pinned_master_key: Option<MasterPubkey>,
 }
 
 impl ReadOnlyUserIdentity {
@@ -713,7 +852,13 @@ impl ReadOnlyUserIdentity {
     ) -> Result<Self, SignatureError> {
         master_key.verify_subkey(&self_signing_key)?;
 
-        Ok(Self { user_id: master_key.0.user_id.clone().into(), master_key, self_signing_key })
+        Ok(Self {
+            user_id: master_key.0.user_id.clone().into(),
+            master_key,
+            self_signing_key,
+            previously_verified: Default::default(),
+            pinned_master_key: None,
+        })
     }
 
     #[cfg(test)]
@@ -722,7 +867,13 @@ impl ReadOnlyUserIdentity {
         let self_signing_key =
             identity.self_signing_key.lock().await.as_ref().unwrap().public_key.clone();
 
-        Self { user_id: identity.user_id().into(), master_key, self_signing_key }
+        Self {
+            user_id: identity.user_id().into(),
+            master_key,
+            self_signing_key,
+            previously_verified: Default::default(),
+            pinned_master_key: None,
+        }
     }
 
     /// Get the user id of this identity.
@@ -740,6 +891,39 @@ impl ReadOnlyUserIdentity {
         &self.self_signing_key
     }
 
+    /// Did we verify this identity at some point in the past.
+    ///
+    /// This stays true if the master key of the identity changes, until the
+    /// verification is withdrawn.
+    pub fn was_previously_verified(&self) -> bool {
+        self.previously_verified.load(Ordering::SeqCst)
+    }
+
+    /// Remember that we verified this identity.
+    pub(crate) fn mark_as_previously_verified(&self) {
+        self.previously_verified.store(true, Ordering::SeqCst)
+    }
+
+    /// Forget that we previously verified this identity.
+    pub(crate) fn withdraw_verification(&self) {
+        self.previously_verified.store(false, Ordering::SeqCst)
+    }
+
+    /// Get the master key we pinned for this identity, if any.
+    pub fn pinned_master_key(&self) -> Option<&MasterPubkey> {
+        self.pinned_master_key.as_ref()
+    }
+
+    /// Does the master key of the identity match the pinned one.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned_master_key.as_ref() == Some(&self.master_key)
+    }
+
+    /// Pin the current master key of the identity.
+    pub(crate) fn pin(&mut self) {
+        self.pinned_master_key = Some(self.master_key.clone());
+    }
+
     /// Update the identity with a new master key and self signing key.
     ///
     /// # Arguments
@@ -801,6 +985,14 @@ pub struct ReadOnlyOwnUserIdentity {
         deserialize_with = "atomic_bool_deserializer"
     )]
     verified: Arc<AtomicBool>,
+    #[serde(
+        default,
+        serialize_with = "atomic_bool_serializer",
+        deserialize_with = "atomic_bool_deserializer"
+    )]
+    previously_verified: Arc<AtomicBool>,
+    #[serde(default)]
+    pinned_master_key: Option<MasterPubkey>,
 }
 
 impl ReadOnlyOwnUserIdentity {
@@ -831,6 +1023,8 @@ impl ReadOnlyOwnUserIdentity {
             self_signing_key,
             user_signing_key,
             verified: Arc::new(AtomicBool::new(false)),
+            previously_verified: Arc::new(AtomicBool::new(false)),
+            pinned_master_key: None,
         })
     }
 
@@ -892,7 +1086,8 @@ impl ReadOnlyOwnUserIdentity {
 
     /// Mark our identity as verified.
     pub fn mark_as_verified(&self) {
-        self.verified.store(true, Ordering::SeqCst)
+        self.verified.store(true, Ordering::SeqCst);
+        self.previously_verified.store(true, Ordering::SeqCst);
     }
 
     /// Check if our identity is verified.
@@ -900,6 +1095,56 @@ impl ReadOnlyOwnUserIdentity {
         self.verified.load(Ordering::SeqCst)
     }
 
+    /// Did we verify our identity at some point in the past.
+    ///
+    /// This stays true if the master key of our identity changes, until the
+    /// verification is withdrawn.
+    pub fn was_previously_verified(&self) -> bool {
+        self.previously_verified.load(Ordering::SeqCst)
+    }
+
+    /// Forget that we previously verified our identity.
+    pub(crate) fn withdraw_verification(&self) {
+        self.previously_verified.store(false, Ordering::SeqCst)
+    }
+
+    /// Has our identity changed since we last verified it.
+    ///
+    /// This is the case if we previously verified our identity, but the master
+    /// key of the identity changed and the new identity isn't verified yet.
+    ///
+    /// The violation can be resolved by verifying the new identity, or
+    /// acknowledged using [`OwnUserIdentity::withdraw_verification()`].
+    pub fn has_verification_violation(&self) -> bool {
+        self.was_previously_verified() && !self.is_verified()
+    }
+
+    /// Get the trust state of our identity.
+    pub fn trust_state(&self) -> IdentityTrustState {
+        if self.is_verified() {
+            IdentityTrustState::Verified
+        } else if self.is_pinned() {
+            IdentityTrustState::Pinned
+        } else {
+            IdentityTrustState::Untrusted
+        }
+    }
+
+    /// Get the master key we pinned for this identity, if any.
+    pub fn pinned_master_key(&self) -> Option<&MasterPubkey> {
+        self.pinned_master_key.as_ref()
+    }
+
+    /// Does the master key of the identity match the pinned one.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned_master_key.as_ref() == Some(&self.master_key)
+    }
+
+    /// Pin the current master key of the identity.
+    pub(crate) fn pin(&mut self) {
+        self.pinned_master_key = Some(self.master_key.clone());
+    }
+
     /// Update the identity with a new master key and self signing key.
     ///
     /// Note: This will reset the verification state if the master keys differ.
@@ -926,6 +1171,12 @@ impl ReadOnlyOwnUserIdentity {
         self.user_signing_key = user_signing_key;
 
         if self.master_key != master_key {
+            // Our identity might have been verified before we started to
+            // remember verifications, don't forget about it now.
+            if self.is_verified() {
+                self.previously_verified.store(true, Ordering::SeqCst);
+            }
+
             self.verified.store(false, Ordering::SeqCst)
         }
 
@@ -941,9 +1192,11 @@ pub(crate) mod test {
 
     use matrix_sdk_common::locks::Mutex;
     use matrix_sdk_test::async_test;
-    use ruma::{api::client::r0::keys::get_keys::Response as KeyQueryResponse, user_id};
+    use ruma::{api::client::r0::keys::get_keys::Response as KeyQueryResponse, device_id, user_id};
 
-    use super::{ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities, ReadOnlyUserIdentity};
+    use super::{
+        ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities, ReadOnlyUserIdentity, UserIdentity,
+    };
     use crate::{
         identities::{
             manager::test::{other_key_query, own_key_query},
@@ -1048,6 +1301,45 @@ pub(crate) mod test {
         assert!(!first.verified());
     }
 
+    #[async_test]
+    async fn verification_violation() {
+        let own_identity = get_own_identity();
+        let other_identity = get_other_identity();
+
+        let private_identity = Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(
+            own_identity.user_id().to_owned(),
+        )));
+        let verification_machine = VerificationMachine::new(
+            ReadOnlyAccount::new(own_identity.user_id(), device_id!("DEVICEID")),
+            private_identity,
+            Arc::new(MemoryStore::new()),
+        );
+
+        let identity = UserIdentity {
+            inner: other_identity.clone(),
+            own_identity: Some(own_identity),
+            verification_machine,
+        };
+
+        assert!(!identity.verified());
+        assert!(!identity.has_verification_violation());
+
+        // We verified the user in the past, but the identity isn't signed by
+        // our current user-signing key.
+        other_identity.mark_as_previously_verified();
+        assert!(identity.has_verification_violation());
+
+        identity.withdraw_verification().await.unwrap();
+        assert!(!identity.has_verification_violation());
+        assert!(!other_identity.was_previously_verified());
+
+        // Identities stored before we remembered verifications still load.
+        let mut value = serde_json::to_value(&other_identity).unwrap();
+        value.as_object_mut().unwrap().remove("previously_verified");
+        let identity: ReadOnlyUserIdentity = serde_json::from_value(value).unwrap();
+        assert!(!identity.was_previously_verified());
+    }
+
     #[async_test]
     async fn own_device_with_private_identity() {
         let response = own_key_query();
@@ -25,9 +25,13 @@
     unused_qualifications
 )]
 
+#[cfg(not(any(feature = "libolm", feature = "vodozemac")))]
+compile_error!("An Olm backend needs to be selected using the `libolm` or `vodozemac` feature.");
+
 #[cfg(feature = "backups_v1")]
 #[cfg_attr(feature = "docs", doc(cfg(backups_v1)))]
 pub mod backups;
+pub mod dehydrated_devices;
 mod error;
 mod file_encryption;
 mod gossiping;
@@ -35,10 +39,12 @@ mod identities;
 mod machine;
 pub mod olm;
 mod requests;
+pub mod secret_storage;
 mod session_manager;
 pub mod store;
 mod utilities;
 mod verification;
+pub mod withheld;
 
 use std::collections::{BTreeMap, BTreeSet};
 
@@ -70,23 +76,32 @@ impl RoomKeyImportResult {
 
 pub use error::{MegolmError, OlmError, SignatureError};
 pub use file_encryption::{
-    decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, KeyExportError, MediaEncryptionInfo,
+    decrypt_key_export, encrypt_key_export, AsyncAttachmentDecryptor, AsyncAttachmentEncryptor,
+    AttachmentDecryptor, AttachmentEncryptor, DecryptorError, KeyExportError, KeyExportReader,
+    KeyExportWriter, MediaEncryptionInfo,
+};
+pub use gossiping::{
+    DefaultKeyForwardingPolicy, KeyForwardDecision, KeyForwardRequest, KeyForwardingPolicy,
+    NeverForwardPolicy,
 };
 pub use identities::{
-    Device, LocalTrust, MasterPubkey, OwnUserIdentity, ReadOnlyDevice, ReadOnlyOwnUserIdentity,
-    ReadOnlyUserIdentities, ReadOnlyUserIdentity, UserDevices, UserIdentities, UserIdentity,
+    Device, IdentityTrustState, LocalTrust, MasterPubkey, OwnUserIdentity, ReadOnlyDevice,
+    ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities, ReadOnlyUserIdentity, RoomTrustReport,
+    ShieldState, UserDevices, UserIdentities, UserIdentity, UserTrustReport,
 };
 pub use machine::OlmMachine;
 #[cfg(feature = "qrcode")]
 pub use matrix_qrcode;
 pub(crate) use olm::ReadOnlyAccount;
-pub use olm::{CrossSigningStatus, EncryptionSettings};
+pub use olm::{CrossSigningStatus, EncryptionSettings, ShareStrategy};
 pub use requests::{
     IncomingResponse, KeysBackupRequest, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
     OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest, UploadSigningKeysRequest,
 };
 pub use store::{CrossSigningKeyExport, CryptoStoreError, SecretImportError};
-pub use verification::{AcceptSettings, CancelInfo, Emoji, Sas, Verification, VerificationRequest};
+pub use verification::{
+    AcceptSettings, CancelInfo, Emoji, PickledVerificationRequest, Sas, SasMethod, Verification,
+    VerificationRequest,
+};
 #[cfg(feature = "qrcode")]
 pub use verification::{QrVerification, ScanError};
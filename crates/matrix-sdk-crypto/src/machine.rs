@@ -15,14 +15,19 @@
 #[cfg(feature = "sled_cryptostore")]
 use std::path::Path;
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
+    collections::{BTreeMap, HashSet},
+    io::Write,
     mem,
     sync::Arc,
 };
 
 use dashmap::DashMap;
 use matrix_sdk_common::{
-    deserialized_responses::{AlgorithmInfo, EncryptionInfo, SyncRoomEvent, VerificationState},
+    deserialized_responses::{
+        AlgorithmInfo, EncryptionInfo, SyncRoomEvent, UnableToDecryptInfo, UnableToDecryptReason,
+        VerificationState,
+    },
+    instant::Duration,
     locks::Mutex,
     uuid::Uuid,
 };
@@ -44,10 +49,11 @@ use ruma::{
         },
         room_key::ToDeviceRoomKeyEvent,
         secret::request::SecretName,
-        AnyMessageEventContent, AnyRoomEvent, AnyToDeviceEvent, EventContent,
+        AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnyToDeviceEvent, EventContent,
     },
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, RoomId, UInt, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, EventId, RoomId, UInt,
+    UserId,
 };
 use serde_json::{value::to_raw_value, Value};
 use tracing::{debug, error, info, trace, warn};
@@ -57,24 +63,34 @@ use crate::backups::BackupMachine;
 #[cfg(feature = "sled_cryptostore")]
 use crate::store::sled::SledStore;
 use crate::{
+    dehydrated_devices::{DehydratedDevice, DehydratedDeviceData, DehydrationError},
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
-    gossiping::GossipMachine,
-    identities::{user::UserIdentities, Device, IdentityManager, UserDevices},
+    gossiping::{GossipMachine, KeyForwardingPolicy},
+    identities::{
+        user::UserIdentities, Device, IdentityManager, RoomTrustReport, UserDevices,
+        UserTrustReport,
+    },
     olm::{
         Account, CrossSigningStatus, EncryptionSettings, ExportedRoomKey, GroupSessionKey,
-        IdentityKeys, InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, SessionType,
+        IdentityKeys, InboundGroupSession, MegolmMessageIndex, OlmDecryptionInfo,
+        OlmGroupSessionError, PrivateCrossSigningIdentity, ReadOnlyAccount, Session, SessionType,
     },
     requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
+    secret_storage::{self, SecretEventContent, SecretStorageError, SecretStorageKey},
     session_manager::{GroupSessionManager, SessionManager},
     store::{
         Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, Result as StoreResult,
         SecretImportError, Store,
     },
     verification::{Verification, VerificationMachine, VerificationRequest},
-    CrossSigningKeyExport, RoomKeyImportResult, ToDeviceRequest,
+    withheld::{ToDeviceRoomKeyWithheldEvent, UNSTABLE_WITHHELD_EVENT_TYPE, WITHHELD_EVENT_TYPE},
+    CrossSigningKeyExport, KeyExportError, KeyExportWriter, RoomKeyImportResult, ToDeviceRequest,
 };
 
+/// The number of room keys that get loaded from the store at once when
+/// streaming a key export.
+const EXPORT_BATCH_SIZE: usize = 1000;
+
 /// State machine implementation of the Olm/Megolm encryption protocol used for
 /// Matrix end to end encryption.
 #[derive(Clone)]
@@ -214,6 +230,9 @@ impl OlmMachine {
     /// pair those will be re-used. Otherwise new ones will be created and
     /// stored.
     ///
+    /// Verification requests that were in progress when the store was last
+    /// used are resumed, the ones that can't be resumed anymore get cancelled.
+    ///
     /// # Arguments
     ///
     /// * `user_id` - The unique id of the user that owns this machine.
@@ -263,7 +282,10 @@ impl OlmMachine {
             }
         };
 
-        Ok(OlmMachine::new_helper(&user_id, device_id, store, account, identity))
+        let machine = OlmMachine::new_helper(&user_id, device_id, store, account, identity);
+        machine.verification_machine.restore_requests().await;
+
+        Ok(machine)
     }
 
     /// Create a new machine with the default crypto store.
@@ -342,6 +364,7 @@ impl OlmMachine {
 
         requests.append(&mut self.verification_machine.outgoing_messages());
         requests.append(&mut self.key_request_machine.outgoing_to_device_requests().await?);
+        requests.append(&mut self.session_manager.outgoing_to_device_requests());
 
         Ok(requests)
     }
@@ -874,6 +897,49 @@ impl OlmMachine {
         self.account.update_uploaded_key_count(one_time_key_count);
     }
 
+    /// Handle a `m.room_key.withheld` event.
+    ///
+    /// The event is only remembered if it was sent by a device we know about,
+    /// decryption failures of events using the room key will then report the
+    /// withheld code.
+    async fn receive_room_key_withheld(
+        &self,
+        raw_event: &Raw<AnyToDeviceEvent>,
+        changes: &mut Changes,
+    ) {
+        let event: ToDeviceRoomKeyWithheldEvent = match raw_event.deserialize_as() {
+            Ok(e) => e,
+            Err(e) => {
+                warn!(error =? e, "Received an invalid m.room_key.withheld event");
+                return;
+            }
+        };
+
+        match self.store.get_device_from_curve_key(&event.sender, &event.content.sender_key).await {
+            Ok(Some(_)) => {
+                info!(
+                    sender = event.sender.as_str(),
+                    room_id =? event.content.room_id,
+                    session_id =? event.content.session_id,
+                    code = event.content.code.as_str(),
+                    "Received a m.room_key.withheld event"
+                );
+
+                changes.withheld_session_info.push(event);
+            }
+            Ok(None) => {
+                warn!(
+                    sender = event.sender.as_str(),
+                    sender_key = event.content.sender_key.as_str(),
+                    "Received a m.room_key.withheld event from an unknown device"
+                );
+            }
+            Err(e) => {
+                error!(error =? e, "Couldn't check the sender of a m.room_key.withheld event");
+            }
+        }
+    }
+
     async fn handle_to_device_event(&self, event: &AnyToDeviceEvent) {
         match event {
             AnyToDeviceEvent::RoomKeyRequest(e) => {
@@ -1007,6 +1073,11 @@ impl OlmMachine {
 
                     raw_event = decrypted.event;
                 }
+                e if e.event_type() == WITHHELD_EVENT_TYPE
+                    || e.event_type() == UNSTABLE_WITHHELD_EVENT_TYPE =>
+                {
+                    self.receive_room_key_withheld(&raw_event, &mut changes).await;
+                }
                 e => self.handle_to_device_event(&e).await,
             }
 
@@ -1017,6 +1088,8 @@ impl OlmMachine {
 
         changes.sessions.extend(changed_sessions);
 
+        self.verification_machine.collect_request_changes(&mut changes);
+
         self.store.save_changes(changes).await?;
 
         let mut to_device = ToDevice::new();
@@ -1057,6 +1130,86 @@ impl OlmMachine {
             .await?)
     }
 
+    /// Get information on why a room event couldn't be decrypted.
+    ///
+    /// Returns `None` if the event wasn't encrypted using the
+    /// `m.megolm.v1.aes-sha2` algorithm.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that couldn't be decrypted.
+    ///
+    /// * `room_id` - The ID of the room where the event was sent to.
+    ///
+    /// * `error` - The error [`OlmMachine::decrypt_room_event()`] returned for
+    /// the event.
+    pub async fn unable_to_decrypt_info(
+        &self,
+        event: &SyncRoomEncryptedEvent,
+        room_id: &RoomId,
+        error: &MegolmError,
+    ) -> Result<Option<UnableToDecryptInfo>, CryptoStoreError> {
+        let content = match &event.content.scheme {
+            EncryptedEventScheme::MegolmV1AesSha2(c) => c,
+            _ => return Ok(None),
+        };
+
+        let reason = match error {
+            MegolmError::MissingRoomKey => UnableToDecryptReason::MissingSession,
+            MegolmError::Withheld(code) => {
+                UnableToDecryptReason::Withheld { code: code.as_str().to_owned() }
+            }
+            MegolmError::OlmGroupSession(OlmGroupSessionError::UnknownMessageIndex) => {
+                UnableToDecryptReason::UnknownMessageIndex
+            }
+            MegolmError::OlmGroupSession(_) => UnableToDecryptReason::OlmFailure,
+            _ => UnableToDecryptReason::Other,
+        };
+
+        let key_request_pending = self
+            .key_request_machine
+            .is_key_request_pending(room_id, &content.sender_key, &content.session_id)
+            .await?;
+
+        Ok(Some(UnableToDecryptInfo {
+            session_id: content.session_id.clone(),
+            sender_key: content.sender_key.clone(),
+            reason,
+            key_request_pending,
+        }))
+    }
+
+    /// Enable or disable trust on first use (TOFU) for user identities.
+    ///
+    /// If enabled, the master key of a user identity gets pinned the first
+    /// time we see the identity. Identities whose master key matches the
+    /// pinned one are considered to be [pinned], which is a weaker trust state
+    /// than being verified, but doesn't require an interactive verification.
+    ///
+    /// If the master key of a user changes later on, the identity stops being
+    /// pinned until the new master key is verified or pinned manually.
+    ///
+    /// This is disabled by default.
+    ///
+    /// [pinned]: crate::IdentityTrustState::Pinned
+    pub fn set_trust_on_first_use(&self, enabled: bool) {
+        self.identity_manager.set_trust_on_first_use(enabled)
+    }
+
+    /// Set the policy that decides if room keys get forwarded to devices that
+    /// request them.
+    ///
+    /// By default the [`DefaultKeyForwardingPolicy`](crate::DefaultKeyForwardingPolicy)
+    /// is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy that should be used for incoming room key
+    /// requests.
+    pub async fn set_key_forwarding_policy(&self, policy: impl KeyForwardingPolicy + 'static) {
+        self.key_request_machine.set_key_forwarding_policy(Arc::new(policy)).await
+    }
+
     async fn get_encryption_info(
         &self,
         session: &InboundGroupSession,
@@ -1095,6 +1248,57 @@ impl OlmMachine {
         })
     }
 
+    /// Check that no other event was decrypted at the given message index of
+    /// the inbound group session, otherwise remember the event for this
+    /// message index.
+    async fn check_message_index(
+        &self,
+        session: &InboundGroupSession,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> MegolmResult<()> {
+        let known_event_id = self
+            .store
+            .get_event_id_for_message_index(
+                session.room_id(),
+                session.sender_key(),
+                session.session_id(),
+                message_index,
+            )
+            .await?;
+
+        match known_event_id {
+            Some(known_event_id) if known_event_id.as_ref() != event_id => {
+                warn!(
+                    room_id = session.room_id().as_str(),
+                    session_id = session.session_id(),
+                    sender_key = session.sender_key(),
+                    message_index,
+                    event_id = event_id.as_str(),
+                    known_event_id = known_event_id.as_str(),
+                    "A Megolm message was replayed, the message index was already used"
+                );
+
+                Err(MegolmError::ReplayedMessage(known_event_id, message_index))
+            }
+            Some(_) => Ok(()),
+            None => {
+                let changes = Changes {
+                    megolm_message_indices: vec![MegolmMessageIndex {
+                        room_id: session.room_id().to_owned(),
+                        sender_key: session.sender_key().to_owned(),
+                        session_id: session.session_id().to_owned(),
+                        message_index,
+                        event_id: event_id.to_owned(),
+                    }],
+                    ..Default::default()
+                };
+
+                Ok(self.store.save_changes(changes).await?)
+            }
+        }
+    }
+
     async fn decrypt_megolm_v1_event(
         &self,
         room_id: &RoomId,
@@ -1106,8 +1310,9 @@ impl OlmMachine {
             .get_inbound_group_session(room_id, &content.sender_key, &content.session_id)
             .await?
         {
-            // TODO check the message index.
-            let (decrypted_event, _) = session.decrypt(event).await?;
+            let (decrypted_event, message_index) = session.decrypt(event).await?;
+
+            self.check_message_index(&session, message_index, &event.event_id).await?;
 
             match decrypted_event.deserialize() {
                 Ok(e) => {
@@ -1141,13 +1346,22 @@ impl OlmMachine {
             let encryption_info =
                 self.get_encryption_info(&session, &event.sender, &content.device_id).await?;
 
-            Ok(SyncRoomEvent { encryption_info: Some(encryption_info), event: decrypted_event })
+            Ok(SyncRoomEvent {
+                encryption_info: Some(encryption_info),
+                unable_to_decrypt_info: None,
+                event: decrypted_event,
+            })
         } else {
             self.key_request_machine
                 .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
                 .await?;
 
-            Err(MegolmError::MissingRoomKey)
+            match self.store.get_withheld_info(room_id, &content.session_id).await? {
+                Some(info) if info.content.sender_key == content.sender_key => {
+                    Err(MegolmError::Withheld(info.content.code))
+                }
+                _ => Err(MegolmError::MissingRoomKey),
+            }
         }
     }
 
@@ -1169,7 +1383,6 @@ impl OlmMachine {
                     Ok(r) => Ok(r),
                     Err(e) => {
                         if let MegolmError::MissingRoomKey = e {
-                            // TODO log the withheld reason if we have one.
                             debug!(
                                 sender = event.sender.as_str(),
                                 room_id = room_id.as_str(),
@@ -1177,6 +1390,15 @@ impl OlmMachine {
                                 session_id = c.session_id.as_str(),
                                 "Failed to decrypt a room event, the room key is missing"
                             );
+                        } else if let MegolmError::Withheld(code) = &e {
+                            debug!(
+                                sender = event.sender.as_str(),
+                                room_id = room_id.as_str(),
+                                sender_key = c.sender_key.as_str(),
+                                session_id = c.session_id.as_str(),
+                                code = code.as_str(),
+                                "Failed to decrypt a room event, the room key was withheld"
+                            );
                         } else {
                             warn!(
                                 sender = event.sender.as_str(),
@@ -1204,6 +1426,22 @@ impl OlmMachine {
         }
     }
 
+    /// Handle an in-room verification event that was sent unencrypted.
+    ///
+    /// In-room verification events that were encrypted get handled when they
+    /// are decrypted using [`OlmMachine::decrypt_room_event()`], events that
+    /// aren't verification events are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The room event that might be a verification event.
+    pub async fn receive_unencrypted_verification_event(
+        &self,
+        event: &AnyMessageEvent,
+    ) -> StoreResult<()> {
+        self.verification_machine.receive_any_event(event).await
+    }
+
     /// Update the tracked users.
     ///
     /// # Arguments
@@ -1293,6 +1531,57 @@ impl OlmMachine {
         self.store.get_user_devices(user_id).await
     }
 
+    /// Get a summary of the trust state of the given user and their devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user.
+    pub async fn get_user_trust_report(&self, user_id: &UserId) -> StoreResult<UserTrustReport> {
+        self.store.get_user_trust_report(user_id).await
+    }
+
+    /// Get a summary of the trust state of all the given room members and
+    /// their devices.
+    ///
+    /// The per-user summaries are cached and only recalculated once the
+    /// identity or the devices of a user change, so this is cheap enough to
+    /// be called every time a room shield needs to be rendered.
+    ///
+    /// # Arguments
+    ///
+    /// * `members` - The user ids of the room members.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use matrix_sdk_crypto::{OlmMachine, ShieldState};
+    /// # use ruma::{device_id, user_id};
+    /// # use futures::executor::block_on;
+    /// # let alice = user_id!("@alice:example.org").to_owned();
+    /// # let machine = OlmMachine::new(&alice, device_id!("DEVICEID"));
+    /// # block_on(async {
+    /// let bob = user_id!("@bob:example.org");
+    /// let report = machine.room_trust_report([&*alice, bob]).await.unwrap();
+    ///
+    /// if report.shield_state() == ShieldState::Red {
+    ///     println!("Some devices in the room can't be trusted");
+    /// }
+    /// # });
+    /// ```
+    pub async fn room_trust_report(
+        &self,
+        members: impl IntoIterator<Item = &UserId>,
+    ) -> StoreResult<RoomTrustReport> {
+        let mut report = RoomTrustReport::default();
+
+        for user_id in members {
+            let user_report = self.store.get_user_trust_report(user_id).await?;
+            report.members.insert(user_id.to_owned(), user_report);
+        }
+
+        Ok(report)
+    }
+
     /// Import the given room keys into our store.
     ///
     /// # Arguments
@@ -1326,85 +1615,10 @@ impl OlmMachine {
     pub async fn import_keys(
         &self,
         exported_keys: Vec<ExportedRoomKey>,
-        #[allow(unused_variables)] from_backup: bool,
+        from_backup: bool,
         progress_listener: impl Fn(usize, usize),
     ) -> StoreResult<RoomKeyImportResult> {
-        type SessionIdToIndexMap = BTreeMap<Arc<str>, u32>;
-
-        #[derive(Debug)]
-        struct ShallowSessions {
-            inner: BTreeMap<Arc<RoomId>, BTreeMap<Arc<str>, SessionIdToIndexMap>>,
-        }
-
-        impl ShallowSessions {
-            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
-                self.inner
-                    .get(&session.room_id)
-                    .and_then(|m| {
-                        m.get(&session.sender_key).and_then(|m| {
-                            m.get(&session.session_id)
-                                .map(|existing| existing <= &session.first_known_index())
-                        })
-                    })
-                    .unwrap_or(false)
-            }
-        }
-
-        let mut sessions = Vec::new();
-
-        let existing_sessions = ShallowSessions {
-            inner: self.store.get_inbound_group_sessions().await?.into_iter().fold(
-                BTreeMap::new(),
-                |mut acc, s| {
-                    let index = s.first_known_index();
-
-                    acc.entry(s.room_id)
-                        .or_default()
-                        .entry(s.sender_key)
-                        .or_default()
-                        .insert(s.session_id, index);
-
-                    acc
-                },
-            ),
-        };
-
-        let total_count = exported_keys.len();
-        let mut keys = BTreeMap::new();
-
-        for (i, key) in exported_keys.into_iter().enumerate() {
-            let session = InboundGroupSession::from_export(key)?;
-
-            // Only import the session if we didn't have this session or if it's
-            // a better version of the same session, that is the first known
-            // index is lower.
-            if !existing_sessions.has_better_session(&session) {
-                #[cfg(feature = "backups_v1")]
-                if from_backup {
-                    session.mark_as_backed_up()
-                }
-
-                keys.entry(session.room_id().to_owned())
-                    .or_insert_with(BTreeMap::new)
-                    .entry(session.sender_key().to_owned())
-                    .or_insert_with(BTreeSet::new)
-                    .insert(session.session_id().to_owned());
-
-                sessions.push(session)
-            }
-
-            progress_listener(i, total_count)
-        }
-
-        let imported_count = sessions.len();
-
-        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
-
-        self.store.save_changes(changes).await?;
-
-        info!(total_count, imported_count, room_keys =? keys, "Successfully imported room keys");
-
-        Ok(RoomKeyImportResult::new(imported_count, total_count, keys))
+        self.store.import_room_keys(exported_keys, from_backup, progress_listener).await
     }
 
     /// Export the keys that match the given predicate.
@@ -1457,6 +1671,183 @@ impl OlmMachine {
         Ok(exported)
     }
 
+    /// Export the room keys that match the given predicate into the given
+    /// [`KeyExportWriter`].
+    ///
+    /// Unlike [`OlmMachine::export_keys()`] this doesn't load all the room keys
+    /// into memory at once, the room keys are loaded from the store in batches
+    /// and written out one by one. The writer needs to be finished using
+    /// [`KeyExportWriter::finish()`] once the export is done.
+    ///
+    /// Returns the number of room keys that were exported.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The writer that will encrypt and write out the room keys.
+    ///
+    /// * `predicate` - A closure that will be called for every known
+    /// `InboundGroupSession`, which represents a room key. If the closure
+    /// returns `true` the `InboundGroupSession` will be included in the export,
+    /// if the closure returns `false` it will not be included.
+    pub async fn export_keys_streaming<W: Write>(
+        &self,
+        writer: &mut KeyExportWriter<W>,
+        mut predicate: impl FnMut(&InboundGroupSession) -> bool,
+    ) -> Result<usize, KeyExportError> {
+        let mut exported_count = 0;
+        let mut last_session = None;
+
+        loop {
+            let sessions = self
+                .store
+                .get_inbound_group_sessions_batch(last_session.as_ref(), EXPORT_BATCH_SIZE)
+                .await?;
+
+            if sessions.is_empty() {
+                break;
+            }
+
+            for session in &sessions {
+                if predicate(session) {
+                    writer.write_key(&session.export().await)?;
+                    exported_count += 1;
+                }
+            }
+
+            last_session = sessions.into_iter().last();
+        }
+
+        Ok(exported_count)
+    }
+
+    /// Import room keys while streaming them from the given iterator, e.g. a
+    /// [`KeyExportReader`](crate::KeyExportReader).
+    ///
+    /// Unlike [`OlmMachine::import_keys()`] this doesn't require all the room
+    /// keys to be held in memory at once, the room keys get saved in batches
+    /// while they are read.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - The room keys that should be imported.
+    ///
+    /// * `from_backup` - Were the room keys imported from the backup, if true
+    /// will mark the room keys as already backed up.
+    ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of room keys that were processed so far.
+    pub async fn import_keys_streaming(
+        &self,
+        exported_keys: impl IntoIterator<Item = Result<ExportedRoomKey, KeyExportError>>,
+        from_backup: bool,
+        progress_listener: impl Fn(usize),
+    ) -> Result<RoomKeyImportResult, KeyExportError> {
+        self.store.import_room_keys_streaming(exported_keys, from_backup, progress_listener).await
+    }
+
+    /// Remove old Olm sessions from the store.
+    ///
+    /// Only the `max_sessions_per_sender` most recently used sessions will be
+    /// kept for every device we share Olm sessions with.
+    ///
+    /// Sessions with devices that aren't part of the device list of any
+    /// tracked user anymore, e.g. devices that were deleted before their
+    /// sessions were removed together with them, are removed as well. This
+    /// is skipped while some users still need to have their keys queried,
+    /// since their device lists might be outdated.
+    ///
+    /// Returns the number of sessions that were removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_sessions_per_sender` - The number of sessions that should be kept
+    /// per sender key.
+    pub async fn prune_olm_sessions(&self, max_sessions_per_sender: usize) -> StoreResult<usize> {
+        let mut deleted_sessions = Vec::new();
+
+        let known_sender_keys = if self.store.users_for_key_query().is_empty() {
+            let mut users = self.store.tracked_users();
+            users.insert(self.user_id().to_owned());
+
+            let mut keys = HashSet::new();
+
+            for user_id in users {
+                keys.extend(
+                    self.store
+                        .get_readonly_devices_unfiltered(&user_id)
+                        .await?
+                        .values()
+                        .filter_map(|d| d.get_key(DeviceKeyAlgorithm::Curve25519))
+                        .map(ToOwned::to_owned),
+                );
+            }
+
+            Some(keys)
+        } else {
+            None
+        };
+
+        for sender_key in self.store.get_session_sender_keys().await? {
+            if let Some(sessions) = self.store.get_sessions(&sender_key).await? {
+                let mut sessions: Vec<Session> = sessions.lock().await.clone();
+
+                if known_sender_keys.as_ref().map_or(false, |k| !k.contains(&sender_key)) {
+                    deleted_sessions.extend(sessions);
+                } else if sessions.len() > max_sessions_per_sender {
+                    sessions.sort_by(|a, b| b.last_use_time.cmp(&a.last_use_time));
+                    deleted_sessions.extend(sessions.drain(max_sessions_per_sender..));
+                }
+            }
+        }
+
+        let count = deleted_sessions.len();
+
+        if count > 0 {
+            debug!(count, "Removing old Olm sessions");
+
+            let changes = Changes { deleted_sessions, ..Default::default() };
+            self.store.save_changes(changes).await?;
+        }
+
+        Ok(count)
+    }
+
+    /// Delete all the room keys, i.e. the inbound group sessions, that belong
+    /// to the given room.
+    ///
+    /// Messages in the room that were encrypted using the deleted room keys
+    /// won't be decryptable anymore, this should be used when a room was left
+    /// or forgotten.
+    ///
+    /// Returns the number of room keys that were deleted.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the room keys should be
+    /// deleted.
+    pub async fn delete_room_keys(&self, room_id: &RoomId) -> StoreResult<usize> {
+        let count = self.store.delete_inbound_group_sessions_for_room(room_id).await?;
+
+        if count > 0 {
+            debug!(room_id = room_id.as_str(), count, "Deleted room keys");
+        }
+
+        Ok(count)
+    }
+
+    /// Remove all the outgoing room key and secret requests that are older than
+    /// the given maximal age.
+    ///
+    /// Requests that were already sent out will be cancelled, the
+    /// cancellations will be returned by the [`outgoing_requests()`] method.
+    ///
+    /// Returns the number of requests that were removed.
+    ///
+    /// [`outgoing_requests()`]: #method.outgoing_requests
+    pub async fn expire_secret_requests(&self, max_age: Duration) -> StoreResult<usize> {
+        self.key_request_machine.expire_outgoing_requests(max_age).await
+    }
+
     /// Get the status of the private cross signing keys.
     ///
     /// This can be used to check which private cross signing keys we have
@@ -1497,6 +1888,83 @@ impl OlmMachine {
         self.store.import_cross_signing_keys(export).await
     }
 
+    /// Encrypt our private cross signing keys and the recovery key of the
+    /// server-side key backup using the given secret storage key.
+    ///
+    /// Returns a map from the secret name to the content of the global account
+    /// data event that holds the encrypted secret. Every event needs to be
+    /// uploaded to the server, the secret name is used as the event type.
+    ///
+    /// Secrets that we don't posses won't be part of the map.
+    pub async fn export_secrets_to_secret_storage(
+        &self,
+        key: &SecretStorageKey,
+    ) -> BTreeMap<String, SecretEventContent> {
+        let mut secrets = BTreeMap::new();
+
+        for secret_name in secret_storage::supported_secrets() {
+            if let Some(secret) = self.store.export_secret(&secret_name).await {
+                let secret = zeroize::Zeroizing::new(secret);
+                let content = key.encrypt_secret(&secret, secret_name.as_ref());
+
+                secrets.insert(secret_name.as_ref().to_owned(), content);
+            }
+        }
+
+        secrets
+    }
+
+    /// Decrypt and import our private cross signing keys and the recovery key
+    /// of the server-side key backup from the secret storage.
+    ///
+    /// The private cross signing keys are imported using
+    /// [`OlmMachine::import_cross_signing_keys()`], this requires our public
+    /// cross signing identity to be known, e.g. after a `/keys/query` request.
+    ///
+    /// The recovery key is only stored, the backup won't be enabled. The
+    /// recovery key should be checked against the backup on the server before
+    /// the backup gets enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The secret storage key the secrets were encrypted with.
+    ///
+    /// * `secrets` - Map from the secret name to the content of the global
+    /// account data event that holds the encrypted secret. Missing secrets will
+    /// be skipped.
+    pub async fn import_secrets_from_secret_storage(
+        &self,
+        key: &SecretStorageKey,
+        secrets: &BTreeMap<String, SecretEventContent>,
+    ) -> Result<CrossSigningStatus, SecretStorageError> {
+        let decrypt = |secret_name: SecretName| {
+            secrets
+                .get(secret_name.as_ref())
+                .map(|content| key.decrypt_secret(content, secret_name.as_ref()))
+                .transpose()
+        };
+
+        let export = CrossSigningKeyExport {
+            master_key: decrypt(SecretName::CrossSigningMasterKey)?.map(|s| s.to_string()),
+            self_signing_key: decrypt(SecretName::CrossSigningSelfSigningKey)?
+                .map(|s| s.to_string()),
+            user_signing_key: decrypt(SecretName::CrossSigningUserSigningKey)?
+                .map(|s| s.to_string()),
+        };
+
+        let status = self.import_cross_signing_keys(export).await?;
+
+        #[cfg(feature = "backups_v1")]
+        if let Some(secret) = decrypt(SecretName::RecoveryKey)? {
+            let recovery_key = crate::backups::RecoveryKey::from_base64(&secret)?;
+            self.backup_machine.save_recovery_key(Some(recovery_key), None).await?;
+
+            info!("Successfully imported the recovery key from the secret storage");
+        }
+
+        Ok(status)
+    }
+
     async fn sign_account(
         &self,
         message: &str,
@@ -1547,6 +2015,91 @@ impl OlmMachine {
         signatures
     }
 
+    /// Create a new [dehydrated device].
+    ///
+    /// The returned device contains the request that needs to be sent out to
+    /// store the dehydrated device on the server. Other devices will send
+    /// room keys to the dehydrated device while we're offline, the device can
+    /// be rehydrated using [`rehydrate_device()`](Self::rehydrate_device) the
+    /// next time we log in.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that should be used to encrypt the Olm account
+    /// of the dehydrated device.
+    ///
+    /// * `display_name` - The display name the dehydrated device should get.
+    ///
+    /// [dehydrated device]: crate::dehydrated_devices
+    pub async fn create_dehydrated_device(
+        &self,
+        pickle_key: &[u8],
+        display_name: &str,
+    ) -> DehydratedDevice {
+        DehydratedDevice::new(self.user_id(), pickle_key, display_name).await
+    }
+
+    /// Rehydrate a [dehydrated device] and import the room keys it received.
+    ///
+    /// The dehydrated device and its to-device events need to be fetched by
+    /// the caller, the to-device events will be decrypted and the room keys they contain will be imported into our
+    /// store.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that was used to create the dehydrated device.
+    ///
+    /// * `device_id` - The device id of the dehydrated device.
+    ///
+    /// * `device_data` - The data of the dehydrated device, as returned by the
+    /// server.
+    ///
+    /// * `to_device_events` - The to-device events the dehydrated device
+    /// received.
+    ///
+    /// [dehydrated device]: crate::dehydrated_devices
+    pub async fn rehydrate_device(
+        &self,
+        pickle_key: &[u8],
+        device_id: &DeviceId,
+        device_data: DehydratedDeviceData,
+        to_device_events: Vec<Raw<AnyToDeviceEvent>>,
+    ) -> Result<RoomKeyImportResult, DehydrationError> {
+        let account = device_data.rehydrate(self.user_id(), device_id, pickle_key)?;
+
+        // The dehydrated device gets its own throwaway machine, we're only
+        // interested in the room keys it receives.
+        let machine = OlmMachine::new_helper(
+            self.user_id(),
+            device_id.to_owned(),
+            Box::new(MemoryStore::new()),
+            account,
+            PrivateCrossSigningIdentity::empty(self.user_id().to_owned()),
+        );
+
+        let mut to_device = ToDevice::new();
+        to_device.events = to_device_events;
+
+        machine
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), None)
+            .await?;
+
+        // The room keys were sent directly to the dehydrated device, which is
+        // one of our own devices, so they keep counting as received
+        // directly instead of being marked as imported.
+        let sessions = machine.store.get_inbound_group_sessions().await?;
+        let result = self.store.import_inbound_group_sessions(sessions, false, |_, _| {}).await?;
+
+        info!(
+            device_id = device_id.as_str(),
+            imported_count = result.imported_count,
+            total_count = result.total_count,
+            "Rehydrated a dehydrated device"
+        );
+
+        Ok(result)
+    }
+
     /// Get a reference to the backup related state machine.
     ///
     /// This state machine can be used to incrementally backup all room keys to
@@ -1559,9 +2112,15 @@ impl OlmMachine {
 
 #[cfg(test)]
 pub(crate) mod test {
-    use std::{collections::BTreeMap, convert::TryInto, iter, sync::Arc};
+    use std::{
+        collections::BTreeMap,
+        convert::{TryFrom, TryInto},
+        iter,
+        sync::Arc,
+    };
 
     use http::Response;
+    use matrix_sdk_common::deserialized_responses::UnableToDecryptReason;
     use matrix_sdk_test::test_json;
     use ruma::{
         api::{
@@ -1577,21 +2136,23 @@ pub(crate) mod test {
                 encrypted::ToDeviceRoomEncryptedEventContent,
                 message::{MessageType, RoomMessageEventContent},
             },
-            AnyMessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
-            AnyToDeviceEventContent, SyncMessageEvent, ToDeviceEvent, Unsigned,
+            AnyMessageEvent, AnyMessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent,
+            AnyToDeviceEvent, AnyToDeviceEventContent, SyncMessageEvent, ToDeviceEvent, Unsigned,
         },
         room_id,
         serde::Raw,
         uint, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch,
         UserId,
     };
-    use serde_json::json;
+    use serde_json::{json, value::to_raw_value};
 
     use crate::{
         machine::OlmMachine,
-        olm::Utility,
+        olm::{PicklingMode, Utility},
+        secret_storage::SecretStorageKey,
+        store::{Changes, DeviceChanges, IdentityChanges},
         verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+        EncryptionSettings, LocalTrust, MegolmError, ReadOnlyDevice, ShieldState, ToDeviceRequest,
     };
 
     /// These keys need to be periodically uploaded to the server.
@@ -2004,6 +2565,133 @@ pub(crate) mod test {
             .await;
 
         assert!(session.unwrap().is_some());
+
+        assert_eq!(bob.delete_room_keys(room_id).await.unwrap(), 1);
+
+        let session = bob
+            .store
+            .get_inbound_group_session(
+                room_id,
+                alice.account.identity_keys().curve25519(),
+                alice_session.session_id(),
+            )
+            .await;
+
+        assert!(session.unwrap().is_none());
+        assert_eq!(bob.delete_room_keys(room_id).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_room_trust_report() {
+        let (alice, bob, _) = get_machine_pair().await;
+        let members = [alice.user_id(), bob.user_id()];
+
+        let report = alice.room_trust_report(members).await.unwrap();
+        let bob_report = &report.members[bob.user_id()];
+
+        assert_eq!(bob_report.identity_state, None);
+        assert_eq!(bob_report.unverified_devices, 1);
+        assert_eq!(bob_report.verified_devices, 0);
+        // Alice doesn't have any other devices.
+        assert_eq!(report.members[alice.user_id()].shield_state(), ShieldState::Grey);
+        assert_eq!(report.shield_state(), ShieldState::Grey);
+        assert!(!report.contains_only_verified_devices());
+
+        let bob_device = alice.get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+
+        bob_device.set_local_trust(LocalTrust::Verified).await.unwrap();
+        let report = alice.room_trust_report(members).await.unwrap();
+        let bob_report = &report.members[bob.user_id()];
+
+        assert_eq!(bob_report.unverified_devices, 0);
+        assert_eq!(bob_report.verified_devices, 1);
+        assert_eq!(bob_report.shield_state(), ShieldState::Green);
+        // Alice not having any other devices doesn't stop the room from
+        // being green.
+        assert_eq!(report.shield_state(), ShieldState::Green);
+        assert!(report.contains_only_verified_devices());
+
+        bob_device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+        let report = alice.room_trust_report(members).await.unwrap();
+
+        assert_eq!(report.members[bob.user_id()].blacklisted_devices, 1);
+        assert_eq!(report.shield_state(), ShieldState::Red);
+
+        let changes = Changes {
+            devices: DeviceChanges {
+                deleted: vec![bob_device.inner.clone()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        alice.store.save_changes(changes).await.unwrap();
+
+        let bob_report = alice.get_user_trust_report(bob.user_id()).await.unwrap();
+
+        assert_eq!(bob_report.blacklisted_devices, 0);
+        assert_eq!(bob_report.shield_state(), ShieldState::Grey);
+    }
+
+    #[tokio::test]
+    async fn test_olm_session_pruning() {
+        let (alice, bob, one_time_keys) = get_machine_pair().await;
+        let sender_key = bob.identity_keys().curve25519();
+
+        let mut sessions = Vec::new();
+
+        for one_time_key in one_time_keys.values().take(3) {
+            let one_time_key = match one_time_key.deserialize().unwrap() {
+                OneTimeKey::SignedKey(k) => k,
+                _ => panic!("Invalid one-time key type"),
+            };
+
+            let session = alice
+                .account
+                .create_outbound_session_helper(sender_key, &one_time_key)
+                .await
+                .unwrap();
+            sessions.push(session);
+        }
+
+        alice.store.save_sessions(&sessions).await.unwrap();
+        alice.store.update_tracked_user(bob.user_id(), false).await.unwrap();
+
+        assert_eq!(alice.prune_olm_sessions(3).await.unwrap(), 0);
+        assert_eq!(alice.prune_olm_sessions(1).await.unwrap(), 2);
+
+        let stored = alice.store.get_sessions(sender_key).await.unwrap().unwrap();
+        let stored = stored.lock().await;
+
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].session_id(), sessions[2].session_id());
+    }
+
+    #[tokio::test]
+    async fn test_olm_session_pruning_of_deleted_devices() {
+        let (alice, bob) = get_machine_pair_with_session().await;
+        let sender_key = bob.identity_keys().curve25519();
+
+        alice.store.update_tracked_user(bob.user_id(), false).await.unwrap();
+
+        // The device was deleted without its sessions being removed.
+        let bob_device =
+            alice.store.get_readonly_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+        let changes = Changes {
+            devices: DeviceChanges { deleted: vec![bob_device], ..Default::default() },
+            ..Default::default()
+        };
+        alice.store.save_changes(changes).await.unwrap();
+
+        // The sessions are kept while the device list of Bob might be outdated.
+        alice.store.update_tracked_user(bob.user_id(), true).await.unwrap();
+        assert_eq!(alice.prune_olm_sessions(3).await.unwrap(), 0);
+
+        alice.store.update_tracked_user(bob.user_id(), false).await.unwrap();
+        assert_eq!(alice.prune_olm_sessions(3).await.unwrap(), 1);
+
+        if let Some(sessions) = alice.store.get_sessions(sender_key).await.unwrap() {
+            assert!(sessions.lock().await.is_empty());
+        }
     }
 
     #[tokio::test]
@@ -2060,6 +2748,52 @@ pub(crate) mod test {
         } else {
             panic!("Decrypted room event has the wrong type")
         }
+
+        // Decrypting the same event again is fine.
+        bob.decrypt_room_event(&event, room_id).await.unwrap();
+
+        // A different event that reuses the ciphertext is a replay.
+        let replayed_event =
+            SyncMessageEvent { event_id: event_id!("$yyyyy:example.org").to_owned(), ..event };
+
+        assert!(matches!(
+            bob.decrypt_room_event(&replayed_event, room_id).await,
+            Err(MegolmError::ReplayedMessage(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unable_to_decrypt_info() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .share_group_session(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content =
+            alice.encrypt(room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org").to_owned(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().to_owned(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        // Bob never received the room key.
+        let error = bob.decrypt_room_event(&event, room_id).await.unwrap_err();
+        let info = bob.unable_to_decrypt_info(&event, room_id, &error).await.unwrap().unwrap();
+
+        let session = alice.group_session_manager.get_outbound_group_session(room_id).unwrap();
+
+        assert_eq!(info.reason, UnableToDecryptReason::MissingSession);
+        assert_eq!(info.session_id, session.session_id());
+        assert_eq!(info.sender_key, alice.identity_keys().curve25519());
+        assert!(!info.key_request_pending);
     }
 
     #[tokio::test]
@@ -2100,6 +2834,114 @@ pub(crate) mod test {
         assert_eq!(ed25519_key, machine.identity_keys().ed25519());
     }
 
+    #[tokio::test]
+    async fn dehydrated_device_cycle() {
+        let pickle_key = b"It is a secret to everybody";
+        let alice = OlmMachine::new(alice_id(), alice_device_id());
+        let bob = OlmMachine::new(user_id(), device_id!("BOBDEVICE"));
+        let room_id = room_id!("!test:example.org");
+
+        let dehydrated_device =
+            alice.create_dehydrated_device(pickle_key, "Dehydrated device").await;
+        let request = dehydrated_device.request().clone();
+        let device_id = &*request.device_id;
+
+        let device_keys = request.device_keys.deserialize().unwrap();
+        assert_eq!(device_keys.device_id, request.device_id);
+        let device = ReadOnlyDevice::try_from(&device_keys).unwrap();
+        bob.store.save_devices(&[device]).await.unwrap();
+
+        let one_time_key = request.one_time_keys.into_iter().next().unwrap();
+        let one_time_keys = BTreeMap::from([(
+            alice.user_id().to_owned(),
+            BTreeMap::from([(device_id.to_owned(), BTreeMap::from([one_time_key]))]),
+        )]);
+        let response = claim_keys::Response::new(one_time_keys);
+        bob.receive_keys_claim_response(&response).await.unwrap();
+
+        let to_device_requests = bob
+            .share_group_session(
+                room_id,
+                iter::once(alice.user_id()),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: bob.user_id().to_owned(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+        let event = Raw::from_json(to_raw_value(&event).unwrap());
+        let device_data = request.device_data;
+
+        assert!(alice
+            .rehydrate_device(
+                b"Wrong pickle key",
+                device_id,
+                device_data.clone(),
+                vec![event.clone()]
+            )
+            .await
+            .is_err());
+
+        let result =
+            alice.rehydrate_device(pickle_key, device_id, device_data, vec![event]).await.unwrap();
+
+        assert_eq!(result.imported_count, 1);
+        assert!(result.keys.contains_key(room_id));
+
+        // The room key was sent to one of our devices, it must not be
+        // treated like an imported or forwarded room key.
+        let session = alice.store.get_inbound_group_sessions().await.unwrap().remove(0);
+        assert!(!session.pickle(PicklingMode::Unencrypted).await.imported);
+    }
+
+    #[tokio::test]
+    async fn secret_storage_cycle() {
+        let alice = OlmMachine::new(alice_id(), alice_device_id());
+        alice.bootstrap_cross_signing(false).await.unwrap();
+
+        #[cfg(feature = "backups_v1")]
+        alice
+            .backup_machine()
+            .save_recovery_key(Some(crate::backups::RecoveryKey::new().unwrap()), None)
+            .await
+            .unwrap();
+
+        let key = SecretStorageKey::new();
+        let secrets = alice.export_secrets_to_secret_storage(&key).await;
+
+        assert!(secrets.contains_key("m.cross_signing.master"));
+        assert!(secrets.contains_key("m.cross_signing.self_signing"));
+        assert!(secrets.contains_key("m.cross_signing.user_signing"));
+        #[cfg(feature = "backups_v1")]
+        assert!(secrets.contains_key("m.megolm_backup.v1"));
+
+        let second_alice = OlmMachine::new(alice_id(), device_id!("SECONDDEVICE"));
+        let identity = alice.store.get_identity(alice_id()).await.unwrap().unwrap();
+        let changes = Changes {
+            identities: IdentityChanges { new: vec![identity], ..Default::default() },
+            ..Default::default()
+        };
+        second_alice.store.save_changes(changes).await.unwrap();
+
+        let wrong_key = SecretStorageKey::new();
+        assert!(second_alice
+            .import_secrets_from_secret_storage(&wrong_key, &secrets)
+            .await
+            .is_err());
+
+        let status = second_alice.import_secrets_from_secret_storage(&key, &secrets).await.unwrap();
+
+        assert!(status.has_master);
+        assert!(status.has_self_signing);
+        assert!(status.has_user_signing);
+
+        #[cfg(feature = "backups_v1")]
+        assert!(second_alice.store.load_backup_keys().await.unwrap().recovery_key.is_some());
+    }
+
     #[tokio::test]
     async fn interactive_verification() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -2179,4 +3021,58 @@ pub(crate) mod test {
         assert!(bob_sas.is_done());
         assert!(alice_device.verified());
     }
+
+    #[tokio::test]
+    async fn unencrypted_in_room_verification_request() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let event_id = event_id!("$verification:example.org");
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "event_id": event_id,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "room_id": room_id!("!test:example.org"),
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.key.verification.request",
+                "body": "Alice is requesting to verify your key",
+                "from_device": alice.device_id(),
+                "methods": ["m.sas.v1"],
+                "to": bob.user_id(),
+            },
+        });
+        let event: AnyMessageEvent = serde_json::from_value(event).unwrap();
+
+        bob.receive_unencrypted_verification_event(&event).await.unwrap();
+
+        let request = bob.get_verification_request(alice.user_id(), event_id).unwrap();
+        assert!(request.room_id().is_some());
+        assert!(!request.we_started());
+    }
+
+    #[tokio::test]
+    async fn in_room_verification_request_for_someone_else() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let event_id = event_id!("$verification:example.org");
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "event_id": event_id,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "room_id": room_id!("!test:example.org"),
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.key.verification.request",
+                "body": "Alice is requesting to verify your key",
+                "from_device": alice.device_id(),
+                "methods": ["m.sas.v1"],
+                "to": user_id!("@carol:example.org"),
+            },
+        });
+        let event: AnyMessageEvent = serde_json::from_value(event).unwrap();
+
+        bob.receive_unencrypted_verification_event(&event).await.unwrap();
+
+        assert!(bob.get_verification_request(alice.user_id(), event_id).is_none());
+    }
 }
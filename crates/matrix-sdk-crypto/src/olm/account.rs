@@ -24,12 +24,6 @@ use std::{
 };
 
 use matrix_sdk_common::{instant::Instant, locks::Mutex};
-use olm_rs::{
-    account::{IdentityKeys, OlmAccount, OneTimeKeys},
-    errors::{OlmAccountError, OlmSessionError},
-    session::{OlmMessage, PreKeyMessage},
-    PicklingMode,
-};
 use ruma::{
     api::client::r0::keys::{upload_keys, upload_signatures::Request as SignatureUploadRequest},
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey, SignedKey},
@@ -52,8 +46,10 @@ use sha2::{Digest, Sha256};
 use tracing::{debug, info, trace, warn};
 
 use super::{
-    EncryptionSettings, InboundGroupSession, OutboundGroupSession, PrivateCrossSigningIdentity,
-    Session,
+    backend::{OneTimeKeys, RawAccount},
+    EncryptionSettings, IdentityKeys, InboundGroupSession, OlmAccountError, OlmMessage,
+    OlmSessionError, OutboundGroupSession, PicklingMode, PreKeyMessage,
+    PrivateCrossSigningIdentity, Session,
 };
 use crate::{
     error::{EventError, OlmResult, SessionCreationError},
@@ -476,7 +472,7 @@ impl Account {
 pub struct ReadOnlyAccount {
     pub(crate) user_id: Arc<UserId>,
     pub(crate) device_id: Arc<DeviceId>,
-    inner: Arc<Mutex<OlmAccount>>,
+    inner: Arc<Mutex<RawAccount>>,
     pub(crate) identity_keys: Arc<IdentityKeys>,
     shared: Arc<AtomicBool>,
     /// The number of signed one-time keys we have uploaded to the server. If
@@ -541,8 +537,8 @@ impl ReadOnlyAccount {
     /// Create a fresh new account, this will generate the identity key-pair.
     #[allow(clippy::ptr_arg)]
     pub fn new(user_id: &UserId, device_id: &DeviceId) -> Self {
-        let account = OlmAccount::new();
-        let identity_keys = account.parsed_identity_keys();
+        let account = RawAccount::new();
+        let identity_keys = account.identity_keys();
 
         Self {
             user_id: user_id.into(),
@@ -600,7 +596,7 @@ impl ReadOnlyAccount {
     ///
     /// This can be empty, keys need to be generated first.
     pub(crate) async fn one_time_keys(&self) -> OneTimeKeys {
-        self.inner.lock().await.parsed_one_time_keys()
+        self.inner.lock().await.one_time_keys()
     }
 
     /// Generate count number of one-time keys.
@@ -734,8 +730,8 @@ impl ReadOnlyAccount {
         pickle: PickledAccount,
         pickle_mode: PicklingMode,
     ) -> Result<Self, OlmAccountError> {
-        let account = OlmAccount::unpickle(pickle.pickle.0, pickle_mode)?;
-        let identity_keys = account.parsed_identity_keys();
+        let account = RawAccount::unpickle(pickle.pickle.0, pickle_mode)?;
+        let identity_keys = account.identity_keys();
 
         Ok(Self {
             user_id: pickle.user_id.into(),
@@ -1027,11 +1023,7 @@ impl ReadOnlyAccount {
         message: PreKeyMessage,
     ) -> Result<Session, OlmSessionError> {
         let session =
-            self.inner.lock().await.create_inbound_session_from(their_identity_key, message)?;
-
-        self.inner.lock().await.remove_one_time_keys(&session).expect(
-            "Session was successfully created but the account doesn't hold a matching one-time key",
-        );
+            self.inner.lock().await.create_inbound_session(their_identity_key, message)?;
 
         let now = Instant::now();
         let session_id = session.session_id();
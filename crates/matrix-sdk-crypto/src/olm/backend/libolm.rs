@@ -0,0 +1,403 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The libolm backend, a thin wrapper around the `olm-rs` types.
+
+use olm_rs::{
+    account::OlmAccount,
+    errors::{
+        OlmGroupSessionError as LibolmGroupSessionError, OlmSasError as LibolmSasError,
+        OlmSessionError as LibolmSessionError,
+    },
+    inbound_group_session::OlmInboundGroupSession,
+    outbound_group_session::OlmOutboundGroupSession,
+    pk::OlmPkSigning,
+    sas::OlmSas,
+    session::{OlmMessage as LibolmMessage, OlmSession, PreKeyMessage as LibolmPreKeyMessage},
+    utility::OlmUtility,
+    PicklingMode as LibolmPicklingMode,
+};
+#[cfg(feature = "backups_v1")]
+use olm_rs::{
+    errors::OlmPkDecryptionError as LibolmPkDecryptionError,
+    pk::{OlmPkDecryption, OlmPkEncryption, PkMessage as LibolmPkMessage},
+};
+
+use super::{
+    IdentityKeys, OlmAccountError, OlmGroupSessionError, OlmMessage, OlmSasError, OlmSessionError,
+    OneTimeKeys, PicklingMode, PreKeyMessage,
+};
+#[cfg(feature = "backups_v1")]
+use super::{OlmPkDecryptionError, PkMessage};
+use crate::error::SignatureError;
+
+/// The name of the Olm backend the crate was compiled with.
+pub const BACKEND: &str = "libolm";
+
+fn pickling_mode(mode: PicklingMode) -> LibolmPicklingMode {
+    match mode {
+        PicklingMode::Unencrypted => LibolmPicklingMode::Unencrypted,
+        PicklingMode::Encrypted { key } => LibolmPicklingMode::Encrypted { key },
+    }
+}
+
+fn session_error(error: LibolmSessionError) -> OlmSessionError {
+    match error {
+        LibolmSessionError::BadAccountKey => OlmSessionError::InvalidKey(error.to_string()),
+        LibolmSessionError::BadMessageKeyId => OlmSessionError::MissingOneTimeKey,
+        LibolmSessionError::BadMessageFormat
+        | LibolmSessionError::BadMessageVersion
+        | LibolmSessionError::InvalidBase64 => OlmSessionError::InvalidMessage(error.to_string()),
+        _ => OlmSessionError::Decryption(error.to_string()),
+    }
+}
+
+fn group_session_error(error: LibolmGroupSessionError) -> OlmGroupSessionError {
+    match error {
+        LibolmGroupSessionError::UnknownMessageIndex => OlmGroupSessionError::UnknownMessageIndex,
+        LibolmGroupSessionError::BadSessionKey => {
+            OlmGroupSessionError::InvalidSessionKey(error.to_string())
+        }
+        LibolmGroupSessionError::BadMessageFormat
+        | LibolmGroupSessionError::BadMessageVersion
+        | LibolmGroupSessionError::InvalidBase64 => {
+            OlmGroupSessionError::InvalidMessage(error.to_string())
+        }
+        _ => OlmGroupSessionError::Decryption(error.to_string()),
+    }
+}
+
+fn sas_error(error: LibolmSasError) -> OlmSasError {
+    match error {
+        LibolmSasError::OtherPublicKeyUnset => OlmSasError::MissingPublicKey,
+        _ => OlmSasError::InvalidKey(error.to_string()),
+    }
+}
+
+#[cfg(feature = "backups_v1")]
+fn pk_decryption_error(error: LibolmPkDecryptionError) -> OlmPkDecryptionError {
+    match error {
+        LibolmPkDecryptionError::BadMessageMac => OlmPkDecryptionError::Mac,
+        LibolmPkDecryptionError::InvalidBase64 => {
+            OlmPkDecryptionError::InvalidMessage(error.to_string())
+        }
+        _ => OlmPkDecryptionError::Decryption(error.to_string()),
+    }
+}
+
+fn to_libolm_message(message: OlmMessage) -> LibolmMessage {
+    let (message_type, ciphertext) = message.to_tuple();
+
+    LibolmMessage::from_type_and_ciphertext(message_type, ciphertext)
+        .expect("Olm messages always have a valid message type")
+}
+
+fn to_libolm_pre_key_message(message: PreKeyMessage) -> LibolmPreKeyMessage {
+    match to_libolm_message(OlmMessage::PreKey(message)) {
+        LibolmMessage::PreKey(m) => m,
+        LibolmMessage::Message(_) => unreachable!("A pre-key message was converted to a message"),
+    }
+}
+
+fn from_libolm_message(message: LibolmMessage) -> OlmMessage {
+    let (message_type, ciphertext) = message.to_tuple();
+
+    OlmMessage::from_type_and_ciphertext(message_type as usize, ciphertext)
+        .expect("libolm returned an Olm message with an unknown message type")
+}
+
+/// Verify an ed25519 signature of the given message.
+pub(crate) fn ed25519_verify(
+    public_key: &str,
+    message: &str,
+    signature: &str,
+) -> Result<(), SignatureError> {
+    OlmUtility::new()
+        .ed25519_verify(public_key, message, signature.to_owned())
+        .map(|_| ())
+        .map_err(|_| SignatureError::VerificationError)
+}
+
+pub(crate) struct RawAccount(OlmAccount);
+
+impl RawAccount {
+    pub fn new() -> Self {
+        Self(OlmAccount::new())
+    }
+
+    pub fn identity_keys(&self) -> IdentityKeys {
+        let keys = self.0.parsed_identity_keys();
+        IdentityKeys::new(keys.curve25519().to_owned(), keys.ed25519().to_owned())
+    }
+
+    pub fn one_time_keys(&self) -> OneTimeKeys {
+        let keys = self.0.parsed_one_time_keys();
+        OneTimeKeys::new(keys.curve25519().iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    pub fn generate_one_time_keys(&mut self, count: usize) {
+        self.0.generate_one_time_keys(count)
+    }
+
+    pub fn max_number_of_one_time_keys(&self) -> usize {
+        self.0.max_number_of_one_time_keys()
+    }
+
+    pub fn mark_keys_as_published(&mut self) {
+        self.0.mark_keys_as_published()
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        self.0.sign(message)
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle(pickling_mode(mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmAccountError> {
+        OlmAccount::unpickle(pickle, pickling_mode(mode))
+            .map(Self)
+            .map_err(|e| OlmAccountError::Pickle(e.to_string()))
+    }
+
+    pub fn create_outbound_session(
+        &self,
+        identity_key: &str,
+        one_time_key: &str,
+    ) -> Result<RawSession, OlmSessionError> {
+        self.0
+            .create_outbound_session(identity_key, one_time_key)
+            .map(RawSession)
+            .map_err(session_error)
+    }
+
+    /// Create an inbound session from a pre-key message and remove the
+    /// one-time key that was used to create the session.
+    pub fn create_inbound_session(
+        &mut self,
+        identity_key: &str,
+        message: PreKeyMessage,
+    ) -> Result<RawSession, OlmSessionError> {
+        let session = self
+            .0
+            .create_inbound_session_from(identity_key, to_libolm_pre_key_message(message))
+            .map_err(session_error)?;
+
+        self.0.remove_one_time_keys(&session).expect(
+            "Session was successfully created but the account doesn't hold a matching one-time key",
+        );
+
+        Ok(RawSession(session))
+    }
+}
+
+pub(crate) struct RawSession(OlmSession);
+
+impl RawSession {
+    pub fn session_id(&self) -> String {
+        self.0.session_id()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &str) -> OlmMessage {
+        from_libolm_message(self.0.encrypt(plaintext))
+    }
+
+    pub fn decrypt(&mut self, message: OlmMessage) -> Result<String, OlmSessionError> {
+        self.0.decrypt(to_libolm_message(message)).map_err(session_error)
+    }
+
+    pub fn matches(
+        &self,
+        identity_key: &str,
+        message: PreKeyMessage,
+    ) -> Result<bool, OlmSessionError> {
+        self.0
+            .matches_inbound_session_from(identity_key, to_libolm_pre_key_message(message))
+            .map_err(session_error)
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle(pickling_mode(mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmSessionError> {
+        OlmSession::unpickle(pickle, pickling_mode(mode))
+            .map(Self)
+            .map_err(|e| OlmSessionError::Pickle(e.to_string()))
+    }
+}
+
+pub(crate) struct RawInboundGroupSession(OlmInboundGroupSession);
+
+impl RawInboundGroupSession {
+    pub fn new(session_key: &str) -> Result<Self, OlmGroupSessionError> {
+        OlmInboundGroupSession::new(session_key)
+            .map(Self)
+            .map_err(|e| OlmGroupSessionError::InvalidSessionKey(e.to_string()))
+    }
+
+    pub fn import(exported_session_key: &str) -> Result<Self, OlmGroupSessionError> {
+        OlmInboundGroupSession::import(exported_session_key)
+            .map(Self)
+            .map_err(|e| OlmGroupSessionError::InvalidSessionKey(e.to_string()))
+    }
+
+    pub fn session_id(&self) -> String {
+        self.0.session_id()
+    }
+
+    pub fn first_known_index(&self) -> u32 {
+        self.0.first_known_index()
+    }
+
+    pub fn export(&self, message_index: u32) -> Result<String, OlmGroupSessionError> {
+        self.0.export(message_index).map_err(group_session_error)
+    }
+
+    pub fn decrypt(&mut self, message: String) -> Result<(String, u32), OlmGroupSessionError> {
+        self.0.decrypt(message).map_err(group_session_error)
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle(pickling_mode(mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmGroupSessionError> {
+        OlmInboundGroupSession::unpickle(pickle, pickling_mode(mode))
+            .map(Self)
+            .map_err(|e| OlmGroupSessionError::Pickle(e.to_string()))
+    }
+}
+
+pub(crate) struct RawOutboundGroupSession(OlmOutboundGroupSession);
+
+impl RawOutboundGroupSession {
+    pub fn new() -> Self {
+        Self(OlmOutboundGroupSession::new())
+    }
+
+    pub fn session_id(&self) -> String {
+        self.0.session_id()
+    }
+
+    pub fn session_key(&self) -> String {
+        self.0.session_key()
+    }
+
+    pub fn message_index(&self) -> u32 {
+        self.0.session_message_index()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &str) -> String {
+        self.0.encrypt(plaintext)
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle(pickling_mode(mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmGroupSessionError> {
+        OlmOutboundGroupSession::unpickle(pickle, pickling_mode(mode))
+            .map(Self)
+            .map_err(|e| OlmGroupSessionError::Pickle(e.to_string()))
+    }
+}
+
+pub(crate) struct RawPkSigning(OlmPkSigning);
+
+impl RawPkSigning {
+    pub fn generate_seed() -> Vec<u8> {
+        OlmPkSigning::generate_seed()
+    }
+
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self(OlmPkSigning::new(seed).expect("Unable to create pk signing object"))
+    }
+
+    pub fn public_key(&self) -> String {
+        self.0.public_key().to_owned()
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        self.0.sign(message)
+    }
+}
+
+pub(crate) struct RawSas(OlmSas);
+
+impl RawSas {
+    pub fn new() -> Self {
+        Self(OlmSas::new())
+    }
+
+    pub fn public_key(&self) -> String {
+        self.0.public_key()
+    }
+
+    pub fn set_their_public_key(&mut self, public_key: String) -> Result<(), OlmSasError> {
+        self.0.set_their_public_key(public_key).map_err(sas_error)
+    }
+
+    pub fn calculate_mac(&self, input: &str, info: &str) -> Result<String, OlmSasError> {
+        self.0.calculate_mac(input, info).map_err(sas_error)
+    }
+
+    pub fn generate_bytes(&self, info: &str, count: usize) -> Result<Vec<u8>, OlmSasError> {
+        self.0.generate_bytes(info, count).map_err(sas_error)
+    }
+}
+
+#[cfg(feature = "backups_v1")]
+pub(crate) struct RawPkEncryption(OlmPkEncryption);
+
+#[cfg(feature = "backups_v1")]
+impl RawPkEncryption {
+    pub fn from_base64(public_key: &str) -> Self {
+        Self(OlmPkEncryption::new(public_key))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> PkMessage {
+        let message = self.0.encrypt(plaintext);
+
+        PkMessage {
+            ciphertext: message.ciphertext,
+            mac: message.mac,
+            ephemeral_key: message.ephemeral_key,
+        }
+    }
+}
+
+#[cfg(feature = "backups_v1")]
+pub(crate) struct RawPkDecryption(OlmPkDecryption);
+
+#[cfg(feature = "backups_v1")]
+impl RawPkDecryption {
+    pub fn from_bytes(private_key: &[u8; 32]) -> Self {
+        Self(
+            OlmPkDecryption::from_bytes(private_key)
+                .expect("Can't create a libolm PkDecryption object from our private key"),
+        )
+    }
+
+    pub fn public_key(&self) -> String {
+        self.0.public_key().to_owned()
+    }
+
+    pub fn decrypt(&self, message: PkMessage) -> Result<String, OlmPkDecryptionError> {
+        let message = LibolmPkMessage::new(message.mac, message.ephemeral_key, message.ciphertext);
+
+        self.0.decrypt(message).map_err(pk_decryption_error)
+    }
+}
@@ -0,0 +1,614 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The implementations of the Olm and Megolm primitives.
+//!
+//! The rest of the crate only uses the types exported from this module, the
+//! implementation behind them is selected at compile time:
+//!
+//! * `libolm` - The default backend, wraps the C libolm library using the
+//!   `olm-rs` crate, selected using the `libolm` cargo feature.
+//! * `vodozemac` - A pure Rust implementation, selected using the `vodozemac`
+//!   cargo feature. It takes precedence if both features are enabled.
+//!
+//! Both backends restore pickles that were created by the libolm backend, so
+//! existing stores keep working if the backend is switched to vodozemac.
+//! Pickles created by the vodozemac backend can't be restored by the libolm
+//! backend.
+
+use std::collections::{btree_map, BTreeMap};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[cfg(feature = "libolm")]
+#[cfg_attr(feature = "vodozemac", allow(dead_code))]
+mod libolm;
+#[cfg(feature = "vodozemac")]
+mod vodozemac;
+
+#[cfg(not(feature = "vodozemac"))]
+pub use libolm::BACKEND;
+#[cfg(not(feature = "vodozemac"))]
+pub(crate) use libolm::{
+    ed25519_verify, RawAccount, RawInboundGroupSession, RawOutboundGroupSession, RawPkSigning,
+    RawSas, RawSession,
+};
+#[cfg(all(not(feature = "vodozemac"), feature = "backups_v1"))]
+pub(crate) use libolm::{RawPkDecryption, RawPkEncryption};
+
+#[cfg(feature = "vodozemac")]
+pub use self::vodozemac::BACKEND;
+#[cfg(feature = "vodozemac")]
+pub(crate) use self::vodozemac::{
+    ed25519_verify, RawAccount, RawInboundGroupSession, RawOutboundGroupSession, RawPkSigning,
+    RawSas, RawSession,
+};
+#[cfg(all(feature = "vodozemac", feature = "backups_v1"))]
+pub(crate) use self::vodozemac::{RawPkDecryption, RawPkEncryption};
+
+/// The mode that should be used to pickle Olm and Megolm objects.
+#[derive(Clone, Debug)]
+pub enum PicklingMode {
+    /// Pickle the objects without encrypting them.
+    Unencrypted,
+    /// Encrypt the pickled objects using the given key.
+    Encrypted {
+        /// The key that should be used to encrypt the pickle.
+        key: Vec<u8>,
+    },
+}
+
+/// The public parts of the identity keys of an Olm account.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IdentityKeys {
+    #[serde(flatten)]
+    keys: BTreeMap<String, String>,
+}
+
+impl IdentityKeys {
+    pub(crate) fn new(curve25519: String, ed25519: String) -> Self {
+        Self {
+            keys: BTreeMap::from([
+                ("curve25519".to_owned(), curve25519),
+                ("ed25519".to_owned(), ed25519),
+            ]),
+        }
+    }
+
+    /// Get the public part of the ed25519 key of the account.
+    pub fn ed25519(&self) -> &str {
+        &self.keys["ed25519"]
+    }
+
+    /// Get the public part of the curve25519 key of the account.
+    pub fn curve25519(&self) -> &str {
+        &self.keys["curve25519"]
+    }
+
+    /// Get a reference to the key of the given key type.
+    pub fn get(&self, key_type: &str) -> Option<&str> {
+        self.keys.get(key_type).map(|k| k.as_str())
+    }
+
+    /// An iterator visiting all the public keys of the account.
+    pub fn values(&self) -> btree_map::Values<'_, String, String> {
+        self.keys.values()
+    }
+
+    /// An iterator visiting all the key types of the account.
+    pub fn keys(&self) -> btree_map::Keys<'_, String, String> {
+        self.keys.keys()
+    }
+
+    /// An iterator visiting all the key type and public key pairs.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, String> {
+        self.keys.iter()
+    }
+
+    /// Returns true if the identity keys contain a key of the given key type.
+    pub fn contains_key(&self, key_type: &str) -> bool {
+        self.keys.contains_key(key_type)
+    }
+}
+
+/// The public parts of the unpublished one-time keys of an Olm account.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct OneTimeKeys {
+    keys: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl OneTimeKeys {
+    pub(crate) fn new(curve25519: BTreeMap<String, String>) -> Self {
+        Self { keys: BTreeMap::from([("curve25519".to_owned(), curve25519)]) }
+    }
+
+    /// Get the curve25519 one-time keys, a map from the key ID to the public
+    /// key.
+    pub(crate) fn curve25519(&self) -> &BTreeMap<String, String> {
+        &self.keys["curve25519"]
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get(&self, key_type: &str) -> Option<&BTreeMap<String, String>> {
+        self.keys.get(key_type)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn values(&self) -> btree_map::Values<'_, String, BTreeMap<String, String>> {
+        self.keys.values()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn keys(&self) -> btree_map::Keys<'_, String, BTreeMap<String, String>> {
+        self.keys.keys()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn iter(&self) -> btree_map::Iter<'_, String, BTreeMap<String, String>> {
+        self.keys.iter()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains_key(&self, key_type: &str) -> bool {
+        self.keys.contains_key(key_type)
+    }
+}
+
+/// A pre-key Olm message, the message that establishes a new Olm session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreKeyMessage {
+    pub(crate) ciphertext: String,
+}
+
+/// An encrypted Olm message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OlmMessage {
+    /// A normal Olm message.
+    Message(String),
+    /// A pre-key Olm message, sent until the session is confirmed by the other
+    /// side.
+    PreKey(PreKeyMessage),
+}
+
+impl OlmMessage {
+    /// The message type of pre-key messages.
+    pub const PRE_KEY: usize = 0;
+    /// The message type of normal messages.
+    pub const MESSAGE: usize = 1;
+
+    /// Create an Olm message from the message type and the base64 encoded
+    /// ciphertext.
+    ///
+    /// Returns an error if the message type is unknown.
+    pub fn from_type_and_ciphertext(
+        message_type: usize,
+        ciphertext: String,
+    ) -> Result<Self, UnknownMessageType> {
+        match message_type {
+            Self::PRE_KEY => Ok(Self::PreKey(PreKeyMessage { ciphertext })),
+            Self::MESSAGE => Ok(Self::Message(ciphertext)),
+            _ => Err(UnknownMessageType(message_type)),
+        }
+    }
+
+    /// Split the message into the message type and the base64 encoded
+    /// ciphertext.
+    pub fn to_tuple(self) -> (usize, String) {
+        match self {
+            Self::PreKey(m) => (Self::PRE_KEY, m.ciphertext),
+            Self::Message(m) => (Self::MESSAGE, m),
+        }
+    }
+}
+
+/// A message encrypted using the `m.megolm_backup.v1.curve25519-aes-sha2`
+/// algorithm.
+#[cfg(feature = "backups_v1")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PkMessage {
+    /// The base64 encoded ciphertext.
+    pub ciphertext: String,
+    /// The base64 encoded, truncated MAC of the message.
+    pub mac: String,
+    /// The base64 encoded ephemeral curve25519 key of the sender.
+    pub ephemeral_key: String,
+}
+
+/// Error type describing an Olm message with an unknown message type.
+#[derive(Debug, Error)]
+#[error("the Olm message has an unknown message type: {0}")]
+pub struct UnknownMessageType(pub usize);
+
+/// Error type describing failures of Olm account operations.
+#[derive(Debug, Error)]
+pub enum OlmAccountError {
+    /// The account pickle couldn't be decrypted or decoded.
+    #[error("the account pickle couldn't be restored: {0}")]
+    Pickle(String),
+}
+
+/// Error type describing failures of Olm session operations.
+#[derive(Debug, Error)]
+pub enum OlmSessionError {
+    /// The session pickle couldn't be decrypted or decoded.
+    #[error("the session pickle couldn't be restored: {0}")]
+    Pickle(String),
+    /// One of the keys used to create the session is invalid.
+    #[error("the session key is invalid: {0}")]
+    InvalidKey(String),
+    /// The pre-key message uses a one-time key that we don't have.
+    #[error("the pre-key message uses an unknown one-time key")]
+    MissingOneTimeKey,
+    /// The message couldn't be decoded.
+    #[error("the message is malformed: {0}")]
+    InvalidMessage(String),
+    /// The message couldn't be decrypted.
+    #[error("the message couldn't be decrypted: {0}")]
+    Decryption(String),
+}
+
+/// Error type describing failures of Megolm group session operations.
+#[derive(Debug, Error)]
+pub enum OlmGroupSessionError {
+    /// The group session pickle couldn't be decrypted or decoded.
+    #[error("the group session pickle couldn't be restored: {0}")]
+    Pickle(String),
+    /// The session key that should be used to create the group session is
+    /// invalid.
+    #[error("the session key is invalid: {0}")]
+    InvalidSessionKey(String),
+    /// The message couldn't be decoded.
+    #[error("the message is malformed: {0}")]
+    InvalidMessage(String),
+    /// The message was encrypted using a message index that is lower than the
+    /// first known index of the group session.
+    #[error("the message was encrypted using an unknown message index")]
+    UnknownMessageIndex,
+    /// The message couldn't be decrypted.
+    #[error("the message couldn't be decrypted: {0}")]
+    Decryption(String),
+}
+
+/// Error type describing failures of the short authentication string
+/// calculations.
+#[derive(Debug, Error)]
+pub enum OlmSasError {
+    /// The public key of the other side is invalid.
+    #[error("the public key of the other side is invalid: {0}")]
+    InvalidKey(String),
+    /// The public key of the other side wasn't set yet.
+    #[error("the public key of the other side wasn't set")]
+    MissingPublicKey,
+    /// The public key of the other side was already set.
+    #[error("the public key of the other side was already set")]
+    PublicKeyAlreadySet,
+}
+
+/// Error type describing failures to decrypt a room key from a server-side key
+/// backup.
+#[cfg(feature = "backups_v1")]
+#[derive(Debug, Error)]
+pub enum OlmPkDecryptionError {
+    /// The ephemeral key of the message is invalid.
+    #[error("the ephemeral key of the message is invalid: {0}")]
+    InvalidKey(String),
+    /// The message couldn't be decoded.
+    #[error("the message is malformed: {0}")]
+    InvalidMessage(String),
+    /// The MAC of the message didn't match.
+    #[error("the MAC of the message doesn't match")]
+    Mac,
+    /// The message couldn't be decrypted.
+    #[error("the message couldn't be decrypted: {0}")]
+    Decryption(String),
+}
+
+#[cfg(test)]
+mod test {
+    use matches::assert_matches;
+
+    use super::{
+        OlmMessage, OlmSasError, PicklingMode, RawAccount, RawInboundGroupSession,
+        RawOutboundGroupSession, RawSas, RawSession, UnknownMessageType,
+    };
+
+    const PLAINTEXT: &str = "It's a secret to everybody";
+
+    fn pickling_mode() -> PicklingMode {
+        PicklingMode::Encrypted { key: b"DEFAULT_PICKLE_PASSPHRASE_123456".to_vec() }
+    }
+
+    /// Create an outbound session for Alice and the matching inbound session
+    /// for Bob, the inbound session is created from the pre-key message that
+    /// Alice sent.
+    fn session_pair(alice: &RawAccount, bob: &mut RawAccount) -> (RawSession, RawSession) {
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys().curve25519().values().next().cloned().unwrap();
+        bob.mark_keys_as_published();
+
+        let mut alice_session = alice
+            .create_outbound_session(bob.identity_keys().curve25519(), &one_time_key)
+            .expect("Can't create an outbound session");
+
+        let message = alice_session.encrypt(PLAINTEXT);
+
+        let pre_key_message = if let OlmMessage::PreKey(m) = message.clone() {
+            m
+        } else {
+            panic!("The first message of a session should be a pre-key message");
+        };
+
+        let mut bob_session = bob
+            .create_inbound_session(alice.identity_keys().curve25519(), pre_key_message)
+            .expect("Can't create an inbound session");
+
+        assert_eq!(bob_session.decrypt(message).unwrap(), PLAINTEXT);
+
+        (alice_session, bob_session)
+    }
+
+    #[test]
+    fn message_type() {
+        let message = OlmMessage::from_type_and_ciphertext(OlmMessage::MESSAGE, "foo".to_owned())
+            .expect("Can't create a normal message");
+        assert_eq!(message.to_tuple(), (OlmMessage::MESSAGE, "foo".to_owned()));
+
+        assert_matches!(
+            OlmMessage::from_type_and_ciphertext(2, "foo".to_owned()),
+            Err(UnknownMessageType(2))
+        );
+    }
+
+    #[test]
+    fn account_pickling() {
+        let mut account = RawAccount::new();
+        account.generate_one_time_keys(10);
+
+        let unpickled = RawAccount::unpickle(account.pickle(pickling_mode()), pickling_mode())
+            .expect("Can't unpickle the account");
+
+        assert_eq!(account.identity_keys(), unpickled.identity_keys());
+        assert_eq!(account.one_time_keys(), unpickled.one_time_keys());
+
+        RawAccount::unpickle(account.pickle(pickling_mode()), PicklingMode::Unencrypted)
+            .err()
+            .expect("The account can't be unpickled using the wrong pickling mode");
+    }
+
+    #[test]
+    fn pickling_with_keys_of_any_size() {
+        let account = RawAccount::new();
+
+        for key in [b"It's a secret".to_vec(), vec![1u8; 32], vec![2u8; 64]] {
+            let pickle = account.pickle(PicklingMode::Encrypted { key: key.clone() });
+
+            let unpickled = RawAccount::unpickle(pickle.clone(), PicklingMode::Encrypted { key })
+                .expect("Can't unpickle the account");
+            assert_eq!(account.identity_keys(), unpickled.identity_keys());
+
+            RawAccount::unpickle(pickle, PicklingMode::Encrypted { key: vec![3u8; 32] })
+                .err()
+                .expect("The account can't be unpickled using the wrong key");
+        }
+    }
+
+    #[test]
+    fn session_encryption_and_pickling() {
+        let alice = RawAccount::new();
+        let mut bob = RawAccount::new();
+
+        let (alice_session, bob_session) = session_pair(&alice, &mut bob);
+        assert_eq!(alice_session.session_id(), bob_session.session_id());
+
+        let mut alice_session =
+            RawSession::unpickle(alice_session.pickle(pickling_mode()), pickling_mode())
+                .expect("Can't unpickle the session");
+        let mut bob_session =
+            RawSession::unpickle(bob_session.pickle(pickling_mode()), pickling_mode())
+                .expect("Can't unpickle the session");
+        assert_eq!(alice_session.session_id(), bob_session.session_id());
+
+        let message = bob_session.encrypt(PLAINTEXT);
+        assert_matches!(message, OlmMessage::Message(_));
+        assert_eq!(alice_session.decrypt(message).unwrap(), PLAINTEXT);
+
+        let message = alice_session.encrypt(PLAINTEXT);
+        assert_matches!(message, OlmMessage::Message(_));
+        assert_eq!(bob_session.decrypt(message).unwrap(), PLAINTEXT);
+    }
+
+    #[test]
+    fn group_session_encryption_and_pickling() {
+        let outbound = RawOutboundGroupSession::new();
+        let inbound = RawInboundGroupSession::new(&outbound.session_key())
+            .expect("Can't create an inbound group session");
+
+        let mut outbound =
+            RawOutboundGroupSession::unpickle(outbound.pickle(pickling_mode()), pickling_mode())
+                .expect("Can't unpickle the outbound group session");
+        let mut inbound =
+            RawInboundGroupSession::unpickle(inbound.pickle(pickling_mode()), pickling_mode())
+                .expect("Can't unpickle the inbound group session");
+        assert_eq!(outbound.session_id(), inbound.session_id());
+
+        let message = outbound.encrypt(PLAINTEXT);
+        assert_eq!(inbound.decrypt(message).unwrap(), (PLAINTEXT.to_owned(), 0));
+        assert_eq!(outbound.message_index(), 1);
+    }
+
+    #[test]
+    fn sas() {
+        let mut alice = RawSas::new();
+        let mut bob = RawSas::new();
+
+        assert_matches!(alice.generate_bytes("info", 6), Err(OlmSasError::MissingPublicKey));
+
+        alice.set_their_public_key(bob.public_key()).expect("Can't set the public key");
+        bob.set_their_public_key(alice.public_key()).expect("Can't set the public key");
+
+        let bytes = alice.generate_bytes("info", 6).unwrap();
+        assert_eq!(bytes.len(), 6);
+        assert_eq!(bytes, bob.generate_bytes("info", 6).unwrap());
+        assert_eq!(bob.generate_bytes("info", 5).unwrap(), bytes[..5]);
+        assert_ne!(bytes, bob.generate_bytes("other info", 6).unwrap());
+
+        assert_eq!(
+            alice.calculate_mac(PLAINTEXT, "info").unwrap(),
+            bob.calculate_mac(PLAINTEXT, "info").unwrap()
+        );
+    }
+
+    /// Objects that were pickled by the libolm backend need to be restored by
+    /// the vodozemac backend and keep working with the libolm objects on the
+    /// other side.
+    #[cfg(all(feature = "libolm", feature = "vodozemac"))]
+    #[test]
+    fn libolm_pickles_are_migrated() {
+        use super::{libolm, vodozemac};
+
+        let mut libolm_account = libolm::RawAccount::new();
+        libolm_account.generate_one_time_keys(1);
+        let one_time_key =
+            libolm_account.one_time_keys().curve25519().values().next().cloned().unwrap();
+
+        let mut account = vodozemac::RawAccount::unpickle(
+            libolm_account.pickle(pickling_mode()),
+            pickling_mode(),
+        )
+        .expect("Can't migrate the account");
+        assert_eq!(account.identity_keys(), libolm_account.identity_keys());
+        // The key IDs are encoded differently by the backends, only the keys
+        // themselves need to survive the migration.
+        assert_eq!(
+            account.one_time_keys().curve25519().values().collect::<Vec<_>>(),
+            libolm_account.one_time_keys().curve25519().values().collect::<Vec<_>>()
+        );
+
+        libolm::RawAccount::unpickle(account.pickle(pickling_mode()), pickling_mode())
+            .err()
+            .expect("The libolm backend can't restore a vodozemac pickle");
+
+        // The migrated account can still create sessions using the one-time
+        // keys that were generated by libolm.
+        let alice = libolm::RawAccount::new();
+        let mut alice_session = alice
+            .create_outbound_session(account.identity_keys().curve25519(), &one_time_key)
+            .expect("Can't create an outbound session");
+        let message = alice_session.encrypt(PLAINTEXT);
+
+        let pre_key_message = if let OlmMessage::PreKey(m) = message.clone() {
+            m
+        } else {
+            panic!("The first message of a session should be a pre-key message");
+        };
+
+        let bob_session = account
+            .create_inbound_session(alice.identity_keys().curve25519(), pre_key_message)
+            .expect("The migrated account can't create an inbound session");
+        assert_eq!(alice_session.session_id(), bob_session.session_id());
+
+        // A session that was established by libolm keeps working after it's
+        // migrated.
+        let mut bob = libolm::RawAccount::new();
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys().curve25519().values().next().cloned().unwrap();
+
+        let mut alice_session = alice
+            .create_outbound_session(bob.identity_keys().curve25519(), &one_time_key)
+            .expect("Can't create an outbound session");
+        let message = alice_session.encrypt(PLAINTEXT);
+
+        let pre_key_message = if let OlmMessage::PreKey(m) = message.clone() {
+            m
+        } else {
+            panic!("The first message of a session should be a pre-key message");
+        };
+
+        let mut libolm_session = bob
+            .create_inbound_session(alice.identity_keys().curve25519(), pre_key_message)
+            .expect("Can't create an inbound session");
+        assert_eq!(libolm_session.decrypt(message).unwrap(), PLAINTEXT);
+
+        let mut bob_session = vodozemac::RawSession::unpickle(
+            libolm_session.pickle(pickling_mode()),
+            pickling_mode(),
+        )
+        .expect("Can't migrate the session");
+        assert_eq!(bob_session.session_id(), alice_session.session_id());
+
+        let reply = bob_session.encrypt(PLAINTEXT);
+        assert_eq!(alice_session.decrypt(reply).unwrap(), PLAINTEXT);
+
+        let message = alice_session.encrypt(PLAINTEXT);
+        assert_eq!(bob_session.decrypt(message).unwrap(), PLAINTEXT);
+
+        // Group sessions continue at the message index they were pickled at.
+        let mut libolm_outbound = libolm::RawOutboundGroupSession::new();
+        let mut libolm_inbound =
+            libolm::RawInboundGroupSession::new(&libolm_outbound.session_key()).unwrap();
+        let message = libolm_outbound.encrypt(PLAINTEXT);
+
+        let mut outbound = vodozemac::RawOutboundGroupSession::unpickle(
+            libolm_outbound.pickle(pickling_mode()),
+            pickling_mode(),
+        )
+        .expect("Can't migrate the outbound group session");
+        let mut inbound = vodozemac::RawInboundGroupSession::unpickle(
+            libolm_inbound.pickle(pickling_mode()),
+            pickling_mode(),
+        )
+        .expect("Can't migrate the inbound group session");
+        assert_eq!(outbound.session_id(), libolm_outbound.session_id());
+        assert_eq!(inbound.session_id(), libolm_inbound.session_id());
+
+        assert_eq!(inbound.decrypt(message).unwrap(), (PLAINTEXT.to_owned(), 0));
+
+        let message = outbound.encrypt(PLAINTEXT);
+        assert_eq!(inbound.decrypt(message.clone()).unwrap(), (PLAINTEXT.to_owned(), 1));
+        assert_eq!(libolm_inbound.decrypt(message).unwrap(), (PLAINTEXT.to_owned(), 1));
+    }
+
+    /// libolm accepted pickle keys of any size, the pickles it created need to
+    /// be restored using the key as it was given to libolm.
+    #[cfg(all(feature = "libolm", feature = "vodozemac"))]
+    #[test]
+    fn libolm_pickles_with_keys_of_any_size_are_migrated() {
+        use super::{libolm, vodozemac};
+
+        let libolm_account = libolm::RawAccount::new();
+        let libolm_outbound = libolm::RawOutboundGroupSession::new();
+        let libolm_inbound =
+            libolm::RawInboundGroupSession::new(&libolm_outbound.session_key()).unwrap();
+
+        for key in [b"It's a secret".to_vec(), vec![1u8; 32], vec![2u8; 64]] {
+            let mode = || PicklingMode::Encrypted { key: key.clone() };
+
+            let account = vodozemac::RawAccount::unpickle(libolm_account.pickle(mode()), mode())
+                .expect("Can't migrate the account");
+            assert_eq!(account.identity_keys(), libolm_account.identity_keys());
+
+            let inbound =
+                vodozemac::RawInboundGroupSession::unpickle(libolm_inbound.pickle(mode()), mode())
+                    .expect("Can't migrate the inbound group session");
+            assert_eq!(inbound.session_id(), libolm_inbound.session_id());
+
+            // The migrated objects are pickled by vodozemac from now on and
+            // need to be restored using the same key.
+            let account = vodozemac::RawAccount::unpickle(account.pickle(mode()), mode())
+                .expect("Can't unpickle the migrated account");
+            assert_eq!(account.identity_keys(), libolm_account.identity_keys());
+        }
+    }
+}
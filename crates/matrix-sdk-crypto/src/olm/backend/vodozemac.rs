@@ -0,0 +1,543 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The pure Rust backend, using the `vodozemac` crate.
+//!
+//! Objects are pickled using the vodozemac pickle format. Restoring a pickle
+//! falls back to the libolm pickle format, so objects that were pickled by the
+//! libolm backend can still be restored.
+
+use std::convert::TryInto;
+
+#[cfg(feature = "backups_v1")]
+use aes::Aes256;
+#[cfg(feature = "backups_v1")]
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use getrandom::getrandom;
+#[cfg(feature = "backups_v1")]
+use hkdf::Hkdf;
+#[cfg(feature = "backups_v1")]
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "backups_v1")]
+use vodozemac::Curve25519SecretKey;
+use vodozemac::{
+    megolm::{
+        DecryptionError as MegolmDecryptionError, ExportedSessionKey, GroupSession,
+        GroupSessionPickle, InboundGroupSession, InboundGroupSessionPickle, MegolmMessage,
+        SessionConfig as MegolmSessionConfig, SessionKey,
+    },
+    olm::{
+        Account, AccountPickle, OlmMessage as VodozemacMessage,
+        PreKeyMessage as VodozemacPreKeyMessage, Session, SessionConfig, SessionCreationError,
+        SessionPickle,
+    },
+    sas::{EstablishedSas, Sas},
+    Curve25519PublicKey, Ed25519PublicKey, Ed25519SecretKey, Ed25519Signature,
+};
+#[cfg(feature = "backups_v1")]
+use zeroize::Zeroizing;
+
+use super::{
+    IdentityKeys, OlmAccountError, OlmGroupSessionError, OlmMessage, OlmSasError, OlmSessionError,
+    OneTimeKeys, PicklingMode, PreKeyMessage,
+};
+#[cfg(feature = "backups_v1")]
+use super::{OlmPkDecryptionError, PkMessage};
+use crate::error::SignatureError;
+#[cfg(feature = "backups_v1")]
+use crate::utilities::{decode, encode};
+
+/// The name of the Olm backend the crate was compiled with.
+pub const BACKEND: &str = "vodozemac";
+
+const SEED_SIZE: usize = 32;
+
+#[cfg(feature = "backups_v1")]
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+/// The size of the truncated MAC of a backed up room key.
+#[cfg(feature = "backups_v1")]
+const PK_MAC_SIZE: usize = 8;
+
+/// The key used to encrypt and decrypt vodozemac pickles.
+///
+/// vodozemac requires a 32 byte key, unencrypted pickles use an all zero key
+/// and keys of a different size are hashed.
+///
+/// This only applies to the pickles vodozemac creates, pickles that were
+/// created by libolm are restored using the key as it was given to libolm,
+/// see [`libolm_pickle_key()`].
+fn pickle_key(mode: &PicklingMode) -> [u8; 32] {
+    match mode {
+        PicklingMode::Unencrypted => [0u8; 32],
+        PicklingMode::Encrypted { key } => {
+            key.as_slice().try_into().unwrap_or_else(|_| Sha256::digest(key).into())
+        }
+    }
+}
+
+/// The key that libolm used to encrypt a pickle.
+fn libolm_pickle_key(mode: &PicklingMode) -> &[u8] {
+    match mode {
+        PicklingMode::Unencrypted => &[],
+        PicklingMode::Encrypted { key } => key,
+    }
+}
+
+/// Derive the AES key, the MAC key and the AES IV of the
+/// `m.megolm_backup.v1.curve25519-aes-sha2` algorithm from the shared secret.
+#[cfg(feature = "backups_v1")]
+fn pk_keys(shared_secret: &[u8]) -> Zeroizing<[u8; 80]> {
+    let mut keys = Zeroizing::new([0u8; 80]);
+
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(&[], &mut keys[..])
+        .expect("Can't expand the shared secret");
+
+    keys
+}
+
+/// Calculate the truncated MAC of a backed up room key.
+///
+/// libolm passes an empty input to the MAC instead of the ciphertext, the
+/// spec kept this behaviour to stay compatible with existing backups.
+#[cfg(feature = "backups_v1")]
+fn pk_mac(mac_key: &[u8]) -> Vec<u8> {
+    let hmac = Hmac::<Sha256>::new_from_slice(mac_key).expect("Can't create HMAC object");
+    hmac.finalize().into_bytes()[..PK_MAC_SIZE].to_vec()
+}
+
+fn curve25519_key(key: &str) -> Result<Curve25519PublicKey, OlmSessionError> {
+    Curve25519PublicKey::from_base64(key).map_err(|e| OlmSessionError::InvalidKey(e.to_string()))
+}
+
+fn pre_key_message(message: &PreKeyMessage) -> Result<VodozemacPreKeyMessage, OlmSessionError> {
+    VodozemacPreKeyMessage::from_base64(&message.ciphertext)
+        .map_err(|e| OlmSessionError::InvalidMessage(e.to_string()))
+}
+
+/// Verify an ed25519 signature of the given message.
+pub(crate) fn ed25519_verify(
+    public_key: &str,
+    message: &str,
+    signature: &str,
+) -> Result<(), SignatureError> {
+    let public_key =
+        Ed25519PublicKey::from_base64(public_key).map_err(|_| SignatureError::VerificationError)?;
+    let signature =
+        Ed25519Signature::from_base64(signature).map_err(|_| SignatureError::VerificationError)?;
+
+    public_key.verify(message.as_bytes(), &signature).map_err(|_| SignatureError::VerificationError)
+}
+
+pub(crate) struct RawAccount(Account);
+
+impl RawAccount {
+    pub fn new() -> Self {
+        Self(Account::new())
+    }
+
+    pub fn identity_keys(&self) -> IdentityKeys {
+        let keys = self.0.identity_keys();
+        IdentityKeys::new(keys.curve25519.to_base64(), keys.ed25519.to_base64())
+    }
+
+    pub fn one_time_keys(&self) -> OneTimeKeys {
+        OneTimeKeys::new(
+            self.0
+                .one_time_keys()
+                .into_iter()
+                .map(|(key_id, key)| (key_id.to_base64(), key.to_base64()))
+                .collect(),
+        )
+    }
+
+    pub fn generate_one_time_keys(&mut self, count: usize) {
+        self.0.generate_one_time_keys(count);
+    }
+
+    pub fn max_number_of_one_time_keys(&self) -> usize {
+        self.0.max_number_of_one_time_keys()
+    }
+
+    pub fn mark_keys_as_published(&mut self) {
+        self.0.mark_keys_as_published()
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        self.0.sign(message).to_base64()
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle().encrypt(&pickle_key(&mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmAccountError> {
+        if let Ok(p) = AccountPickle::from_encrypted(&pickle, &pickle_key(&mode)) {
+            Ok(Self(Account::from_pickle(p)))
+        } else {
+            Account::from_libolm_pickle(&pickle, libolm_pickle_key(&mode))
+                .map(Self)
+                .map_err(|e| OlmAccountError::Pickle(e.to_string()))
+        }
+    }
+
+    pub fn create_outbound_session(
+        &self,
+        identity_key: &str,
+        one_time_key: &str,
+    ) -> Result<RawSession, OlmSessionError> {
+        let session = self.0.create_outbound_session(
+            SessionConfig::version_1(),
+            curve25519_key(identity_key)?,
+            curve25519_key(one_time_key)?,
+        );
+
+        Ok(RawSession { inner: session, initial_message: None })
+    }
+
+    /// Create an inbound session from a pre-key message and remove the
+    /// one-time key that was used to create the session.
+    pub fn create_inbound_session(
+        &mut self,
+        identity_key: &str,
+        message: PreKeyMessage,
+    ) -> Result<RawSession, OlmSessionError> {
+        let result = self
+            .0
+            .create_inbound_session(curve25519_key(identity_key)?, &pre_key_message(&message)?)
+            .map_err(|e| match e {
+                SessionCreationError::MissingOneTimeKey(..) => OlmSessionError::MissingOneTimeKey,
+                e => OlmSessionError::Decryption(e.to_string()),
+            })?;
+
+        Ok(RawSession {
+            inner: result.session,
+            initial_message: Some((message.ciphertext, result.plaintext)),
+        })
+    }
+}
+
+pub(crate) struct RawSession {
+    inner: Session,
+    /// The ciphertext and plaintext of the pre-key message that created this
+    /// session.
+    ///
+    /// vodozemac decrypts the pre-key message while it creates an inbound
+    /// session, the message can't be decrypted a second time. We hold on to the
+    /// plaintext so the message can be decrypted the same way it's done with
+    /// libolm, after the session was created.
+    initial_message: Option<(String, Vec<u8>)>,
+}
+
+impl RawSession {
+    pub fn session_id(&self) -> String {
+        self.inner.session_id()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &str) -> OlmMessage {
+        let (message_type, ciphertext) = self.inner.encrypt(plaintext).to_parts();
+
+        OlmMessage::from_type_and_ciphertext(message_type, ciphertext)
+            .expect("vodozemac returned an Olm message with an unknown message type")
+    }
+
+    pub fn decrypt(&mut self, message: OlmMessage) -> Result<String, OlmSessionError> {
+        let is_initial_message = matches!(
+            (&message, &self.initial_message),
+            (OlmMessage::PreKey(m), Some((ciphertext, _))) if &m.ciphertext == ciphertext
+        );
+
+        let initial_plaintext =
+            if is_initial_message { self.initial_message.take().map(|(_, p)| p) } else { None };
+
+        let plaintext = if let Some(plaintext) = initial_plaintext {
+            plaintext
+        } else {
+            let (message_type, ciphertext) = message.to_tuple();
+            let message = VodozemacMessage::from_parts(message_type, &ciphertext)
+                .map_err(|e| OlmSessionError::InvalidMessage(e.to_string()))?;
+
+            self.inner.decrypt(&message).map_err(|e| OlmSessionError::Decryption(e.to_string()))?
+        };
+
+        String::from_utf8(plaintext).map_err(|e| OlmSessionError::Decryption(e.to_string()))
+    }
+
+    pub fn matches(
+        &self,
+        identity_key: &str,
+        message: PreKeyMessage,
+    ) -> Result<bool, OlmSessionError> {
+        let identity_key = curve25519_key(identity_key)?;
+        let message = pre_key_message(&message)?;
+
+        Ok(message.identity_key() == identity_key
+            && message.session_id() == self.inner.session_id())
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.inner.pickle().encrypt(&pickle_key(&mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmSessionError> {
+        let inner = if let Ok(p) = SessionPickle::from_encrypted(&pickle, &pickle_key(&mode)) {
+            Session::from_pickle(p)
+        } else {
+            Session::from_libolm_pickle(&pickle, libolm_pickle_key(&mode))
+                .map_err(|e| OlmSessionError::Pickle(e.to_string()))?
+        };
+
+        Ok(Self { inner, initial_message: None })
+    }
+}
+
+pub(crate) struct RawInboundGroupSession(InboundGroupSession);
+
+impl RawInboundGroupSession {
+    pub fn new(session_key: &str) -> Result<Self, OlmGroupSessionError> {
+        let session_key = SessionKey::from_base64(session_key)
+            .map_err(|e| OlmGroupSessionError::InvalidSessionKey(e.to_string()))?;
+
+        Ok(Self(InboundGroupSession::new(&session_key, MegolmSessionConfig::version_1())))
+    }
+
+    pub fn import(exported_session_key: &str) -> Result<Self, OlmGroupSessionError> {
+        let session_key = ExportedSessionKey::from_base64(exported_session_key)
+            .map_err(|e| OlmGroupSessionError::InvalidSessionKey(e.to_string()))?;
+
+        Ok(Self(InboundGroupSession::import(&session_key, MegolmSessionConfig::version_1())))
+    }
+
+    pub fn session_id(&self) -> String {
+        self.0.session_id()
+    }
+
+    pub fn first_known_index(&self) -> u32 {
+        self.0.first_known_index()
+    }
+
+    pub fn export(&mut self, message_index: u32) -> Result<String, OlmGroupSessionError> {
+        self.0
+            .export_at(message_index)
+            .map(|k| k.to_base64())
+            .ok_or(OlmGroupSessionError::UnknownMessageIndex)
+    }
+
+    pub fn decrypt(&mut self, message: String) -> Result<(String, u32), OlmGroupSessionError> {
+        let message = MegolmMessage::from_base64(&message)
+            .map_err(|e| OlmGroupSessionError::InvalidMessage(e.to_string()))?;
+
+        let decrypted = self.0.decrypt(&message).map_err(|e| match e {
+            MegolmDecryptionError::UnknownMessageIndex(..) => {
+                OlmGroupSessionError::UnknownMessageIndex
+            }
+            e => OlmGroupSessionError::Decryption(e.to_string()),
+        })?;
+
+        let plaintext = String::from_utf8(decrypted.plaintext)
+            .map_err(|e| OlmGroupSessionError::Decryption(e.to_string()))?;
+
+        Ok((plaintext, decrypted.message_index))
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle().encrypt(&pickle_key(&mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmGroupSessionError> {
+        if let Ok(p) = InboundGroupSessionPickle::from_encrypted(&pickle, &pickle_key(&mode)) {
+            Ok(Self(InboundGroupSession::from_pickle(p)))
+        } else {
+            InboundGroupSession::from_libolm_pickle(&pickle, libolm_pickle_key(&mode))
+                .map(Self)
+                .map_err(|e| OlmGroupSessionError::Pickle(e.to_string()))
+        }
+    }
+}
+
+pub(crate) struct RawOutboundGroupSession(GroupSession);
+
+impl RawOutboundGroupSession {
+    pub fn new() -> Self {
+        Self(GroupSession::new(MegolmSessionConfig::version_1()))
+    }
+
+    pub fn session_id(&self) -> String {
+        self.0.session_id()
+    }
+
+    pub fn session_key(&self) -> String {
+        self.0.session_key().to_base64()
+    }
+
+    pub fn message_index(&self) -> u32 {
+        self.0.message_index()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &str) -> String {
+        self.0.encrypt(plaintext).to_base64()
+    }
+
+    pub fn pickle(&self, mode: PicklingMode) -> String {
+        self.0.pickle().encrypt(&pickle_key(&mode))
+    }
+
+    pub fn unpickle(pickle: String, mode: PicklingMode) -> Result<Self, OlmGroupSessionError> {
+        if let Ok(p) = GroupSessionPickle::from_encrypted(&pickle, &pickle_key(&mode)) {
+            Ok(Self(GroupSession::from_pickle(p)))
+        } else {
+            GroupSession::from_libolm_pickle(&pickle, libolm_pickle_key(&mode))
+                .map(Self)
+                .map_err(|e| OlmGroupSessionError::Pickle(e.to_string()))
+        }
+    }
+}
+
+pub(crate) struct RawPkSigning(Ed25519SecretKey);
+
+impl RawPkSigning {
+    pub fn generate_seed() -> Vec<u8> {
+        let mut seed = vec![0u8; SEED_SIZE];
+        getrandom(&mut seed).expect("Can't generate a seed for the signing key");
+        seed
+    }
+
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let seed: &[u8; SEED_SIZE] = seed.try_into().expect("Unable to create pk signing object");
+        Self(Ed25519SecretKey::from_slice(seed))
+    }
+
+    pub fn public_key(&self) -> String {
+        self.0.public_key().to_base64()
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        self.0.sign(message.as_bytes()).to_base64()
+    }
+}
+
+pub(crate) struct RawSas {
+    public_key: String,
+    /// The SAS object until the public key of the other side is set.
+    sas: Option<Sas>,
+    /// The SAS object after the public key of the other side was set.
+    established: Option<EstablishedSas>,
+}
+
+impl RawSas {
+    pub fn new() -> Self {
+        let sas = Sas::new();
+
+        Self { public_key: sas.public_key().to_base64(), sas: Some(sas), established: None }
+    }
+
+    pub fn public_key(&self) -> String {
+        self.public_key.clone()
+    }
+
+    pub fn set_their_public_key(&mut self, public_key: String) -> Result<(), OlmSasError> {
+        let public_key = Curve25519PublicKey::from_base64(&public_key)
+            .map_err(|e| OlmSasError::InvalidKey(e.to_string()))?;
+        let sas = self.sas.take().ok_or(OlmSasError::PublicKeyAlreadySet)?;
+
+        self.established = Some(sas.diffie_hellman(public_key));
+
+        Ok(())
+    }
+
+    fn established(&self) -> Result<&EstablishedSas, OlmSasError> {
+        self.established.as_ref().ok_or(OlmSasError::MissingPublicKey)
+    }
+
+    /// Calculate a MAC the same way libolm does, the MAC is encoded using the
+    /// invalid base64 encoding of libolm that the `hkdf-hmac-sha256` MAC
+    /// method of the spec expects.
+    pub fn calculate_mac(&self, input: &str, info: &str) -> Result<String, OlmSasError> {
+        Ok(self.established()?.calculate_mac_invalid_base64(input, info))
+    }
+
+    pub fn generate_bytes(&self, info: &str, count: usize) -> Result<Vec<u8>, OlmSasError> {
+        Ok(self.established()?.bytes(info).as_bytes().iter().take(count).copied().collect())
+    }
+}
+
+#[cfg(feature = "backups_v1")]
+pub(crate) struct RawPkEncryption(Curve25519PublicKey);
+
+#[cfg(feature = "backups_v1")]
+impl RawPkEncryption {
+    pub fn from_base64(public_key: &str) -> Self {
+        Self(Curve25519PublicKey::from_base64(public_key).expect("Invalid backup key"))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> PkMessage {
+        let ephemeral_key = Curve25519SecretKey::new();
+        let shared_secret = ephemeral_key.diffie_hellman(&self.0);
+
+        let keys = pk_keys(shared_secret.as_bytes());
+        let (aes_key, keys) = keys.split_at(32);
+        let (mac_key, iv) = keys.split_at(32);
+
+        let cipher = Aes256Cbc::new_from_slices(aes_key, iv).expect("Can't create AES object");
+        let ciphertext = cipher.encrypt_vec(plaintext.as_bytes());
+
+        PkMessage {
+            ciphertext: encode(ciphertext),
+            mac: encode(pk_mac(mac_key)),
+            ephemeral_key: Curve25519PublicKey::from(&ephemeral_key).to_base64(),
+        }
+    }
+}
+
+#[cfg(feature = "backups_v1")]
+pub(crate) struct RawPkDecryption(Curve25519SecretKey);
+
+#[cfg(feature = "backups_v1")]
+impl RawPkDecryption {
+    pub fn from_bytes(private_key: &[u8; 32]) -> Self {
+        Self(Curve25519SecretKey::from_slice(private_key))
+    }
+
+    pub fn public_key(&self) -> String {
+        Curve25519PublicKey::from(&self.0).to_base64()
+    }
+
+    pub fn decrypt(&self, message: PkMessage) -> Result<String, OlmPkDecryptionError> {
+        let ephemeral_key = Curve25519PublicKey::from_base64(&message.ephemeral_key)
+            .map_err(|e| OlmPkDecryptionError::InvalidKey(e.to_string()))?;
+        let ciphertext = decode(&message.ciphertext)
+            .map_err(|e| OlmPkDecryptionError::InvalidMessage(e.to_string()))?;
+        let mac = decode(&message.mac)
+            .map_err(|e| OlmPkDecryptionError::InvalidMessage(e.to_string()))?;
+
+        let shared_secret = self.0.diffie_hellman(&ephemeral_key);
+
+        let keys = pk_keys(shared_secret.as_bytes());
+        let (aes_key, keys) = keys.split_at(32);
+        let (mac_key, iv) = keys.split_at(32);
+
+        if mac != pk_mac(mac_key) {
+            return Err(OlmPkDecryptionError::Mac);
+        }
+
+        let cipher = Aes256Cbc::new_from_slices(aes_key, iv).expect("Can't create AES object");
+        let plaintext = cipher
+            .decrypt_vec(&ciphertext)
+            .map_err(|e| OlmPkDecryptionError::Decryption(e.to_string()))?;
+
+        String::from_utf8(plaintext).map_err(|e| OlmPkDecryptionError::Decryption(e.to_string()))
+    }
+}
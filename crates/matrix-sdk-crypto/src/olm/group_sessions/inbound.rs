@@ -23,14 +23,6 @@ use std::{
 };
 
 use matrix_sdk_common::locks::Mutex;
-pub use olm_rs::{
-    account::IdentityKeys,
-    session::{OlmMessage, PreKeyMessage},
-    utility::OlmUtility,
-};
-use olm_rs::{
-    errors::OlmGroupSessionError, inbound_group_session::OlmInboundGroupSession, PicklingMode,
-};
 use ruma::{
     events::{
         forwarded_room_key::ToDeviceForwardedRoomKeyEventContent,
@@ -41,15 +33,37 @@ use ruma::{
         AnySyncRoomEvent,
     },
     serde::Raw,
-    DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId,
+    DeviceKeyAlgorithm, EventEncryptionAlgorithm, EventId, RoomId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use zeroize::Zeroizing;
 
-use super::{BackedUpRoomKey, ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey};
+use super::{
+    super::{backend::RawInboundGroupSession, OlmGroupSessionError, PicklingMode},
+    ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey,
+};
 use crate::error::{EventError, MegolmResult};
 
+/// The message index at which an event was decrypted using an
+/// `InboundGroupSession`.
+///
+/// Can be used to check if a Megolm message has been replayed to us.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MegolmMessageIndex {
+    /// The room the inbound group session belongs to.
+    pub room_id: Box<RoomId>,
+    /// The curve25519 key of the device that created the inbound group
+    /// session.
+    pub sender_key: String,
+    /// The unique id of the inbound group session.
+    pub session_id: String,
+    /// The message index at which the event was decrypted.
+    pub message_index: u32,
+    /// The id of the event that was decrypted at this message index.
+    pub event_id: Box<EventId>,
+}
+
 // TODO add creation times to the inbound group sessions so we can export
 // sessions that were created between some time period, this should only be set
 // for non-imported sessions.
@@ -60,7 +74,7 @@ use crate::error::{EventError, MegolmResult};
 /// participants. Inbound group sessions are used to decrypt the room messages.
 #[derive(Clone)]
 pub struct InboundGroupSession {
-    inner: Arc<Mutex<OlmInboundGroupSession>>,
+    inner: Arc<Mutex<RawInboundGroupSession>>,
     history_visibility: Arc<Option<HistoryVisibility>>,
     pub(crate) session_id: Arc<str>,
     first_known_index: u32,
@@ -96,7 +110,7 @@ impl InboundGroupSession {
         session_key: GroupSessionKey,
         history_visibility: Option<HistoryVisibility>,
     ) -> Result<Self, OlmGroupSessionError> {
-        let session = OlmInboundGroupSession::new(&session_key.0)?;
+        let session = RawInboundGroupSession::new(&session_key.0)?;
         let session_id = session.session_id();
         let first_known_index = session.first_known_index();
 
@@ -131,25 +145,6 @@ impl InboundGroupSession {
         Self::try_from(exported_session.into())
     }
 
-    #[allow(dead_code)]
-    fn from_backup(
-        room_id: &RoomId,
-        backup: BackedUpRoomKey,
-    ) -> Result<Self, OlmGroupSessionError> {
-        let session = OlmInboundGroupSession::new(&backup.session_key.0)?;
-        let session_id = session.session_id();
-
-        Self::from_export(ExportedRoomKey {
-            algorithm: backup.algorithm,
-            room_id: room_id.to_owned(),
-            sender_key: backup.sender_key,
-            session_id,
-            session_key: backup.session_key,
-            sender_claimed_keys: backup.sender_claimed_keys,
-            forwarding_curve25519_key_chain: backup.forwarding_curve25519_key_chain,
-        })
-    }
-
     /// Create a new inbound group session from a forwarded room key content.
     ///
     /// # Arguments
@@ -165,7 +160,7 @@ impl InboundGroupSession {
     ) -> Result<Self, OlmGroupSessionError> {
         let key = Zeroizing::from(mem::take(&mut content.session_key));
 
-        let session = OlmInboundGroupSession::import(&key)?;
+        let session = RawInboundGroupSession::import(&key)?;
         let first_known_index = session.first_known_index();
         let mut forwarding_chains = content.forwarding_curve25519_key_chain.clone();
         forwarding_chains.push(sender_key.to_owned());
@@ -285,7 +280,7 @@ impl InboundGroupSession {
         pickle: PickledInboundGroupSession,
         pickle_mode: PicklingMode,
     ) -> Result<Self, OlmGroupSessionError> {
-        let session = OlmInboundGroupSession::unpickle(pickle.pickle.0, pickle_mode)?;
+        let session = RawInboundGroupSession::unpickle(pickle.pickle.0, pickle_mode)?;
         let first_known_index = session.first_known_index();
         let session_id = session.session_id();
 
@@ -313,6 +308,14 @@ impl InboundGroupSession {
         &self.session_id
     }
 
+    /// The history visibility of the room at the time the session was
+    /// created.
+    ///
+    /// This is only known for sessions we created ourselves.
+    pub fn history_visibility(&self) -> Option<&HistoryVisibility> {
+        self.history_visibility.as_ref().as_ref()
+    }
+
     /// Get the first message index we know how to decrypt.
     pub fn first_known_index(&self) -> u32 {
         self.first_known_index
@@ -334,7 +337,7 @@ impl InboundGroupSession {
     }
 
     #[cfg(feature = "backups_v1")]
-    pub(crate) async fn to_backup(&self) -> BackedUpRoomKey {
+    pub(crate) async fn to_backup(&self) -> super::BackedUpRoomKey {
         self.export().await.into()
     }
 
@@ -456,7 +459,7 @@ impl TryFrom<ExportedRoomKey> for InboundGroupSession {
     type Error = OlmGroupSessionError;
 
     fn try_from(key: ExportedRoomKey) -> Result<Self, Self::Error> {
-        let session = OlmInboundGroupSession::import(&key.session_key.0)?;
+        let session = RawInboundGroupSession::import(&key.session_key.0)?;
         let first_known_index = session.first_known_index();
 
         Ok(InboundGroupSession {
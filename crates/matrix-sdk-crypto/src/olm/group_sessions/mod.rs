@@ -26,9 +26,12 @@ use zeroize::Zeroize;
 mod inbound;
 mod outbound;
 
-pub use inbound::{InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession};
+pub use inbound::{
+    InboundGroupSession, InboundGroupSessionPickle, MegolmMessageIndex, PickledInboundGroupSession,
+};
 pub use outbound::{
     EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareInfo, ShareState,
+    ShareStrategy,
 };
 
 /// The private session key of a group session.
@@ -95,6 +98,30 @@ pub struct BackedUpRoomKey {
     pub forwarding_curve25519_key_chain: Vec<String>,
 }
 
+impl ExportedRoomKey {
+    /// Create an `ExportedRoomKey` from a room key that was downloaded from a
+    /// server-side key backup.
+    ///
+    /// The backed up room key doesn't contain the room id nor the session id,
+    /// those are part of the backup structure and need to be provided
+    /// separately.
+    pub fn from_backed_up_room_key(
+        room_id: Box<RoomId>,
+        session_id: String,
+        room_key: BackedUpRoomKey,
+    ) -> Self {
+        Self {
+            algorithm: room_key.algorithm,
+            room_id,
+            sender_key: room_key.sender_key,
+            session_id,
+            session_key: room_key.session_key,
+            sender_claimed_keys: room_key.sender_claimed_keys,
+            forwarding_curve25519_key_chain: room_key.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
 impl TryInto<ToDeviceForwardedRoomKeyEventContent> for ExportedRoomKey {
     type Error = ();
 
@@ -23,16 +23,8 @@ use std::{
     time::Duration,
 };
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use matrix_sdk_common::{instant::Instant, locks::Mutex, uuid::Uuid};
-pub use olm_rs::{
-    account::IdentityKeys,
-    session::{OlmMessage, PreKeyMessage},
-    utility::OlmUtility,
-};
-use olm_rs::{
-    errors::OlmGroupSessionError, outbound_group_session::OlmOutboundGroupSession, PicklingMode,
-};
 use ruma::{
     events::{
         room::{
@@ -52,7 +44,10 @@ use serde_json::{json, Value};
 use tracing::{debug, error, info};
 
 use super::{
-    super::{deserialize_instant, serialize_instant},
+    super::{
+        backend::RawOutboundGroupSession, deserialize_instant, serialize_instant, IdentityKeys,
+        OlmGroupSessionError, PicklingMode,
+    },
     GroupSessionKey,
 };
 use crate::{Device, ToDeviceRequest};
@@ -60,10 +55,16 @@ use crate::{Device, ToDeviceRequest};
 const ROTATION_PERIOD: Duration = Duration::from_millis(604800000);
 const ROTATION_MESSAGES: u64 = 100;
 
+/// Describes if, and how, an outbound group session was shared with a device.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ShareState {
+    /// The session wasn't shared with the device.
     NotShared,
+    /// The session was shared with the device, but the device changed its
+    /// curve25519 key since then.
     SharedButChangedSenderKey,
+    /// The session was shared with the device, starting from the given
+    /// message index.
     Shared(u32),
 }
 
@@ -80,6 +81,52 @@ pub struct EncryptionSettings {
     pub rotation_period_msgs: u64,
     /// The history visibility of the room when the session was created.
     pub history_visibility: HistoryVisibility,
+    /// The strategy deciding which devices of the room members should
+    /// receive the room key.
+    #[serde(default)]
+    pub sharing_strategy: ShareStrategy,
+}
+
+/// Strategy deciding which devices receive a room key.
+///
+/// Devices that are excluded by the strategy receive a `m.room_key.withheld`
+/// event instead of the room key, blacklisted devices never receive the room
+/// key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum ShareStrategy {
+    /// Share the room key with all devices.
+    AllDevices,
+    /// Share the room key only with devices that are verified, either locally
+    /// or using cross signing.
+    TrustedDevices,
+    /// Share the room key only with devices that are verified using cross
+    /// signing, locally verified devices are excluded.
+    CrossSigningTrustedDevices,
+    /// Share the room key only with the devices of users whose identity we
+    /// verified. Only devices that are signed by the identity of their owner
+    /// receive the room key.
+    VerifiedUsers,
+    /// Share the room key only with devices that are signed by a verified or
+    /// pinned user identity, verified devices are always included.
+    ///
+    /// This can be combined with
+    /// [`OlmMachine::set_trust_on_first_use()`](crate::OlmMachine::set_trust_on_first_use)
+    /// to share room keys safely without interactive verification.
+    PinnedIdentities,
+    /// Refuse to share the room key if any of the devices isn't verified.
+    ///
+    /// Sharing fails with an [`OlmError::UnverifiedDevices`] error listing the
+    /// unverified devices.
+    ///
+    /// [`OlmError::UnverifiedDevices`]: crate::OlmError::UnverifiedDevices
+    ErrorOnUnverifiedDevices,
+}
+
+impl Default for ShareStrategy {
+    fn default() -> Self {
+        Self::AllDevices
+    }
 }
 
 impl Default for EncryptionSettings {
@@ -89,6 +136,7 @@ impl Default for EncryptionSettings {
             rotation_period: ROTATION_PERIOD,
             rotation_period_msgs: ROTATION_MESSAGES,
             history_visibility: HistoryVisibility::Shared,
+            sharing_strategy: ShareStrategy::default(),
         }
     }
 }
@@ -107,6 +155,7 @@ impl EncryptionSettings {
             rotation_period,
             rotation_period_msgs,
             history_visibility,
+            sharing_strategy: ShareStrategy::default(),
         }
     }
 }
@@ -118,7 +167,7 @@ impl EncryptionSettings {
 /// messages.
 #[derive(Clone)]
 pub struct OutboundGroupSession {
-    inner: Arc<Mutex<OlmOutboundGroupSession>>,
+    inner: Arc<Mutex<RawOutboundGroupSession>>,
     device_id: Arc<DeviceId>,
     account_identity_keys: Arc<IdentityKeys>,
     session_id: Arc<str>,
@@ -131,6 +180,7 @@ pub struct OutboundGroupSession {
     #[allow(clippy::type_complexity)]
     pub(crate) shared_with_set: Arc<DashMap<Box<UserId>, DashMap<Box<DeviceId>, ShareInfo>>>,
     to_share_with_set: Arc<DashMap<Uuid, (Arc<ToDeviceRequest>, ShareInfoSet)>>,
+    withheld_from_set: Arc<DashSet<(Box<UserId>, Box<DeviceId>)>>,
 }
 
 /// A a map of userid/device it to a `ShareInfo`.
@@ -170,7 +220,7 @@ impl OutboundGroupSession {
         room_id: &RoomId,
         settings: EncryptionSettings,
     ) -> Self {
-        let session = OlmOutboundGroupSession::new();
+        let session = RawOutboundGroupSession::new();
         let session_id = session.session_id();
 
         OutboundGroupSession {
@@ -186,6 +236,7 @@ impl OutboundGroupSession {
             settings: Arc::new(settings),
             shared_with_set: Arc::new(DashMap::new()),
             to_share_with_set: Arc::new(DashMap::new()),
+            withheld_from_set: Arc::new(DashSet::new()),
         }
     }
 
@@ -257,7 +308,7 @@ impl OutboundGroupSession {
     ///
     /// * `plaintext` - The plaintext that should be encrypted.
     pub(crate) async fn encrypt_helper(&self, plaintext: String) -> String {
-        let session = self.inner.lock().await;
+        let mut session = self.inner.lock().await;
         self.message_count.fetch_add(1, Ordering::SeqCst);
         session.encrypt(&plaintext)
     }
@@ -366,7 +417,7 @@ impl OutboundGroupSession {
     /// message index that will be used for the next encrypted message.
     pub async fn message_index(&self) -> u32 {
         let session = self.inner.lock().await;
-        session.session_message_index()
+        session.message_index()
     }
 
     pub(crate) async fn as_content(&self) -> AnyToDeviceEventContent {
@@ -380,6 +431,18 @@ impl OutboundGroupSession {
         ))
     }
 
+    /// Was the given device told that the session is withheld from it.
+    pub(crate) fn is_withheld_from(&self, device: &Device) -> bool {
+        self.withheld_from_set
+            .contains(&(device.user_id().to_owned(), device.device_id().to_owned()))
+    }
+
+    /// Remember that the given device was told that the session is withheld
+    /// from it.
+    pub(crate) fn mark_as_withheld(&self, device: &Device) {
+        self.withheld_from_set.insert((device.user_id().to_owned(), device.device_id().to_owned()));
+    }
+
     /// Has or will the session be shared with the given user/device pair.
     pub(crate) fn is_shared_with(&self, device: &Device) -> ShareState {
         // Check if we shared the session.
@@ -483,7 +546,7 @@ impl OutboundGroupSession {
         pickle: PickledOutboundGroupSession,
         pickling_mode: PicklingMode,
     ) -> Result<Self, OlmGroupSessionError> {
-        let inner = OlmOutboundGroupSession::unpickle(pickle.pickle.0, pickling_mode)?;
+        let inner = RawOutboundGroupSession::unpickle(pickle.pickle.0, pickling_mode)?;
         let session_id = inner.session_id();
 
         Ok(Self {
@@ -505,6 +568,7 @@ impl OutboundGroupSession {
                     .collect(),
             ),
             to_share_with_set: Arc::new(pickle.requests.into_iter().collect()),
+            withheld_from_set: Arc::new(pickle.withheld_from_set.into_iter().collect()),
         })
     }
 
@@ -543,6 +607,7 @@ impl OutboundGroupSession {
                 .iter()
                 .map(|r| (*r.key(), r.value().clone()))
                 .collect(),
+            withheld_from_set: self.withheld_from_set.iter().map(|d| d.key().clone()).collect(),
         }
     }
 }
@@ -593,6 +658,10 @@ pub struct PickledOutboundGroupSession {
     pub shared_with_set: BTreeMap<Box<UserId>, BTreeMap<Box<DeviceId>, ShareInfo>>,
     /// Requests that need to be sent out to share the session.
     pub requests: BTreeMap<Uuid, (Arc<ToDeviceRequest>, ShareInfoSet)>,
+    /// The set of devices that were told that the session is withheld from
+    /// them.
+    #[serde(default)]
+    pub withheld_from_set: BTreeSet<(Box<UserId>, Box<DeviceId>)>,
 }
 
 #[cfg(test)]
@@ -18,6 +18,7 @@
 //! `CryptoStore`.
 
 mod account;
+mod backend;
 mod group_sessions;
 mod session;
 mod signing;
@@ -25,13 +26,22 @@ mod utility;
 
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
 pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
+#[cfg(feature = "backups_v1")]
+pub use backend::OlmPkDecryptionError;
+pub(crate) use backend::RawSas;
+pub use backend::{
+    IdentityKeys, OlmAccountError, OlmGroupSessionError, OlmMessage, OlmSessionError, PicklingMode,
+    PreKeyMessage, UnknownMessageType, BACKEND,
+};
+#[cfg(feature = "backups_v1")]
+pub(crate) use backend::{PkMessage, RawPkDecryption, RawPkEncryption};
+pub(crate) use group_sessions::GroupSessionKey;
 pub use group_sessions::{
-    EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession, ShareInfo,
+    BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession,
+    InboundGroupSessionPickle, MegolmMessageIndex, OutboundGroupSession,
+    PickledInboundGroupSession, PickledOutboundGroupSession, ShareInfo, ShareState, ShareStrategy,
 };
-pub(crate) use group_sessions::{GroupSessionKey, ShareState};
 use matrix_sdk_common::instant::{Duration, Instant};
-pub use olm_rs::{account::IdentityKeys, PicklingMode};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 pub use session::{PickledSession, Session, SessionPickle};
 pub use signing::{CrossSigningStatus, PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
@@ -62,7 +72,6 @@ pub(crate) mod test {
     use std::{collections::BTreeMap, convert::TryInto};
 
     use matches::assert_matches;
-    use olm_rs::session::OlmMessage;
     use ruma::{
         device_id,
         encryption::SignedKey,
@@ -77,7 +86,7 @@ pub(crate) mod test {
     use serde_json::json;
 
     use crate::{
-        olm::{InboundGroupSession, ReadOnlyAccount, Session},
+        olm::{InboundGroupSession, OlmMessage, ReadOnlyAccount, Session},
         MegolmError,
     };
 
@@ -15,11 +15,6 @@
 use std::{collections::BTreeMap, fmt, sync::Arc};
 
 use matrix_sdk_common::{instant::Instant, locks::Mutex};
-use olm_rs::{errors::OlmSessionError, session::OlmSession, PicklingMode};
-pub use olm_rs::{
-    session::{OlmMessage, PreKeyMessage},
-    utility::OlmUtility,
-};
 use ruma::{
     events::{
         room::encrypted::{
@@ -33,7 +28,10 @@ use ruma::{
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{deserialize_instant, serialize_instant, IdentityKeys};
+use super::{
+    backend::RawSession, deserialize_instant, serialize_instant, IdentityKeys, OlmMessage,
+    OlmSessionError, PicklingMode, PreKeyMessage,
+};
 use crate::{
     error::{EventError, OlmResult, SessionUnpicklingError},
     ReadOnlyDevice,
@@ -46,7 +44,7 @@ pub struct Session {
     pub(crate) user_id: Arc<UserId>,
     pub(crate) device_id: Arc<DeviceId>,
     pub(crate) our_identity_keys: Arc<IdentityKeys>,
-    pub(crate) inner: Arc<Mutex<OlmSession>>,
+    pub(crate) inner: Arc<Mutex<RawSession>>,
     pub(crate) session_id: Arc<str>,
     pub(crate) sender_key: Arc<str>,
     pub(crate) created_using_fallback_key: bool,
@@ -164,7 +162,7 @@ impl Session {
         their_identity_key: &str,
         message: PreKeyMessage,
     ) -> Result<bool, OlmSessionError> {
-        self.inner.lock().await.matches_inbound_session_from(their_identity_key, message)
+        self.inner.lock().await.matches(their_identity_key, message)
     }
 
     /// Returns the unique identifier for this session.
@@ -214,7 +212,7 @@ impl Session {
         pickle: PickledSession,
         pickle_mode: PicklingMode,
     ) -> Result<Self, SessionUnpicklingError> {
-        let session = OlmSession::unpickle(pickle.pickle.0, pickle_mode)?;
+        let session = RawSession::unpickle(pickle.pickle.0, pickle_mode)?;
         let session_id = session.session_id();
 
         Ok(Session {
@@ -20,9 +20,6 @@ use aes_gcm::{
 };
 use getrandom::getrandom;
 use matrix_sdk_common::locks::Mutex;
-use olm_rs::pk::OlmPkSigning;
-#[cfg(test)]
-use olm_rs::{errors::OlmUtilityError, utility::OlmUtility};
 use ruma::{
     encryption::{CrossSigningKey, CrossSigningKeySignatures, DeviceKeys, KeyUsage},
     serde::CanonicalJsonValue,
@@ -36,6 +33,7 @@ use zeroize::Zeroizing;
 use crate::{
     error::SignatureError,
     identities::{MasterPubkey, SelfSigningPubkey, UserSigningPubkey},
+    olm::backend::RawPkSigning,
     utilities::{decode_url_safe, encode, encode_url_safe, DecodeError},
     ReadOnlyUserIdentity,
 };
@@ -60,7 +58,7 @@ pub enum SigningError {
 
 #[derive(Clone)]
 pub struct Signing {
-    inner: Arc<Mutex<OlmPkSigning>>,
+    inner: Arc<Mutex<RawPkSigning>>,
     seed: Arc<Zeroizing<Vec<u8>>>,
     public_key: PublicSigningKey,
 }
@@ -332,12 +330,12 @@ pub struct PickledSigning(String);
 
 impl Signing {
     pub fn new() -> Self {
-        let seed = OlmPkSigning::generate_seed();
+        let seed = RawPkSigning::generate_seed();
         Self::from_seed(seed)
     }
 
     pub fn from_seed(seed: Vec<u8>) -> Self {
-        let inner = OlmPkSigning::new(&seed).expect("Unable to create pk signing object");
+        let inner = RawPkSigning::from_seed(&seed);
         let public_key = PublicSigningKey(inner.public_key().into());
 
         Signing {
@@ -398,13 +396,8 @@ impl Signing {
     }
 
     #[cfg(test)]
-    pub async fn verify(
-        &self,
-        message: &str,
-        signature: &Signature,
-    ) -> Result<bool, OlmUtilityError> {
-        let utility = OlmUtility::new();
-        utility.ed25519_verify(self.public_key.as_str(), message, signature.to_string())
+    pub async fn verify(&self, message: &str, signature: &Signature) -> Result<(), SignatureError> {
+        crate::olm::backend::ed25519_verify(self.public_key.as_str(), message, &signature.0)
     }
 
     pub async fn sign_json(&self, mut json: Value) -> Result<Signature, SignatureError> {
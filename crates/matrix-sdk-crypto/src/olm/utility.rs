@@ -14,19 +14,17 @@
 
 use std::convert::TryInto;
 
-use olm_rs::utility::OlmUtility;
 use ruma::{serde::CanonicalJsonValue, DeviceKeyAlgorithm, DeviceKeyId, UserId};
 use serde_json::Value;
 
+use super::backend::ed25519_verify;
 use crate::error::SignatureError;
 
-pub(crate) struct Utility {
-    inner: OlmUtility,
-}
+pub(crate) struct Utility;
 
 impl Utility {
     pub fn new() -> Self {
-        Self { inner: OlmUtility::new() }
+        Self
     }
 
     /// Verify a signed JSON object.
@@ -75,11 +73,7 @@ impl Utility {
             signature.get(key_id.to_string()).ok_or(SignatureError::NoSignatureFound)?;
         let signature = signature.as_str().ok_or(SignatureError::NoSignatureFound)?;
 
-        let ret =
-            match self.inner.ed25519_verify(signing_key, &canonical_json, signature.to_owned()) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(SignatureError::VerificationError),
-            };
+        let ret = ed25519_verify(signing_key, &canonical_json, signature);
 
         let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
 
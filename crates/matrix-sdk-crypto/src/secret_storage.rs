@@ -0,0 +1,796 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for [Secure Secret Storage and Sharing][ssss].
+//!
+//! Secret storage allows users to store their secrets, e.g. the private cross
+//! signing keys or the recovery key of the server-side key backup, encrypted
+//! in their global account data.
+//!
+//! Secrets are encrypted using a [`SecretStorageKey`], the key is either
+//! randomly generated or derived from a passphrase. Only the
+//! `m.secret_storage.v1.aes-hmac-sha2` algorithm is supported.
+//!
+//! This module doesn't do any network IO, the account data events this module
+//! produces need to be uploaded and fetched by the user of this crate.
+//!
+//! [ssss]: https://spec.matrix.org/unstable/client-server-api/#storage
+
+use std::{collections::BTreeMap, string::FromUtf8Error};
+
+use aes::{
+    cipher::{generic_array::GenericArray, FromBlockCipher, NewBlockCipher, StreamCipher},
+    Aes256, Aes256Ctr,
+};
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use pbkdf2::pbkdf2;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use ruma::events::secret::request::SecretName;
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    store::{CryptoStoreError, SecretImportError},
+    utilities::{decode, encode, DecodeError},
+};
+
+/// The name of the only secret storage algorithm we support.
+pub const SECRET_STORAGE_V1_AES_HMAC_SHA2: &str = "m.secret_storage.v1.aes-hmac-sha2";
+
+/// The name of the only passphrase key derivation algorithm we support.
+pub const PBKDF2: &str = "m.pbkdf2";
+
+/// The event type of the global account data event that points to the default
+/// secret storage key.
+pub const DEFAULT_KEY_EVENT_TYPE: &str = "m.secret_storage.default_key";
+
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const KEY_ID_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 32;
+const PBKDF2_ROUNDS: u32 = 500_000;
+const BASE58_PREFIX: [u8; 2] = [0x8b, 0x01];
+const DISPLAY_CHUNK_SIZE: usize = 4;
+
+/// Get the event type of the global account data event that describes the
+/// secret storage key with the given id.
+pub fn key_event_type(key_id: &str) -> String {
+    format!("m.secret_storage.key.{}", key_id)
+}
+
+/// Get the names of the secrets we put into the secret storage.
+///
+/// Those are the private cross signing keys and the recovery key of the
+/// server-side key backup.
+pub fn supported_secrets() -> [SecretName; 4] {
+    [
+        SecretName::CrossSigningMasterKey,
+        SecretName::CrossSigningSelfSigningKey,
+        SecretName::CrossSigningUserSigningKey,
+        SecretName::RecoveryKey,
+    ]
+}
+
+/// Error type for the secret storage subsystem.
+#[derive(Debug, Error)]
+pub enum SecretStorageError {
+    /// The secret storage key uses an algorithm we don't support.
+    #[error("The secret storage key uses an unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    /// The secret storage key was derived from a passphrase using an algorithm
+    /// we don't support.
+    #[error("The secret storage key uses an unsupported passphrase algorithm: {0}")]
+    UnsupportedPassphraseAlgorithm(String),
+    /// A passphrase was given, but the secret storage key wasn't derived from a
+    /// passphrase.
+    #[error("The secret storage key wasn't derived from a passphrase")]
+    MissingPassphraseInfo,
+    /// The given passphrase or key doesn't match to the secret storage key.
+    #[error("The given passphrase or key doesn't match to the secret storage key")]
+    InvalidKey,
+    /// The MAC of an encrypted secret is invalid.
+    #[error("The MAC of the encrypted secret is invalid")]
+    InvalidMac,
+    /// There's no default secret storage key.
+    #[error("The secret storage doesn't have a default key")]
+    MissingDefaultKey,
+    /// The description of a secret storage key couldn't be found.
+    #[error("The secret storage key {0} couldn't be found")]
+    MissingKey(String),
+    /// The secret isn't encrypted for the secret storage key we're using.
+    #[error("The secret isn't encrypted for the secret storage key {0}")]
+    MissingSecret(String),
+    /// The base58 encoded key has an invalid prefix.
+    #[error("The decoded secret storage key has an invalid prefix: expected {0:?}, got {1:?}")]
+    Prefix([u8; 2], [u8; 2]),
+    /// The parity byte of the base58 encoded key didn't match.
+    #[error("The parity byte of the secret storage key doesn't match: expected {0:?}, got {1:?}")]
+    Parity(u8, u8),
+    /// The base58 encoded key has an invalid length.
+    #[error("The decoded secret storage key has an invalid length: expected {0}, got {1}")]
+    Length(usize, usize),
+    /// The key isn't valid base58.
+    #[error(transparent)]
+    Base58(#[from] bs58::decode::Error),
+    /// Some part of the encrypted secret isn't valid base64.
+    #[error(transparent)]
+    Base64(#[from] DecodeError),
+    /// The decrypted secret isn't valid UTF-8.
+    #[error(transparent)]
+    Utf8(#[from] FromUtf8Error),
+    /// The decrypted recovery key of the backup isn't valid.
+    #[cfg(feature = "backups_v1")]
+    #[error(transparent)]
+    RecoveryKey(#[from] crate::backups::DecodeError),
+    /// The decrypted secret couldn't be imported.
+    #[error(transparent)]
+    SecretImport(#[from] SecretImportError),
+    /// The decrypted secret couldn't be stored.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// Information on how a secret storage key was derived from a passphrase.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PassphraseInfo {
+    /// The key derivation algorithm, only `m.pbkdf2` is supported.
+    pub algorithm: String,
+    /// The salt that was used for the key derivation.
+    pub salt: String,
+    /// The number of PBKDF2 rounds that were used for the key derivation.
+    pub iterations: u32,
+    /// The number of bits the key derivation generates, defaults to 256.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u32>,
+}
+
+/// The content of a `m.secret_storage.key.[key_id]` global account data event.
+///
+/// This describes a secret storage key, it never contains the private key
+/// itself.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SecretStorageKeyEventContent {
+    /// The algorithm the key is used for, only
+    /// `m.secret_storage.v1.aes-hmac-sha2` is supported.
+    pub algorithm: String,
+    /// The human readable name of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Information on how the key was derived from a passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<PassphraseInfo>,
+    /// The initialization vector that was used to encrypt the key check.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iv: Option<String>,
+    /// The MAC of the key check, used to verify that a key is the right one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+/// The content of the `m.secret_storage.default_key` global account data
+/// event.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DefaultKeyEventContent {
+    /// The id of the default secret storage key.
+    pub key: String,
+}
+
+/// A secret encrypted with the `m.secret_storage.v1.aes-hmac-sha2` algorithm.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AesHmacSha2EncryptedData {
+    /// The base64 encoded initialization vector.
+    pub iv: String,
+    /// The base64 encoded ciphertext.
+    pub ciphertext: String,
+    /// The base64 encoded MAC of the ciphertext.
+    pub mac: String,
+}
+
+/// The content of a global account data event that holds an encrypted secret,
+/// the event type is the name of the secret, e.g. `m.cross_signing.master`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SecretEventContent {
+    /// Map from the secret storage key id to the secret encrypted with that
+    /// key.
+    pub encrypted: BTreeMap<String, AesHmacSha2EncryptedData>,
+}
+
+/// A key that is used to encrypt and decrypt secrets in the secret storage.
+pub struct SecretStorageKey {
+    key: [u8; KEY_SIZE],
+    key_id: String,
+    content: SecretStorageKeyEventContent,
+}
+
+impl Drop for SecretStorageKey {
+    fn drop(&mut self) {
+        self.key.zeroize()
+    }
+}
+
+impl std::fmt::Debug for SecretStorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretStorageKey")
+            .field("key_id", &self.key_id)
+            .field("content", &self.content)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for SecretStorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = Zeroizing::new(self.to_base58());
+
+        let string = Zeroizing::new(
+            string
+                .chars()
+                .collect::<Vec<char>>()
+                .chunks(DISPLAY_CHUNK_SIZE)
+                .map(|c| c.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+
+        write!(f, "{}", string.as_str())
+    }
+}
+
+impl SecretStorageKey {
+    /// Create a new random secret storage key.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        getrandom(&mut key).expect("Can't generate randomness");
+
+        Self::from_parts(key, Self::random_key_id(), None)
+    }
+
+    /// Create a new secret storage key that is derived from the given
+    /// passphrase.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new_from_passphrase(passphrase: &str) -> Self {
+        Self::new_from_passphrase_with_rounds(passphrase, PBKDF2_ROUNDS)
+    }
+
+    fn new_from_passphrase_with_rounds(passphrase: &str, rounds: u32) -> Self {
+        let salt: String =
+            thread_rng().sample_iter(&Alphanumeric).take(SALT_LENGTH).map(char::from).collect();
+
+        let info = PassphraseInfo {
+            algorithm: PBKDF2.to_owned(),
+            salt,
+            iterations: rounds,
+            bits: Some(KEY_SIZE as u32 * 8),
+        };
+
+        let key = Self::derive_key(passphrase, &info);
+
+        Self::from_parts(key, Self::random_key_id(), Some(info))
+    }
+
+    /// Restore a secret storage key from a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase the key was derived from.
+    ///
+    /// * `key_id` - The id of the secret storage key.
+    ///
+    /// * `content` - The content of the `m.secret_storage.key.[key_id]` global
+    /// account data event.
+    pub fn from_passphrase(
+        passphrase: &str,
+        key_id: &str,
+        content: SecretStorageKeyEventContent,
+    ) -> Result<Self, SecretStorageError> {
+        Self::check_algorithm(&content)?;
+
+        let info = content.passphrase.as_ref().ok_or(SecretStorageError::MissingPassphraseInfo)?;
+
+        if info.algorithm != PBKDF2 {
+            return Err(SecretStorageError::UnsupportedPassphraseAlgorithm(
+                info.algorithm.to_owned(),
+            ));
+        }
+
+        let key = Self::derive_key(passphrase, info);
+
+        Self::from_checked_parts(key, key_id, content)
+    }
+
+    /// Restore a secret storage key from its base58 encoded form.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The base58 encoded key, whitespace is ignored.
+    ///
+    /// * `key_id` - The id of the secret storage key.
+    ///
+    /// * `content` - The content of the `m.secret_storage.key.[key_id]` global
+    /// account data event.
+    pub fn from_base58(
+        value: &str,
+        key_id: &str,
+        content: SecretStorageKeyEventContent,
+    ) -> Result<Self, SecretStorageError> {
+        Self::check_algorithm(&content)?;
+
+        let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+        let decoded =
+            Zeroizing::new(bs58::decode(value).with_alphabet(bs58::Alphabet::BITCOIN).into_vec()?);
+
+        let expected_length = BASE58_PREFIX.len() + KEY_SIZE + 1;
+
+        if decoded.len() != expected_length {
+            return Err(SecretStorageError::Length(expected_length, decoded.len()));
+        }
+
+        let (prefix, rest) = decoded.split_at(BASE58_PREFIX.len());
+        let (key_bytes, expected_parity) = rest.split_at(KEY_SIZE);
+        let prefix = [prefix[0], prefix[1]];
+        let expected_parity = expected_parity[0];
+        let parity = Self::parity_byte(key_bytes);
+
+        if prefix != BASE58_PREFIX {
+            Err(SecretStorageError::Prefix(BASE58_PREFIX, prefix))
+        } else if expected_parity != parity {
+            Err(SecretStorageError::Parity(expected_parity, parity))
+        } else {
+            let mut key = [0u8; KEY_SIZE];
+            key.copy_from_slice(key_bytes);
+
+            Self::from_checked_parts(key, key_id, content)
+        }
+    }
+
+    /// Restore a secret storage key from user input that is either the
+    /// passphrase or the base58 encoded key.
+    ///
+    /// The input is first treated as a base58 encoded key, if that fails and
+    /// the key was derived from a passphrase, the input is treated as a
+    /// passphrase.
+    pub fn from_passphrase_or_key(
+        input: &str,
+        key_id: &str,
+        content: SecretStorageKeyEventContent,
+    ) -> Result<Self, SecretStorageError> {
+        match Self::from_base58(input, key_id, content.clone()) {
+            Ok(key) => Ok(key),
+            Err(e) if content.passphrase.is_none() => Err(e),
+            Err(_) => Self::from_passphrase(input, key_id, content),
+        }
+    }
+
+    /// Export the key as a base58 encoded string, this is the form that should
+    /// be presented to the user.
+    pub fn to_base58(&self) -> String {
+        let bytes = Zeroizing::new(
+            [
+                BASE58_PREFIX.as_ref(),
+                self.key.as_ref(),
+                [Self::parity_byte(self.key.as_ref())].as_ref(),
+            ]
+            .concat(),
+        );
+
+        bs58::encode(bytes.as_slice()).with_alphabet(bs58::Alphabet::BITCOIN).into_string()
+    }
+
+    /// Get the unique id of this key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Get the event type of the global account data event that describes
+    /// this key.
+    pub fn event_type(&self) -> String {
+        key_event_type(&self.key_id)
+    }
+
+    /// Get the content of the global account data event that describes this
+    /// key.
+    ///
+    /// This needs to be uploaded to the server as the
+    /// [`event_type()`](Self::event_type) global account data event.
+    pub fn event_content(&self) -> &SecretStorageKeyEventContent {
+        &self.content
+    }
+
+    /// Get the content of the `m.secret_storage.default_key` global account
+    /// data event that marks this key as the default key.
+    pub fn default_key_content(&self) -> DefaultKeyEventContent {
+        DefaultKeyEventContent { key: self.key_id.to_owned() }
+    }
+
+    /// Encrypt the given secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret that should be encrypted.
+    ///
+    /// * `secret_name` - The name of the secret, this is also the event type of
+    /// the global account data event that will hold the secret.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn encrypt(&self, secret: &str, secret_name: &str) -> AesHmacSha2EncryptedData {
+        let mut iv = [0u8; IV_SIZE];
+        getrandom(&mut iv).expect("Can't generate randomness");
+
+        // Clear bit 63 of the IV, this prevents the counter from overflowing
+        // on some implementations.
+        iv[8] &= 0x7f;
+
+        self.encrypt_with_iv(secret.as_bytes(), secret_name, iv)
+    }
+
+    /// Decrypt the given encrypted secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The encrypted secret.
+    ///
+    /// * `secret_name` - The name of the secret, needs to match to the name
+    /// that was used to encrypt the secret.
+    pub fn decrypt(
+        &self,
+        secret: &AesHmacSha2EncryptedData,
+        secret_name: &str,
+    ) -> Result<Zeroizing<String>, SecretStorageError> {
+        let plaintext = Zeroizing::new(self.decrypt_bytes(secret, secret_name)?);
+
+        Ok(Zeroizing::new(String::from_utf8(plaintext.to_vec())?))
+    }
+
+    /// Encrypt the given secret and put it into the content of a global
+    /// account data event.
+    ///
+    /// The resulting content needs to be uploaded as the global account data
+    /// event with the secret name as the event type.
+    pub fn encrypt_secret(&self, secret: &str, secret_name: &str) -> SecretEventContent {
+        let encrypted = self.encrypt(secret, secret_name);
+
+        SecretEventContent { encrypted: BTreeMap::from([(self.key_id.to_owned(), encrypted)]) }
+    }
+
+    /// Decrypt the secret that is stored in the given global account data
+    /// event content.
+    pub fn decrypt_secret(
+        &self,
+        content: &SecretEventContent,
+        secret_name: &str,
+    ) -> Result<Zeroizing<String>, SecretStorageError> {
+        let encrypted = content
+            .encrypted
+            .get(&self.key_id)
+            .ok_or_else(|| SecretStorageError::MissingSecret(self.key_id.to_owned()))?;
+
+        self.decrypt(encrypted, secret_name)
+    }
+
+    fn from_parts(key: [u8; KEY_SIZE], key_id: String, passphrase: Option<PassphraseInfo>) -> Self {
+        let mut key = Self {
+            key,
+            key_id,
+            content: SecretStorageKeyEventContent {
+                algorithm: SECRET_STORAGE_V1_AES_HMAC_SHA2.to_owned(),
+                name: None,
+                passphrase,
+                iv: None,
+                mac: None,
+            },
+        };
+
+        let mut iv = [0u8; IV_SIZE];
+        getrandom(&mut iv).expect("Can't generate randomness");
+        iv[8] &= 0x7f;
+
+        let check = key.encrypt_with_iv(&[0u8; KEY_SIZE], "", iv);
+
+        key.content.iv = Some(check.iv);
+        key.content.mac = Some(check.mac);
+
+        key
+    }
+
+    fn from_checked_parts(
+        key: [u8; KEY_SIZE],
+        key_id: &str,
+        content: SecretStorageKeyEventContent,
+    ) -> Result<Self, SecretStorageError> {
+        let key = Self { key, key_id: key_id.to_owned(), content };
+
+        // Keys that were created before the key check was introduced don't
+        // have an IV and MAC, we can't check those.
+        if let (Some(iv), Some(mac)) = (&key.content.iv, &key.content.mac) {
+            let iv = Self::decode_iv(iv)?;
+            let expected = key.encrypt_with_iv(&[0u8; KEY_SIZE], "", iv);
+            let ciphertext = decode_base64(&expected.ciphertext)?;
+
+            key.hmac(&ciphertext, "")
+                .verify(&decode_base64(mac)?)
+                .map_err(|_| SecretStorageError::InvalidKey)?;
+        }
+
+        Ok(key)
+    }
+
+    fn check_algorithm(content: &SecretStorageKeyEventContent) -> Result<(), SecretStorageError> {
+        if content.algorithm == SECRET_STORAGE_V1_AES_HMAC_SHA2 {
+            Ok(())
+        } else {
+            Err(SecretStorageError::UnsupportedAlgorithm(content.algorithm.to_owned()))
+        }
+    }
+
+    fn random_key_id() -> String {
+        thread_rng().sample_iter(&Alphanumeric).take(KEY_ID_LENGTH).map(char::from).collect()
+    }
+
+    fn parity_byte(bytes: &[u8]) -> u8 {
+        BASE58_PREFIX.iter().chain(bytes).fold(0, |acc, x| acc ^ x)
+    }
+
+    fn derive_key(passphrase: &str, info: &PassphraseInfo) -> [u8; KEY_SIZE] {
+        let mut key = [0u8; KEY_SIZE];
+
+        pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            info.salt.as_bytes(),
+            info.iterations,
+            &mut key,
+        );
+
+        key
+    }
+
+    fn derive_keys(&self, secret_name: &str) -> Zeroizing<[u8; KEY_SIZE * 2]> {
+        let mut keys = Zeroizing::new([0u8; KEY_SIZE * 2]);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE]), &self.key);
+        hkdf.expand(secret_name.as_bytes(), &mut keys[..])
+            .expect("Can't expand the secret storage key");
+
+        keys
+    }
+
+    /// Create a HMAC object that authenticates the given ciphertext of the
+    /// secret with the given name.
+    fn hmac(&self, ciphertext: &[u8], secret_name: &str) -> Hmac<Sha256> {
+        let keys = self.derive_keys(secret_name);
+        let (_, hmac_key) = keys.split_at(KEY_SIZE);
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create HMAC object");
+        hmac.update(ciphertext);
+
+        hmac
+    }
+
+    fn decode_iv(iv: &str) -> Result<[u8; IV_SIZE], SecretStorageError> {
+        let decoded = decode_base64(iv)?;
+
+        if decoded.len() != IV_SIZE {
+            Err(SecretStorageError::Length(IV_SIZE, decoded.len()))
+        } else {
+            let mut iv = [0u8; IV_SIZE];
+            iv.copy_from_slice(&decoded);
+
+            Ok(iv)
+        }
+    }
+
+    fn encrypt_with_iv(
+        &self,
+        plaintext: &[u8],
+        secret_name: &str,
+        iv: [u8; IV_SIZE],
+    ) -> AesHmacSha2EncryptedData {
+        let keys = self.derive_keys(secret_name);
+        let (aes_key, _) = keys.split_at(KEY_SIZE);
+
+        let mut ciphertext = plaintext.to_vec();
+
+        let aes = Aes256::new(GenericArray::from_slice(aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(&mut ciphertext);
+
+        let mac = self.hmac(&ciphertext, secret_name).finalize().into_bytes();
+
+        AesHmacSha2EncryptedData {
+            iv: encode(iv),
+            ciphertext: encode(ciphertext),
+            mac: encode(mac),
+        }
+    }
+
+    fn decrypt_bytes(
+        &self,
+        secret: &AesHmacSha2EncryptedData,
+        secret_name: &str,
+    ) -> Result<Vec<u8>, SecretStorageError> {
+        let iv = Self::decode_iv(&secret.iv)?;
+        let mut ciphertext = decode_base64(&secret.ciphertext)?;
+        let mac = decode_base64(&secret.mac)?;
+
+        self.hmac(&ciphertext, secret_name)
+            .verify(&mac)
+            .map_err(|_| SecretStorageError::InvalidMac)?;
+
+        let keys = self.derive_keys(secret_name);
+        let (aes_key, _) = keys.split_at(KEY_SIZE);
+
+        let aes = Aes256::new(GenericArray::from_slice(aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(&mut ciphertext);
+
+        Ok(ciphertext)
+    }
+}
+
+impl Default for SecretStorageKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode base64 that may or may not be padded, other clients pad their
+/// base64 when they encrypt secrets.
+fn decode_base64(input: &str) -> Result<Vec<u8>, DecodeError> {
+    decode(input.trim_end_matches('='))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{
+        AesHmacSha2EncryptedData, SecretEventContent, SecretStorageError, SecretStorageKey,
+        SecretStorageKeyEventContent, KEY_SIZE, SECRET_STORAGE_V1_AES_HMAC_SHA2,
+    };
+
+    const SECRET: &str = "It's a secret to everybody";
+    const SECRET_NAME: &str = "m.cross_signing.master";
+
+    fn test_key() -> SecretStorageKey {
+        let mut key_bytes = [0u8; KEY_SIZE];
+        key_bytes.iter_mut().zip(0u8..).for_each(|(byte, value)| *byte = value);
+
+        let content = SecretStorageKeyEventContent {
+            algorithm: SECRET_STORAGE_V1_AES_HMAC_SHA2.to_owned(),
+            name: None,
+            passphrase: None,
+            iv: Some("EREREREREREiIiIiIiIiIg".to_owned()),
+            mac: Some("GTpUyB+GoP4qhiYYo6YqUi2mqGE0lHfuDbCdhGy5+t4=".to_owned()),
+        };
+
+        SecretStorageKey::from_checked_parts(key_bytes, "KEYID", content)
+            .expect("The key check of the test key should succeed")
+    }
+
+    #[test]
+    fn decrypting_known_secret() {
+        let key = test_key();
+
+        let encrypted = AesHmacSha2EncryptedData {
+            iv: "EREREREREREiIiIiIiIiIg".to_owned(),
+            ciphertext: "Poi23pSTF2HOGu/ZWQBMXxaqTuLSGCx8q3w".to_owned(),
+            mac: "Zw2j4DespGuTtxlg0VGjvonBhS0iTV4S96dEM6mP3pg".to_owned(),
+        };
+
+        assert_eq!(key.decrypt(&encrypted, SECRET_NAME).unwrap().as_str(), SECRET);
+        assert!(matches!(
+            key.decrypt(&encrypted, "m.cross_signing.self_signing"),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn encryption_cycle() {
+        let key = SecretStorageKey::new();
+        let content = key.encrypt_secret(SECRET, SECRET_NAME);
+
+        assert!(content.encrypted.contains_key(key.key_id()));
+        assert_eq!(key.decrypt_secret(&content, SECRET_NAME).unwrap().as_str(), SECRET);
+
+        let other_key = SecretStorageKey::new();
+        assert!(matches!(
+            other_key.decrypt_secret(&content, SECRET_NAME),
+            Err(SecretStorageError::MissingSecret(_))
+        ));
+
+        let content = SecretEventContent {
+            encrypted: BTreeMap::from([(
+                other_key.key_id().to_owned(),
+                content.encrypted[key.key_id()].clone(),
+            )]),
+        };
+        assert!(matches!(
+            other_key.decrypt_secret(&content, SECRET_NAME),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn base58_restoring() {
+        let key = SecretStorageKey::new();
+        let encoded = key.to_string();
+
+        let restored =
+            SecretStorageKey::from_base58(&encoded, key.key_id(), key.event_content().clone())
+                .unwrap();
+
+        assert_eq!(key.to_base58(), restored.to_base58());
+
+        let other_key = SecretStorageKey::new();
+        assert!(matches!(
+            SecretStorageKey::from_base58(
+                &other_key.to_base58(),
+                key.key_id(),
+                key.event_content().clone()
+            ),
+            Err(SecretStorageError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn passphrase_restoring() {
+        let key = SecretStorageKey::new_from_passphrase_with_rounds("passphrase", 10);
+        let content = key.event_content().clone();
+
+        let restored =
+            SecretStorageKey::from_passphrase("passphrase", key.key_id(), content.clone()).unwrap();
+        assert_eq!(key.to_base58(), restored.to_base58());
+
+        let restored =
+            SecretStorageKey::from_passphrase_or_key("passphrase", key.key_id(), content.clone())
+                .unwrap();
+        assert_eq!(key.to_base58(), restored.to_base58());
+
+        let restored = SecretStorageKey::from_passphrase_or_key(
+            &key.to_base58(),
+            key.key_id(),
+            content.clone(),
+        )
+        .unwrap();
+        assert_eq!(key.to_base58(), restored.to_base58());
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase("wrong passphrase", key.key_id(), content),
+            Err(SecretStorageError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn unsupported_algorithm() {
+        let key = SecretStorageKey::new();
+        let mut content = key.event_content().clone();
+        content.algorithm = "m.secret_storage.v2.something".to_owned();
+
+        assert!(matches!(
+            SecretStorageKey::from_base58(&key.to_base58(), key.key_id(), content),
+            Err(SecretStorageError::UnsupportedAlgorithm(_))
+        ));
+    }
+}
@@ -22,10 +22,7 @@ use dashmap::DashMap;
 use futures_util::future::join_all;
 use matrix_sdk_common::{executor::spawn, uuid::Uuid};
 use ruma::{
-    events::{
-        room::{encrypted::RoomEncryptedEventContent, history_visibility::HistoryVisibility},
-        AnyToDeviceEventContent, EventType,
-    },
+    events::{room::encrypted::RoomEncryptedEventContent, AnyToDeviceEventContent, EventType},
     serde::Raw,
     to_device::DeviceIdOrAllDevices,
     DeviceId, RoomId, UserId,
@@ -37,7 +34,8 @@ use crate::{
     error::{EventError, MegolmResult, OlmResult},
     olm::{Account, InboundGroupSession, OutboundGroupSession, Session, ShareInfo, ShareState},
     store::{Changes, Result as StoreResult, Store},
-    Device, EncryptionSettings, OlmError, ToDeviceRequest,
+    withheld::{withheld_request, RoomKeyWithheldEventContent, WithheldCode},
+    Device, EncryptionSettings, OlmError, ShareStrategy, ToDeviceRequest,
 };
 
 #[derive(Clone, Debug)]
@@ -105,6 +103,17 @@ impl GroupSessionCache {
     }
 }
 
+/// The result of the recipient calculation for a room key.
+#[derive(Debug)]
+pub struct CollectRecipientsResult {
+    /// Does the room key need to be rotated before it's shared.
+    pub should_rotate: bool,
+    /// The devices that should receive the room key.
+    pub devices: HashMap<Box<UserId>, Vec<Device>>,
+    /// The devices the room key should be withheld from, and the reason why.
+    pub withheld_devices: Vec<(Device, WithheldCode)>,
+}
+
 #[derive(Debug, Clone)]
 pub struct GroupSessionManager {
     account: Account,
@@ -225,10 +234,12 @@ impl GroupSessionManager {
         ToDeviceRequest,
         BTreeMap<Box<UserId>, BTreeMap<Box<DeviceId>, ShareInfo>>,
         Vec<Session>,
+        Vec<Device>,
     )> {
         let mut messages = BTreeMap::new();
         let mut changed_sessions = Vec::new();
         let mut share_infos = BTreeMap::new();
+        let mut no_olm_devices = Vec::new();
 
         let encrypt = |device: Device, content: AnyToDeviceEventContent| async move {
             let mut message = BTreeMap::new();
@@ -236,7 +247,7 @@ impl GroupSessionManager {
 
             let encrypted = device.encrypt(content.clone()).await;
 
-            let used_session = match encrypted {
+            let (used_session, no_olm) = match encrypted {
                 Ok((session, encrypted)) => {
                     message
                         .entry(device.user_id().to_owned())
@@ -257,15 +268,14 @@ impl GroupSessionManager {
                             },
                         );
 
-                    Some(session)
+                    (Some(session), None)
                 }
-                // TODO we'll want to create m.room_key.withheld here.
                 Err(OlmError::MissingSession)
-                | Err(OlmError::EventError(EventError::MissingSenderKey)) => None,
+                | Err(OlmError::EventError(EventError::MissingSenderKey)) => (None, Some(device)),
                 Err(e) => return Err(e),
             };
 
-            Ok((used_session, share_infos, message))
+            Ok((used_session, no_olm, share_infos, message))
         };
 
         let tasks: Vec<_> =
@@ -274,12 +284,17 @@ impl GroupSessionManager {
         let results = join_all(tasks).await;
 
         for result in results {
-            let (used_session, infos, message) = result.expect("Encryption task panicked")?;
+            let (used_session, no_olm, infos, message) =
+                result.expect("Encryption task panicked")?;
 
             if let Some(session) = used_session {
                 changed_sessions.push(session);
             }
 
+            if let Some(device) = no_olm {
+                no_olm_devices.push(device);
+            }
+
             for (user, device_messages) in message.into_iter() {
                 messages.entry(user).or_insert_with(BTreeMap::new).extend(device_messages);
             }
@@ -300,22 +315,25 @@ impl GroupSessionManager {
             "Created a to-device request carrying a room_key"
         );
 
-        Ok((id, request, share_infos, changed_sessions))
+        Ok((id, request, share_infos, changed_sessions, no_olm_devices))
     }
 
     /// Given a list of user and an outbound session, return the list of users
     /// and their devices that this session should be shared with.
     ///
-    /// Returns a boolean indicating whether the session needs to be rotated and
-    /// the list of users/devices that should receive the session.
+    /// Returns whether the session needs to be rotated, the list of
+    /// users/devices that should receive the session and the list of devices
+    /// the session should be withheld from.
     pub async fn collect_session_recipients(
         &self,
         users: impl Iterator<Item = &UserId>,
-        history_visibility: HistoryVisibility,
+        settings: &EncryptionSettings,
         outbound: &OutboundGroupSession,
-    ) -> OlmResult<(bool, HashMap<Box<UserId>, Vec<Device>>)> {
+    ) -> OlmResult<CollectRecipientsResult> {
+        let history_visibility = &settings.history_visibility;
         let users: HashSet<&UserId> = users.collect();
         let mut devices: HashMap<Box<UserId>, Vec<Device>> = HashMap::new();
+        let mut withheld_devices = Vec::new();
 
         trace!(
             users = ?users,
@@ -335,30 +353,68 @@ impl GroupSessionManager {
         // get the session but is in the set of users that received the session.
         let user_left = !users_shared_with.difference(&users).collect::<HashSet<_>>().is_empty();
 
-        let visibility_changed = outbound.settings().history_visibility != history_visibility;
+        let visibility_changed = &outbound.settings().history_visibility != history_visibility;
 
         // To protect the room history we need to rotate the session if either:
         //
         // 1. Any user left the room.
-        // 2. Any of the users' devices got deleted or blacklisted.
+        // 2. Any of the users' devices got deleted, blacklisted or got excluded
+        //    by the sharing strategy, e.g. because they lost their trust.
         // 3. The history visibility changed.
         //
         // This is calculated in the following code and stored in this variable.
         let mut should_rotate = user_left || visibility_changed;
 
+        // Devices that aren't verified, if the sharing strategy refuses to share
+        // the room key in that case.
+        let mut unverified_devices: BTreeMap<Box<UserId>, BTreeSet<Box<DeviceId>>> =
+            BTreeMap::new();
+
         for user_id in users {
             let user_devices = self.store.get_user_devices(user_id).await?;
-            let non_blacklisted_devices: Vec<Device> =
-                user_devices.devices().filter(|d| !d.is_blacklisted()).collect();
+            let mut recipient_devices = Vec::new();
+
+            for device in user_devices.devices() {
+                if device.is_blacklisted() {
+                    withheld_devices.push((device, WithheldCode::Blacklisted));
+                    continue;
+                }
+
+                let allowed = match settings.sharing_strategy {
+                    ShareStrategy::AllDevices => true,
+                    ShareStrategy::TrustedDevices => device.verified(),
+                    ShareStrategy::CrossSigningTrustedDevices => device.is_cross_signing_trusted(),
+                    ShareStrategy::VerifiedUsers => device.is_signed_by_verified_identity(),
+                    ShareStrategy::PinnedIdentities => {
+                        device.verified() || device.is_signed_by_pinned_identity()
+                    }
+                    ShareStrategy::ErrorOnUnverifiedDevices => {
+                        if !device.verified() {
+                            unverified_devices
+                                .entry(device.user_id().to_owned())
+                                .or_default()
+                                .insert(device.device_id().to_owned());
+                        }
+
+                        true
+                    }
+                };
+
+                if allowed {
+                    recipient_devices.push(device);
+                } else {
+                    withheld_devices.push((device, WithheldCode::Unverified));
+                }
+            }
 
             // If we haven't already concluded that the session should be
             // rotated for other reasons, we also need to check whether any
-            // of the devices in the session got deleted or blacklisted in the
+            // of the devices in the session got deleted or withheld from in the
             // meantime. If so, we should also rotate the session.
             if !should_rotate {
                 // Device IDs that should receive this session
-                let non_blacklisted_device_ids: HashSet<&DeviceId> =
-                    non_blacklisted_devices.iter().map(|d| d.device_id()).collect();
+                let recipient_device_ids: HashSet<&DeviceId> =
+                    recipient_devices.iter().map(|d| d.device_id()).collect();
 
                 if let Some(shared) = outbound.shared_with_set.get(user_id) {
                     // Devices that received this session
@@ -371,21 +427,28 @@ impl GroupSessionManager {
                     // 1. Devices that had previously received the session, and
                     // 2. Devices that would now receive the session
                     //
-                    // represents newly deleted or blacklisted devices. If this
+                    // represents newly deleted or withheld devices. If this
                     // set is non-empty, we must rotate.
-                    let newly_deleted_or_blacklisted =
-                        shared.difference(&non_blacklisted_device_ids).collect::<HashSet<_>>();
+                    let newly_deleted_or_withheld =
+                        shared.difference(&recipient_device_ids).collect::<HashSet<_>>();
 
-                    if !newly_deleted_or_blacklisted.is_empty() {
+                    if !newly_deleted_or_withheld.is_empty() {
                         should_rotate = true;
                     }
                 };
             }
 
-            devices
-                .entry(user_id.to_owned())
-                .or_insert_with(Vec::new)
-                .extend(non_blacklisted_devices);
+            devices.entry(user_id.to_owned()).or_insert_with(Vec::new).extend(recipient_devices);
+        }
+
+        if !unverified_devices.is_empty() {
+            info!(
+                room_id = outbound.room_id().as_str(),
+                unverified_devices =? unverified_devices,
+                "Refusing to share the room key, the room contains unverified devices"
+            );
+
+            return Err(OlmError::UnverifiedDevices(unverified_devices));
         }
 
         trace!(
@@ -395,7 +458,7 @@ impl GroupSessionManager {
             "Done calculating group session recipients"
         );
 
-        Ok((should_rotate, devices))
+        Ok(CollectRecipientsResult { should_rotate, devices, withheld_devices })
     }
 
     pub async fn encrypt_request(
@@ -404,8 +467,8 @@ impl GroupSessionManager {
         outbound: OutboundGroupSession,
         message_index: u32,
         being_shared: Arc<DashMap<Uuid, OutboundGroupSession>>,
-    ) -> OlmResult<Vec<Session>> {
-        let (id, request, share_infos, used_sessions) =
+    ) -> OlmResult<(Vec<Session>, Vec<Device>)> {
+        let (id, request, share_infos, used_sessions, no_olm_devices) =
             Self::encrypt_session_for(content.clone(), chunk, message_index).await?;
 
         if !request.messages.is_empty() {
@@ -413,7 +476,61 @@ impl GroupSessionManager {
             being_shared.insert(id, outbound.clone());
         }
 
-        Ok(used_sessions)
+        Ok((used_sessions, no_olm_devices))
+    }
+
+    /// Tell the given devices that we won't share the room key of the
+    /// outbound session with them.
+    ///
+    /// Devices that were already told that the room key is withheld from
+    /// them are skipped. Returns true if a new `m.room_key.withheld` request
+    /// was queued up.
+    fn withhold_room_key(
+        &self,
+        outbound: &OutboundGroupSession,
+        devices: Vec<(Device, WithheldCode)>,
+    ) -> bool {
+        let devices: Vec<_> =
+            devices.into_iter().filter(|(d, _)| !outbound.is_withheld_from(d)).collect();
+
+        if devices.is_empty() {
+            return false;
+        }
+
+        let sender_key = self.account.identity_keys().curve25519();
+
+        let request = withheld_request(devices.iter().map(|(d, code)| {
+            (
+                d.user_id(),
+                d.device_id(),
+                RoomKeyWithheldEventContent::new(
+                    code.clone(),
+                    outbound.room_id(),
+                    outbound.session_id(),
+                    sender_key,
+                ),
+            )
+        }));
+
+        let withheld: BTreeMap<_, _> =
+            devices.iter().map(|(d, code)| ((d.user_id(), d.device_id()), code.as_str())).collect();
+
+        info!(
+            room_id = outbound.room_id().as_str(),
+            session_id = outbound.session_id(),
+            ?withheld,
+            "Withholding a room key from some devices"
+        );
+
+        for (device, _) in &devices {
+            outbound.mark_as_withheld(device);
+        }
+
+        let id = request.txn_id;
+        outbound.add_request(id, request.into(), BTreeMap::new());
+        self.sessions.sessions_being_shared.insert(id, outbound.clone());
+
+        true
     }
 
     pub(crate) fn session_cache(&self) -> GroupSessionCache {
@@ -440,7 +557,6 @@ impl GroupSessionManager {
         trace!(room_id = room_id.as_str(), "Checking if a room key needs to be shared",);
 
         let encryption_settings = encryption_settings.into();
-        let history_visibility = encryption_settings.history_visibility.clone();
         let mut changes = Changes::default();
 
         let (outbound, inbound) =
@@ -451,8 +567,8 @@ impl GroupSessionManager {
             changes.inbound_group_sessions.push(inbound);
         }
 
-        let (should_rotate, devices) =
-            self.collect_session_recipients(users, history_visibility, &outbound).await?;
+        let CollectRecipientsResult { should_rotate, devices, mut withheld_devices } =
+            self.collect_session_recipients(users, &encryption_settings, &outbound).await?;
 
         let outbound = if should_rotate {
             let old_session_id = outbound.session_id();
@@ -515,9 +631,21 @@ impl GroupSessionManager {
             .collect();
 
         for result in join_all(tasks).await {
-            let used_sessions: OlmResult<Vec<Session>> = result.expect("Encryption task panicked");
+            let (used_sessions, no_olm_devices) = result.expect("Encryption task panicked")?;
+
+            changes.sessions.extend(used_sessions);
+
+            // Unlike the other codes, `m.no_olm` is sent once per device and not
+            // once per room key.
+            for device in no_olm_devices.into_iter().filter(|d| !d.was_withheld_code_sent()) {
+                device.mark_withheld_code_as_sent();
+                changes.devices.changed.push(device.inner.clone());
+                withheld_devices.push((device, WithheldCode::NoOlm));
+            }
+        }
 
-            changes.sessions.extend(used_sessions?);
+        if self.withhold_room_key(&outbound, withheld_devices) {
+            changes.outbound_group_sessions.push(outbound.clone());
         }
 
         let requests = outbound.pending_requests();
@@ -546,7 +674,6 @@ impl GroupSessionManager {
 
             let transaction_ids: Vec<Uuid> = requests.iter().map(|r| r.txn_id).collect();
 
-            // TODO log the withheld reasons here as well.
             info!(
                 room_id = room_id.as_str(),
                 session_id = outbound.session_id(),
@@ -576,7 +703,7 @@ impl GroupSessionManager {
 
 #[cfg(test)]
 mod test {
-    use std::ops::Deref;
+    use std::{collections::BTreeMap, ops::Deref, sync::Arc};
 
     use matrix_sdk_common::uuid::Uuid;
     use matrix_sdk_test::response_from_file;
@@ -585,11 +712,20 @@ mod test {
             client::r0::keys::{claim_keys, get_keys},
             IncomingResponse,
         },
-        device_id, room_id, user_id, DeviceId, UserId,
+        device_id,
+        events::EventType,
+        room_id,
+        to_device::DeviceIdOrAllDevices,
+        user_id, DeviceId, RoomId, UserId,
     };
     use serde_json::Value;
 
-    use crate::{EncryptionSettings, OlmMachine};
+    use crate::{
+        identities::manager::test::own_key_query,
+        store::{Changes, IdentityChanges},
+        withheld::{RoomKeyWithheldEventContent, WithheldCode, WITHHELD_EVENT_TYPE},
+        EncryptionSettings, LocalTrust, OlmError, OlmMachine, ShareStrategy, ToDeviceRequest,
+    };
 
     fn alice_id() -> &'static UserId {
         user_id!("@alice:example.org")
@@ -628,6 +764,20 @@ mod test {
         machine
     }
 
+    /// Collect the withheld codes the devices were sent, by device id.
+    fn withheld_codes(
+        requests: &[Arc<ToDeviceRequest>],
+    ) -> BTreeMap<DeviceIdOrAllDevices, WithheldCode> {
+        requests
+            .iter()
+            .filter(|r| r.event_type.as_str() == WITHHELD_EVENT_TYPE)
+            .flat_map(|r| r.messages.values().flat_map(|m| m.iter()))
+            .map(|(d, c)| {
+                (d.clone(), c.deserialize_as::<RoomKeyWithheldEventContent>().unwrap().code)
+            })
+            .collect()
+    }
+
     #[tokio::test]
     async fn test_sharing() {
         let machine = machine().await;
@@ -641,11 +791,226 @@ mod test {
             .await
             .unwrap();
 
-        let event_count: usize = requests.iter().map(|r| r.message_count()).sum();
+        let event_count: usize = requests
+            .iter()
+            .filter(|r| r.event_type == EventType::RoomEncrypted)
+            .map(|r| r.message_count())
+            .sum();
 
         // The keys claim response has a couple of one-time keys with invalid
         // signatures, thus only 148 sessions are actually created, we check
         // that all 148 valid sessions get an room key.
         assert_eq!(event_count, 148);
+
+        // The devices we couldn't establish a session with get told why they
+        // didn't receive the room key.
+        let withheld: Vec<_> = requests
+            .iter()
+            .filter(|r| r.event_type.as_str() == WITHHELD_EVENT_TYPE)
+            .flat_map(|r| r.messages.values().flat_map(|m| m.values()))
+            .map(|c| c.deserialize_as::<RoomKeyWithheldEventContent>().unwrap())
+            .collect();
+
+        assert!(!withheld.is_empty());
+        assert!(withheld.iter().all(|c| c.code == WithheldCode::NoOlm));
+
+        // The `m.no_olm` code is only sent once per device, sharing a room key
+        // for another room doesn't send it again.
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let requests = machine
+            .share_group_session(room_id!("!other:localhost"), users, EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        assert!(!requests.iter().any(|r| r.event_type.as_str() == WITHHELD_EVENT_TYPE));
+    }
+
+    #[tokio::test]
+    async fn blacklisted_devices_get_a_withheld_notice() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let (user_id, devices) = keys_claim.one_time_keys.iter().next().unwrap();
+        let device_id = devices.keys().next().unwrap();
+
+        let device = machine.get_device(user_id, device_id).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+
+        let requests = machine
+            .share_group_session(room_id, users, EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let withheld_request = requests
+            .iter()
+            .find(|r| r.event_type.as_str() == WITHHELD_EVENT_TYPE)
+            .expect("A withheld request should have been created");
+
+        let content: RoomKeyWithheldEventContent = withheld_request.messages[user_id]
+            [&DeviceIdOrAllDevices::DeviceId(device_id.to_owned())]
+            .deserialize_as()
+            .unwrap();
+
+        assert_eq!(content.code, WithheldCode::Blacklisted);
+        assert_eq!(content.room_id.as_deref(), Some(room_id));
+
+        let encrypted_to_device = requests
+            .iter()
+            .filter(|r| r.event_type == EventType::RoomEncrypted)
+            .flat_map(|r| r.messages.get(user_id))
+            .any(|m| m.contains_key(&DeviceIdOrAllDevices::DeviceId(device_id.to_owned())));
+
+        assert!(!encrypted_to_device);
+    }
+
+    #[tokio::test]
+    async fn sharing_strategies() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let (user_id, devices) = keys_claim.one_time_keys.iter().next().unwrap();
+        let device_id = devices.keys().next().unwrap();
+
+        let device = machine.get_device(user_id, device_id).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+
+        let settings = EncryptionSettings {
+            sharing_strategy: ShareStrategy::ErrorOnUnverifiedDevices,
+            ..Default::default()
+        };
+
+        let error =
+            machine.share_group_session(room_id, users.clone(), settings).await.unwrap_err();
+
+        if let OlmError::UnverifiedDevices(unverified) = error {
+            assert!(!unverified.is_empty());
+            assert!(!unverified.get(user_id).map_or(false, |d| d.contains(device_id)));
+        } else {
+            panic!("Sharing the room key should have failed because of unverified devices");
+        }
+
+        let settings = EncryptionSettings {
+            sharing_strategy: ShareStrategy::TrustedDevices,
+            ..Default::default()
+        };
+
+        let requests = machine.share_group_session(room_id, users, settings).await.unwrap();
+
+        let recipients: Vec<_> = requests
+            .iter()
+            .filter(|r| r.event_type == EventType::RoomEncrypted)
+            .flat_map(|r| r.messages.iter())
+            .flat_map(|(u, m)| m.keys().map(move |d| (u.clone(), d.clone())))
+            .collect();
+
+        assert!(recipients.iter().all(|(u, d)| {
+            u == user_id && d == &DeviceIdOrAllDevices::DeviceId(device_id.to_owned())
+        }));
+
+        let withheld: Vec<_> = requests
+            .iter()
+            .filter(|r| r.event_type.as_str() == WITHHELD_EVENT_TYPE)
+            .flat_map(|r| r.messages.values().flat_map(|m| m.values()))
+            .map(|c| c.deserialize_as::<RoomKeyWithheldEventContent>().unwrap())
+            .collect();
+
+        assert!(withheld.iter().any(|c| c.code == WithheldCode::Unverified));
+    }
+
+    #[tokio::test]
+    async fn identity_based_sharing_strategies() {
+        // Our own identity signed the `WSKKLTJZCL` device, but not the
+        // `LVWOVGOXME` device.
+        let user_id = user_id!("@example:localhost");
+        let signed = DeviceIdOrAllDevices::DeviceId(device_id!("WSKKLTJZCL").to_owned());
+        let unsigned = DeviceIdOrAllDevices::DeviceId(device_id!("LVWOVGOXME").to_owned());
+
+        let machine = OlmMachine::new(user_id, device_id!("NEWDEVICE"));
+        machine.mark_request_as_sent(&Uuid::new_v4(), &own_key_query()).await.unwrap();
+
+        let share = |room_id: &'static RoomId, sharing_strategy: ShareStrategy| {
+            let machine = machine.clone();
+
+            async move {
+                let settings = EncryptionSettings { sharing_strategy, ..Default::default() };
+                let requests = machine
+                    .share_group_session(room_id, [user_id].into_iter(), settings)
+                    .await
+                    .unwrap();
+
+                withheld_codes(&requests)
+            }
+        };
+
+        let unverified = Some(&WithheldCode::Unverified);
+
+        // Nothing is trusted yet, the identity isn't verified nor pinned.
+        for (room_id, strategy) in [
+            (room_id!("!cross_signing:localhost"), ShareStrategy::CrossSigningTrustedDevices),
+            (room_id!("!verified:localhost"), ShareStrategy::VerifiedUsers),
+            (room_id!("!pinned:localhost"), ShareStrategy::PinnedIdentities),
+        ] {
+            let codes = share(room_id, strategy).await;
+            assert_eq!(codes.get(&signed), unverified);
+            assert_eq!(codes.get(&unsigned), unverified);
+        }
+
+        // A pinned identity is enough for the pinned strategy, but only for
+        // the devices it signed.
+        let identity = machine.get_identity(user_id).await.unwrap().unwrap().own().unwrap();
+        identity.pin_current_master_key().await.unwrap();
+
+        let codes = share(room_id!("!pinned_2:localhost"), ShareStrategy::PinnedIdentities).await;
+        assert_ne!(codes.get(&signed), unverified);
+        assert_eq!(codes.get(&unsigned), unverified);
+
+        let codes = share(room_id!("!verified_2:localhost"), ShareStrategy::VerifiedUsers).await;
+        assert_eq!(codes.get(&signed), unverified);
+
+        // Locally verified devices are included in the pinned strategy, but
+        // not in the cross signing one.
+        let device = machine.get_device(user_id, device_id!("LVWOVGOXME")).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+
+        let codes = share(room_id!("!pinned_3:localhost"), ShareStrategy::PinnedIdentities).await;
+        assert_ne!(codes.get(&unsigned), unverified);
+
+        let codes = share(
+            room_id!("!cross_signing_2:localhost"),
+            ShareStrategy::CrossSigningTrustedDevices,
+        )
+        .await;
+        assert_eq!(codes.get(&unsigned), unverified);
+
+        // Once our identity is verified, the devices it signed are trusted by
+        // all the identity based strategies.
+        identity.mark_as_verified();
+        identity
+            .verification_machine
+            .store
+            .save_changes(Changes {
+                identities: IdentityChanges {
+                    changed: vec![identity.inner.clone().into()],
+                    new: vec![],
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        for (room_id, strategy) in [
+            (room_id!("!cross_signing_3:localhost"), ShareStrategy::CrossSigningTrustedDevices),
+            (room_id!("!verified_3:localhost"), ShareStrategy::VerifiedUsers),
+        ] {
+            let codes = share(room_id, strategy).await;
+            assert_ne!(codes.get(&signed), unverified);
+            assert_eq!(codes.get(&unsigned), unverified);
+        }
     }
 }
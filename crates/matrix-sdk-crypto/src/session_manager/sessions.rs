@@ -33,9 +33,9 @@ use tracing::{debug, error, info, warn};
 use crate::{
     error::OlmResult,
     gossiping::GossipMachine,
-    olm::Account,
+    olm::{Account, Session},
     requests::{OutgoingRequest, ToDeviceRequest},
-    store::{Changes, Result as StoreResult, Store},
+    store::{Changes, Result as StoreResult, Store, UnwedgingInfo},
     ReadOnlyDevice,
 };
 
@@ -78,41 +78,54 @@ impl SessionManager {
         self.outgoing_to_device_requests.remove(id);
     }
 
+    /// Get the to-device requests that need to be sent out to finish the
+    /// unwedging of Olm sessions.
+    pub fn outgoing_to_device_requests(&self) -> Vec<OutgoingRequest> {
+        self.outgoing_to_device_requests.iter().map(|r| r.value().clone()).collect()
+    }
+
+    /// Mark the device that owns the given curve25519 key as wedged.
+    ///
+    /// A new Olm session will be created with the device the next time
+    /// [`get_missing_sessions`](#method.get_missing_sessions) is called, unless
+    /// the device was already unwedged in the last `UNWEDGING_INTERVAL`. The
+    /// time of the unwedging is stored together with the new Olm session.
     pub async fn mark_device_as_wedged(&self, sender: &UserId, curve_key: &str) -> StoreResult<()> {
         if let Some(device) = self.store.get_device_from_curve_key(sender, curve_key).await? {
-            let sessions = device.get_sessions().await?;
-
-            if let Some(sessions) = sessions {
-                let mut sessions = sessions.lock().await;
-                sessions.sort_by_key(|s| s.creation_time.clone());
-
-                let session = sessions.get(0);
-
-                if let Some(session) = session {
-                    info!(
+            if let Some(info) =
+                self.store.get_unwedging_info(device.user_id(), device.device_id()).await?
+            {
+                if info.elapsed() < Self::UNWEDGING_INTERVAL {
+                    debug!(
                         sender = sender.as_str(),
                         sender_key = curve_key,
-                        "Marking session to be unwedged"
+                        last_unwedging =? info.timestamp,
+                        "The session was recently unwedged, not unwedging it again"
                     );
 
-                    if session.creation_time.elapsed() > Self::UNWEDGING_INTERVAL {
-                        self.users_for_key_claim
-                            .entry(device.user_id().to_owned())
-                            .or_insert_with(DashSet::new)
-                            .insert(device.device_id().into());
-                        self.wedged_devices
-                            .entry(device.user_id().to_owned())
-                            .or_insert_with(DashSet::new)
-                            .insert(device.device_id().into());
-                    }
+                    return Ok(());
                 }
             }
+
+            info!(
+                sender = sender.as_str(),
+                sender_key = curve_key,
+                "Marking session to be unwedged"
+            );
+
+            self.users_for_key_claim
+                .entry(device.user_id().to_owned())
+                .or_insert_with(DashSet::new)
+                .insert(device.device_id().into());
+            self.wedged_devices
+                .entry(device.user_id().to_owned())
+                .or_insert_with(DashSet::new)
+                .insert(device.device_id().into());
         }
 
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn is_device_wedged(&self, device: &ReadOnlyDevice) -> bool {
         self.wedged_devices
             .get(device.user_id())
@@ -122,26 +135,39 @@ impl SessionManager {
 
     /// Check if the session was created to unwedge a Device.
     ///
-    /// If the device was wedged this will queue up a dummy to-device message.
-    async fn check_if_unwedged(&self, user_id: &UserId, device_id: &DeviceId) -> OlmResult<()> {
-        if self.wedged_devices.get(user_id).and_then(|d| d.remove(device_id)).is_some() {
-            if let Some(device) = self.store.get_device(user_id, device_id).await? {
-                let content = AnyToDeviceEventContent::Dummy(ToDeviceDummyEventContent::new());
-                let (_, content) = device.encrypt(content).await?;
-
-                let request = ToDeviceRequest::new(
-                    device.user_id(),
-                    device.device_id().to_owned(),
-                    AnyToDeviceEventContent::RoomEncrypted(content),
-                );
-
-                let request = OutgoingRequest {
-                    request_id: request.txn_id,
-                    request: Arc::new(request.into()),
-                };
-
-                self.outgoing_to_device_requests.insert(request.request_id, request);
-            }
+    /// If the device was wedged this will queue up a dummy to-device message,
+    /// encrypted using the new session, to let the device know about the new
+    /// session.
+    async fn check_if_unwedged(
+        &self,
+        device: &ReadOnlyDevice,
+        session: &mut Session,
+    ) -> OlmResult<()> {
+        if self
+            .wedged_devices
+            .get(device.user_id())
+            .and_then(|d| d.remove(device.device_id()))
+            .is_some()
+        {
+            let content = AnyToDeviceEventContent::Dummy(ToDeviceDummyEventContent::new());
+            let content = session.encrypt(device, content).await?;
+
+            let request = ToDeviceRequest::new(
+                device.user_id(),
+                device.device_id().to_owned(),
+                AnyToDeviceEventContent::RoomEncrypted(content),
+            );
+
+            let request =
+                OutgoingRequest { request_id: request.txn_id, request: Arc::new(request.into()) };
+
+            info!(
+                user_id = device.user_id().as_str(),
+                device_id = device.device_id().as_str(),
+                "Queued up a m.dummy event to finish the unwedging of an Olm session"
+            );
+
+            self.outgoing_to_device_requests.insert(request.request_id, request);
         }
 
         Ok(())
@@ -285,25 +311,32 @@ impl SessionManager {
                     }
                 };
 
-                let session = match self.account.create_outbound_session(device, key_map).await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        warn!(
-                            user_id = user_id.as_str(),
-                            device_id = device_id.as_str(),
-                            error =? e,
-                            "Error creating outbound session"
-                        );
-                        continue;
-                    }
-                };
+                let mut session =
+                    match self.account.create_outbound_session(device.clone(), key_map).await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!(
+                                user_id = user_id.as_str(),
+                                device_id = device_id.as_str(),
+                                error =? e,
+                                "Error creating outbound session"
+                            );
+                            continue;
+                        }
+                    };
 
                 self.key_request_machine.retry_keyshare(user_id, device_id);
 
-                if let Err(e) = self.check_if_unwedged(user_id, device_id).await {
+                if self.is_device_wedged(&device) {
+                    changes.unwedging_info.push(UnwedgingInfo::new(user_id, device_id));
+                }
+
+                if let Err(e) = self.check_if_unwedged(&device, &mut session).await {
                     error!(
-                        "Error while treating an unwedged device {} {} {:?}",
-                        user_id, device_id, e
+                        user_id = user_id.as_str(),
+                        device_id = device_id.as_str(),
+                        error =? e,
+                        "Error while treating an unwedged device"
                     );
                 }
 
@@ -339,8 +372,9 @@ mod test {
     use matrix_sdk_common::locks::Mutex;
     use matrix_sdk_test::async_test;
     use ruma::{
-        api::client::r0::keys::claim_keys::Response as KeyClaimResponse, device_id, user_id,
-        DeviceId, UserId,
+        api::client::r0::keys::claim_keys::Response as KeyClaimResponse, device_id,
+        events::EventType, to_device::DeviceIdOrAllDevices, user_id, DeviceId, DeviceKeyAlgorithm,
+        UserId,
     };
 
     use super::SessionManager;
@@ -428,20 +462,13 @@ mod test {
         assert!(manager.get_missing_sessions(iter::once(bob.user_id())).await.unwrap().is_none());
     }
 
-    // This test doesn't run on macos because we're modifying the session
-    // creation time so we can get around the UNWEDGING_INTERVAL.
     #[async_test]
-    #[cfg(target_os = "linux")]
     async fn session_unwedging() {
-        use matrix_sdk_common::instant::{Duration, Instant};
-        use ruma::DeviceKeyAlgorithm;
-
         let manager = session_manager().await;
         let bob = bob_account();
-        let (_, mut session) = bob.create_session_for(&manager.account).await;
+        let (_, session) = bob.create_session_for(&manager.account).await;
 
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
-        session.creation_time = Arc::new(Instant::now() - Duration::from_secs(3601));
 
         manager.store.save_devices(&[bob_device.clone()]).await.unwrap();
         manager.store.save_sessions(&[session]).await.unwrap();
@@ -456,6 +483,14 @@ mod test {
         assert!(manager.is_device_wedged(&bob_device));
         assert!(manager.users_for_key_claim.contains_key(bob.user_id()));
 
+        // The unwedging is only recorded once the new Olm session exists.
+        assert!(manager
+            .store
+            .get_unwedging_info(bob.user_id(), bob.device_id())
+            .await
+            .unwrap()
+            .is_none());
+
         let (_, request) =
             manager.get_missing_sessions(iter::once(bob.user_id())).await.unwrap().unwrap();
 
@@ -477,8 +512,27 @@ mod test {
 
         manager.receive_keys_claim_response(&response).await.unwrap();
 
+        assert!(!manager.is_device_wedged(&bob_device));
+        assert!(manager
+            .store
+            .get_unwedging_info(bob.user_id(), bob.device_id())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(manager.get_missing_sessions(iter::once(bob.user_id())).await.unwrap().is_none());
+
+        let requests = manager.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+
+        let request = requests[0].request.to_device().unwrap();
+        assert_eq!(request.event_type, EventType::RoomEncrypted);
+        assert!(request.messages[bob.user_id()]
+            .contains_key(&DeviceIdOrAllDevices::DeviceId(bob.device_id().to_owned())));
+
+        // The device was just unwedged, it shouldn't be unwedged again before
+        // the unwedging interval passed.
+        manager.mark_device_as_wedged(bob_device.user_id(), curve_key).await.unwrap();
         assert!(!manager.is_device_wedged(&bob_device));
         assert!(manager.get_missing_sessions(iter::once(bob.user_id())).await.unwrap().is_none());
-        assert!(!manager.outgoing_to_device_requests.is_empty())
     }
 }
@@ -69,6 +69,29 @@ impl SessionStore {
     pub fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
         self.entries.insert(sender_key.to_owned(), Arc::new(Mutex::new(sessions)));
     }
+
+    /// Remove a session from the store.
+    ///
+    /// Returns true if the session was removed, false if the session wasn't
+    /// in the store.
+    pub async fn remove(&self, session: &Session) -> bool {
+        let sessions_lock = if let Some(s) = self.get(&session.sender_key) {
+            s
+        } else {
+            return false;
+        };
+
+        let mut sessions = sessions_lock.lock().await;
+        let count = sessions.len();
+        sessions.retain(|s| s != session);
+
+        count != sessions.len()
+    }
+
+    /// Get the sender keys of all the sessions in the store.
+    pub fn sender_keys(&self) -> Vec<String> {
+        self.entries.iter().map(|e| e.key().to_owned()).collect()
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -134,6 +157,37 @@ impl GroupSessionStore {
             .get(room_id)
             .and_then(|m| m.get(sender_key).and_then(|m| m.get(session_id).cloned()))
     }
+
+    /// Remove an inbound group session from the store.
+    ///
+    /// Returns the session if it was removed, None if it wasn't in the store.
+    ///
+    /// # Arguments
+    /// * `room_id` - The room id of the room that the session belongs to.
+    ///
+    /// * `sender_key` - The sender key that sent us the session.
+    ///
+    /// * `session_id` - The unique id of the session.
+    pub fn remove(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Option<InboundGroupSession> {
+        self.entries
+            .get_mut(room_id)
+            .and_then(|mut m| m.get_mut(sender_key).and_then(|m| m.remove(session_id)))
+    }
+
+    /// Remove all the inbound group sessions that belong to the given room.
+    ///
+    /// Returns the number of sessions that were removed.
+    pub fn remove_room(&self, room_id: &RoomId) -> usize {
+        self.entries
+            .remove(room_id)
+            .map(|(_, sessions)| sessions.values().map(|s| s.len()).sum())
+            .unwrap_or(0)
+    }
 }
 
 /// In-memory store holding the devices of users.
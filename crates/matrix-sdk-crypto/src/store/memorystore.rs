@@ -19,17 +19,19 @@ use std::{
 
 use dashmap::{DashMap, DashSet};
 use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid};
-use ruma::{DeviceId, RoomId, UserId};
+use ruma::{DeviceId, EventId, RoomId, UserId};
 
 use super::{
     caches::{DeviceStore, GroupSessionStore, SessionStore},
     BackupKeys, Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, RoomKeyCounts,
-    Session,
+    Session, UnwedgingInfo,
 };
 use crate::{
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
     olm::{OutboundGroupSession, PrivateCrossSigningIdentity},
+    verification::PickledVerificationRequest,
+    withheld::ToDeviceRoomKeyWithheldEvent,
 };
 
 fn encode_key_info(info: &SecretInfo) -> String {
@@ -49,10 +51,14 @@ pub struct MemoryStore {
     tracked_users: Arc<DashSet<Box<UserId>>>,
     users_for_key_query: Arc<DashSet<Box<UserId>>>,
     olm_hashes: Arc<DashMap<String, DashSet<String>>>,
+    megolm_message_indices: Arc<DashMap<(Box<RoomId>, String, String, u32), Box<EventId>>>,
+    withheld_session_info: Arc<DashMap<(Box<RoomId>, String), ToDeviceRoomKeyWithheldEvent>>,
+    unwedging_info: Arc<DashMap<(Box<UserId>, Box<DeviceId>), UnwedgingInfo>>,
     devices: DeviceStore,
     identities: Arc<DashMap<Box<UserId>, ReadOnlyUserIdentities>>,
     outgoing_key_requests: Arc<DashMap<Uuid, GossipRequest>>,
     key_requests_by_info: Arc<DashMap<String, Uuid>>,
+    verification_requests: Arc<DashMap<String, PickledVerificationRequest>>,
 }
 
 impl Default for MemoryStore {
@@ -63,10 +69,14 @@ impl Default for MemoryStore {
             tracked_users: Default::default(),
             users_for_key_query: Default::default(),
             olm_hashes: Default::default(),
+            megolm_message_indices: Default::default(),
+            withheld_session_info: Default::default(),
+            unwedging_info: Default::default(),
             devices: DeviceStore::new(),
             identities: Default::default(),
             outgoing_key_requests: Default::default(),
             key_requests_by_info: Default::default(),
+            verification_requests: Default::default(),
         }
     }
 }
@@ -121,6 +131,18 @@ impl CryptoStore for MemoryStore {
         self.save_sessions(changes.sessions).await;
         self.save_inbound_group_sessions(changes.inbound_group_sessions).await;
 
+        for session in changes.deleted_sessions {
+            self.sessions.remove(&session).await;
+        }
+
+        for session in changes.deleted_inbound_group_sessions {
+            self.inbound_group_sessions.remove(
+                &session.room_id,
+                &session.sender_key,
+                session.session_id(),
+            );
+        }
+
         self.save_devices(changes.devices.new).await;
         self.save_devices(changes.devices.changed).await;
         self.delete_devices(changes.devices.deleted).await;
@@ -136,6 +158,25 @@ impl CryptoStore for MemoryStore {
                 .insert(hash.hash.clone());
         }
 
+        for index in changes.megolm_message_indices {
+            self.megolm_message_indices.insert(
+                (index.room_id, index.sender_key, index.session_id, index.message_index),
+                index.event_id,
+            );
+        }
+
+        for info in changes.withheld_session_info {
+            if let (Some(room_id), Some(session_id)) =
+                (info.content.room_id.clone(), info.content.session_id.clone())
+            {
+                self.withheld_session_info.insert((room_id, session_id), info);
+            }
+        }
+
+        for info in changes.unwedging_info {
+            self.unwedging_info.insert((info.user_id.clone(), info.device_id.clone()), info);
+        }
+
         for key_request in changes.key_requests {
             let id = key_request.request_id;
             let info_string = encode_key_info(&key_request.info);
@@ -144,6 +185,14 @@ impl CryptoStore for MemoryStore {
             self.key_requests_by_info.insert(info_string, id);
         }
 
+        for request in changes.verification_requests {
+            self.verification_requests.insert(request.flow_id().to_owned(), request);
+        }
+
+        for flow_id in changes.deleted_verification_requests {
+            self.verification_requests.remove(&flow_id);
+        }
+
         Ok(())
     }
 
@@ -151,6 +200,10 @@ impl CryptoStore for MemoryStore {
         Ok(self.sessions.get(sender_key))
     }
 
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        Ok(self.sessions.sender_keys())
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -164,6 +217,25 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        fn key(s: &InboundGroupSession) -> (&RoomId, &str, &str) {
+            (s.room_id(), s.sender_key(), s.session_id())
+        }
+
+        let mut sessions = self.inbound_group_sessions.get_all();
+        sessions.sort_by(|a, b| key(a).cmp(&key(b)));
+
+        Ok(sessions
+            .into_iter()
+            .filter(|s| after.map_or(true, |after| key(s) > key(after)))
+            .take(limit)
+            .collect())
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         let backed_up =
             self.get_inbound_group_sessions().await?.into_iter().filter(|s| s.backed_up()).count();
@@ -192,6 +264,10 @@ impl CryptoStore for MemoryStore {
         Ok(())
     }
 
+    async fn delete_inbound_group_sessions_for_room(&self, room_id: &RoomId) -> Result<usize> {
+        Ok(self.inbound_group_sessions.remove_room(room_id))
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         _: &RoomId,
@@ -264,6 +340,38 @@ impl CryptoStore for MemoryStore {
             .contains(&message_hash.hash))
     }
 
+    async fn get_event_id_for_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<Box<EventId>>> {
+        let key = (room_id.to_owned(), sender_key.to_owned(), session_id.to_owned(), message_index);
+
+        Ok(self.megolm_message_indices.get(&key).map(|e| e.clone()))
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<ToDeviceRoomKeyWithheldEvent>> {
+        let key = (room_id.to_owned(), session_id.to_owned());
+
+        Ok(self.withheld_session_info.get(&key).map(|e| e.clone()))
+    }
+
+    async fn get_unwedging_info(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<UnwedgingInfo>> {
+        let key = (user_id.to_owned(), device_id.to_owned());
+
+        Ok(self.unwedging_info.get(&key).map(|i| i.clone()))
+    }
+
     async fn get_outgoing_secret_requests(
         &self,
         request_id: Uuid,
@@ -292,6 +400,15 @@ impl CryptoStore for MemoryStore {
             .collect())
     }
 
+    async fn get_sent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        Ok(self
+            .outgoing_key_requests
+            .iter()
+            .filter(|i| i.value().sent_out)
+            .map(|i| i.value().clone())
+            .collect())
+    }
+
     async fn delete_outgoing_secret_requests(&self, request_id: Uuid) -> Result<()> {
         self.outgoing_key_requests.remove(&request_id).and_then(|(_, i)| {
             let key_info_string = encode_key_info(&i.info);
@@ -304,16 +421,25 @@ impl CryptoStore for MemoryStore {
     async fn load_backup_keys(&self) -> Result<BackupKeys> {
         Ok(BackupKeys::default())
     }
+
+    async fn get_verification_requests(&self) -> Result<Vec<PickledVerificationRequest>> {
+        Ok(self.verification_requests.iter().map(|r| r.value().clone()).collect())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use ruma::room_id;
+    use std::time::Duration;
+
+    use ruma::{device_id, event_id, room_id, user_id};
 
     use crate::{
         identities::device::test::get_device,
-        olm::{test::get_account_and_session, InboundGroupSession, OlmMessageHash},
-        store::{memorystore::MemoryStore, Changes, CryptoStore},
+        olm::{
+            test::get_account_and_session, InboundGroupSession, MegolmMessageIndex, OlmMessageHash,
+        },
+        store::{memorystore::MemoryStore, Changes, CryptoStore, UnwedgingInfo},
+        withheld::{RoomKeyWithheldEventContent, ToDeviceRoomKeyWithheldEvent, WithheldCode},
     };
 
     #[tokio::test]
@@ -410,4 +536,87 @@ mod test {
         store.save_changes(changes).await.unwrap();
         assert!(store.is_message_known(&hash).await.unwrap());
     }
+
+    #[tokio::test]
+    async fn test_megolm_message_index() {
+        let store = MemoryStore::new();
+        let room_id = room_id!("!test:localhost");
+
+        let index = MegolmMessageIndex {
+            room_id: room_id.to_owned(),
+            sender_key: "test_sender".to_owned(),
+            session_id: "test_session".to_owned(),
+            message_index: 2,
+            event_id: event_id!("$event:localhost").to_owned(),
+        };
+
+        let mut changes = Changes::default();
+        changes.megolm_message_indices.push(index.clone());
+
+        assert!(store
+            .get_event_id_for_message_index(room_id, "test_sender", "test_session", 2)
+            .await
+            .unwrap()
+            .is_none());
+
+        store.save_changes(changes).await.unwrap();
+
+        assert_eq!(
+            store
+                .get_event_id_for_message_index(room_id, "test_sender", "test_session", 2)
+                .await
+                .unwrap(),
+            Some(index.event_id)
+        );
+        assert!(store
+            .get_event_id_for_message_index(room_id, "test_sender", "test_session", 3)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_withheld_info() {
+        let store = MemoryStore::new();
+        let room_id = room_id!("!test:localhost");
+
+        let info = ToDeviceRoomKeyWithheldEvent {
+            sender: user_id!("@alice:localhost").to_owned(),
+            content: RoomKeyWithheldEventContent::new(
+                WithheldCode::Unverified,
+                room_id,
+                "test_session",
+                "test_sender",
+            ),
+        };
+
+        let mut changes = Changes::default();
+        changes.withheld_session_info.push(info);
+
+        assert!(store.get_withheld_info(room_id, "test_session").await.unwrap().is_none());
+
+        store.save_changes(changes).await.unwrap();
+
+        let info = store.get_withheld_info(room_id, "test_session").await.unwrap().unwrap();
+        assert_eq!(info.content.code, WithheldCode::Unverified);
+    }
+
+    #[tokio::test]
+    async fn test_unwedging_info() {
+        let store = MemoryStore::new();
+        let user_id = user_id!("@alice:localhost");
+        let device_id = device_id!("ALICEDEVICE");
+
+        assert!(store.get_unwedging_info(user_id, device_id).await.unwrap().is_none());
+
+        let changes = Changes {
+            unwedging_info: vec![UnwedgingInfo::new(user_id, device_id)],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let info = store.get_unwedging_info(user_id, device_id).await.unwrap().unwrap();
+        assert_eq!(&*info.device_id, device_id);
+        assert!(info.elapsed() < Duration::from_secs(60));
+    }
 }
@@ -44,22 +44,24 @@ mod pickle_key;
 pub(crate) mod sled;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
     io::Error as IoError,
+    mem,
     ops::Deref,
     sync::Arc,
+    time::Duration,
 };
 
 use base64::DecodeError;
 use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid, AsyncTraitDeps};
 pub use memorystore::MemoryStore;
-use olm_rs::errors::{OlmAccountError, OlmGroupSessionError, OlmSessionError};
 pub use pickle_key::{EncryptedPickleKey, PickleKey};
 use ruma::{
     events::secret::request::SecretName, identifiers::Error as IdentifierValidationError, DeviceId,
-    DeviceKeyAlgorithm, RoomId, UserId,
+    DeviceKeyAlgorithm, EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 use tracing::{info, warn};
@@ -71,14 +73,16 @@ use crate::{
     error::SessionUnpicklingError,
     identities::{
         user::{OwnUserIdentity, UserIdentities, UserIdentity},
-        Device, ReadOnlyDevice, ReadOnlyUserIdentities, UserDevices,
+        Device, ReadOnlyDevice, ReadOnlyUserIdentities, UserDevices, UserTrustReport,
     },
     olm::{
-        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        ExportedRoomKey, InboundGroupSession, MegolmMessageIndex, OlmAccountError,
+        OlmGroupSessionError, OlmMessageHash, OlmSessionError, OutboundGroupSession,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
     },
-    verification::VerificationMachine,
-    CrossSigningStatus,
+    verification::{PickledVerificationRequest, VerificationMachine},
+    withheld::ToDeviceRoomKeyWithheldEvent,
+    CrossSigningStatus, RoomKeyImportResult,
 };
 
 /// A `CryptoStore` specific result type.
@@ -86,6 +90,10 @@ pub type Result<T, E = CryptoStoreError> = std::result::Result<T, E>;
 
 pub use crate::gossiping::{GossipRequest, SecretInfo};
 
+/// The number of room keys that get saved at once when streaming room keys
+/// into the store.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
 /// A wrapper for our CryptoStore trait object.
 ///
 /// This is needed because we want to have a generic interface so we can
@@ -110,10 +118,18 @@ pub struct Changes {
     #[cfg(feature = "backups_v1")]
     pub recovery_key: Option<crate::backups::RecoveryKey>,
     pub sessions: Vec<Session>,
+    pub deleted_sessions: Vec<Session>,
     pub message_hashes: Vec<OlmMessageHash>,
+    pub megolm_message_indices: Vec<MegolmMessageIndex>,
+    pub withheld_session_info: Vec<ToDeviceRoomKeyWithheldEvent>,
+    pub unwedging_info: Vec<UnwedgingInfo>,
     pub inbound_group_sessions: Vec<InboundGroupSession>,
+    pub deleted_inbound_group_sessions: Vec<InboundGroupSession>,
     pub outbound_group_sessions: Vec<OutboundGroupSession>,
     pub key_requests: Vec<GossipRequest>,
+    pub verification_requests: Vec<PickledVerificationRequest>,
+    /// The flow ids of the verification requests that should be removed.
+    pub deleted_verification_requests: Vec<String>,
     pub identities: IdentityChanges,
     pub devices: DeviceChanges,
 }
@@ -124,10 +140,17 @@ impl Changes {
         self.account.is_none()
             && self.private_identity.is_none()
             && self.sessions.is_empty()
+            && self.deleted_sessions.is_empty()
             && self.message_hashes.is_empty()
+            && self.megolm_message_indices.is_empty()
+            && self.withheld_session_info.is_empty()
+            && self.unwedging_info.is_empty()
             && self.inbound_group_sessions.is_empty()
+            && self.deleted_inbound_group_sessions.is_empty()
             && self.outbound_group_sessions.is_empty()
             && self.key_requests.is_empty()
+            && self.verification_requests.is_empty()
+            && self.deleted_verification_requests.is_empty()
             && self.identities.is_empty()
             && self.devices.is_empty()
     }
@@ -167,6 +190,38 @@ impl DeviceChanges {
     }
 }
 
+/// Info about the last time we tried to unwedge the Olm sessions we share with
+/// a device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnwedgingInfo {
+    /// The user the device belongs to.
+    pub user_id: Box<UserId>,
+    /// The id of the device.
+    pub device_id: Box<DeviceId>,
+    /// The point in time when we started to unwedge the Olm sessions.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+}
+
+impl UnwedgingInfo {
+    /// Create a new `UnwedgingInfo` for the given device, marking the current
+    /// point in time as the time the device got unwedged.
+    pub fn new(user_id: &UserId, device_id: &DeviceId) -> Self {
+        Self {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            timestamp: MilliSecondsSinceUnixEpoch::now(),
+        }
+    }
+
+    /// The time that passed since the device got unwedged.
+    pub fn elapsed(&self) -> Duration {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let then: u64 = self.timestamp.get().into();
+
+        Duration::from_millis(now.saturating_sub(then))
+    }
+}
+
 /// Struct holding info about how many room keys the store has.
 #[derive(Debug, Clone, Default)]
 pub struct RoomKeyCounts {
@@ -260,6 +315,12 @@ impl Store {
         self.identity.clone()
     }
 
+    /// Save the given changes, this shadows [`CryptoStore::save_changes()`] so
+    /// the cached trust reports get invalidated by the changes.
+    pub async fn save_changes(&self, changes: Changes) -> Result<()> {
+        self.verification_machine.store.save_changes(changes).await
+    }
+
     pub async fn save_sessions(&self, sessions: &[Session]) -> Result<()> {
         let changes = Changes { sessions: sessions.to_vec(), ..Default::default() };
 
@@ -286,6 +347,236 @@ impl Store {
         self.save_changes(changes).await
     }
 
+    /// Import the given room keys into the store.
+    ///
+    /// Room keys for which we already have a better version, that is a
+    /// version with a lower first known index, are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - The room keys that should be imported.
+    ///
+    /// * `from_backup` - Were the room keys imported from the backup, if true
+    /// will mark the room keys as already backed up.
+    ///
+    /// * `progress_listener` - A closure that will be called with the index of
+    /// the room key that is being imported and the total number of room keys.
+    pub async fn import_room_keys(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        from_backup: bool,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let sessions = exported_keys
+            .into_iter()
+            .map(InboundGroupSession::from_export)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.import_inbound_group_sessions(sessions, from_backup, progress_listener).await
+    }
+
+    /// Add the given inbound group sessions to the store.
+    ///
+    /// Unlike [`Store::import_room_keys()`] the sessions keep their origin,
+    /// i.e. sessions we received directly over an `m.room_key` event don't
+    /// get marked as imported.
+    ///
+    /// Sessions for which we already have a better version, that is a version
+    /// with a lower first known index, are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `sessions` - The sessions that should be added to the store.
+    ///
+    /// * `from_backup` - Were the sessions imported from the backup, if true
+    /// will mark the sessions as already backed up.
+    ///
+    /// * `progress_listener` - A closure that will be called with the index of
+    /// the session that is being imported and the total number of sessions.
+    pub(crate) async fn import_inbound_group_sessions(
+        &self,
+        new_sessions: Vec<InboundGroupSession>,
+        #[allow(unused_variables)] from_backup: bool,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        type SessionIdToIndexMap = BTreeMap<Arc<str>, u32>;
+
+        #[derive(Debug)]
+        struct ShallowSessions {
+            inner: BTreeMap<Arc<RoomId>, BTreeMap<Arc<str>, SessionIdToIndexMap>>,
+        }
+
+        impl ShallowSessions {
+            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
+                self.inner
+                    .get(&session.room_id)
+                    .and_then(|m| {
+                        m.get(&session.sender_key).and_then(|m| {
+                            m.get(&session.session_id)
+                                .map(|existing| existing <= &session.first_known_index())
+                        })
+                    })
+                    .unwrap_or(false)
+            }
+        }
+
+        let mut sessions = Vec::new();
+
+        let existing_sessions = ShallowSessions {
+            inner: self.get_inbound_group_sessions().await?.into_iter().fold(
+                BTreeMap::new(),
+                |mut acc, s| {
+                    let index = s.first_known_index();
+
+                    acc.entry(s.room_id)
+                        .or_default()
+                        .entry(s.sender_key)
+                        .or_default()
+                        .insert(s.session_id, index);
+
+                    acc
+                },
+            ),
+        };
+
+        let total_count = new_sessions.len();
+        let mut keys = BTreeMap::new();
+
+        for (i, session) in new_sessions.into_iter().enumerate() {
+            // Only import the session if we didn't have this session or if it's
+            // a better version of the same session, that is the first known
+            // index is lower.
+            if !existing_sessions.has_better_session(&session) {
+                #[cfg(feature = "backups_v1")]
+                if from_backup {
+                    session.mark_as_backed_up()
+                }
+
+                keys.entry(session.room_id().to_owned())
+                    .or_insert_with(BTreeMap::new)
+                    .entry(session.sender_key().to_owned())
+                    .or_insert_with(BTreeSet::new)
+                    .insert(session.session_id().to_owned());
+
+                sessions.push(session)
+            }
+
+            progress_listener(i, total_count)
+        }
+
+        let imported_count = sessions.len();
+
+        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+
+        self.save_changes(changes).await?;
+
+        info!(total_count, imported_count, room_keys =? keys, "Successfully imported room keys");
+
+        Ok(RoomKeyImportResult::new(imported_count, total_count, keys))
+    }
+
+    /// Import room keys into the store while streaming them from the given
+    /// iterator.
+    ///
+    /// Unlike [`Store::import_room_keys()`] this doesn't require all the room
+    /// keys to be held in memory at once, room keys are saved to the store in
+    /// batches while they are read.
+    ///
+    /// Room keys for which we already have a better version, that is a
+    /// version with a lower first known index, are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - The room keys that should be imported.
+    ///
+    /// * `from_backup` - Were the room keys imported from the backup, if true
+    /// will mark the room keys as already backed up.
+    ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of room keys that were processed so far.
+    pub async fn import_room_keys_streaming<E: From<CryptoStoreError>>(
+        &self,
+        exported_keys: impl IntoIterator<Item = Result<ExportedRoomKey, E>>,
+        #[allow(unused_variables)] from_backup: bool,
+        progress_listener: impl Fn(usize),
+    ) -> Result<RoomKeyImportResult, E> {
+        type SessionKey = (Arc<RoomId>, Arc<str>, Arc<str>);
+
+        let mut batch: BTreeMap<SessionKey, InboundGroupSession> = BTreeMap::new();
+        let mut keys = BTreeMap::new();
+        let mut total_count = 0;
+        let mut imported_count = 0;
+
+        for key in exported_keys {
+            let session = InboundGroupSession::from_export(key?).map_err(CryptoStoreError::from)?;
+            total_count += 1;
+
+            let session_key =
+                (session.room_id.clone(), session.sender_key.clone(), session.session_id.clone());
+
+            let existing = if let Some(s) = batch.get(&session_key) {
+                Some(s.first_known_index())
+            } else {
+                self.get_inbound_group_session(
+                    &session.room_id,
+                    &session.sender_key,
+                    &session.session_id,
+                )
+                .await?
+                .map(|s| s.first_known_index())
+            };
+
+            // Only import the session if we didn't have this session or if it's
+            // a better version of the same session, that is the first known
+            // index is lower.
+            if existing.map_or(true, |index| index > session.first_known_index()) {
+                #[cfg(feature = "backups_v1")]
+                if from_backup {
+                    session.mark_as_backed_up()
+                }
+
+                batch.insert(session_key, session);
+            }
+
+            progress_listener(total_count);
+
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                imported_count += self.save_imported_room_keys(&mut batch, &mut keys).await?;
+            }
+        }
+
+        imported_count += self.save_imported_room_keys(&mut batch, &mut keys).await?;
+
+        info!(total_count, imported_count, "Successfully imported room keys");
+
+        Ok(RoomKeyImportResult::new(imported_count, total_count, keys))
+    }
+
+    async fn save_imported_room_keys(
+        &self,
+        batch: &mut BTreeMap<(Arc<RoomId>, Arc<str>, Arc<str>), InboundGroupSession>,
+        keys: &mut BTreeMap<Box<RoomId>, BTreeMap<String, BTreeSet<String>>>,
+    ) -> Result<usize> {
+        let sessions: Vec<InboundGroupSession> = mem::take(batch).into_values().collect();
+
+        for session in &sessions {
+            keys.entry(session.room_id().to_owned())
+                .or_insert_with(BTreeMap::new)
+                .entry(session.sender_key().to_owned())
+                .or_insert_with(BTreeSet::new)
+                .insert(session.session_id().to_owned());
+        }
+
+        let count = sessions.len();
+
+        if count > 0 {
+            let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+            self.save_changes(changes).await?;
+        }
+
+        Ok(count)
+    }
+
     /// Get the display name of our own device.
     pub async fn device_display_name(&self) -> Result<Option<String>, CryptoStoreError> {
         Ok(self
@@ -407,6 +698,49 @@ impl Store {
         })
     }
 
+    /// Get a summary of the trust state of the given user and their devices.
+    ///
+    /// The summary is cached until the identity or the devices of the user
+    /// change.
+    pub async fn get_user_trust_report(&self, user_id: &UserId) -> Result<UserTrustReport> {
+        let cache = self.verification_machine.store.trust_reports();
+
+        if let Some(report) = cache.get(user_id) {
+            return Ok(report);
+        }
+
+        // The report might get invalidated while we're computing it, in which
+        // case it shouldn't be cached.
+        let generation = cache.generation(user_id);
+
+        let identity_state = self.get_identity(user_id).await?.map(|i| match i {
+            UserIdentities::Own(i) => i.trust_state(),
+            UserIdentities::Other(i) => i.trust_state(),
+        });
+
+        let mut report = UserTrustReport { identity_state, ..Default::default() };
+
+        let devices = self.get_user_devices(user_id).await?;
+
+        // Our own device is implicitly trusted, it doesn't count.
+        for device in devices
+            .devices()
+            .filter(|d| !(d.user_id() == self.user_id() && d.device_id() == self.device_id()))
+        {
+            if device.is_blacklisted() {
+                report.blacklisted_devices += 1;
+            } else if device.verified() {
+                report.verified_devices += 1;
+            } else {
+                report.unverified_devices += 1;
+            }
+        }
+
+        cache.insert(user_id, generation, report.clone());
+
+        Ok(report)
+    }
+
     /// Try to export the secret with the given secret name.
     ///
     /// The exported secret will be encoded as unpadded base64. Returns `Null`
@@ -558,6 +892,10 @@ pub enum CryptoStoreError {
     #[error("An object failed to be decrypted while unpickling")]
     UnpicklingError,
 
+    /// The given passphrase isn't the passphrase that protects the store.
+    #[error("the given passphrase doesn't match the passphrase of the store")]
+    InvalidPassphrase,
+
     /// A Matrix identifier failed to be validated.
     #[error(transparent)]
     IdentifierValidation(#[from] IdentifierValidationError),
@@ -565,10 +903,53 @@ pub enum CryptoStoreError {
     /// The store failed to (de)serialize a data type.
     #[error(transparent)]
     Serialization(#[from] SerdeError),
+
+    /// The database was created by a newer version of the library and uses a
+    /// schema that this version doesn't know about.
+    #[error(
+        "the database uses schema version {version}, the latest supported version is \
+         {max_supported}"
+    )]
+    UnsupportedDatabaseVersion {
+        /// The schema version of the database.
+        version: u8,
+        /// The latest schema version this version of the library supports.
+        max_supported: u8,
+    },
+
+    /// The database was written by an Olm backend whose pickles can't be
+    /// restored by the backend this library was compiled with.
+    ///
+    /// This is only returned by stores that record the Olm backend, see the
+    /// documentation of the [`CryptoStore`] trait.
+    #[error(
+        "the database was written by the {backend} Olm backend, it can't be opened using the {} \
+         backend",
+        crate::olm::BACKEND
+    )]
+    UnsupportedBackend {
+        /// The Olm backend that wrote the database.
+        backend: String,
+    },
 }
 
 /// Trait abstracting a store that the `OlmMachine` uses to store cryptographic
 /// keys.
+///
+/// # Olm backends
+///
+/// The pickles of the libolm backend can be restored by every Olm backend, but
+/// the pickles of the vodozemac backend can't be restored by the libolm
+/// backend. Protecting against a downgrade from vodozemac to libolm is the job
+/// of the store implementation, this crate doesn't check the pickles before
+/// handing them to the backend.
+///
+/// The `SledStore` records the backend that wrote to it and refuses to be
+/// opened by a backend that can't restore its pickles, returning
+/// [`CryptoStoreError::UnsupportedBackend`]. The `MemoryStore` doesn't persist
+/// anything, so it's unaffected. Other stores get no such protection unless
+/// they implement it themselves, e.g. by storing [`crate::olm::BACKEND`] next
+/// to the pickles.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 pub trait CryptoStore: AsyncTraitDeps {
@@ -599,6 +980,9 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `sender_key` - The sender key that was used to establish the sessions.
     async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>>;
 
+    /// Get the sender keys of all the devices we share an Olm session with.
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>>;
+
     /// Get the inbound group session from our store.
     ///
     /// # Arguments
@@ -617,6 +1001,24 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>>;
 
+    /// Get a batch of inbound group sessions.
+    ///
+    /// The sessions are returned in a stable order, this allows to go through
+    /// all the inbound group sessions without loading all of them at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - The last session of the previous batch, the batch will
+    /// start with the session that comes after it. If `None` the first batch
+    /// will be returned.
+    ///
+    /// * `limit` - The maximal number of sessions the batch should contain.
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>>;
+
     /// Get the number inbound group sessions we have and how many of them are
     /// backed up.
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts>;
@@ -630,6 +1032,11 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Reset the backup state of all the stored inbound group sessions.
     async fn reset_backup_state(&self) -> Result<()>;
 
+    /// Delete all the inbound group sessions that belong to the given room.
+    ///
+    /// Returns the number of sessions that were deleted.
+    async fn delete_inbound_group_sessions_for_room(&self, room_id: &RoomId) -> Result<usize>;
+
     /// Get the backup keys we have stored.
     async fn load_backup_keys(&self) -> Result<BackupKeys>;
 
@@ -697,6 +1104,54 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Check if a hash for an Olm message stored in the database.
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool>;
 
+    /// Get the id of the event that was decrypted at the given message index
+    /// of an inbound group session.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the room that the session belongs to.
+    ///
+    /// * `sender_key` - The sender key that sent us the session.
+    ///
+    /// * `session_id` - The unique id of the session.
+    ///
+    /// * `message_index` - The message index of the decrypted event.
+    async fn get_event_id_for_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<Box<EventId>>>;
+
+    /// Get the `m.room_key.withheld` event we received for the given room
+    /// key, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the room that the room key belongs to.
+    ///
+    /// * `session_id` - The unique id of the room key.
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<ToDeviceRoomKeyWithheldEvent>>;
+
+    /// Get the info about the last time we tried to unwedge the Olm sessions
+    /// we share with the given device, if we ever did.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user id of the user the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    async fn get_unwedging_info(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<UnwedgingInfo>>;
+
     /// Get an outgoing secret request that we created that matches the given
     /// request id.
     ///
@@ -721,6 +1176,10 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all outgoing secret requests that we have in the store.
     async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>>;
 
+    /// Get all outgoing secret requests that were already sent out and are
+    /// still waiting for a response.
+    async fn get_sent_secret_requests(&self) -> Result<Vec<GossipRequest>>;
+
     /// Delete an outgoing key request that we created that matches the given
     /// request id.
     ///
@@ -729,4 +1188,11 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `request_id` - The unique request id that identifies this outgoing key
     /// request.
     async fn delete_outgoing_secret_requests(&self, request_id: Uuid) -> Result<()>;
+
+    /// Get all the verification requests that were still in progress when
+    /// they were last saved.
+    ///
+    /// The requests might contain the secret of a QR code verification, stores
+    /// should encrypt them like the rest of the private data.
+    async fn get_verification_requests(&self) -> Result<Vec<PickledVerificationRequest>>;
 }
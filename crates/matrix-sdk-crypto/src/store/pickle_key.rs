@@ -20,12 +20,13 @@ use aes_gcm::{
 };
 use getrandom::getrandom;
 use hmac::Hmac;
-use olm_rs::PicklingMode;
 use pbkdf2::pbkdf2;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use zeroize::{Zeroize, Zeroizing};
 
+use crate::olm::PicklingMode;
+
 const KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 12;
 const KDF_SALT_SIZE: usize = 32;
@@ -44,13 +45,13 @@ pub enum KdfInfo {
 }
 
 /// Version specific info for encryption method that is used to encrypt our
-/// pickle key.
+/// pickle key, or a value using our pickle key.
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum CipherTextInfo {
     Aes256Gcm {
         /// The nonce that was used to encrypt the ciphertext.
         nonce: Vec<u8>,
-        /// The encrypted pickle key.
+        /// The encrypted pickle key or value.
         ciphertext: Vec<u8>,
     },
 }
@@ -149,6 +150,41 @@ impl PickleKey {
         }
     }
 
+    /// Encrypt the given value using this pickle key.
+    ///
+    /// This is used for private data that isn't already pickled using the
+    /// pickle key, the encrypted value can be safely stored in a database.
+    pub fn encrypt_value(&self, value: &[u8]) -> CipherTextInfo {
+        let key = GenericArray::from_slice(&self.aes256_key);
+        let cipher = Aes256Gcm::new(key);
+
+        let mut nonce = vec![0u8; NONCE_SIZE];
+        getrandom(&mut nonce).expect("Can't generate a random nonce to encrypt a value");
+
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(nonce.as_ref()), value)
+            .expect("Can't encrypt value");
+
+        CipherTextInfo::Aes256Gcm { nonce, ciphertext }
+    }
+
+    /// Decrypt a value that was encrypted using
+    /// [`PickleKey::encrypt_value()`].
+    pub fn decrypt_value(
+        &self,
+        encrypted: CipherTextInfo,
+    ) -> Result<Zeroizing<Vec<u8>>, DecryptionError> {
+        match encrypted {
+            CipherTextInfo::Aes256Gcm { nonce, ciphertext } => {
+                let key = GenericArray::from_slice(&self.aes256_key);
+                let cipher = Aes256Gcm::new(key);
+                let nonce = GenericArray::from_slice(&nonce);
+
+                Ok(Zeroizing::new(cipher.decrypt(nonce, ciphertext.as_ref())?))
+            }
+        }
+    }
+
     /// Restore a pickle key from an encrypted export.
     ///
     /// # Arguments
@@ -198,4 +234,16 @@ mod test {
 
         assert_eq!(pickle_key, decrypted);
     }
+
+    #[test]
+    fn encrypting_values() {
+        let pickle_key = PickleKey::new();
+        let value = b"it's a secret to everybody";
+
+        let encrypted = pickle_key.encrypt_value(value);
+        assert!(PickleKey::new().decrypt_value(encrypted).is_err());
+
+        let encrypted = pickle_key.encrypt_value(value);
+        assert_eq!(pickle_key.decrypt_value(encrypted).unwrap().as_slice(), value);
+    }
 }
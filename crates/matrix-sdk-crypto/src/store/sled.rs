@@ -13,37 +13,48 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     convert::{TryFrom, TryInto},
+    ops::Bound,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
 use dashmap::DashSet;
-use matrix_sdk_common::{async_trait, locks::Mutex, uuid};
-use olm_rs::{account::IdentityKeys, PicklingMode};
+use matrix_sdk_common::{
+    async_trait,
+    locks::{Mutex, RwLock as AsyncRwLock},
+    uuid,
+};
 use ruma::{
     encryption::DeviceKeys,
     events::{room_key_request::RequestedKeyInfo, secret::request::SecretName},
-    DeviceId, DeviceKeyId, EventEncryptionAlgorithm, RoomId, UserId,
+    DeviceId, DeviceKeyId, EventEncryptionAlgorithm, EventId, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
 pub use sled::Error;
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
-    Config, Db, IVec, Transactional, Tree,
+    Batch, Config, Db, IVec, Transactional, Tree,
 };
-use tracing::debug;
+use tracing::{debug, warn};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 use super::{
-    caches::SessionStore, BackupKeys, Changes, CryptoStore, CryptoStoreError, InboundGroupSession,
-    PickleKey, ReadOnlyAccount, Result, RoomKeyCounts, Session,
+    caches::SessionStore, pickle_key::CipherTextInfo, BackupKeys, Changes, CryptoStore,
+    CryptoStoreError, EncryptedPickleKey, InboundGroupSession, PickleKey, ReadOnlyAccount, Result,
+    RoomKeyCounts, Session, UnwedgingInfo,
 };
 use crate::{
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
-    olm::{OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{
+        IdentityKeys, OutboundGroupSession, PickledInboundGroupSession, PicklingMode,
+        PrivateCrossSigningIdentity, BACKEND,
+    },
+    verification::PickledVerificationRequest,
+    withheld::ToDeviceRoomKeyWithheldEvent,
     LocalTrust,
 };
 
@@ -51,6 +62,16 @@ use crate::{
 /// panic once we try to pickle a Signing object.
 const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
 const DATABASE_VERSION: u8 = 3;
+const REKEY_MARKER: &str = "crypto_store_rekey";
+const BACKEND_MARKER: &str = "crypto_backend";
+/// The backend that wrote databases that don't record a backend, pickles of
+/// this backend can be restored by every backend.
+const LIBOLM_BACKEND: &str = "libolm";
+
+/// The migrations of the database schema, the migration at index `n`
+/// upgrades the database from version `n` to version `n + 1`.
+const MIGRATIONS: [fn(&SledStore) -> Result<()>; DATABASE_VERSION as usize] =
+    [SledStore::migrate_to_v1, SledStore::migrate_to_v2, SledStore::migrate_to_v3];
 
 trait EncodeKey {
     const SEPARATOR: u8 = 0xff;
@@ -138,6 +159,33 @@ impl EncodeKey for (&str, &str, &str) {
     }
 }
 
+fn encode_message_index(
+    room_id: &RoomId,
+    sender_key: &str,
+    session_id: &str,
+    message_index: u32,
+) -> Vec<u8> {
+    [(room_id.as_str(), sender_key, session_id).encode(), message_index.to_be_bytes().to_vec()]
+        .concat()
+}
+
+/// Marker that is persisted while the store gets re-keyed, it allows an
+/// interrupted re-keying to be finished the next time the store is opened.
+#[derive(Debug, Deserialize, Serialize)]
+struct RekeyMarker {
+    /// The pickle key the private data is currently encrypted with, encrypted
+    /// using the new passphrase. An empty passphrase is used if the store
+    /// won't be encrypted anymore. `None` if the default pickle key is used.
+    old_key: Option<EncryptedPickleKey>,
+    /// The pickle key the private data will be encrypted with, encrypted using
+    /// the new passphrase. `None` if the store keeps its pickle key or won't
+    /// be encrypted anymore.
+    ///
+    /// Either this or `old_key` is set, so the new passphrase can always be
+    /// checked when an interrupted re-keying is finished.
+    next_key: Option<EncryptedPickleKey>,
+}
+
 #[derive(Clone, Debug)]
 pub struct AccountInfo {
     user_id: Arc<UserId>,
@@ -153,7 +201,14 @@ pub struct SledStore {
     account_info: Arc<RwLock<Option<AccountInfo>>>,
     path: Option<PathBuf>,
     inner: Db,
-    pickle_key: Arc<PickleKey>,
+    pickle_key: Arc<RwLock<PickleKey>>,
+    /// Held for reading while private data is pickled or unpickled and for
+    /// writing while the store is re-encrypted, so nothing gets pickled or
+    /// unpickled using an outdated key.
+    rekey_lock: Arc<AsyncRwLock<()>>,
+    /// The new passphrase of a re-keying that was interrupted, the re-keying
+    /// is finished once the account gets loaded.
+    pending_rekey: Arc<RwLock<Option<Option<Zeroizing<String>>>>>,
 
     session_cache: SessionStore,
     tracked_users_cache: Arc<DashSet<Box<UserId>>>,
@@ -163,6 +218,9 @@ pub struct SledStore {
     private_identity: Tree,
 
     olm_hashes: Tree,
+    megolm_message_indices: Tree,
+    withheld_session_info: Tree,
+    unwedging_info: Tree,
     sessions: Tree,
     inbound_group_sessions: Tree,
     outbound_group_sessions: Tree,
@@ -171,6 +229,8 @@ pub struct SledStore {
     unsent_secret_requests: Tree,
     secret_requests_by_info: Tree,
 
+    verification_requests: Tree,
+
     devices: Tree,
     identities: Tree,
 
@@ -212,11 +272,176 @@ impl SledStore {
         SledStore::open_helper(db, None, passphrase)
     }
 
+    /// Change the passphrase that is used to encrypt the store.
+    ///
+    /// If the store is encrypted and stays encrypted only the pickle key gets
+    /// encrypted using the new passphrase. Encrypting a previously
+    /// unencrypted store, or removing the encryption of an encrypted store,
+    /// re-encrypts all the private data in the store under a new pickle key.
+    ///
+    /// If the process is interrupted the store needs to be opened using the
+    /// new passphrase, which finishes the change.
+    ///
+    /// If the store shares its database with the state store, the passphrase
+    /// of both stores needs to be changed at once using
+    /// `BaseClient::change_store_passphrase()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the store,
+    /// `None` if the store isn't encrypted.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the store from
+    /// now on, `None` if the store shouldn't be encrypted anymore.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+    ) -> Result<()> {
+        if old_passphrase.is_none() && new_passphrase.is_none() {
+            return self.check_passphrase(None);
+        }
+
+        let mut batch = Batch::default();
+        self.begin_rekey(&mut batch, old_passphrase, new_passphrase, false).await?;
+        self.inner.apply_batch(batch)?;
+
+        self.finish_rekey(new_passphrase).await
+    }
+
+    /// Re-encrypt all the private data in the store under a freshly generated
+    /// pickle key.
+    ///
+    /// This should be used if the pickle key might have been compromised,
+    /// unlike [`SledStore::change_passphrase()`] this replaces the pickle key
+    /// itself. If the process is interrupted the re-keying is finished the
+    /// next time the store is opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that protects the store, the new pickle
+    /// key will be encrypted using the same passphrase.
+    pub async fn rekey(&self, passphrase: &str) -> Result<()> {
+        let mut batch = Batch::default();
+        self.begin_rekey(&mut batch, Some(passphrase), Some(passphrase), true).await?;
+        self.inner.apply_batch(batch)?;
+
+        self.finish_rekey(Some(passphrase)).await
+    }
+
+    /// Start to re-key the store.
+    ///
+    /// A marker containing the current pickle key, encrypted using the new
+    /// passphrase, is added to the given batch. After the batch has been
+    /// applied to the database of the store, the re-keying needs to be
+    /// finished using [`SledStore::finish_rekey()`]. If the process gets
+    /// interrupted before that, the re-keying is finished the next time the
+    /// store is opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The batch the marker should be added to.
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the store.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the store from
+    /// now on.
+    ///
+    /// * `new_key` - Should a new pickle key be generated even if the store
+    /// stays encrypted.
+    pub async fn begin_rekey(
+        &self,
+        batch: &mut Batch,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+        new_key: bool,
+    ) -> Result<()> {
+        self.finish_pending_rekey().await?;
+        self.check_passphrase(old_passphrase)?;
+
+        let old_key = old_passphrase
+            .map(|_| self.pickle_key.read().unwrap().encrypt(new_passphrase.unwrap_or_default()));
+
+        let next_key = match new_passphrase {
+            Some(passphrase) if new_key || old_key.is_none() => {
+                Some(PickleKey::new().encrypt(passphrase))
+            }
+            _ => None,
+        };
+
+        batch
+            .insert(REKEY_MARKER.encode(), serde_json::to_vec(&RekeyMarker { old_key, next_key })?);
+
+        Ok(())
+    }
+
+    /// Finish a re-keying that was started using
+    /// [`SledStore::begin_rekey()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should protect the store from now
+    /// on.
+    pub async fn finish_rekey(&self, passphrase: Option<&str>) -> Result<()> {
+        let _lock = self.rekey_lock.write().await;
+
+        let marker: Option<RekeyMarker> = self
+            .inner
+            .get(REKEY_MARKER.encode())?
+            .map(|m| serde_json::from_slice(&m))
+            .transpose()?;
+
+        let marker = if let Some(marker) = marker {
+            marker
+        } else {
+            return Ok(());
+        };
+
+        match (passphrase, marker.next_key) {
+            (Some(passphrase), Some(next_key)) => {
+                let pickle_key = PickleKey::from_encrypted(passphrase, next_key)
+                    .map_err(|_| CryptoStoreError::InvalidPassphrase)?;
+                let encrypted = pickle_key.encrypt(passphrase);
+
+                self.reencrypt(pickle_key, Some(encrypted)).await
+            }
+            (Some(passphrase), None) if marker.old_key.is_some() => {
+                // The pickle key stays the same, it only needs to be encrypted
+                // using the new passphrase.
+                let encrypted = self.pickle_key.read().unwrap().encrypt(passphrase);
+
+                let mut batch = Batch::default();
+                batch.insert("pickle_key".encode(), serde_json::to_vec(&encrypted)?);
+                batch.remove(REKEY_MARKER.encode());
+
+                self.inner.apply_batch(batch)?;
+                self.inner.flush_async().await?;
+
+                Ok(())
+            }
+            (None, None) => self.reencrypt(Self::default_pickle_key(), None).await,
+            _ => Err(CryptoStoreError::InvalidPassphrase),
+        }
+    }
+
+    /// Finish a re-keying that was interrupted before the store was opened.
+    async fn finish_pending_rekey(&self) -> Result<()> {
+        let pending = self.pending_rekey.write().unwrap().take();
+
+        if let Some(passphrase) = pending {
+            self.finish_rekey(passphrase.as_deref().map(|p| p.as_str())).await?;
+        }
+
+        Ok(())
+    }
+
     fn get_account_info(&self) -> Option<AccountInfo> {
         self.account_info.read().unwrap().clone()
     }
 
     async fn reset_backup_state(&self) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         let mut pickles: Vec<(IVec, PickledInboundGroupSession)> = self
             .inbound_group_sessions
             .iter()
@@ -252,94 +477,162 @@ impl SledStore {
         Ok(())
     }
 
-    fn upgrade(&self) -> Result<()> {
-        let version = self
+    /// Get the schema version of the database.
+    ///
+    /// Databases that were created before the schema version was recorded
+    /// are treated as version `0`.
+    fn database_version(&self) -> Result<u8> {
+        Ok(self.inner.get("store_version")?.and_then(|v| v.first().copied()).unwrap_or_default())
+    }
+
+    fn set_database_version(&self, version: u8) -> Result<()> {
+        self.inner.insert("store_version", version.to_be_bytes().as_ref())?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    /// Get the Olm backend that last wrote to the database.
+    fn database_backend(&self) -> Result<String> {
+        Ok(self
             .inner
-            .get("store_version")?
-            .map(|v| {
-                let (version_bytes, _) = v.split_at(std::mem::size_of::<u8>());
-                u8::from_be_bytes(version_bytes.try_into().unwrap_or_default())
-            })
-            .unwrap_or_default();
+            .get(BACKEND_MARKER)?
+            .map(|b| String::from_utf8_lossy(&b).into_owned())
+            .unwrap_or_else(|| LIBOLM_BACKEND.to_owned()))
+    }
+
+    /// Check that the Olm backend we're compiled with can restore the pickles
+    /// in the database and record it as the backend of the database.
+    ///
+    /// Pickles of the libolm backend can be restored by every backend, but the
+    /// pickles of other backends can only be restored by the backend that
+    /// created them, switching back from such a backend is refused.
+    fn check_backend(&self) -> Result<()> {
+        let backend = self.database_backend()?;
+
+        if backend == BACKEND {
+            Ok(())
+        } else if backend != LIBOLM_BACKEND {
+            Err(CryptoStoreError::UnsupportedBackend { backend })
+        } else {
+            debug!(
+                backend = backend.as_str(),
+                new_backend = BACKEND,
+                "Switching the Olm backend of the Sled crypto store"
+            );
+
+            self.inner.insert(BACKEND_MARKER, BACKEND)?;
+            self.inner.flush()?;
+
+            Ok(())
+        }
+    }
+
+    /// Bring the database up to the latest schema version.
+    ///
+    /// Every migration upgrades the schema by exactly one version, the new
+    /// version is recorded after each step so an interrupted upgrade resumes
+    /// where it stopped the next time the store is opened.
+    fn upgrade(&self) -> Result<()> {
+        let version = self.database_version()?;
+
+        if version > DATABASE_VERSION {
+            return Err(CryptoStoreError::UnsupportedDatabaseVersion {
+                version,
+                max_supported: DATABASE_VERSION,
+            });
+        }
 
         if version != DATABASE_VERSION {
             debug!(version, new_version = DATABASE_VERSION, "Upgrading the Sled crypto store");
         }
 
-        if version == 0 {
-            // We changed the schema but migrating this isn't important since we
-            // rotate the group sessions relatively often anyways so we just
-            // clear the tree.
-            self.outbound_group_sessions.clear()?;
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version.into()) {
+            let to = from as u8 + 1;
+
+            debug!(from, to, "Migrating the Sled crypto store");
+
+            migration(self)?;
+            self.set_database_version(to)?;
         }
 
-        if version <= 1 {
-            #[derive(Serialize, Deserialize)]
-            pub struct OldReadOnlyDevice {
-                user_id: Box<UserId>,
-                device_id: Box<DeviceId>,
-                algorithms: Vec<EventEncryptionAlgorithm>,
-                keys: BTreeMap<Box<DeviceKeyId>, String>,
-                signatures: BTreeMap<Box<UserId>, BTreeMap<Box<DeviceKeyId>, String>>,
-                display_name: Option<String>,
-                deleted: bool,
-                trust_state: LocalTrust,
-            }
+        Ok(())
+    }
 
-            #[allow(clippy::from_over_into)]
-            impl Into<ReadOnlyDevice> for OldReadOnlyDevice {
-                fn into(self) -> ReadOnlyDevice {
-                    let mut device_keys = DeviceKeys::new(
-                        self.user_id,
-                        self.device_id,
-                        self.algorithms,
-                        self.keys,
-                        self.signatures,
-                    );
-                    device_keys.unsigned.device_display_name = self.display_name;
-
-                    ReadOnlyDevice::new(device_keys, self.trust_state)
-                }
-            }
+    fn migrate_to_v1(&self) -> Result<()> {
+        // We changed the schema but migrating this isn't important since we
+        // rotate the group sessions relatively often anyways so we just
+        // clear the tree.
+        self.outbound_group_sessions.clear()?;
 
-            let devices: Vec<ReadOnlyDevice> = self
-                .devices
-                .iter()
-                .map(|d| serde_json::from_slice(&d?.1).map_err(CryptoStoreError::Serialization))
-                .map(|d| {
-                    let d: OldReadOnlyDevice = d?;
-                    Ok(d.into())
-                })
-                .collect::<Result<Vec<ReadOnlyDevice>, CryptoStoreError>>()?;
-
-            self.devices.transaction(move |tree| {
-                for device in &devices {
-                    let key = device.encode();
-                    let device =
-                        serde_json::to_vec(device).map_err(ConflictableTransactionError::Abort)?;
-                    tree.insert(key, device)?;
-                }
+        Ok(())
+    }
 
-                Ok(())
-            })?;
+    fn migrate_to_v2(&self) -> Result<()> {
+        #[derive(Serialize, Deserialize)]
+        pub struct OldReadOnlyDevice {
+            user_id: Box<UserId>,
+            device_id: Box<DeviceId>,
+            algorithms: Vec<EventEncryptionAlgorithm>,
+            keys: BTreeMap<Box<DeviceKeyId>, String>,
+            signatures: BTreeMap<Box<UserId>, BTreeMap<Box<DeviceKeyId>, String>>,
+            display_name: Option<String>,
+            deleted: bool,
+            trust_state: LocalTrust,
         }
 
-        if version <= 2 {
-            // We're treating our own device now differently, we're checking if
-            // the keys match to what we have locally, remove the unchecked
-            // device and mark our own user as dirty.
-            if let Some(pickle) = self.account.get("account".encode())? {
-                let pickle = serde_json::from_slice(&pickle)?;
-                let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
-
-                self.devices
-                    .remove((account.user_id().as_str(), account.device_id.as_str()).encode())?;
-                self.tracked_users.insert(account.user_id().as_str(), &[true as u8])?;
+        #[allow(clippy::from_over_into)]
+        impl Into<ReadOnlyDevice> for OldReadOnlyDevice {
+            fn into(self) -> ReadOnlyDevice {
+                let mut device_keys = DeviceKeys::new(
+                    self.user_id,
+                    self.device_id,
+                    self.algorithms,
+                    self.keys,
+                    self.signatures,
+                );
+                device_keys.unsigned.device_display_name = self.display_name;
+
+                ReadOnlyDevice::new(device_keys, self.trust_state)
             }
         }
 
-        self.inner.insert("store_version", DATABASE_VERSION.to_be_bytes().as_ref())?;
-        self.inner.flush()?;
+        let devices: Vec<ReadOnlyDevice> = self
+            .devices
+            .iter()
+            .map(|d| serde_json::from_slice(&d?.1).map_err(CryptoStoreError::Serialization))
+            .map(|d| {
+                let d: OldReadOnlyDevice = d?;
+                Ok(d.into())
+            })
+            .collect::<Result<Vec<ReadOnlyDevice>, CryptoStoreError>>()?;
+
+        self.devices.transaction(move |tree| {
+            for device in &devices {
+                let key = device.encode();
+                let device =
+                    serde_json::to_vec(device).map_err(ConflictableTransactionError::Abort)?;
+                tree.insert(key, device)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn migrate_to_v3(&self) -> Result<()> {
+        // We're treating our own device now differently, we're checking if
+        // the keys match to what we have locally, remove the unchecked
+        // device and mark our own user as dirty.
+        if let Some(pickle) = self.account.get("account".encode())? {
+            let pickle = serde_json::from_slice(&pickle)?;
+            let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
+
+            self.devices
+                .remove((account.user_id().as_str(), account.device_id.as_str()).encode())?;
+            self.tracked_users.insert(account.user_id().as_str(), &[true as u8])?;
+        }
 
         Ok(())
     }
@@ -355,6 +648,9 @@ impl SledStore {
 
         let tracked_users = db.open_tree("tracked_users")?;
         let olm_hashes = db.open_tree("olm_hashes")?;
+        let megolm_message_indices = db.open_tree("megolm_message_indices")?;
+        let withheld_session_info = db.open_tree("withheld_session_info")?;
+        let unwedging_info = db.open_tree("unwedging_info")?;
 
         let devices = db.open_tree("devices")?;
         let identities = db.open_tree("identities")?;
@@ -363,20 +659,46 @@ impl SledStore {
         let unsent_secret_requests = db.open_tree("unsent_secret_requests")?;
         let secret_requests_by_info = db.open_tree("secret_requests_by_info")?;
 
+        let verification_requests = db.open_tree("verification_requests")?;
+
         let session_cache = SessionStore::new();
 
-        let pickle_key = if let Some(passphrase) = passphrase {
-            Self::get_or_create_pickle_key(passphrase, &db)?
+        let marker: Option<RekeyMarker> =
+            db.get(REKEY_MARKER.encode())?.map(|m| serde_json::from_slice(&m)).transpose()?;
+
+        let (pickle_key, pending_rekey) = if let Some(marker) = marker {
+            // The re-keying was interrupted, the private data is still
+            // encrypted using the old pickle key which we restore from the
+            // marker using the new passphrase.
+            let pickle_key = match (marker.old_key, marker.next_key) {
+                (Some(key), _) => PickleKey::from_encrypted(passphrase.unwrap_or_default(), key)
+                    .map_err(|_| CryptoStoreError::InvalidPassphrase)?,
+                // The store wasn't encrypted, only the new pickle key tells us
+                // if we got the new passphrase.
+                (None, Some(key)) => {
+                    let passphrase = passphrase.ok_or(CryptoStoreError::InvalidPassphrase)?;
+                    PickleKey::from_encrypted(passphrase, key)
+                        .map_err(|_| CryptoStoreError::InvalidPassphrase)?;
+
+                    Self::default_pickle_key()
+                }
+                (None, None) => return Err(CryptoStoreError::InvalidPassphrase),
+            };
+
+            (pickle_key, Some(passphrase.map(|p| Zeroizing::new(p.to_owned()))))
+        } else if let Some(passphrase) = passphrase {
+            (Self::get_or_create_pickle_key(passphrase, &db)?, None)
         } else {
-            PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
-                .expect("Can't create default pickle key")
+            (Self::default_pickle_key(), None)
         };
 
         let database = Self {
             account_info: RwLock::new(None).into(),
             path,
             inner: db,
-            pickle_key: pickle_key.into(),
+            pickle_key: RwLock::new(pickle_key).into(),
+            rekey_lock: AsyncRwLock::new(()).into(),
+            pending_rekey: RwLock::new(pending_rekey).into(),
             account,
             private_identity,
             sessions,
@@ -388,13 +710,18 @@ impl SledStore {
             outgoing_secret_requests,
             unsent_secret_requests,
             secret_requests_by_info,
+            verification_requests,
             devices,
             tracked_users,
             olm_hashes,
+            megolm_message_indices,
+            withheld_session_info,
+            unwedging_info,
             identities,
         };
 
         database.upgrade()?;
+        database.check_backend()?;
 
         Ok(database)
     }
@@ -415,12 +742,239 @@ impl SledStore {
         Ok(key)
     }
 
+    fn default_pickle_key() -> PickleKey {
+        PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
+            .expect("Can't create default pickle key")
+    }
+
+    /// Check that the given passphrase is the one that protects the store.
+    fn check_passphrase(&self, passphrase: Option<&str>) -> Result<()> {
+        let encrypted: Option<EncryptedPickleKey> = self
+            .inner
+            .get("pickle_key".encode())?
+            .map(|k| serde_json::from_slice(&k))
+            .transpose()?;
+
+        match (encrypted, passphrase) {
+            (None, None) => Ok(()),
+            (Some(encrypted), Some(passphrase)) => PickleKey::from_encrypted(passphrase, encrypted)
+                .map(|_| ())
+                .map_err(|_| CryptoStoreError::InvalidPassphrase),
+            _ => Err(CryptoStoreError::InvalidPassphrase),
+        }
+    }
+
+    /// Re-encrypt all the pickles in the store using the given pickle key and
+    /// remove the re-keying marker.
+    ///
+    /// The caller needs to hold the `rekey_lock` for writing.
+    async fn reencrypt(
+        &self,
+        pickle_key: PickleKey,
+        encrypted_pickle_key: Option<EncryptedPickleKey>,
+    ) -> Result<()> {
+        let old_mode = self.get_pickle_mode();
+        let old_key = self.get_pickle_key();
+        let new_mode = pickle_key.pickle_mode();
+
+        let account = self
+            .account
+            .get("account".encode())?
+            .map(|p| serde_json::from_slice(&p))
+            .transpose()?
+            .map(|p| ReadOnlyAccount::from_pickle(p, old_mode.clone()))
+            .transpose()?;
+
+        let mut account_pickles = Vec::new();
+
+        if let Some(account) = &account {
+            let pickle = account.pickle(new_mode.clone()).await;
+            account_pickles.push(("account".encode(), serde_json::to_vec(&pickle)?));
+        }
+
+        #[cfg(feature = "backups_v1")]
+        if let Some(p) = self.account.get("recovery_key_v1".encode())? {
+            let recovery_key =
+                crate::backups::RecoveryKey::from_pickle(serde_json::from_slice(&p)?, &old_key)
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?;
+            let pickle = recovery_key.pickle(pickle_key.key());
+            account_pickles.push(("recovery_key_v1".encode(), serde_json::to_vec(&pickle)?));
+        }
+
+        let identity_pickle = if let Some(p) = self.private_identity.get("identity".encode())? {
+            let identity =
+                PrivateCrossSigningIdentity::from_pickle(serde_json::from_slice(&p)?, &old_key)
+                    .await
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?;
+            Some(serde_json::to_vec(&identity.pickle(pickle_key.key()).await?)?)
+        } else {
+            None
+        };
+
+        let mut session_pickles = Vec::new();
+
+        for item in self.sessions.iter() {
+            let (key, pickle) = item?;
+            let account = account.as_ref().ok_or(CryptoStoreError::AccountUnset)?;
+
+            let session = Session::from_pickle(
+                account.user_id.clone(),
+                account.device_id.clone(),
+                account.identity_keys.clone(),
+                serde_json::from_slice(&pickle)?,
+                old_mode.clone(),
+            )?;
+
+            session_pickles
+                .push((key, serde_json::to_vec(&session.pickle(new_mode.clone()).await)?));
+        }
+
+        let mut inbound_pickles = Vec::new();
+
+        for item in self.inbound_group_sessions.iter() {
+            let (key, pickle) = item?;
+            let session = InboundGroupSession::from_pickle(
+                serde_json::from_slice(&pickle)?,
+                old_mode.clone(),
+            )?;
+
+            inbound_pickles
+                .push((key, serde_json::to_vec(&session.pickle(new_mode.clone()).await)?));
+        }
+
+        let mut outbound_pickles = Vec::new();
+
+        for item in self.outbound_group_sessions.iter() {
+            let (key, pickle) = item?;
+            let account = account.as_ref().ok_or(CryptoStoreError::AccountUnset)?;
+
+            let session = OutboundGroupSession::from_pickle(
+                account.device_id.clone(),
+                account.identity_keys.clone(),
+                serde_json::from_slice(&pickle)?,
+                old_mode.clone(),
+            )?;
+
+            outbound_pickles
+                .push((key, serde_json::to_vec(&session.pickle(new_mode.clone()).await)?));
+        }
+
+        let mut verification_pickles = Vec::new();
+        let mut invalid_verification_requests = Vec::new();
+
+        {
+            let old_pickle_key = self.pickle_key.read().unwrap();
+
+            for item in self.verification_requests.iter() {
+                let (key, value) = item?;
+
+                // Verification requests are restored on a best effort basis,
+                // drop the ones that can't be restored anyway.
+                match Self::decrypt_verification_request(&old_pickle_key, &value) {
+                    Ok(request) => verification_pickles
+                        .push((key, Self::encrypt_verification_request(&pickle_key, &request)?)),
+                    Err(_) => invalid_verification_requests.push(key),
+                }
+            }
+        }
+
+        let encrypted_pickle_key =
+            encrypted_pickle_key.map(|k| serde_json::to_vec(&k)).transpose()?;
+
+        let ret: Result<(), TransactionError<serde_json::Error>> = (
+            &*self.inner,
+            &self.account,
+            &self.private_identity,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.verification_requests,
+        )
+            .transaction(
+                |(
+                    inner,
+                    account,
+                    private_identity,
+                    sessions,
+                    inbound_sessions,
+                    outbound_sessions,
+                    verification_requests,
+                )| {
+                    for (key, pickle) in &account_pickles {
+                        account.insert(key.as_slice(), pickle.as_slice())?;
+                    }
+
+                    if let Some(pickle) = &identity_pickle {
+                        private_identity.insert("identity".encode(), pickle.as_slice())?;
+                    }
+
+                    for (key, pickle) in &session_pickles {
+                        sessions.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    for (key, pickle) in &inbound_pickles {
+                        inbound_sessions.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    for (key, pickle) in &outbound_pickles {
+                        outbound_sessions.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    for (key, pickle) in &verification_pickles {
+                        verification_requests.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    for key in &invalid_verification_requests {
+                        verification_requests.remove(key.clone())?;
+                    }
+
+                    if let Some(encrypted) = &encrypted_pickle_key {
+                        inner.insert("pickle_key".encode(), encrypted.as_slice())?;
+                    } else {
+                        inner.remove("pickle_key".encode())?;
+                    }
+
+                    inner.remove(REKEY_MARKER.encode())?;
+
+                    Ok(())
+                },
+            );
+
+        ret?;
+
+        self.inner.flush_async().await?;
+        *self.pickle_key.write().unwrap() = pickle_key;
+
+        Ok(())
+    }
+
     fn get_pickle_mode(&self) -> PicklingMode {
-        self.pickle_key.pickle_mode()
+        self.pickle_key.read().unwrap().pickle_mode()
+    }
+
+    fn get_pickle_key(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.pickle_key.read().unwrap().key().to_vec())
+    }
+
+    /// Serialize the given verification request and encrypt it using the given
+    /// pickle key, requests might contain the secret of a QR code.
+    fn encrypt_verification_request(
+        pickle_key: &PickleKey,
+        request: &PickledVerificationRequest,
+    ) -> Result<Vec<u8>> {
+        let request = Zeroizing::new(serde_json::to_vec(request)?);
+        Ok(serde_json::to_vec(&pickle_key.encrypt_value(&request))?)
     }
 
-    fn get_pickle_key(&self) -> &[u8] {
-        self.pickle_key.key()
+    fn decrypt_verification_request(
+        pickle_key: &PickleKey,
+        value: &[u8],
+    ) -> Result<PickledVerificationRequest> {
+        let encrypted: CipherTextInfo = serde_json::from_slice(value)?;
+        let request =
+            pickle_key.decrypt_value(encrypted).map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        Ok(serde_json::from_slice(&request)?)
     }
 
     async fn load_tracked_users(&self) -> Result<()> {
@@ -462,6 +1016,8 @@ impl SledStore {
     }
 
     async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let _lock = self.rekey_lock.read().await;
+
         let account_pickle = if let Some(a) = changes.account {
             Some(a.pickle(self.get_pickle_mode()).await)
         } else {
@@ -469,13 +1025,13 @@ impl SledStore {
         };
 
         let private_identity_pickle = if let Some(i) = changes.private_identity {
-            Some(i.pickle(self.get_pickle_key()).await?)
+            Some(i.pickle(&self.get_pickle_key()).await?)
         } else {
             None
         };
 
         #[cfg(feature = "backups_v1")]
-        let recovery_key_pickle = changes.recovery_key.map(|r| r.pickle(self.get_pickle_key()));
+        let recovery_key_pickle = changes.recovery_key.map(|r| r.pickle(&self.get_pickle_key()));
 
         let device_changes = changes.devices;
         let mut session_changes = HashMap::new();
@@ -491,6 +1047,13 @@ impl SledStore {
             session_changes.insert(key, pickle);
         }
 
+        let mut deleted_sessions = Vec::new();
+
+        for session in changes.deleted_sessions {
+            deleted_sessions.push((session.sender_key(), session.session_id()).encode());
+            self.session_cache.remove(&session).await;
+        }
+
         let mut inbound_session_changes = HashMap::new();
 
         for session in changes.inbound_group_sessions {
@@ -503,6 +1066,12 @@ impl SledStore {
             inbound_session_changes.insert(key, pickle);
         }
 
+        let deleted_inbound_sessions: Vec<Vec<u8>> = changes
+            .deleted_inbound_group_sessions
+            .iter()
+            .map(|s| (s.room_id().as_str(), s.sender_key(), s.session_id()).encode())
+            .collect();
+
         let mut outbound_session_changes = HashMap::new();
 
         for session in changes.outbound_group_sessions {
@@ -514,7 +1083,22 @@ impl SledStore {
 
         let identity_changes = changes.identities;
         let olm_hashes = changes.message_hashes;
+        let megolm_message_indices = changes.megolm_message_indices;
+        let withheld_session_info = changes.withheld_session_info;
+        let unwedging_info = changes.unwedging_info;
         let key_requests = changes.key_requests;
+        let verification_requests: Vec<(Vec<u8>, Vec<u8>)> = {
+            let pickle_key = self.pickle_key.read().unwrap();
+
+            changes
+                .verification_requests
+                .iter()
+                .map(|r| {
+                    Ok((r.flow_id().encode(), Self::encrypt_verification_request(&pickle_key, r)?))
+                })
+                .collect::<Result<_>>()?
+        };
+        let deleted_verification_requests = changes.deleted_verification_requests;
         #[cfg(feature = "backups_v1")]
         let backup_version = changes.backup_version;
 
@@ -527,9 +1111,12 @@ impl SledStore {
             &self.inbound_group_sessions,
             &self.outbound_group_sessions,
             &self.olm_hashes,
+            &self.megolm_message_indices,
+            &self.withheld_session_info,
             &self.outgoing_secret_requests,
             &self.unsent_secret_requests,
             &self.secret_requests_by_info,
+            &self.unwedging_info,
         )
             .transaction(
                 |(
@@ -541,9 +1128,12 @@ impl SledStore {
                     inbound_sessions,
                     outbound_sessions,
                     hashes,
+                    message_indices,
+                    withheld_info,
                     outgoing_secret_requests,
                     unsent_secret_requests,
                     secret_requests_by_info,
+                    unwedging_info_tree,
                 )| {
                     if let Some(a) = &account_pickle {
                         account.insert(
@@ -603,6 +1193,10 @@ impl SledStore {
                         )?;
                     }
 
+                    for key in &deleted_sessions {
+                        sessions.remove(key.as_slice())?;
+                    }
+
                     for (key, session) in &inbound_session_changes {
                         inbound_sessions.insert(
                             key.as_slice(),
@@ -611,6 +1205,10 @@ impl SledStore {
                         )?;
                     }
 
+                    for key in &deleted_inbound_sessions {
+                        inbound_sessions.remove(key.as_slice())?;
+                    }
+
                     for (key, session) in &outbound_session_changes {
                         outbound_sessions.insert(
                             (&**key).encode(),
@@ -627,6 +1225,41 @@ impl SledStore {
                         )?;
                     }
 
+                    for index in &megolm_message_indices {
+                        message_indices.insert(
+                            encode_message_index(
+                                &index.room_id,
+                                &index.sender_key,
+                                &index.session_id,
+                                index.message_index,
+                            ),
+                            serde_json::to_vec(&index.event_id)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
+                    for info in &withheld_session_info {
+                        if let (Some(room_id), Some(session_id)) =
+                            (&info.content.room_id, &info.content.session_id)
+                        {
+                            withheld_info.insert(
+                                (room_id.as_str(), session_id.as_str()).encode(),
+                                serde_json::to_vec(&info)
+                                    .map_err(ConflictableTransactionError::Abort)?,
+                            )?;
+                        }
+                    }
+
+                    // The unwedging info is stored together with the Olm
+                    // session that unwedged the device.
+                    for info in &unwedging_info {
+                        unwedging_info_tree.insert(
+                            (info.user_id.as_str(), info.device_id.as_str()).encode(),
+                            serde_json::to_vec(info)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
                     for key_request in &key_requests {
                         secret_requests_by_info.insert(
                             (&key_request.info).encode(),
@@ -657,6 +1290,17 @@ impl SledStore {
             );
 
         ret?;
+
+        // Verification requests get resumed on a best effort basis, so they
+        // are stored outside of the transaction.
+        for (key, request) in verification_requests {
+            self.verification_requests.insert(key, request)?;
+        }
+
+        for flow_id in &deleted_verification_requests {
+            self.verification_requests.remove(flow_id.as_str().encode())?;
+        }
+
         self.inner.flush_async().await?;
 
         Ok(())
@@ -682,6 +1326,10 @@ impl SledStore {
 #[async_trait]
 impl CryptoStore for SledStore {
     async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        self.finish_pending_rekey().await?;
+
+        let _lock = self.rekey_lock.read().await;
+
         if let Some(pickle) = self.account.get("account".encode())? {
             let pickle = serde_json::from_slice(&pickle)?;
 
@@ -718,10 +1366,12 @@ impl CryptoStore for SledStore {
     }
 
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        let _lock = self.rekey_lock.read().await;
+
         if let Some(i) = self.private_identity.get("identity".encode())? {
             let pickle = serde_json::from_slice(&i)?;
             Ok(Some(
-                PrivateCrossSigningIdentity::from_pickle(pickle, self.get_pickle_key())
+                PrivateCrossSigningIdentity::from_pickle(pickle, &self.get_pickle_key())
                     .await
                     .map_err(|_| CryptoStoreError::UnpicklingError)?,
             ))
@@ -735,6 +1385,8 @@ impl CryptoStore for SledStore {
     }
 
     async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        let _lock = self.rekey_lock.read().await;
+
         let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
 
         if self.session_cache.get(sender_key).is_none() {
@@ -760,12 +1412,27 @@ impl CryptoStore for SledStore {
         Ok(self.session_cache.get(sender_key))
     }
 
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        let mut sender_keys = BTreeSet::new();
+
+        for key in self.sessions.iter().keys() {
+            let key = key?;
+            let sender_key =
+                key.split(|b| *b == <&str as EncodeKey>::SEPARATOR).next().unwrap_or_default();
+            sender_keys.insert(String::from_utf8_lossy(sender_key).into_owned());
+        }
+
+        Ok(sender_keys.into_iter().collect())
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
         sender_key: &str,
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>> {
+        let _lock = self.rekey_lock.read().await;
+
         let key = (room_id.as_str(), sender_key, session_id).encode();
         let pickle = self.inbound_group_sessions.get(&key)?.map(|p| serde_json::from_slice(&p));
 
@@ -777,6 +1444,8 @@ impl CryptoStore for SledStore {
     }
 
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        let _lock = self.rekey_lock.read().await;
+
         let pickles: Result<Vec<PickledInboundGroupSession>> = self
             .inbound_group_sessions
             .iter()
@@ -789,6 +1458,36 @@ impl CryptoStore for SledStore {
             .collect())
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let _lock = self.rekey_lock.read().await;
+
+        let iter = if let Some(s) = after {
+            let key = (s.room_id().as_str(), s.sender_key(), s.session_id()).encode();
+            self.inbound_group_sessions.range((Bound::Excluded(key), Bound::Unbounded))
+        } else {
+            self.inbound_group_sessions.iter()
+        };
+
+        let pickles: Result<Vec<PickledInboundGroupSession>> = iter
+            .take(limit)
+            .map(|p| serde_json::from_slice(&p?.1).map_err(CryptoStoreError::Serialization))
+            .collect();
+
+        // Don't skip sessions that fail to unpickle, exports would silently
+        // end up incomplete.
+        pickles?
+            .into_iter()
+            .map(|p| {
+                InboundGroupSession::from_pickle(p, self.get_pickle_mode())
+                    .map_err(CryptoStoreError::OlmGroupSession)
+            })
+            .collect()
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         let pickles: Vec<PickledInboundGroupSession> = self
             .inbound_group_sessions
@@ -809,6 +1508,8 @@ impl CryptoStore for SledStore {
         &self,
         limit: usize,
     ) -> Result<Vec<InboundGroupSession>> {
+        let _lock = self.rekey_lock.read().await;
+
         let pickles: Vec<InboundGroupSession> = self
             .inbound_group_sessions
             .iter()
@@ -840,10 +1541,37 @@ impl CryptoStore for SledStore {
         self.reset_backup_state().await
     }
 
+    async fn delete_inbound_group_sessions_for_room(&self, room_id: &RoomId) -> Result<usize> {
+        let _lock = self.rekey_lock.read().await;
+
+        let keys = self
+            .inbound_group_sessions
+            .scan_prefix(room_id.encode())
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let ret: Result<(), TransactionError<serde_json::Error>> =
+            self.inbound_group_sessions.transaction(|inbound_sessions| {
+                for key in &keys {
+                    inbound_sessions.remove(key)?;
+                }
+
+                Ok(())
+            });
+
+        ret?;
+
+        self.inner.flush_async().await?;
+
+        Ok(keys.len())
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>> {
+        let _lock = self.rekey_lock.read().await;
+
         self.load_outbound_group_session(room_id).await
     }
 
@@ -912,6 +1640,38 @@ impl CryptoStore for SledStore {
         Ok(self.olm_hashes.contains_key(serde_json::to_vec(message_hash)?)?)
     }
 
+    async fn get_event_id_for_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<Box<EventId>>> {
+        let key = encode_message_index(room_id, sender_key, session_id, message_index);
+
+        Ok(self.megolm_message_indices.get(key)?.map(|e| serde_json::from_slice(&e)).transpose()?)
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<ToDeviceRoomKeyWithheldEvent>> {
+        let key = (room_id.as_str(), session_id).encode();
+
+        Ok(self.withheld_session_info.get(key)?.map(|e| serde_json::from_slice(&e)).transpose()?)
+    }
+
+    async fn get_unwedging_info(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<UnwedgingInfo>> {
+        let key = (user_id.as_str(), device_id.as_str()).encode();
+
+        Ok(self.unwedging_info.get(key)?.map(|i| serde_json::from_slice(&i)).transpose()?)
+    }
+
     async fn get_outgoing_secret_requests(
         &self,
         request_id: Uuid,
@@ -944,6 +1704,37 @@ impl CryptoStore for SledStore {
         requests
     }
 
+    async fn get_sent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        self.outgoing_secret_requests
+            .iter()
+            .map(|i| serde_json::from_slice(&i?.1).map_err(CryptoStoreError::from))
+            .collect()
+    }
+
+    async fn get_verification_requests(&self) -> Result<Vec<PickledVerificationRequest>> {
+        let _lock = self.rekey_lock.read().await;
+        let mut requests = Vec::new();
+
+        for item in self.verification_requests.iter() {
+            let (key, value) = item?;
+
+            // A request might have been stored by a version that supports more
+            // verification methods, e.g. one with the `qrcode` feature enabled.
+            let request =
+                Self::decrypt_verification_request(&self.pickle_key.read().unwrap(), &value);
+
+            match request {
+                Ok(request) => requests.push(request),
+                Err(e) => {
+                    warn!(error = ?e, "Removing a verification request that can't be restored");
+                    self.verification_requests.remove(key)?;
+                }
+            }
+        }
+
+        Ok(requests)
+    }
+
     async fn delete_outgoing_secret_requests(&self, request_id: Uuid) -> Result<()> {
         let ret: Result<(), TransactionError<serde_json::Error>> = (
             &self.outgoing_secret_requests,
@@ -983,6 +1774,8 @@ impl CryptoStore for SledStore {
     }
 
     async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        let _lock = self.rekey_lock.read().await;
+
         let version = self
             .account
             .get("backup_version_v1".encode())?
@@ -996,7 +1789,7 @@ impl CryptoStore for SledStore {
                 .map(|p| serde_json::from_slice(&p))
                 .transpose()?
                 .map(|p| {
-                    crate::backups::RecoveryKey::from_pickle(p, self.get_pickle_key())
+                    crate::backups::RecoveryKey::from_pickle(p, &self.get_pickle_key())
                         .map_err(|_| CryptoStoreError::UnpicklingError)
                 })
                 .transpose()?
@@ -1013,27 +1806,33 @@ impl CryptoStore for SledStore {
 mod test {
     use std::collections::BTreeMap;
 
+    use matches::assert_matches;
     use matrix_sdk_common::uuid::Uuid;
     use matrix_sdk_test::async_test;
-    use olm_rs::outbound_group_session::OlmOutboundGroupSession;
     use ruma::{
-        device_id, encryption::SignedKey, events::room_key_request::RequestedKeyInfo, room_id,
-        user_id, DeviceId, EventEncryptionAlgorithm, UserId,
+        device_id, encryption::SignedKey, event_id, events::room_key_request::RequestedKeyInfo,
+        room_id, user_id, DeviceId, EventEncryptionAlgorithm, MilliSecondsSinceUnixEpoch, UserId,
     };
+    use serde_json::json;
     use tempfile::tempdir;
 
-    use super::{CryptoStore, GossipRequest, SledStore};
+    use super::{
+        Batch, CryptoStore, CryptoStoreError, EncodeKey, GossipRequest, PickledVerificationRequest,
+        SledStore, BACKEND, BACKEND_MARKER, DATABASE_VERSION, LIBOLM_BACKEND, REKEY_MARKER,
+    };
     use crate::{
         gossiping::SecretInfo,
         identities::{
             device::test::get_device,
             user::test::{get_other_identity, get_own_identity},
+            ReadOnlyDevice, ReadOnlyUserIdentities,
         },
         olm::{
-            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
+            InboundGroupSession, MegolmMessageIndex, OlmMessageHash, PrivateCrossSigningIdentity,
             ReadOnlyAccount, Session,
         },
-        store::{Changes, DeviceChanges, IdentityChanges},
+        store::{Changes, DeviceChanges, IdentityChanges, UnwedgingInfo},
+        withheld::{RoomKeyWithheldEventContent, ToDeviceRoomKeyWithheldEvent, WithheldCode},
     };
 
     fn alice_id() -> &'static UserId {
@@ -1096,6 +1895,75 @@ mod test {
         let _ = SledStore::open_with_passphrase(tmpdir_path, None).expect("Can't create store");
     }
 
+    #[async_test]
+    async fn database_version() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let store = SledStore::open_with_database(db.clone(), None).expect("Can't create store");
+        assert_eq!(store.database_version().unwrap(), DATABASE_VERSION);
+        drop(store);
+
+        // Pretend the database was written by a newer version of the library.
+        db.insert("store_version", (DATABASE_VERSION + 1).to_be_bytes().as_ref()).unwrap();
+
+        assert_matches!(
+            SledStore::open_with_database(db, None),
+            Err(CryptoStoreError::UnsupportedDatabaseVersion { version, max_supported })
+                if version == DATABASE_VERSION + 1 && max_supported == DATABASE_VERSION
+        );
+    }
+
+    #[async_test]
+    async fn database_backend() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let store = SledStore::open_with_database(db.clone(), None).expect("Can't create store");
+        assert_eq!(store.database_backend().unwrap(), BACKEND);
+        drop(store);
+
+        // Databases that were written by libolm can be opened by every
+        // backend, the backend we're using is recorded once we open them.
+        db.insert(BACKEND_MARKER, LIBOLM_BACKEND).unwrap();
+        let store = SledStore::open_with_database(db.clone(), None).expect("Can't open the store");
+        assert_eq!(store.database_backend().unwrap(), BACKEND);
+        drop(store);
+
+        // Pretend the database was written by a backend whose pickles we can't
+        // restore.
+        db.insert(BACKEND_MARKER, "unknown_backend").unwrap();
+
+        assert_matches!(
+            SledStore::open_with_database(db, None),
+            Err(CryptoStoreError::UnsupportedBackend { backend }) if backend == "unknown_backend"
+        );
+    }
+
+    #[async_test]
+    async fn older_database_is_migrated() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SledStore::open_with_database(db.clone(), None).expect("Can't create store");
+
+        let account = get_account();
+        store.save_account(account.clone()).await.expect("Can't save account");
+        let device = ReadOnlyDevice::from_account(&account).await;
+        store
+            .save_changes(Changes {
+                devices: DeviceChanges { new: vec![device], ..Default::default() },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        drop(store);
+
+        // Version 2 databases still contain our own device, the migration to
+        // version 3 removes it.
+        db.insert("store_version", 2u8.to_be_bytes().as_ref()).unwrap();
+
+        let store = SledStore::open_with_database(db, None).expect("Can't open the store");
+        assert_eq!(store.database_version().unwrap(), DATABASE_VERSION);
+        assert!(store.get_device(account.user_id(), account.device_id()).await.unwrap().is_none());
+    }
+
     #[async_test]
     async fn save_account() {
         let (store, _dir) = get_store(None).await;
@@ -1199,20 +2067,283 @@ mod test {
         assert_eq!(session_id, session.session_id());
     }
 
+    #[async_test]
+    async fn delete_sessions() {
+        let (store, dir) = get_store(None).await;
+        let (account, session) = get_account_and_session().await;
+        let sender_key = session.sender_key.to_owned();
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        let (_, inbound) = account
+            .create_group_session_pair_with_defaults(room_id!("!test:localhost"))
+            .await
+            .expect("Can't create session");
+
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            inbound_group_sessions: vec![inbound.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        assert_eq!(store.get_session_sender_keys().await.unwrap(), vec![sender_key.to_string()]);
+
+        let changes = Changes {
+            deleted_sessions: vec![session],
+            deleted_inbound_group_sessions: vec![inbound],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert!(sessions.lock().await.is_empty());
+        assert!(store.get_inbound_group_sessions().await.unwrap().is_empty());
+
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+        store.load_account().await.unwrap();
+
+        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert!(sessions.lock().await.is_empty());
+        assert!(store.get_session_sender_keys().await.unwrap().is_empty());
+        assert!(store.get_inbound_group_sessions().await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn delete_inbound_group_sessions_for_room() {
+        let (store, _dir) = get_store(None).await;
+        let (account, _) = get_account_and_session().await;
+        let room_id = room_id!("!test:localhost");
+        let other_room_id = room_id!("!other:localhost");
+
+        let (_, inbound) = account.create_group_session_pair_with_defaults(room_id).await.unwrap();
+        let (_, other_inbound) =
+            account.create_group_session_pair_with_defaults(other_room_id).await.unwrap();
+
+        let changes =
+            Changes { inbound_group_sessions: vec![inbound, other_inbound], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        assert_eq!(store.delete_inbound_group_sessions_for_room(room_id).await.unwrap(), 1);
+        assert_eq!(store.delete_inbound_group_sessions_for_room(room_id).await.unwrap(), 0);
+
+        let sessions = store.get_inbound_group_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].room_id(), other_room_id);
+    }
+
+    #[async_test]
+    async fn invalid_verification_requests_get_removed() {
+        let (store, _dir) = get_store(None).await;
+
+        store
+            .verification_requests
+            .insert("$invalid".encode(), b"{\"invalid\": true}".to_vec())
+            .unwrap();
+
+        assert!(store.get_verification_requests().await.unwrap().is_empty());
+        assert!(store.verification_requests.is_empty());
+    }
+
+    #[async_test]
+    async fn verification_requests_are_encrypted() {
+        let (store, dir) = get_store(Some("secret")).await;
+
+        let request: PickledVerificationRequest = serde_json::from_value(json!({
+            "flow_id": { "ToDevice": "TEST_FLOW_ID" },
+            "other_user_id": bob_id(),
+            "we_started": true,
+            "recipient_devices": [bob_device_id()],
+            "creation_time": MilliSecondsSinceUnixEpoch::now(),
+            "state": { "Created": { "our_methods": ["m.sas.v1"] } },
+        }))
+        .unwrap();
+
+        let changes = Changes { verification_requests: vec![request], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        let (_, stored) = store.verification_requests.iter().next().unwrap().unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains(bob_id().as_str()));
+
+        // Removing the passphrase re-encrypts the request with a new pickle key.
+        store.change_passphrase(Some("secret"), None).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None)
+            .expect("Can't open the unencrypted store");
+
+        let requests = store.get_verification_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].flow_id(), "TEST_FLOW_ID");
+        assert_eq!(requests[0].other_user_id(), bob_id());
+    }
+
+    #[async_test]
+    async fn change_passphrase() {
+        let (store, dir) = get_store(None).await;
+        let (account, session) = get_account_and_session().await;
+        let sender_key = session.sender_key.to_owned();
+        let session_id = session.session_id().to_owned();
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+        let changes = Changes { sessions: vec![session], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        store.change_passphrase(None, Some("secret")).await.unwrap();
+        store.change_passphrase(Some("secret"), Some("new secret")).await.unwrap();
+
+        assert_matches!(
+            store.change_passphrase(Some("secret"), None).await,
+            Err(CryptoStoreError::InvalidPassphrase)
+        );
+
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("new secret"))
+            .expect("Can't open the store with the new passphrase");
+
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+
+        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert_eq!(session_id, sessions.lock().await[0].session_id());
+
+        store.change_passphrase(Some("new secret"), None).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None)
+            .expect("Can't open the unencrypted store");
+
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+    }
+
+    #[async_test]
+    async fn rekey() {
+        let (store, dir) = get_store(Some("secret")).await;
+        let account = get_account();
+        let room_id = room_id!("!test:localhost");
+        let (_, session) = account.create_group_session_pair_with_defaults(room_id).await.unwrap();
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+        let changes =
+            Changes { inbound_group_sessions: vec![session.clone()], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        let old_pickle_key = store.get_pickle_key();
+
+        assert_matches!(
+            store.rekey("wrong secret").await,
+            Err(CryptoStoreError::InvalidPassphrase)
+        );
+        store.rekey("secret").await.unwrap();
+
+        assert_ne!(old_pickle_key, store.get_pickle_key());
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("secret"))
+            .expect("Can't open the rekeyed store");
+
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+
+        let loaded_session = store
+            .get_inbound_group_session(room_id, session.sender_key(), session.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(session, loaded_session);
+    }
+
+    #[async_test]
+    async fn interrupted_rekey() {
+        let (store, dir) = get_store(Some("secret")).await;
+        let account = get_account();
+        let room_id = room_id!("!test:localhost");
+        let (_, session) = account.create_group_session_pair_with_defaults(room_id).await.unwrap();
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+        let changes =
+            Changes { inbound_group_sessions: vec![session.clone()], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        // Only start the re-keying, as if the process got interrupted.
+        let mut batch = Batch::default();
+        store.begin_rekey(&mut batch, Some("secret"), Some("new secret"), true).await.unwrap();
+        store.inner.apply_batch(batch).unwrap();
+        drop(store);
+
+        assert_matches!(
+            SledStore::open_with_passphrase(dir.path(), Some("secret")),
+            Err(CryptoStoreError::InvalidPassphrase)
+        );
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("new secret"))
+            .expect("Can't open the store with the new passphrase");
+
+        // Loading the account finishes the re-keying.
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+        assert!(store.inner.get(REKEY_MARKER.encode()).unwrap().is_none());
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("new secret"))
+            .expect("Can't open the rekeyed store");
+
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+
+        let loaded_session = store
+            .get_inbound_group_session(room_id, session.sender_key(), session.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(session, loaded_session);
+    }
+
+    #[async_test]
+    async fn interrupted_encryption_of_an_unencrypted_store() {
+        let (store, dir) = get_store(None).await;
+        let account = get_account();
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        // Only start the re-keying, as if the process got interrupted.
+        let mut batch = Batch::default();
+        store.begin_rekey(&mut batch, None, Some("secret"), false).await.unwrap();
+        store.inner.apply_batch(batch).unwrap();
+        drop(store);
+
+        assert_matches!(
+            SledStore::open_with_passphrase(dir.path(), None),
+            Err(CryptoStoreError::InvalidPassphrase)
+        );
+        assert_matches!(
+            SledStore::open_with_passphrase(dir.path(), Some("wrong secret")),
+            Err(CryptoStoreError::InvalidPassphrase)
+        );
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("secret"))
+            .expect("Can't open the store with the new passphrase");
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+        drop(store);
+
+        assert_matches!(
+            SledStore::open_with_passphrase(dir.path(), Some("wrong secret")),
+            Err(CryptoStoreError::UnpicklingError)
+        );
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("secret"))
+            .expect("Can't open the encrypted store");
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+    }
+
     #[async_test]
     async fn save_inbound_group_session() {
         let (account, store, _dir) = get_loaded_store().await;
 
-        let identity_keys = account.identity_keys();
-        let outbound_session = OlmOutboundGroupSession::new();
-        let session = InboundGroupSession::new(
-            identity_keys.curve25519(),
-            identity_keys.ed25519(),
-            room_id!("!test:localhost"),
-            GroupSessionKey(outbound_session.session_key()),
-            None,
-        )
-        .expect("Can't create session");
+        let (_, session) = account
+            .create_group_session_pair_with_defaults(room_id!("!test:localhost"))
+            .await
+            .expect("Can't create session");
 
         let changes = Changes { inbound_group_sessions: vec![session], ..Default::default() };
 
@@ -1223,16 +2354,10 @@ mod test {
     async fn load_inbound_group_session() {
         let (account, store, dir) = get_loaded_store().await;
 
-        let identity_keys = account.identity_keys();
-        let outbound_session = OlmOutboundGroupSession::new();
-        let session = InboundGroupSession::new(
-            identity_keys.curve25519(),
-            identity_keys.ed25519(),
-            room_id!("!test:localhost"),
-            GroupSessionKey(outbound_session.session_key()),
-            None,
-        )
-        .expect("Can't create session");
+        let (_, session) = account
+            .create_group_session_pair_with_defaults(room_id!("!test:localhost"))
+            .await
+            .expect("Can't create session");
 
         let mut export = session.export().await;
 
@@ -1427,6 +2552,30 @@ mod test {
         assert!(loaded_user.own().unwrap().is_verified())
     }
 
+    #[async_test]
+    async fn pinned_identity_saving() {
+        let (_, store, dir) = get_loaded_store().await;
+
+        let mut identity: ReadOnlyUserIdentities = get_other_identity().into();
+        let master_key = identity.master_key().to_owned();
+        identity.pin_master_key(master_key.clone());
+
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![identity.clone()], ..Default::default() },
+            ..Default::default()
+        };
+
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+        store.load_account().await.unwrap();
+
+        let loaded_identity = store.get_user_identity(identity.user_id()).await.unwrap().unwrap();
+        assert!(loaded_identity.is_pinned());
+        assert_eq!(loaded_identity.pinned_master_key(), Some(&master_key));
+    }
+
     #[async_test]
     async fn private_identity_saving() {
         let (_, store, _dir) = get_loaded_store().await;
@@ -1455,6 +2604,107 @@ mod test {
         assert!(store.is_message_known(&hash).await.unwrap());
     }
 
+    #[async_test]
+    async fn megolm_message_index_saving() {
+        let (_, store, dir) = get_loaded_store().await;
+        let room_id = room_id!("!test:localhost");
+
+        let index = MegolmMessageIndex {
+            room_id: room_id.to_owned(),
+            sender_key: "test_sender".to_owned(),
+            session_id: "test_session".to_owned(),
+            message_index: 2,
+            event_id: event_id!("$event:localhost").to_owned(),
+        };
+
+        let mut changes = Changes::default();
+        changes.megolm_message_indices.push(index.clone());
+
+        assert!(store
+            .get_event_id_for_message_index(room_id, "test_sender", "test_session", 2)
+            .await
+            .unwrap()
+            .is_none());
+
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        assert_eq!(
+            store
+                .get_event_id_for_message_index(room_id, "test_sender", "test_session", 2)
+                .await
+                .unwrap(),
+            Some(index.event_id)
+        );
+        assert!(store
+            .get_event_id_for_message_index(room_id, "test_sender", "test_session", 3)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_test]
+    async fn withheld_info_saving() {
+        let (_, store, dir) = get_loaded_store().await;
+        let room_id = room_id!("!test:localhost");
+
+        let info = ToDeviceRoomKeyWithheldEvent {
+            sender: user_id!("@alice:localhost").to_owned(),
+            content: RoomKeyWithheldEventContent::new(
+                WithheldCode::Blacklisted,
+                room_id,
+                "test_session",
+                "test_sender",
+            ),
+        };
+
+        let mut changes = Changes::default();
+        changes.withheld_session_info.push(info);
+
+        assert!(store.get_withheld_info(room_id, "test_session").await.unwrap().is_none());
+
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        let info = store.get_withheld_info(room_id, "test_session").await.unwrap().unwrap();
+        assert_eq!(info.content.code, WithheldCode::Blacklisted);
+        assert!(store.get_withheld_info(room_id, "other_session").await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn unwedging_info_saving() {
+        let (account, store, dir) = get_loaded_store().await;
+        let device = get_device();
+
+        assert!(store
+            .get_unwedging_info(device.user_id(), device.device_id())
+            .await
+            .unwrap()
+            .is_none());
+
+        let changes = Changes {
+            unwedging_info: vec![UnwedgingInfo::new(device.user_id(), device.device_id())],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        let info =
+            store.get_unwedging_info(device.user_id(), device.device_id()).await.unwrap().unwrap();
+        assert_eq!(&*info.user_id, device.user_id());
+        assert!(store
+            .get_unwedging_info(account.user_id(), account.device_id())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
     #[async_test]
     async fn key_request_saving() {
         let (account, store, _dir) = get_loaded_store().await;
@@ -1473,6 +2723,7 @@ mod test {
             request_id: id,
             info: info.clone(),
             sent_out: false,
+            creation_time: MilliSecondsSinceUnixEpoch::now(),
         };
 
         assert!(store.get_outgoing_secret_requests(id).await.unwrap().is_none());
@@ -1495,6 +2746,7 @@ mod test {
             request_id: id,
             info: info.clone(),
             sent_out: true,
+            creation_time: MilliSecondsSinceUnixEpoch::now(),
         };
 
         let mut changes = Changes::default();
@@ -42,9 +42,10 @@ use ruma::{
         room::message::{KeyVerificationRequestEventContent, MessageType},
         AnyMessageEvent, AnyMessageEventContent, AnyToDeviceEvent, AnyToDeviceEventContent,
     },
-    serde::CanonicalJsonValue,
+    serde::CanonicalValue,
     DeviceId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
+use serde_json::Value;
 
 use super::FlowId;
 
@@ -285,6 +286,15 @@ impl RequestContent<'_> {
             Self::Room(r) => &r.methods,
         }
     }
+
+    /// The user the request is addressed to, to-device requests are addressed
+    /// by the to-device message itself.
+    pub fn to(&self) -> Option<&UserId> {
+        match self {
+            Self::ToDevice(_) => None,
+            Self::Room(r) => Some(&r.to),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -443,13 +453,17 @@ impl<'a> StartContent<'a> {
         }
     }
 
-    pub fn canonical_json(&self) -> CanonicalJsonValue {
+    pub fn to_json(&self) -> Value {
         let content = match self {
             Self::ToDevice(c) => serde_json::to_value(c),
             Self::Room(c) => serde_json::to_value(c),
         };
 
-        content.expect("Can't serialize content").try_into().expect("Can't canonicalize content")
+        content.expect("Can't serialize content")
+    }
+
+    pub fn canonical_json(&self) -> CanonicalValue {
+        self.to_json().try_into().expect("Can't canonicalize content")
     }
 }
 
@@ -593,6 +607,15 @@ pub enum OwnedStartContent {
 }
 
 impl OwnedStartContent {
+    /// Deserialize the start content of the verification flow with the given
+    /// flow id.
+    pub fn from_json(flow_id: &FlowId, content: Value) -> Result<Self, serde_json::Error> {
+        Ok(match flow_id {
+            FlowId::ToDevice(_) => Self::ToDevice(serde_json::from_value(content)?),
+            FlowId::InRoom(r, _) => Self::Room(r.clone(), serde_json::from_value(content)?),
+        })
+    }
+
     pub fn method(&self) -> &StartMethod {
         match self {
             Self::ToDevice(c) => &c.method,
@@ -621,7 +644,7 @@ impl OwnedStartContent {
         }
     }
 
-    pub fn canonical_json(self) -> CanonicalJsonValue {
+    pub fn canonical_json(self) -> CanonicalValue {
         let content = match self {
             Self::ToDevice(c) => serde_json::to_value(c),
             Self::Room(_, c) => serde_json::to_value(c),
@@ -725,7 +748,6 @@ impl TryFrom<ToDeviceRequest> for OutgoingContent {
 
     fn try_from(request: ToDeviceRequest) -> Result<Self, Self::Error> {
         use ruma::events::EventType;
-        use serde_json::Value;
 
         let json: Value = serde_json::from_str(
             request
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+    collections::HashSet,
     convert::{TryFrom, TryInto},
     sync::Arc,
 };
@@ -32,14 +33,14 @@ use tracing::{info, trace, warn};
 use super::{
     cache::VerificationCache,
     event_enums::{AnyEvent, AnyVerificationContent, OutgoingContent},
-    requests::VerificationRequest,
-    sas::Sas,
+    requests::{PickledVerificationRequest, VerificationRequest},
+    sas::{Sas, SasMethod},
     FlowId, Verification, VerificationResult, VerificationStore,
 };
 use crate::{
     olm::PrivateCrossSigningIdentity,
     requests::OutgoingRequest,
-    store::{CryptoStore, CryptoStoreError},
+    store::{Changes, CryptoStore, CryptoStoreError},
     OutgoingVerificationRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentity,
     RoomMessageRequest, ToDeviceRequest,
 };
@@ -60,12 +61,99 @@ impl VerificationMachine {
     ) -> Self {
         Self {
             private_identity: identity,
-            store: VerificationStore { account, inner: store },
+            store: VerificationStore::new(account, store),
             verifications: VerificationCache::new(),
             requests: Default::default(),
         }
     }
 
+    /// Restore the verification requests that were still in progress when the
+    /// store was last used.
+    ///
+    /// Restoring is best-effort, requests that can't be restored are removed
+    /// from the store and won't prevent the machine from being created.
+    pub(crate) async fn restore_requests(&self) {
+        let pickles = match self.store.get_verification_requests().await {
+            Ok(p) => p,
+            Err(e) => {
+                warn!(error = ?e, "Couldn't load the stored verification requests");
+                return;
+            }
+        };
+
+        let mut changes = Changes::default();
+
+        for pickle in pickles {
+            let flow_id = pickle.flow_id().to_owned();
+            let other_user_id = pickle.other_user_id().to_owned();
+
+            match VerificationRequest::from_pickle(
+                self.verifications.clone(),
+                self.private_identity.lock().await.clone(),
+                self.store.clone(),
+                pickle,
+            )
+            .await
+            {
+                Ok(request) => {
+                    info!(
+                        other_user = other_user_id.as_str(),
+                        flow_id = flow_id.as_str(),
+                        "Restored a verification request"
+                    );
+
+                    self.insert_request(request);
+                    self.store.stored_requests().insert(flow_id);
+                }
+                Err(e) => {
+                    warn!(
+                        other_user = other_user_id.as_str(),
+                        flow_id = flow_id.as_str(),
+                        error = ?e,
+                        "Couldn't restore a verification request, removing it"
+                    );
+
+                    changes.deleted_verification_requests.push(flow_id);
+                }
+            }
+        }
+
+        if !changes.is_empty() {
+            if let Err(e) = self.store.save_changes(changes).await {
+                warn!(error = ?e, "Couldn't remove the verification requests that failed to restore");
+            }
+        }
+    }
+
+    /// Put the verification requests that are in progress into the given
+    /// changes, and remove the ones that are done or got cancelled from the
+    /// store.
+    pub(crate) fn collect_request_changes(&self, changes: &mut Changes) {
+        let pickles: Vec<PickledVerificationRequest> = self
+            .requests
+            .iter()
+            .flat_map(|r| r.value().iter().filter_map(|r| r.pickle()).collect::<Vec<_>>())
+            .collect();
+
+        let flow_ids: HashSet<String> = pickles.iter().map(|p| p.flow_id().to_owned()).collect();
+
+        let stored_requests = self.store.stored_requests();
+
+        changes.deleted_verification_requests.extend(
+            stored_requests
+                .iter()
+                .filter(|f| !flow_ids.contains(f.key()))
+                .map(|f| f.key().to_owned()),
+        );
+        stored_requests.retain(|f| flow_ids.contains(f));
+
+        for flow_id in flow_ids {
+            stored_requests.insert(flow_id);
+        }
+
+        changes.verification_requests.extend(pickles);
+    }
+
     pub(crate) fn own_user_id(&self) -> &UserId {
         self.store.account.user_id()
     }
@@ -126,6 +214,7 @@ impl VerificationMachine {
     pub async fn start_sas(
         &self,
         device: ReadOnlyDevice,
+        methods: &[SasMethod],
     ) -> Result<(Sas, OutgoingVerificationRequest), CryptoStoreError> {
         let identity = self.store.get_user_identity(device.user_id()).await?;
         let own_identity =
@@ -141,6 +230,7 @@ impl VerificationMachine {
             None,
             true,
             None,
+            methods,
         );
 
         let request = match content {
@@ -342,7 +432,15 @@ impl VerificationMachine {
                         "Received a new verification request",
                     );
 
-                    if let Some(timestamp) = event.timestamp() {
+                    if r.to().map_or(false, |to| to != self.own_user_id()) {
+                        // Anyone in a room can send an in-room request, only
+                        // the ones addressed to us concern us.
+                        trace!(
+                            sender = event.sender().as_str(),
+                            from_device = r.from_device().as_str(),
+                            "The received verification request isn't addressed to us, ignoring it",
+                        );
+                    } else if let Some(timestamp) = event.timestamp() {
                         if Self::is_timestamp_valid(timestamp) {
                             if !event_sent_from_us(&event, r.from_device()) {
                                 let request = VerificationRequest::from_request(
@@ -380,11 +478,16 @@ impl VerificationMachine {
                 }
                 AnyVerificationContent::Cancel(c) => {
                     if let Some(verification) = self.get_request(event.sender(), flow_id.as_str()) {
-                        verification.receive_cancel(event.sender(), c);
+                        if verification.flow_id() == &flow_id {
+                            verification.receive_cancel(event.sender(), c);
+                        } else {
+                            flow_id_mismatch();
+                        }
                     }
 
-                    if let Some(verification) =
-                        self.get_verification(event.sender(), flow_id.as_str())
+                    if let Some(verification) = self
+                        .get_verification(event.sender(), flow_id.as_str())
+                        .filter(|v| v.room_id() == flow_id.room_id())
                     {
                         match verification {
                             Verification::SasV1(sas) => {
@@ -479,11 +582,18 @@ impl VerificationMachine {
                 }
                 AnyVerificationContent::Done(c) => {
                     if let Some(verification) = self.get_request(event.sender(), flow_id.as_str()) {
-                        verification.receive_done(event.sender(), c);
+                        if verification.flow_id() == &flow_id {
+                            verification.receive_done(event.sender(), c);
+                        } else {
+                            flow_id_mismatch();
+                        }
                     }
 
                     #[allow(clippy::single_match)]
-                    match self.get_verification(event.sender(), flow_id.as_str()) {
+                    match self
+                        .get_verification(event.sender(), flow_id.as_str())
+                        .filter(|v| v.room_id() == flow_id.room_id())
+                    {
                         Some(Verification::SasV1(sas)) => {
                             let content = sas.receive_any_event(event.sender(), &content);
 
@@ -532,7 +642,7 @@ mod test {
         verification::{
             event_enums::{AcceptContent, KeyContent, MacContent, OutgoingContent},
             test::wrap_any_to_device_content,
-            VerificationStore,
+            VerificationStore, DEFAULT_SAS_METHODS,
         },
         ReadOnlyAccount, ReadOnlyDevice,
     };
@@ -565,7 +675,7 @@ mod test {
         store.save_devices(vec![bob_device]).await;
         bob_store.save_devices(vec![alice_device.clone()]).await;
 
-        let bob_store = VerificationStore { account: bob, inner: Arc::new(bob_store) };
+        let bob_store = VerificationStore::new(bob, Arc::new(bob_store));
 
         let identity =
             Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(alice_id().to_owned())));
@@ -579,6 +689,7 @@ mod test {
             None,
             true,
             None,
+            DEFAULT_SAS_METHODS,
         );
 
         machine
@@ -25,12 +25,13 @@ use std::{
     sync::Arc,
 };
 
+use dashmap::DashSet;
 use event_enums::OutgoingContent;
 pub use machine::VerificationMachine;
 use matrix_sdk_common::locks::Mutex;
 #[cfg(feature = "qrcode")]
 pub use qrcode::{QrVerification, ScanError};
-pub use requests::VerificationRequest;
+pub use requests::{PickledVerificationRequest, VerificationRequest};
 use ruma::{
     api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
     events::{
@@ -46,12 +47,15 @@ use ruma::{
     },
     DeviceId, DeviceKeyId, EventId, RoomId, UserId,
 };
-pub use sas::{AcceptSettings, Sas};
+pub(crate) use sas::DEFAULT_SAS_METHODS;
+pub use sas::{AcceptSettings, Sas, SasMethod};
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, trace, warn};
 
 use crate::{
     error::SignatureError,
     gossiping::{GossipMachine, GossipRequest},
+    identities::TrustReportCache,
     olm::{PrivateCrossSigningIdentity, ReadOnlyAccount, Session},
     store::{Changes, CryptoStore},
     CryptoStoreError, LocalTrust, ReadOnlyDevice, ReadOnlyUserIdentities,
@@ -61,6 +65,9 @@ use crate::{
 pub(crate) struct VerificationStore {
     pub account: ReadOnlyAccount,
     inner: Arc<dyn CryptoStore>,
+    trust_reports: TrustReportCache,
+    /// The flow ids of the verification requests that are currently stored.
+    stored_requests: Arc<DashSet<String>>,
 }
 
 /// An emoji that is used for interactive verification using a short auth
@@ -80,6 +87,15 @@ pub struct Emoji {
 }
 
 impl VerificationStore {
+    pub fn new(account: ReadOnlyAccount, inner: Arc<dyn CryptoStore>) -> Self {
+        Self {
+            account,
+            inner,
+            trust_reports: TrustReportCache::default(),
+            stored_requests: Default::default(),
+        }
+    }
+
     pub async fn get_device(
         &self,
         user_id: &UserId,
@@ -98,7 +114,33 @@ impl VerificationStore {
     }
 
     pub async fn save_changes(&self, changes: Changes) -> Result<(), CryptoStoreError> {
-        self.inner.save_changes(changes).await
+        let identities = changes.identities.clone();
+        let devices = changes.devices.clone();
+
+        let result = self.inner.save_changes(changes).await;
+        self.trust_reports.apply_changes(self.account.user_id(), &identities, &devices);
+
+        result
+    }
+
+    pub async fn get_verification_requests(
+        &self,
+    ) -> Result<Vec<PickledVerificationRequest>, CryptoStoreError> {
+        self.inner.get_verification_requests().await
+    }
+
+    pub async fn save_verification_request(
+        &self,
+        pickle: PickledVerificationRequest,
+    ) -> Result<(), CryptoStoreError> {
+        self.stored_requests.insert(pickle.flow_id().to_owned());
+
+        let changes = Changes { verification_requests: vec![pickle], ..Default::default() };
+        self.save_changes(changes).await
+    }
+
+    pub fn stored_requests(&self) -> &DashSet<String> {
+        &self.stored_requests
     }
 
     pub async fn get_user_devices(
@@ -130,6 +172,10 @@ impl VerificationStore {
     pub fn inner(&self) -> &dyn CryptoStore {
         &*self.inner
     }
+
+    pub fn trust_reports(&self) -> &TrustReportCache {
+        &self.trust_reports
+    }
 }
 
 /// An enum over the different verification types the SDK supports.
@@ -181,6 +227,16 @@ impl Verification {
         }
     }
 
+    /// Get the room id of the room this verification takes place in, if it's
+    /// an in-room verification.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            Verification::SasV1(s) => s.room_id(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.room_id(),
+        }
+    }
+
     /// Has the verification been cancelled.
     pub fn is_cancelled(&self) -> bool {
         match self {
@@ -349,7 +405,7 @@ impl Cancelled {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, Hash, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum FlowId {
     ToDevice(String),
     InRoom(Box<RoomId>, Box<EventId>),
@@ -584,11 +640,15 @@ impl IdentitiesBeingVerified {
                         "Marking the user identity of as verified."
                     );
 
-                    let should_request_secrets = if let ReadOnlyUserIdentities::Own(i) = &identity {
-                        i.mark_as_verified();
-                        true
-                    } else {
-                        false
+                    let should_request_secrets = match &identity {
+                        ReadOnlyUserIdentities::Own(i) => {
+                            i.mark_as_verified();
+                            true
+                        }
+                        ReadOnlyUserIdentities::Other(i) => {
+                            i.mark_as_previously_verified();
+                            false
+                        }
                     };
 
                     (Some(identity), should_request_secrets)
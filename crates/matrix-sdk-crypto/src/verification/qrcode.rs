@@ -35,8 +35,9 @@ use ruma::{
     },
     DeviceId, DeviceKeyAlgorithm, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::trace;
+use tracing::{trace, warn};
 
 use super::{
     event_enums::{CancelContent, DoneContent, OutgoingContent, OwnedStartContent, StartContent},
@@ -604,6 +605,69 @@ impl QrVerification {
         })
     }
 
+    /// Create a snapshot of this QR code verification that can be stored and
+    /// used to resume the verification flow after a restart.
+    ///
+    /// Returns `None` if the verification flow already finished.
+    pub(crate) fn pickle(&self) -> Option<PickledQrVerification> {
+        let state = match &*self.state.lock().unwrap() {
+            InnerState::Created(s) => PickledQrState::Created { secret: s.state.secret.clone() },
+            InnerState::Scanned(_) => PickledQrState::Scanned,
+            InnerState::Confirmed(_) => PickledQrState::Confirmed,
+            InnerState::Reciprocated(s) => PickledQrState::Reciprocated {
+                own_device_id: s.state.own_device_id.clone(),
+                secret: s.state.secret.clone(),
+            },
+            InnerState::Done(_) | InnerState::Cancelled(_) => return None,
+        };
+
+        let data = self.inner.to_bytes().ok()?;
+
+        Some(PickledQrVerification { data: crate::utilities::encode(data), state })
+    }
+
+    /// Restore a QR code verification from a previously created pickle.
+    ///
+    /// Returns `None` if the pickle doesn't contain a valid QR code.
+    pub(crate) fn from_pickle(
+        flow_id: FlowId,
+        pickle: PickledQrVerification,
+        identities: IdentitiesBeingVerified,
+        we_started: bool,
+        request_handle: Option<RequestHandle>,
+    ) -> Option<Self> {
+        let inner = match crate::utilities::decode(&pickle.data)
+            .ok()
+            .and_then(|d| QrVerificationData::from_bytes(d).ok())
+        {
+            Some(d) => d,
+            None => {
+                warn!(flow_id = flow_id.as_str(), "Can't restore a QR code verification");
+                return None;
+            }
+        };
+
+        let state = match pickle.state {
+            PickledQrState::Created { secret } => {
+                InnerState::Created(QrState { state: Created { secret } })
+            }
+            PickledQrState::Scanned => InnerState::Scanned(QrState { state: Scanned {} }),
+            PickledQrState::Confirmed => InnerState::Confirmed(QrState { state: Confirmed {} }),
+            PickledQrState::Reciprocated { own_device_id, secret } => {
+                InnerState::Reciprocated(QrState { state: Reciprocated { own_device_id, secret } })
+            }
+        };
+
+        Some(Self {
+            flow_id,
+            inner: inner.into(),
+            state: Mutex::new(state).into(),
+            identities,
+            we_started,
+            request_handle,
+        })
+    }
+
     fn new_helper(
         flow_id: FlowId,
         inner: QrVerificationData,
@@ -624,6 +688,22 @@ impl QrVerification {
     }
 }
 
+/// A serializable snapshot of a QR code verification flow.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct PickledQrVerification {
+    /// The base64 encoded data of the QR code.
+    data: String,
+    state: PickledQrState,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum PickledQrState {
+    Created { secret: String },
+    Scanned,
+    Confirmed,
+    Reciprocated { own_device_id: Box<DeviceId>, secret: String },
+}
+
 #[derive(Debug, Clone)]
 enum InnerState {
     Created(QrState<Created>),
@@ -827,7 +907,7 @@ mod test {
         let store = memory_store();
         let account = ReadOnlyAccount::new(user_id(), device_id());
 
-        let store = VerificationStore { account: account.clone(), inner: store };
+        let store = VerificationStore::new(account.clone(), store);
 
         let private_identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
         let flow_id = FlowId::ToDevice("test_transaction".to_owned());
@@ -896,7 +976,7 @@ mod test {
             let alice_account = ReadOnlyAccount::new(user_id(), device_id());
             let store = memory_store();
 
-            let store = VerificationStore { account: alice_account.clone(), inner: store };
+            let store = VerificationStore::new(alice_account.clone(), store);
 
             let bob_account =
                 ReadOnlyAccount::new(alice_account.user_id(), device_id!("BOBDEVICE"));
@@ -935,7 +1015,7 @@ mod test {
 
             let bob_store = memory_store();
 
-            let bob_store = VerificationStore { account: bob_account.clone(), inner: bob_store };
+            let bob_store = VerificationStore::new(bob_account.clone(), bob_store);
 
             let mut changes = Changes::default();
             changes.identities.new.push(identity.into());
@@ -35,8 +35,9 @@ use ruma::{
         AnyMessageEventContent, AnyToDeviceEventContent,
     },
     to_device::DeviceIdOrAllDevices,
-    DeviceId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tracing::{info, trace, warn};
 
 use super::{
@@ -44,11 +45,12 @@ use super::{
     event_enums::{
         CancelContent, DoneContent, OutgoingContent, ReadyContent, RequestContent, StartContent,
     },
+    sas::{PickledSas, SasMethod, DEFAULT_SAS_METHODS},
     CancelInfo, Cancelled, FlowId, VerificationStore,
 };
 #[cfg(feature = "qrcode")]
 use super::{
-    qrcode::{QrVerification, ScanError},
+    qrcode::{PickledQrVerification, QrVerification, ScanError},
     IdentitiesBeingVerified,
 };
 use crate::{
@@ -77,6 +79,7 @@ const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(60 * 10);
 pub struct VerificationRequest {
     verification_cache: VerificationCache,
     account: ReadOnlyAccount,
+    store: VerificationStore,
     flow_id: Arc<FlowId>,
     other_user_id: Arc<UserId>,
     inner: Arc<Mutex<InnerRequest>>,
@@ -85,6 +88,81 @@ pub struct VerificationRequest {
     recipient_devices: Arc<Vec<Box<DeviceId>>>,
 }
 
+/// A serializable snapshot of a verification request that is still in
+/// progress.
+///
+/// The snapshot contains the state of the request and of the verification flow
+/// the request transitioned into, it's used to resume the verification after a
+/// restart.
+///
+/// SAS verifications can only be resumed until the ephemeral keys are
+/// exchanged, the keys of the underlying SAS object can't be pickled. A request
+/// whose SAS verification exchanged its keys already gets cancelled with a
+/// `m.unknown_transaction` cancel code once it's restored, so the other side
+/// knows it needs to start over.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PickledVerificationRequest {
+    flow_id: FlowId,
+    other_user_id: Box<UserId>,
+    we_started: bool,
+    recipient_devices: Vec<Box<DeviceId>>,
+    creation_time: MilliSecondsSinceUnixEpoch,
+    state: PickledRequestState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verification: Option<PickledVerification>,
+}
+
+impl PickledVerificationRequest {
+    /// The unique ID of the verification request.
+    pub fn flow_id(&self) -> &str {
+        self.flow_id.as_str()
+    }
+
+    /// The id of the other user that is participating in the verification
+    /// request.
+    pub fn other_user_id(&self) -> &UserId {
+        &self.other_user_id
+    }
+
+    fn elapsed(&self) -> Duration {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let then: u64 = self.creation_time.get().into();
+
+        Duration::from_millis(now.saturating_sub(then))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum PickledRequestState {
+    Created {
+        our_methods: Vec<VerificationMethod>,
+    },
+    Requested {
+        their_methods: Vec<VerificationMethod>,
+        other_device_id: Box<DeviceId>,
+    },
+    Ready {
+        their_methods: Vec<VerificationMethod>,
+        our_methods: Vec<VerificationMethod>,
+        other_device_id: Box<DeviceId>,
+    },
+    Passive {
+        other_device_id: Box<DeviceId>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum PickledVerification {
+    /// The ephemeral keys of a SAS verification can't be stored, for a SAS
+    /// verification that already exchanged them we only remember that it was
+    /// in progress.
+    Sas,
+    /// A SAS verification that didn't exchange its ephemeral keys yet.
+    ResumableSas(PickledSas),
+    #[cfg(feature = "qrcode")]
+    Qr(PickledQrVerification),
+}
+
 /// A handle to a request so child verification flows can cancel the request.
 ///
 /// A verification flow can branch off into different types of verification
@@ -125,7 +203,7 @@ impl VerificationRequest {
         let inner = Mutex::new(InnerRequest::Created(RequestState::new(
             private_cross_signing_identity,
             cache.clone(),
-            store,
+            store.clone(),
             other_user,
             &flow_id,
             methods,
@@ -134,6 +212,7 @@ impl VerificationRequest {
 
         Self {
             account,
+            store,
             verification_cache: cache,
             flow_id: flow_id.into(),
             inner,
@@ -313,7 +392,14 @@ impl VerificationRequest {
     pub async fn generate_qr_code(&self) -> Result<Option<QrVerification>, CryptoStoreError> {
         let inner = self.inner.lock().unwrap().clone();
 
-        inner.generate_qr_code(self.we_started, self.inner.clone().into()).await
+        let qr_verification =
+            inner.generate_qr_code(self.we_started, self.inner.clone().into()).await?;
+
+        if qr_verification.is_some() {
+            self.save().await?;
+        }
+
+        Ok(qr_verification)
     }
 
     /// Start a QR code verification by providing a scanned QR code for this
@@ -346,6 +432,7 @@ impl VerificationRequest {
         if let Some(future) = fut {
             let qr_verification = future.await?;
             self.verification_cache.insert_qr(qr_verification.clone());
+            self.save().await?;
 
             Ok(Some(qr_verification))
         } else {
@@ -368,12 +455,13 @@ impl VerificationRequest {
             inner: Arc::new(Mutex::new(InnerRequest::Requested(RequestState::from_request_event(
                 private_cross_signing_identity,
                 cache,
-                store,
+                store.clone(),
                 sender,
                 &flow_id,
                 content,
             )))),
             account,
+            store,
             other_user_id: sender.to_owned().into(),
             flow_id: flow_id.into(),
             we_started: false,
@@ -382,6 +470,235 @@ impl VerificationRequest {
         }
     }
 
+    /// Create a snapshot of this verification request that can be stored and
+    /// used to resume the request after a restart.
+    ///
+    /// Returns `None` if the request is already done or cancelled.
+    pub(crate) fn pickle(&self) -> Option<PickledVerificationRequest> {
+        let state = match &*self.inner.lock().unwrap() {
+            InnerRequest::Created(s) => {
+                PickledRequestState::Created { our_methods: s.state.our_methods.clone() }
+            }
+            InnerRequest::Requested(s) => PickledRequestState::Requested {
+                their_methods: s.state.their_methods.clone(),
+                other_device_id: s.state.other_device_id.clone(),
+            },
+            InnerRequest::Ready(s) => PickledRequestState::Ready {
+                their_methods: s.state.their_methods.clone(),
+                our_methods: s.state.our_methods.clone(),
+                other_device_id: s.state.other_device_id.clone(),
+            },
+            InnerRequest::Passive(s) => {
+                PickledRequestState::Passive { other_device_id: s.state.other_device_id.clone() }
+            }
+            InnerRequest::Done(_) | InnerRequest::Cancelled(_) => return None,
+        };
+
+        let verification =
+            match self.verification_cache.get(self.other_user(), self.flow_id().as_str()) {
+                Some(crate::Verification::SasV1(s)) if !(s.is_done() || s.is_cancelled()) => Some(
+                    s.pickle().map_or(PickledVerification::Sas, PickledVerification::ResumableSas),
+                ),
+                #[cfg(feature = "qrcode")]
+                Some(crate::Verification::QrV1(q)) => q.pickle().map(PickledVerification::Qr),
+                _ => None,
+            };
+
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let elapsed = self.creation_time.elapsed().as_millis() as u64;
+        let creation_time =
+            MilliSecondsSinceUnixEpoch(UInt::new_saturating(now.saturating_sub(elapsed)));
+
+        Some(PickledVerificationRequest {
+            flow_id: self.flow_id().to_owned(),
+            other_user_id: self.other_user().to_owned(),
+            we_started: self.we_started,
+            recipient_devices: self.recipient_devices.to_vec(),
+            creation_time,
+            state,
+            verification,
+        })
+    }
+
+    /// Restore a verification request from a pickle.
+    ///
+    /// Requests that timed out while we weren't running get cancelled, as do
+    /// SAS verifications that already exchanged their ephemeral keys since
+    /// those don't survive a restart. The cancellations are queued up to be
+    /// sent out to the other side.
+    pub(crate) async fn from_pickle(
+        cache: VerificationCache,
+        private_cross_signing_identity: PrivateCrossSigningIdentity,
+        store: VerificationStore,
+        pickle: PickledVerificationRequest,
+    ) -> Result<Self, CryptoStoreError> {
+        let elapsed = pickle.elapsed();
+        let flow_id: Arc<FlowId> = pickle.flow_id.into();
+
+        let restored = RequestState {
+            private_cross_signing_identity,
+            verification_cache: cache.clone(),
+            store: store.clone(),
+            flow_id: flow_id.clone(),
+            other_user_id: pickle.other_user_id.clone(),
+            state: (),
+        };
+
+        let inner = match pickle.state {
+            PickledRequestState::Created { our_methods } => {
+                InnerRequest::Created(restored.with_state(Created { our_methods }))
+            }
+            PickledRequestState::Requested { their_methods, other_device_id } => {
+                InnerRequest::Requested(
+                    restored.with_state(Requested { their_methods, other_device_id }),
+                )
+            }
+            PickledRequestState::Ready { their_methods, our_methods, other_device_id } => {
+                InnerRequest::Ready(restored.with_state(Ready {
+                    their_methods,
+                    our_methods,
+                    other_device_id,
+                }))
+            }
+            PickledRequestState::Passive { other_device_id } => {
+                InnerRequest::Passive(restored.with_state(Passive { other_device_id }))
+            }
+        };
+
+        let request = Self {
+            account: store.account.clone(),
+            store,
+            verification_cache: cache.clone(),
+            flow_id,
+            other_user_id: pickle.other_user_id.into(),
+            inner: Arc::new(Mutex::new(inner)),
+            // The monotonic clock might have been reset by a reboot, in that
+            // case the timeout restarts as well.
+            creation_time: Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now).into(),
+            we_started: pickle.we_started,
+            recipient_devices: pickle.recipient_devices.into(),
+        };
+
+        let cancel_code = if elapsed > VERIFICATION_TIMEOUT {
+            Some(CancelCode::Timeout)
+        } else {
+            match pickle.verification {
+                Some(PickledVerification::Sas) => Some(CancelCode::UnknownTransaction),
+                Some(PickledVerification::ResumableSas(sas)) => {
+                    if request.restore_sas(sas).await? {
+                        None
+                    } else {
+                        Some(CancelCode::UnknownTransaction)
+                    }
+                }
+                #[cfg(feature = "qrcode")]
+                Some(PickledVerification::Qr(qr)) => {
+                    if request.restore_qr_code(qr).await? {
+                        None
+                    } else {
+                        Some(CancelCode::UnknownTransaction)
+                    }
+                }
+                None => None,
+            }
+        };
+
+        if let Some(code) = cancel_code {
+            trace!(
+                other_user = request.other_user().as_str(),
+                flow_id = request.flow_id().as_str(),
+                code = code.as_str(),
+                "Cancelling a verification request that can't be resumed"
+            );
+
+            if let Some(r) = request.cancel_with_code(code) {
+                cache.add_verification_request(r);
+            }
+        }
+
+        Ok(request)
+    }
+
+    async fn restore_sas(&self, pickle: PickledSas) -> Result<bool, CryptoStoreError> {
+        let state = if let InnerRequest::Ready(s) = &*self.inner.lock().unwrap() {
+            s.clone()
+        } else {
+            return Ok(false);
+        };
+
+        let device = if let Some(device) =
+            state.store.get_device(&state.other_user_id, &state.state.other_device_id).await?
+        {
+            device
+        } else {
+            return Ok(false);
+        };
+
+        let other_identity = state.store.get_user_identity(&state.other_user_id).await?;
+        let own_identity = state
+            .store
+            .get_user_identity(state.store.account.user_id())
+            .await?
+            .and_then(|i| i.into_own());
+
+        if let Some(sas) = Sas::from_pickle(
+            self.flow_id().to_owned(),
+            pickle,
+            state.store.clone(),
+            state.private_cross_signing_identity.clone(),
+            device,
+            own_identity,
+            other_identity,
+            self.we_started,
+            self.inner.clone().into(),
+        ) {
+            self.verification_cache.insert_sas(sas);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    #[cfg(feature = "qrcode")]
+    async fn restore_qr_code(
+        &self,
+        pickle: PickledQrVerification,
+    ) -> Result<bool, CryptoStoreError> {
+        let state = if let InnerRequest::Ready(s) = &*self.inner.lock().unwrap() {
+            s.clone()
+        } else {
+            return Ok(false);
+        };
+
+        let device = if let Some(device) =
+            state.store.get_device(&state.other_user_id, &state.state.other_device_id).await?
+        {
+            device
+        } else {
+            return Ok(false);
+        };
+
+        let identities = IdentitiesBeingVerified {
+            private_identity: state.private_cross_signing_identity.clone(),
+            store: state.store.clone(),
+            device_being_verified: device,
+            identity_being_verified: state.store.get_user_identity(&state.other_user_id).await?,
+        };
+
+        if let Some(qr) = QrVerification::from_pickle(
+            self.flow_id().to_owned(),
+            pickle,
+            identities,
+            self.we_started,
+            Some(self.inner.clone().into()),
+        ) {
+            self.verification_cache.insert_qr(qr);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Accept the verification request signaling that our client supports the
     /// given verification methods.
     ///
@@ -420,6 +737,23 @@ impl VerificationRequest {
         self.accept_with_methods(SUPPORTED_METHODS.to_vec())
     }
 
+    /// Store the current state of the verification request, so it can be
+    /// resumed after a restart.
+    ///
+    /// The async methods that move the request forward, e.g. [`start_sas()`],
+    /// do this on their own. Since [`accept()`] isn't async, this should be
+    /// called once the request it returned was sent out.
+    ///
+    /// [`start_sas()`]: #method.start_sas
+    /// [`accept()`]: #method.accept
+    pub async fn save(&self) -> Result<(), CryptoStoreError> {
+        if let Some(pickle) = self.pickle() {
+            self.store.save_verification_request(pickle).await?;
+        }
+
+        Ok(())
+    }
+
     /// Cancel the verification request
     pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
         self.cancel_with_code(CancelCode::User)
@@ -631,6 +965,23 @@ impl VerificationRequest {
     /// Transition from this verification request into a SAS verification flow.
     pub async fn start_sas(
         &self,
+    ) -> Result<Option<(Sas, OutgoingVerificationRequest)>, CryptoStoreError> {
+        self.start_sas_with_methods(DEFAULT_SAS_METHODS).await
+    }
+
+    /// Transition from this verification request into a SAS verification flow
+    /// that only offers the given short auth string methods.
+    ///
+    /// The decimal method is always offered, since the spec requires every
+    /// client to support it.
+    ///
+    /// # Arguments
+    ///
+    /// * `methods` - The methods the client is able to display the short auth
+    /// string with.
+    pub async fn start_sas_with_methods(
+        &self,
+        methods: &[SasMethod],
     ) -> Result<Option<(Sas, OutgoingVerificationRequest)>, CryptoStoreError> {
         let inner = self.inner.lock().unwrap().clone();
 
@@ -643,10 +994,12 @@ impl VerificationRequest {
                         s.private_cross_signing_identity.clone(),
                         self.we_started,
                         self.inner.clone().into(),
+                        methods,
                     )
                     .await?
                 {
                     self.verification_cache.insert_sas(sas.clone());
+                    self.save().await?;
 
                     let request = match content {
                         OutgoingContent::ToDevice(content) => ToDeviceRequest::new(
@@ -774,6 +1127,17 @@ struct RequestState<S: Clone> {
 }
 
 impl<S: Clone> RequestState<S> {
+    fn with_state<T: Clone>(self, state: T) -> RequestState<T> {
+        RequestState {
+            private_cross_signing_identity: self.private_cross_signing_identity,
+            verification_cache: self.verification_cache,
+            store: self.store,
+            flow_id: self.flow_id,
+            other_user_id: self.other_user_id,
+            state,
+        }
+    }
+
     fn into_done(self, _: &DoneContent) -> RequestState<Done> {
         RequestState::<Done> {
             private_cross_signing_identity: self.private_cross_signing_identity,
@@ -1195,6 +1559,7 @@ impl RequestState<Ready> {
         private_identity: PrivateCrossSigningIdentity,
         we_started: bool,
         request_handle: RequestHandle,
+        methods: &[SasMethod],
     ) -> Result<Option<(Sas, OutgoingContent)>, CryptoStoreError> {
         if !self.state.their_methods.contains(&VerificationMethod::SasV1) {
             return Ok(None);
@@ -1233,6 +1598,7 @@ impl RequestState<Ready> {
                     Some(t.to_owned()),
                     we_started,
                     Some(request_handle),
+                    methods,
                 );
                 (sas, content)
             }
@@ -1247,6 +1613,7 @@ impl RequestState<Ready> {
                     other_identity,
                     we_started,
                     request_handle,
+                    methods,
                 );
                 (sas, content)
             }
@@ -1257,7 +1624,6 @@ impl RequestState<Ready> {
 #[derive(Clone, Debug)]
 struct Passive {
     /// The device id of the device that responded to the verification request.
-    #[allow(dead_code)]
     pub other_device_id: Box<DeviceId>,
 }
 
@@ -1269,15 +1635,21 @@ mod test {
     use std::convert::{TryFrom, TryInto};
 
     use matrix_sdk_test::async_test;
-    use ruma::{device_id, event_id, room_id, user_id, DeviceId, UserId};
+    use ruma::{
+        device_id, event_id, events::key::verification::cancel::CancelCode, room_id, uint, user_id,
+        DeviceId, MilliSecondsSinceUnixEpoch, UserId,
+    };
 
-    use super::VerificationRequest;
+    use super::{PickledVerificationRequest, VerificationRequest};
     use crate::{
         olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
         store::{Changes, CryptoStore, MemoryStore},
         verification::{
             cache::VerificationCache,
-            event_enums::{OutgoingContent, ReadyContent, RequestContent, StartContent},
+            event_enums::{
+                AcceptContent, KeyContent, OutgoingContent, ReadyContent, RequestContent,
+                StartContent,
+            },
             FlowId, VerificationStore,
         },
         ReadOnlyDevice,
@@ -1308,13 +1680,13 @@ mod test {
         let alice_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
         let alice_identity = PrivateCrossSigningIdentity::empty(alice_id().to_owned());
 
-        let alice_store = VerificationStore { account: alice, inner: alice_store.into() };
+        let alice_store = VerificationStore::new(alice, alice_store.into());
 
         let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
         let bob_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
         let bob_identity = PrivateCrossSigningIdentity::empty(alice_id().to_owned());
 
-        let bob_store = VerificationStore { account: bob.clone(), inner: bob_store.into() };
+        let bob_store = VerificationStore::new(bob.clone(), bob_store.into());
 
         let content =
             VerificationRequest::request(bob.user_id(), bob.device_id(), alice_id(), None);
@@ -1360,14 +1732,14 @@ mod test {
         let alice_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
         let alice_identity = PrivateCrossSigningIdentity::empty(alice_id().to_owned());
 
-        let alice_store = VerificationStore { account: alice.clone(), inner: alice_store.into() };
+        let alice_store = VerificationStore::new(alice.clone(), alice_store.into());
 
         let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
         let bob_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
         let bob_identity = PrivateCrossSigningIdentity::empty(alice_id().to_owned());
 
-        let bob_store = VerificationStore { account: bob.clone(), inner: bob_store.into() };
+        let bob_store = VerificationStore::new(bob.clone(), bob_store.into());
 
         let mut changes = Changes::default();
         changes.devices.new.push(bob_device.clone());
@@ -1410,6 +1782,12 @@ mod test {
 
         let (bob_sas, request) = bob_request.start_sas().await.unwrap().unwrap();
 
+        // Starting the SAS verification is a local action, the request needs
+        // to be stored without waiting for a sync.
+        let stored = bob_request.store.get_verification_requests().await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].flow_id(), bob_request.flow_id().as_str());
+
         let content: OutgoingContent = request.try_into().unwrap();
         let content = StartContent::try_from(&content).unwrap();
         let flow_id = content.flow_id().to_owned();
@@ -1429,7 +1807,7 @@ mod test {
         let alice_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
         let alice_identity = PrivateCrossSigningIdentity::empty(alice_id().to_owned());
 
-        let alice_store = VerificationStore { account: alice.clone(), inner: alice_store.into() };
+        let alice_store = VerificationStore::new(alice.clone(), alice_store.into());
 
         let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
@@ -1444,7 +1822,7 @@ mod test {
         changes.devices.new.push(alice_device.clone());
         bob_store.save_changes(changes).await.unwrap();
 
-        let bob_store = VerificationStore { account: bob.clone(), inner: bob_store.into() };
+        let bob_store = VerificationStore::new(bob.clone(), bob_store.into());
 
         let flow_id = FlowId::from("TEST_FLOW_ID".to_owned());
 
@@ -1492,4 +1870,153 @@ mod test {
         assert!(!bob_sas.is_cancelled());
         assert!(!alice_sas.is_cancelled());
     }
+
+    #[async_test]
+    async fn test_request_pickling() {
+        let alice = ReadOnlyAccount::new(alice_id(), alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let alice_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let alice_identity = PrivateCrossSigningIdentity::empty(alice_id().to_owned());
+        let alice_store = VerificationStore::new(alice.clone(), alice_store.into());
+
+        let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
+        let bob_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let bob_identity = PrivateCrossSigningIdentity::empty(bob_id().to_owned());
+        let bob_store = VerificationStore::new(bob.clone(), bob_store.into());
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(alice_device);
+        bob_store.save_changes(changes).await.unwrap();
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(ReadOnlyDevice::from_account(&bob).await);
+        alice_store.save_changes(changes).await.unwrap();
+
+        let bob_request = VerificationRequest::new(
+            VerificationCache::new(),
+            bob_identity.clone(),
+            bob_store.clone(),
+            FlowId::from("TEST_FLOW_ID".to_owned()),
+            alice_id(),
+            vec![],
+            None,
+        );
+
+        let content: OutgoingContent = bob_request.request_to_device().try_into().unwrap();
+        let content = RequestContent::try_from(&content).unwrap();
+
+        let alice_request = VerificationRequest::from_request(
+            VerificationCache::new(),
+            alice_identity.clone(),
+            alice_store.clone(),
+            bob_id(),
+            bob_request.flow_id().to_owned(),
+            &content,
+        );
+
+        let content: OutgoingContent = alice_request.accept().unwrap().try_into().unwrap();
+        let content = ReadyContent::try_from(&content).unwrap();
+        bob_request.receive_ready(alice_id(), &content);
+
+        let pickle = bob_request.pickle().unwrap();
+        let pickle: PickledVerificationRequest =
+            serde_json::from_str(&serde_json::to_string(&pickle).unwrap()).unwrap();
+        assert_eq!(pickle.flow_id(), "TEST_FLOW_ID");
+
+        let cache = VerificationCache::new();
+        let restored = VerificationRequest::from_pickle(
+            cache.clone(),
+            bob_identity.clone(),
+            bob_store.clone(),
+            pickle.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert!(restored.is_ready());
+        assert!(restored.we_started());
+        assert_eq!(restored.other_device_id().as_deref(), Some(alice_device_id()));
+        assert!(cache.outgoing_requests().is_empty());
+
+        // Requests that timed out while we weren't running get cancelled.
+        let mut expired = pickle;
+        expired.creation_time = MilliSecondsSinceUnixEpoch(uint!(0));
+
+        let cache = VerificationCache::new();
+        let restored = VerificationRequest::from_pickle(
+            cache.clone(),
+            bob_identity.clone(),
+            bob_store.clone(),
+            expired,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(restored.cancel_info().unwrap().cancel_code(), &CancelCode::Timeout);
+        assert_eq!(cache.outgoing_requests().len(), 1);
+
+        // SAS verifications that didn't exchange their ephemeral keys yet get
+        // resumed.
+        let (_, request) = bob_request.start_sas().await.unwrap().unwrap();
+        let pickle = bob_request.pickle().unwrap();
+        let pickle: PickledVerificationRequest =
+            serde_json::from_str(&serde_json::to_string(&pickle).unwrap()).unwrap();
+
+        let bob_cache = VerificationCache::new();
+        let restored = VerificationRequest::from_pickle(
+            bob_cache.clone(),
+            bob_identity.clone(),
+            bob_store.clone(),
+            pickle,
+        )
+        .await
+        .unwrap();
+        let bob_sas = bob_cache.get_sas(alice_id(), "TEST_FLOW_ID").unwrap();
+
+        assert!(!restored.is_cancelled());
+        assert!(!bob_sas.is_cancelled());
+        assert!(bob_cache.outgoing_requests().is_empty());
+
+        let content: OutgoingContent = request.try_into().unwrap();
+        let content = StartContent::try_from(&content).unwrap();
+        alice_request.receive_start(bob_id(), &content).await.unwrap();
+
+        let pickle = alice_request.pickle().unwrap();
+        let alice_cache = VerificationCache::new();
+        VerificationRequest::from_pickle(alice_cache.clone(), alice_identity, alice_store, pickle)
+            .await
+            .unwrap();
+        let alice_sas = alice_cache.get_sas(bob_id(), "TEST_FLOW_ID").unwrap();
+
+        assert!(!alice_sas.is_cancelled());
+        assert!(alice_cache.outgoing_requests().is_empty());
+
+        // Both of the resumed verifications can be finished.
+        let content = OutgoingContent::try_from(alice_sas.accept().unwrap()).unwrap();
+        let content = AcceptContent::try_from(&content).unwrap();
+        let content = bob_sas.receive_any_event(alice_id(), &content.into()).unwrap();
+
+        let content = KeyContent::try_from(&content).unwrap();
+        let content = alice_sas.receive_any_event(bob_id(), &content.into()).unwrap();
+
+        let content = KeyContent::try_from(&content).unwrap();
+        bob_sas.receive_any_event(alice_id(), &content.into());
+
+        assert!(!bob_sas.is_cancelled());
+        assert_eq!(alice_sas.decimals().unwrap(), bob_sas.decimals().unwrap());
+
+        // Once the keys are exchanged the verification can't be resumed
+        // anymore, it gets cancelled.
+        let pickle = restored.pickle().unwrap();
+
+        let cache = VerificationCache::new();
+        let restored =
+            VerificationRequest::from_pickle(cache.clone(), bob_identity, bob_store, pickle)
+                .await
+                .unwrap();
+
+        assert_eq!(restored.cancel_info().unwrap().cancel_code(), &CancelCode::UnknownTransaction);
+        assert_eq!(cache.outgoing_requests().len(), 1);
+    }
 }
@@ -14,7 +14,6 @@
 
 use std::{collections::BTreeMap, convert::TryInto};
 
-use olm_rs::sas::OlmSas;
 use ruma::{
     events::{
         key::verification::{
@@ -32,6 +31,7 @@ use tracing::{trace, warn};
 use super::{FlowId, OutgoingContent};
 use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
+    olm::RawSas,
     utilities::encode,
     verification::event_enums::{MacContent, StartContent},
     Emoji, ReadOnlyAccount, ReadOnlyOwnUserIdentity,
@@ -178,7 +178,7 @@ fn extra_mac_info_receive(ids: &SasIds, flow_id: &str) -> String {
 /// * `event` - The m.key.verification.mac event that was sent to us by
 /// the other side.
 pub fn receive_mac_event(
-    sas: &OlmSas,
+    sas: &RawSas,
     ids: &SasIds,
     flow_id: &str,
     sender: &UserId,
@@ -293,7 +293,7 @@ fn extra_mac_info_send(ids: &SasIds, flow_id: &str) -> String {
 /// # Panics
 ///
 /// This will panic if the public key of the other side wasn't set.
-pub fn get_mac_content(sas: &OlmSas, ids: &SasIds, flow_id: &FlowId) -> OutgoingContent {
+pub fn get_mac_content(sas: &RawSas, ids: &SasIds, flow_id: &FlowId) -> OutgoingContent {
     let mut mac: BTreeMap<String, String> = BTreeMap::new();
 
     let key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, ids.account.device_id());
@@ -400,7 +400,7 @@ fn extra_info_sas(
 ///
 /// This will panic if the public key of the other side wasn't set.
 pub fn get_emoji(
-    sas: &OlmSas,
+    sas: &RawSas,
     ids: &SasIds,
     their_pubkey: &str,
     flow_id: &str,
@@ -437,7 +437,7 @@ pub fn get_emoji(
 ///
 /// This will panic if the public key of the other side wasn't set.
 pub fn get_emoji_index(
-    sas: &OlmSas,
+    sas: &RawSas,
     ids: &SasIds,
     their_pubkey: &str,
     flow_id: &str,
@@ -512,7 +512,7 @@ fn bytes_to_emoji(bytes: Vec<u8>) -> [Emoji; 7] {
 ///
 /// This will panic if the public key of the other side wasn't set.
 pub fn get_decimal(
-    sas: &OlmSas,
+    sas: &RawSas,
     ids: &SasIds,
     their_pubkey: &str,
     flow_id: &str,
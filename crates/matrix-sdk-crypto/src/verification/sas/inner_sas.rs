@@ -26,12 +26,15 @@ use super::{
         Accepted, Confirmed, Created, KeyReceived, MacReceived, SasState, Started, WaitingForDone,
         WeAccepted,
     },
-    FlowId,
+    FlowId, PickledSas,
 };
 use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
     verification::{
-        event_enums::{AnyVerificationContent, OutgoingContent, OwnedAcceptContent, StartContent},
+        event_enums::{
+            AnyVerificationContent, OutgoingContent, OwnedAcceptContent, OwnedStartContent,
+            StartContent,
+        },
         Cancelled, Done,
     },
     Emoji, ReadOnlyAccount, ReadOnlyOwnUserIdentity,
@@ -58,6 +61,7 @@ impl InnerSas {
         own_identity: Option<ReadOnlyOwnUserIdentity>,
         other_identity: Option<ReadOnlyUserIdentities>,
         transaction_id: Option<String>,
+        short_auth_strings: Vec<ShortAuthenticationString>,
     ) -> (InnerSas, OutgoingContent) {
         let sas = SasState::<Created>::new(
             account,
@@ -65,6 +69,7 @@ impl InnerSas {
             own_identity,
             other_identity,
             transaction_id,
+            short_auth_strings,
         );
         let content = sas.as_content();
         (InnerSas::Created(sas), content.into())
@@ -133,6 +138,23 @@ impl InnerSas {
         }
     }
 
+    /// The short auth string methods both sides agreed on, `None` if the
+    /// methods haven't been negotiated yet.
+    pub fn short_auth_strings(&self) -> Option<Vec<ShortAuthenticationString>> {
+        match self {
+            InnerSas::WeAccepted(s) => Some(s.state.accepted_protocols.short_auth_string.clone()),
+            InnerSas::Accepted(s) => Some(s.state.accepted_protocols.short_auth_string.clone()),
+            InnerSas::KeyReceived(s) => Some(s.state.accepted_protocols.short_auth_string.clone()),
+            InnerSas::Confirmed(s) => Some(s.state.accepted_protocols.short_auth_string.clone()),
+            InnerSas::MacReceived(s) => Some(s.state.accepted_protocols.short_auth_string.clone()),
+            InnerSas::Created(_)
+            | InnerSas::Started(_)
+            | InnerSas::WaitingForDone(_)
+            | InnerSas::Done(_)
+            | InnerSas::Cancelled(_) => None,
+        }
+    }
+
     pub fn start_in_room(
         event_id: Box<EventId>,
         room_id: Box<RoomId>,
@@ -140,6 +162,7 @@ impl InnerSas {
         other_device: ReadOnlyDevice,
         own_identity: Option<ReadOnlyOwnUserIdentity>,
         other_identity: Option<ReadOnlyUserIdentities>,
+        short_auth_strings: Vec<ShortAuthenticationString>,
     ) -> (InnerSas, OutgoingContent) {
         let sas = SasState::<Created>::new_in_room(
             room_id,
@@ -148,6 +171,7 @@ impl InnerSas {
             other_device,
             own_identity,
             other_identity,
+            short_auth_strings,
         );
         let content = sas.as_content();
         (InnerSas::Created(sas), content.into())
@@ -176,6 +200,75 @@ impl InnerSas {
         }
     }
 
+    /// Create a snapshot of this SAS verification.
+    ///
+    /// Returns `None` if we already sent out or committed to our ephemeral
+    /// key, the verification can't be resumed from that point on.
+    pub fn pickle(&self) -> Option<PickledSas> {
+        match self {
+            InnerSas::Created(s) => {
+                Some(PickledSas::Created { short_auth_strings: s.short_auth_strings().to_vec() })
+            }
+            InnerSas::Started(s) => {
+                Some(PickledSas::Started { content: s.start_content().clone() })
+            }
+            _ => None,
+        }
+    }
+
+    /// Restore a SAS verification from a snapshot.
+    ///
+    /// A new ephemeral key is generated, this is fine since the other side
+    /// didn't receive our old key, nor a commitment to it, yet.
+    ///
+    /// Only SAS verifications that were started from a verification request
+    /// get pickled.
+    pub fn from_pickle(
+        pickle: PickledSas,
+        account: ReadOnlyAccount,
+        other_device: ReadOnlyDevice,
+        flow_id: FlowId,
+        own_identity: Option<ReadOnlyOwnUserIdentity>,
+        other_identity: Option<ReadOnlyUserIdentities>,
+    ) -> Option<InnerSas> {
+        match pickle {
+            PickledSas::Created { short_auth_strings } => Some(match flow_id {
+                FlowId::ToDevice(t) => InnerSas::Created(SasState::<Created>::new(
+                    account,
+                    other_device,
+                    own_identity,
+                    other_identity,
+                    Some(t),
+                    short_auth_strings,
+                )),
+                FlowId::InRoom(r, e) => InnerSas::Created(SasState::<Created>::new_in_room(
+                    r,
+                    e,
+                    account,
+                    other_device,
+                    own_identity,
+                    other_identity,
+                    short_auth_strings,
+                )),
+            }),
+            PickledSas::Started { content } => {
+                let content = OwnedStartContent::from_json(&flow_id, content).ok()?;
+
+                SasState::<Started>::from_start_event(
+                    account,
+                    other_device,
+                    own_identity,
+                    other_identity,
+                    flow_id,
+                    &(&content).into(),
+                    true,
+                )
+                .ok()
+                .map(InnerSas::Started)
+            }
+        }
+    }
+
     pub fn accept(
         self,
         methods: Vec<ShortAuthenticationString>,
@@ -392,6 +485,10 @@ impl InnerSas {
     }
 
     pub fn emoji(&self) -> Option<[Emoji; 7]> {
+        if !self.supports_emoji() {
+            return None;
+        }
+
         match self {
             InnerSas::KeyReceived(s) => Some(s.get_emoji()),
             InnerSas::MacReceived(s) => Some(s.get_emoji()),
@@ -400,6 +497,10 @@ impl InnerSas {
     }
 
     pub fn emoji_index(&self) -> Option<[u8; 7]> {
+        if !self.supports_emoji() {
+            return None;
+        }
+
         match self {
             InnerSas::KeyReceived(s) => Some(s.get_emoji_index()),
             InnerSas::MacReceived(s) => Some(s.get_emoji_index()),
@@ -30,6 +30,8 @@ use ruma::{
     },
     DeviceId, EventId, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tracing::trace;
 
 use super::{
@@ -45,6 +47,19 @@ use crate::{
     Emoji, ReadOnlyAccount, ReadOnlyOwnUserIdentity, ToDeviceRequest,
 };
 
+/// A serializable snapshot of a SAS verification flow.
+///
+/// Only the states before the ephemeral keys get exchanged can be stored, the
+/// keys of the underlying SAS object can't be pickled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum PickledSas {
+    /// We sent out the start event and are waiting for the other side to
+    /// accept it.
+    Created { short_auth_strings: Vec<ShortAuthenticationString> },
+    /// The other side sent us the start event which we didn't accept yet.
+    Started { content: Value },
+}
+
 /// Short authentication string object.
 #[derive(Clone, Debug)]
 pub struct Sas {
@@ -102,6 +117,22 @@ impl Sas {
         self.inner.lock().unwrap().supports_emoji()
     }
 
+    /// Get the short auth string method that should be used to present the
+    /// short auth string to the user.
+    ///
+    /// Emoji are preferred if both sides agreed on them, otherwise the short
+    /// auth string needs to be presented as decimals. Returns `None` if the
+    /// methods haven't been negotiated yet.
+    pub fn sas_method(&self) -> Option<SasMethod> {
+        self.inner.lock().unwrap().short_auth_strings().map(|methods| {
+            if methods.contains(&ShortAuthenticationString::Emoji) {
+                SasMethod::Emoji
+            } else {
+                SasMethod::Decimal
+            }
+        })
+    }
+
     /// Did this verification flow start from a verification request.
     pub fn started_from_request(&self) -> bool {
         self.inner.lock().unwrap().started_from_request()
@@ -181,6 +212,8 @@ impl Sas {
     ///
     /// * `other_device` - The other device which we are going to verify.
     ///
+    /// * `methods` - The short auth string methods our client can display.
+    ///
     /// Returns the new `Sas` object and a `StartEventContent` that needs to be
     /// sent out through the server to the other device.
     #[allow(clippy::too_many_arguments)]
@@ -193,6 +226,7 @@ impl Sas {
         transaction_id: Option<String>,
         we_started: bool,
         request_handle: Option<RequestHandle>,
+        methods: &[SasMethod],
     ) -> (Sas, OutgoingContent) {
         let (inner, content) = InnerSas::start(
             store.account.clone(),
@@ -200,6 +234,7 @@ impl Sas {
             own_identity,
             other_identity.clone(),
             transaction_id,
+            SasMethod::to_short_auth_strings(methods),
         );
 
         (
@@ -224,6 +259,8 @@ impl Sas {
     ///
     /// * `other_device` - The other device which we are going to verify.
     ///
+    /// * `methods` - The short auth string methods our client can display.
+    ///
     /// Returns the new `Sas` object and a `StartEventContent` that needs to be
     /// sent out through the server to the other device.
     #[allow(clippy::too_many_arguments)]
@@ -237,6 +274,7 @@ impl Sas {
         other_identity: Option<ReadOnlyUserIdentities>,
         we_started: bool,
         request_handle: RequestHandle,
+        methods: &[SasMethod],
     ) -> (Sas, OutgoingContent) {
         let (inner, content) = InnerSas::start_in_room(
             flow_id,
@@ -245,6 +283,7 @@ impl Sas {
             other_device.clone(),
             own_identity,
             other_identity.clone(),
+            SasMethod::to_short_auth_strings(methods),
         );
 
         (
@@ -304,6 +343,51 @@ impl Sas {
         ))
     }
 
+    /// Create a snapshot of this SAS verification that can be stored and used
+    /// to resume the verification flow after a restart.
+    ///
+    /// Returns `None` if the ephemeral keys were already exchanged, or if the
+    /// verification flow already finished.
+    pub(crate) fn pickle(&self) -> Option<PickledSas> {
+        self.inner.lock().unwrap().pickle()
+    }
+
+    /// Restore a SAS verification that belongs to a verification request from
+    /// a previously created pickle.
+    ///
+    /// Returns `None` if the pickle doesn't contain a valid start event.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn from_pickle(
+        flow_id: FlowId,
+        pickle: PickledSas,
+        store: VerificationStore,
+        private_identity: PrivateCrossSigningIdentity,
+        other_device: ReadOnlyDevice,
+        own_identity: Option<ReadOnlyOwnUserIdentity>,
+        other_identity: Option<ReadOnlyUserIdentities>,
+        we_started: bool,
+        request_handle: RequestHandle,
+    ) -> Option<Sas> {
+        let inner = InnerSas::from_pickle(
+            pickle,
+            store.account.clone(),
+            other_device.clone(),
+            flow_id,
+            own_identity,
+            other_identity.clone(),
+        )?;
+
+        Some(Self::start_helper(
+            inner,
+            private_identity,
+            other_device,
+            store,
+            other_identity,
+            we_started,
+            Some(request_handle),
+        ))
+    }
+
     /// Accept the SAS verification.
     ///
     /// This does nothing if the verification was already accepted, otherwise it
@@ -473,17 +557,18 @@ impl Sas {
 
     /// Get the emoji version of the short auth string.
     ///
-    /// Returns None if we can't yet present the short auth string, otherwise
-    /// seven tuples containing the emoji and description.
+    /// Returns None if we can't yet present the short auth string or if the
+    /// emoji method wasn't agreed on, otherwise seven tuples containing the
+    /// emoji and description.
     pub fn emoji(&self) -> Option<[Emoji; 7]> {
         self.inner.lock().unwrap().emoji()
     }
 
     /// Get the index of the emoji representing the short auth string
     ///
-    /// Returns None if we can't yet present the short auth string, otherwise
-    /// seven u8 numbers in the range from 0 to 63 inclusive which can be
-    /// converted to an emoji using the
+    /// Returns None if we can't yet present the short auth string or if the
+    /// emoji method wasn't agreed on, otherwise seven u8 numbers in the range
+    /// from 0 to 63 inclusive which can be converted to an emoji using the
     /// [relevant spec entry](https://spec.matrix.org/unstable/client-server-api/#sas-method-emoji).
     pub fn emoji_index(&self) -> Option<[u8; 7]> {
         self.inner.lock().unwrap().emoji_index()
@@ -524,6 +609,44 @@ impl Sas {
     }
 }
 
+/// The short auth string methods we offer if the client doesn't restrict them.
+pub(crate) const DEFAULT_SAS_METHODS: &[SasMethod] = &[SasMethod::Emoji, SasMethod::Decimal];
+
+/// The methods a short auth string can be presented with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SasMethod {
+    /// The short auth string is presented as seven emoji.
+    Emoji,
+    /// The short auth string is presented as three 4-digit numbers.
+    Decimal,
+}
+
+impl SasMethod {
+    /// Convert the methods a client can display into the short auth strings
+    /// we offer to the other side.
+    ///
+    /// Decimal is always offered, the spec requires every client to support
+    /// it.
+    fn to_short_auth_strings(methods: &[SasMethod]) -> Vec<ShortAuthenticationString> {
+        let mut short_auth_strings = vec![ShortAuthenticationString::Decimal];
+
+        if methods.contains(&SasMethod::Emoji) {
+            short_auth_strings.push(ShortAuthenticationString::Emoji);
+        }
+
+        short_auth_strings
+    }
+}
+
+impl From<SasMethod> for ShortAuthenticationString {
+    fn from(method: SasMethod) -> Self {
+        match method {
+            SasMethod::Emoji => ShortAuthenticationString::Emoji,
+            SasMethod::Decimal => ShortAuthenticationString::Decimal,
+        }
+    }
+}
+
 /// Customize the accept-reply for a verification process
 #[derive(Debug)]
 pub struct AcceptSettings {
@@ -551,6 +674,15 @@ impl AcceptSettings {
     pub fn with_allowed_methods(methods: Vec<ShortAuthenticationString>) -> Self {
         Self { allowed_methods: methods }
     }
+
+    /// Create settings allowing only the SAS methods our client can display
+    ///
+    /// # Arguments
+    ///
+    /// * `methods` - The methods this client can display
+    pub fn with_sas_methods(methods: &[SasMethod]) -> Self {
+        Self { allowed_methods: SasMethod::to_short_auth_strings(methods) }
+    }
 }
 
 #[cfg(test)]
@@ -559,7 +691,7 @@ mod test {
 
     use ruma::{device_id, user_id, DeviceId, UserId};
 
-    use super::Sas;
+    use super::{AcceptSettings, Sas, SasMethod};
     use crate::{
         olm::PrivateCrossSigningIdentity,
         store::MemoryStore,
@@ -594,13 +726,12 @@ mod test {
         let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
 
-        let alice_store =
-            VerificationStore { account: alice.clone(), inner: Arc::new(MemoryStore::new()) };
+        let alice_store = VerificationStore::new(alice.clone(), Arc::new(MemoryStore::new()));
 
         let bob_store = MemoryStore::new();
         bob_store.save_devices(vec![alice_device.clone()]).await;
 
-        let bob_store = VerificationStore { account: bob.clone(), inner: Arc::new(bob_store) };
+        let bob_store = VerificationStore::new(bob.clone(), Arc::new(bob_store));
 
         let (alice, content) = Sas::start(
             PrivateCrossSigningIdentity::empty(alice_id().to_owned()),
@@ -611,6 +742,7 @@ mod test {
             None,
             true,
             None,
+            &[SasMethod::Emoji, SasMethod::Decimal],
         );
 
         let flow_id = alice.flow_id().to_owned();
@@ -647,6 +779,8 @@ mod test {
         alice.receive_any_event(bob.user_id(), &content.into());
         assert!(alice.can_be_presented());
 
+        assert_eq!(alice.sas_method(), Some(SasMethod::Emoji));
+        assert_eq!(bob.sas_method(), Some(SasMethod::Emoji));
         assert_eq!(alice.emoji().unwrap(), bob.emoji().unwrap());
         assert_eq!(alice.decimals().unwrap(), bob.decimals().unwrap());
 
@@ -663,4 +797,65 @@ mod test {
         assert!(alice.verified_devices().unwrap().contains(alice.other_device()));
         assert!(bob.verified_devices().unwrap().contains(bob.other_device()));
     }
+
+    #[tokio::test]
+    async fn sas_decimal_only() {
+        let alice = ReadOnlyAccount::new(alice_id(), alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+
+        let alice_store = VerificationStore::new(alice.clone(), Arc::new(MemoryStore::new()));
+        let bob_store = VerificationStore::new(bob.clone(), Arc::new(MemoryStore::new()));
+
+        let (alice, content) = Sas::start(
+            PrivateCrossSigningIdentity::empty(alice_id().to_owned()),
+            bob_device,
+            alice_store,
+            None,
+            None,
+            None,
+            true,
+            None,
+            &[SasMethod::Decimal],
+        );
+
+        let flow_id = alice.flow_id().to_owned();
+        let content = StartContent::try_from(&content).unwrap();
+
+        let bob = Sas::from_start_event(
+            flow_id,
+            &content,
+            bob_store,
+            PrivateCrossSigningIdentity::empty(bob_id().to_owned()),
+            alice_device,
+            None,
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(bob.sas_method(), None);
+
+        // Bob could display emoji, but Alice didn't offer them.
+        let request = bob.accept_with_settings(AcceptSettings::with_sas_methods(&[
+            SasMethod::Emoji,
+            SasMethod::Decimal,
+        ]));
+        let content = OutgoingContent::try_from(request.unwrap()).unwrap();
+        let content = AcceptContent::try_from(&content).unwrap();
+
+        let content = alice.receive_any_event(bob.user_id(), &content.into()).unwrap();
+        let content = KeyContent::try_from(&content).unwrap();
+        let content = bob.receive_any_event(alice.user_id(), &content.into()).unwrap();
+        let content = KeyContent::try_from(&content).unwrap();
+        alice.receive_any_event(bob.user_id(), &content.into());
+
+        assert_eq!(alice.sas_method(), Some(SasMethod::Decimal));
+        assert_eq!(bob.sas_method(), Some(SasMethod::Decimal));
+        assert!(alice.emoji().is_none());
+        assert_eq!(alice.decimals().unwrap(), bob.decimals().unwrap());
+    }
 }
@@ -20,7 +20,6 @@ use std::{
 };
 
 use matrix_sdk_common::uuid::Uuid;
-use olm_rs::sas::OlmSas;
 use ruma::{
     events::{
         key::verification::{
@@ -42,6 +41,7 @@ use ruma::{
     },
     DeviceId, EventId, RoomId, UserId,
 };
+use serde_json::Value;
 use tracing::info;
 
 use super::{
@@ -53,6 +53,7 @@ use super::{
 };
 use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
+    olm::RawSas,
     verification::{
         event_enums::{
             AcceptContent, DoneContent, KeyContent, MacContent, OwnedAcceptContent,
@@ -67,8 +68,6 @@ const KEY_AGREEMENT_PROTOCOLS: &[KeyAgreementProtocol] =
     &[KeyAgreementProtocol::Curve25519HkdfSha256];
 const HASHES: &[HashAlgorithm] = &[HashAlgorithm::Sha256];
 const MACS: &[MessageAuthenticationCode] = &[MessageAuthenticationCode::HkdfHmacSha256];
-const STRINGS: &[ShortAuthenticationString] =
-    &[ShortAuthenticationString::Decimal, ShortAuthenticationString::Emoji];
 
 // The max time a SAS flow can take from start to done.
 const MAX_AGE: Duration = Duration::from_secs(60 * 5);
@@ -182,7 +181,7 @@ impl Default for AcceptedProtocols {
 #[derive(Clone)]
 pub struct SasState<S: Clone> {
     /// The Olm SAS struct.
-    inner: Arc<Mutex<OlmSas>>,
+    inner: Arc<Mutex<RawSas>>,
 
     /// Struct holding the identities that are doing the SAS dance.
     ids: SasIds,
@@ -229,6 +228,7 @@ pub struct Created {
 #[derive(Clone, Debug)]
 pub struct Started {
     commitment: String,
+    start_content: Arc<Value>,
     pub accepted_protocols: Arc<AcceptedProtocols>,
 }
 
@@ -359,12 +359,15 @@ impl SasState<Created> {
     /// * `other_device` - The other device which we are going to verify.
     ///
     /// * `other_identity` - The identity of the other user if one exists.
+    ///
+    /// * `short_auth_strings` - The short auth string methods we should offer.
     pub fn new(
         account: ReadOnlyAccount,
         other_device: ReadOnlyDevice,
         own_identity: Option<ReadOnlyOwnUserIdentity>,
         other_identity: Option<ReadOnlyUserIdentities>,
         transaction_id: Option<String>,
+        short_auth_strings: Vec<ShortAuthenticationString>,
     ) -> SasState<Created> {
         let started_from_request = transaction_id.is_some();
         let flow_id =
@@ -376,6 +379,7 @@ impl SasState<Created> {
             own_identity,
             other_identity,
             started_from_request,
+            short_auth_strings,
         )
     }
 
@@ -391,6 +395,8 @@ impl SasState<Created> {
     /// * `other_device` - The other device which we are going to verify.
     ///
     /// * `other_identity` - The identity of the other user if one exists.
+    ///
+    /// * `short_auth_strings` - The short auth string methods we should offer.
     pub fn new_in_room(
         room_id: Box<RoomId>,
         event_id: Box<EventId>,
@@ -398,9 +404,18 @@ impl SasState<Created> {
         other_device: ReadOnlyDevice,
         own_identity: Option<ReadOnlyOwnUserIdentity>,
         other_identity: Option<ReadOnlyUserIdentities>,
+        short_auth_strings: Vec<ShortAuthenticationString>,
     ) -> SasState<Created> {
         let flow_id = FlowId::InRoom(room_id, event_id);
-        Self::new_helper(flow_id, account, other_device, own_identity, other_identity, false)
+        Self::new_helper(
+            flow_id,
+            account,
+            other_device,
+            own_identity,
+            other_identity,
+            false,
+            short_auth_strings,
+        )
     }
 
     fn new_helper(
@@ -410,9 +425,15 @@ impl SasState<Created> {
         own_identity: Option<ReadOnlyOwnUserIdentity>,
         other_identity: Option<ReadOnlyUserIdentities>,
         started_from_request: bool,
+        mut short_auth_strings: Vec<ShortAuthenticationString>,
     ) -> SasState<Created> {
+        // Decimal is required per spec.
+        if !short_auth_strings.contains(&ShortAuthenticationString::Decimal) {
+            short_auth_strings.insert(0, ShortAuthenticationString::Decimal);
+        }
+
         SasState {
-            inner: Arc::new(Mutex::new(OlmSas::new())),
+            inner: Arc::new(Mutex::new(RawSas::new())),
             ids: SasIds { account, other_device, other_identity, own_identity },
             verification_flow_id: flow_id.into(),
 
@@ -422,7 +443,7 @@ impl SasState<Created> {
 
             state: Arc::new(Created {
                 protocol_definitions: SasV1ContentInit {
-                    short_authentication_string: STRINGS.to_vec(),
+                    short_authentication_string: short_auth_strings,
                     key_agreement_protocols: KEY_AGREEMENT_PROTOCOLS.to_vec(),
                     message_authentication_codes: MACS.to_vec(),
                     hashes: HASHES.to_vec(),
@@ -433,6 +454,11 @@ impl SasState<Created> {
         }
     }
 
+    /// Get the short auth string methods we offered to the other side.
+    pub fn short_auth_strings(&self) -> &[ShortAuthenticationString] {
+        &self.state.protocol_definitions.short_authentication_string
+    }
+
     pub fn as_content(&self) -> OwnedStartContent {
         match self.verification_flow_id.as_ref() {
             FlowId::ToDevice(s) => {
@@ -468,9 +494,19 @@ impl SasState<Created> {
         self.check_event(sender, content.flow_id()).map_err(|c| self.clone().cancel(true, c))?;
 
         if let AcceptMethod::SasV1(content) = content.method() {
-            let accepted_protocols = AcceptedProtocols::try_from(content.clone())
+            let mut accepted_protocols = AcceptedProtocols::try_from(content.clone())
                 .map_err(|c| self.clone().cancel(true, c))?;
 
+            // The other side may only pick from the short auth string methods
+            // we offered, decimal is always offered and must be accepted.
+            accepted_protocols.short_auth_string.retain(|m| {
+                self.state.protocol_definitions.short_authentication_string.contains(m)
+            });
+
+            if !accepted_protocols.short_auth_string.contains(&ShortAuthenticationString::Decimal) {
+                return Err(self.cancel(true, CancelCode::UnknownMethod));
+            }
+
             let start_content = self.as_content().into();
 
             Ok(SasState {
@@ -518,7 +554,7 @@ impl SasState<Started> {
         let flow_id = Arc::new(flow_id);
 
         let canceled = || SasState {
-            inner: Arc::new(Mutex::new(OlmSas::new())),
+            inner: Arc::new(Mutex::new(RawSas::new())),
 
             creation_time: Arc::new(Instant::now()),
             last_event_time: Arc::new(Instant::now()),
@@ -536,7 +572,7 @@ impl SasState<Started> {
         };
 
         if let StartMethod::SasV1(method_content) = content.method() {
-            let sas = OlmSas::new();
+            let sas = RawSas::new();
 
             let pubkey = sas.public_key();
             let commitment = calculate_commitment(&pubkey, content);
@@ -561,6 +597,7 @@ impl SasState<Started> {
                     state: Arc::new(Started {
                         accepted_protocols: accepted_protocols.into(),
                         commitment,
+                        start_content: content.to_json().into(),
                     }),
                 })
             } else {
@@ -571,9 +608,19 @@ impl SasState<Started> {
         }
     }
 
+    /// Get the content of the start event the other side sent us.
+    pub fn start_content(&self) -> &Value {
+        &self.state.start_content
+    }
+
     pub fn into_accepted(self, methods: Vec<ShortAuthenticationString>) -> SasState<WeAccepted> {
         let mut accepted_protocols = self.state.accepted_protocols.as_ref().to_owned();
-        accepted_protocols.short_auth_string = methods;
+
+        // Only accept the methods that were offered by the other side.
+        accepted_protocols.short_auth_string = methods
+            .into_iter()
+            .filter(|m| self.state.accepted_protocols.short_auth_string.contains(m))
+            .collect();
 
         // Decimal is required per spec.
         if !accepted_protocols.short_auth_string.contains(&ShortAuthenticationString::Decimal) {
@@ -1141,6 +1188,7 @@ mod test {
         device_id,
         events::key::verification::{
             accept::{AcceptMethod, ToDeviceKeyVerificationAcceptEventContent},
+            cancel::CancelCode,
             start::{StartMethod, ToDeviceKeyVerificationStartEventContent},
             ShortAuthenticationString,
         },
@@ -1171,13 +1219,24 @@ mod test {
     }
 
     async fn get_sas_pair() -> (SasState<Created>, SasState<WeAccepted>) {
+        get_sas_pair_with_methods(vec![
+            ShortAuthenticationString::Decimal,
+            ShortAuthenticationString::Emoji,
+        ])
+        .await
+    }
+
+    async fn get_sas_pair_with_methods(
+        methods: Vec<ShortAuthenticationString>,
+    ) -> (SasState<Created>, SasState<WeAccepted>) {
         let alice = ReadOnlyAccount::new(alice_id(), alice_device_id());
         let alice_device = ReadOnlyDevice::from_account(&alice).await;
 
         let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
 
-        let alice_sas = SasState::<Created>::new(alice.clone(), bob_device, None, None, None);
+        let alice_sas =
+            SasState::<Created>::new(alice.clone(), bob_device, None, None, None, methods);
 
         let start_content = alice_sas.as_content();
         let flow_id = start_content.flow_id();
@@ -1333,6 +1392,54 @@ mod test {
             .expect_err("Didn't cancel on an invalid SAS method");
     }
 
+    #[tokio::test]
+    async fn sas_accept_unoffered_method() {
+        let (alice, bob) =
+            get_sas_pair_with_methods(vec![ShortAuthenticationString::Decimal]).await;
+
+        let mut content = bob.as_content();
+        let mut method = content.method_mut();
+
+        match &mut method {
+            AcceptMethod::SasV1(ref mut c) => {
+                c.short_authentication_string =
+                    vec![ShortAuthenticationString::Decimal, ShortAuthenticationString::Emoji];
+            }
+            _ => panic!("Unknown accept event content"),
+        }
+
+        let content = AcceptContent::from(&content);
+        let alice = alice.into_accepted(bob.user_id(), &content).unwrap();
+
+        assert_eq!(
+            alice.state.accepted_protocols.short_auth_string,
+            vec![ShortAuthenticationString::Decimal]
+        );
+    }
+
+    #[tokio::test]
+    async fn sas_accept_without_decimal() {
+        let (alice, bob) = get_sas_pair().await;
+
+        let mut content = bob.as_content();
+        let mut method = content.method_mut();
+
+        match &mut method {
+            AcceptMethod::SasV1(ref mut c) => {
+                c.short_authentication_string = vec![ShortAuthenticationString::Emoji];
+            }
+            _ => panic!("Unknown accept event content"),
+        }
+
+        let content = AcceptContent::from(&content);
+
+        let alice = alice
+            .into_accepted(bob.user_id(), &content)
+            .expect_err("Didn't cancel on a missing decimal SAS method");
+
+        assert_eq!(alice.state.cancel_code, CancelCode::UnknownMethod);
+    }
+
     #[tokio::test]
     async fn sas_unknown_method() {
         let (alice, bob) = get_sas_pair().await;
@@ -1360,7 +1467,14 @@ mod test {
         let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
 
-        let alice_sas = SasState::<Created>::new(alice.clone(), bob_device, None, None, None);
+        let alice_sas = SasState::<Created>::new(
+            alice.clone(),
+            bob_device,
+            None,
+            None,
+            None,
+            vec![ShortAuthenticationString::Decimal, ShortAuthenticationString::Emoji],
+        );
 
         let mut start_content = alice_sas.as_content();
         let method = start_content.method_mut();
@@ -0,0 +1,217 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the [`m.room_key.withheld`][msc2399] to-device event.
+//!
+//! The event is sent, unencrypted, to devices that won't receive a room key,
+//! either because we refused to share it with them or because we couldn't.
+//!
+//! [msc2399]: https://github.com/matrix-org/matrix-doc/pull/2399
+
+use std::{collections::BTreeMap, fmt};
+
+use matrix_sdk_common::uuid::Uuid;
+use ruma::{
+    serde::Raw, to_device::DeviceIdOrAllDevices, DeviceId, EventEncryptionAlgorithm, RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+
+use crate::ToDeviceRequest;
+
+/// The event type of the `m.room_key.withheld` to-device event.
+pub const WITHHELD_EVENT_TYPE: &str = "m.room_key.withheld";
+
+/// The unstable event type of the `m.room_key.withheld` to-device event, some
+/// clients still send it out using this type.
+pub(crate) const UNSTABLE_WITHHELD_EVENT_TYPE: &str = "org.matrix.room_key.withheld";
+
+/// The reason why a room key was withheld.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum WithheldCode {
+    /// The sender blacklisted the receiving device.
+    Blacklisted,
+    /// The sender only shares room keys with verified devices and the
+    /// receiving device isn't verified.
+    Unverified,
+    /// The receiving device isn't allowed to see the room key, e.g. the key
+    /// was requested by a device that wasn't in the room when the key was
+    /// sent.
+    Unauthorised,
+    /// The requested room key isn't known to the sender.
+    Unavailable,
+    /// The sender couldn't establish an Olm session with the receiving
+    /// device.
+    NoOlm,
+    /// A code this crate doesn't know about.
+    Custom(String),
+}
+
+impl WithheldCode {
+    /// Get the string representation of the code, as it's used in the event.
+    pub fn as_str(&self) -> &str {
+        match self {
+            WithheldCode::Blacklisted => "m.blacklisted",
+            WithheldCode::Unverified => "m.unverified",
+            WithheldCode::Unauthorised => "m.unauthorised",
+            WithheldCode::Unavailable => "m.unavailable",
+            WithheldCode::NoOlm => "m.no_olm",
+            WithheldCode::Custom(c) => c,
+        }
+    }
+
+    /// Get a human readable reason for the code.
+    pub fn reason(&self) -> &str {
+        match self {
+            WithheldCode::Blacklisted => "The sender has blocked you.",
+            WithheldCode::Unverified => "The sender has disabled encrypting to unverified devices.",
+            WithheldCode::Unauthorised => "You are not authorised to read the message.",
+            WithheldCode::Unavailable => "The requested key was not found.",
+            WithheldCode::NoOlm => "Unable to establish a secure channel.",
+            WithheldCode::Custom(_) => "The room key was withheld for an unknown reason.",
+        }
+    }
+}
+
+impl From<String> for WithheldCode {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "m.blacklisted" => WithheldCode::Blacklisted,
+            "m.unverified" => WithheldCode::Unverified,
+            "m.unauthorised" => WithheldCode::Unauthorised,
+            "m.unavailable" => WithheldCode::Unavailable,
+            "m.no_olm" => WithheldCode::NoOlm,
+            _ => WithheldCode::Custom(code),
+        }
+    }
+}
+
+impl From<WithheldCode> for String {
+    fn from(code: WithheldCode) -> Self {
+        code.as_str().to_owned()
+    }
+}
+
+impl fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.as_str(), self.reason())
+    }
+}
+
+/// The content of a `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomKeyWithheldEventContent {
+    /// The encryption algorithm of the room key that was withheld.
+    pub algorithm: EventEncryptionAlgorithm,
+    /// The reason why the room key was withheld.
+    pub code: WithheldCode,
+    /// A human readable version of the reason.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The room the room key belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<Box<RoomId>>,
+    /// The id of the room key that was withheld.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// The curve25519 key of the device that withheld the room key.
+    pub sender_key: String,
+}
+
+impl RoomKeyWithheldEventContent {
+    /// Create a new withheld event content for the given room key.
+    pub fn new(code: WithheldCode, room_id: &RoomId, session_id: &str, sender_key: &str) -> Self {
+        Self {
+            algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
+            reason: Some(code.reason().to_owned()),
+            code,
+            room_id: Some(room_id.to_owned()),
+            session_id: Some(session_id.to_owned()),
+            sender_key: sender_key.to_owned(),
+        }
+    }
+}
+
+/// A `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToDeviceRoomKeyWithheldEvent {
+    /// The user that sent the event.
+    pub sender: Box<UserId>,
+    /// The content of the event.
+    pub content: RoomKeyWithheldEventContent,
+}
+
+/// Create a to-device request that sends out the given withheld contents,
+/// unencrypted, to the given devices.
+pub(crate) fn withheld_request<'a>(
+    contents: impl IntoIterator<Item = (&'a UserId, &'a DeviceId, RoomKeyWithheldEventContent)>,
+) -> ToDeviceRequest {
+    let mut messages = BTreeMap::new();
+
+    for (user_id, device_id, content) in contents {
+        let content = Raw::from_json(
+            to_raw_value(&content).expect("Can't serialize a m.room_key.withheld event"),
+        );
+
+        messages
+            .entry(user_id.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(DeviceIdOrAllDevices::DeviceId(device_id.to_owned()), content);
+    }
+
+    ToDeviceRequest { event_type: WITHHELD_EVENT_TYPE.into(), txn_id: Uuid::new_v4(), messages }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::room_id;
+    use serde_json::json;
+
+    use super::{RoomKeyWithheldEventContent, WithheldCode};
+
+    #[test]
+    fn withheld_content_serialization() {
+        let content = RoomKeyWithheldEventContent::new(
+            WithheldCode::Unverified,
+            room_id!("!test:localhost"),
+            "session_id",
+            "sender_key",
+        );
+
+        let json = serde_json::to_value(&content).unwrap();
+
+        assert_eq!(
+            json,
+            json!({
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "code": "m.unverified",
+                "reason": "The sender has disabled encrypting to unverified devices.",
+                "room_id": "!test:localhost",
+                "session_id": "session_id",
+                "sender_key": "sender_key",
+            })
+        );
+
+        let content: RoomKeyWithheldEventContent = serde_json::from_value(json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "code": "org.example.custom",
+            "sender_key": "sender_key",
+        }))
+        .unwrap();
+
+        assert_eq!(content.code, WithheldCode::Custom("org.example.custom".to_owned()));
+        assert!(content.room_id.is_none());
+    }
+}
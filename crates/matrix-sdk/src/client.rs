@@ -26,6 +26,7 @@ use std::{
 use anymap2::any::CloneAnySendSync;
 use dashmap::DashMap;
 use futures_core::stream::Stream;
+use futures_util::io::AsyncRead;
 use matrix_sdk_base::{
     deserialized_responses::SyncResponse,
     media::{MediaEventContent, MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
@@ -70,7 +71,7 @@ use crate::{
     config::{ClientConfig, RequestConfig},
     error::{HttpError, HttpResult},
     event_handler::{EventHandler, EventHandlerData, EventHandlerResult, EventKind, SyncEvent},
-    http_client::{client_with_config, HttpClient},
+    http_client::{client_with_config, reader_stream, BodyStream, HttpClient},
     room, Error, Result,
 };
 
@@ -87,6 +88,11 @@ type NotificationHandlerFut = EventHandlerFut;
 type NotificationHandlerFn =
     Box<dyn Fn(Notification, room::Room, Client) -> NotificationHandlerFut + Send + Sync>;
 
+#[cfg(feature = "encryption")]
+type VerificationViolationHandlerFn = Box<
+    dyn Fn(crate::encryption::identities::UserIdentity, Client) -> EventHandlerFut + Send + Sync,
+>;
+
 type AnyMap = anymap2::Map<dyn CloneAnySendSync + Send + Sync>;
 
 /// Enum controlling if a loop running callbacks should continue or abort.
@@ -125,6 +131,23 @@ pub(crate) struct ClientInner {
     #[cfg(feature = "encryption")]
     /// Lock making sure we're only doing one key claim request at a time.
     pub(crate) key_claim_lock: Mutex<()>,
+    /// Whether undecryptable events should be retried once their room key
+    /// arrives. See `ClientConfig::retry_decryption`.
+    #[cfg(feature = "encryption")]
+    pub(crate) retry_decryption_enabled: bool,
+    /// Room events we failed to decrypt, grouped by the room id and the
+    /// session id of the missing room key.
+    #[cfg(feature = "encryption")]
+    pub(crate) undecryptable_events:
+        DashMap<(Box<RoomId>, String), crate::encryption::UndecryptableEvents>,
+    /// Room keys of undecryptable events that were imported or restored from
+    /// the server-side key backup since the last sync.
+    #[cfg(feature = "encryption")]
+    pub(crate) restored_room_keys: dashmap::DashSet<(Box<RoomId>, String)>,
+    /// Did we already check the server-side key backup since the client was
+    /// created.
+    #[cfg(feature = "encryption")]
+    pub(crate) backups_checked: std::sync::atomic::AtomicBool,
     pub(crate) members_request_locks: DashMap<Box<RoomId>, Arc<Mutex<()>>>,
     pub(crate) typing_notice_times: DashMap<Box<RoomId>, Instant>,
     /// Event handlers. See `register_event_handler`.
@@ -133,6 +156,10 @@ pub(crate) struct ClientInner {
     event_handler_data: StdRwLock<AnyMap>,
     /// Notification handlers. See `register_notification_handler`.
     notification_handlers: RwLock<Vec<NotificationHandlerFn>>,
+    /// Verification violation handlers. See
+    /// `register_verification_violation_handler`.
+    #[cfg(feature = "encryption")]
+    pub(crate) verification_violation_handlers: RwLock<Vec<VerificationViolationHandlerFn>>,
     /// Whether the client should operate in application service style mode.
     /// This is low-level functionality. For an high-level API check the
     /// `matrix_sdk_appservice` crate.
@@ -146,6 +173,11 @@ pub(crate) struct ClientInner {
     /// wait for the sync to get the data to fetch a room object from the state
     /// store.
     pub(crate) sync_beat: event_listener::Event,
+    /// An event that is fired every time a verification flow might have
+    /// changed its state, i.e. after a sync or after we sent out a
+    /// verification event.
+    #[cfg(feature = "encryption")]
+    pub(crate) verification_beat: event_listener::Event,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -197,14 +229,26 @@ impl Client {
             group_session_locks: Default::default(),
             #[cfg(feature = "encryption")]
             key_claim_lock: Default::default(),
+            #[cfg(feature = "encryption")]
+            retry_decryption_enabled: config.retry_decryption_enabled,
+            #[cfg(feature = "encryption")]
+            undecryptable_events: Default::default(),
+            #[cfg(feature = "encryption")]
+            restored_room_keys: Default::default(),
+            #[cfg(feature = "encryption")]
+            backups_checked: Default::default(),
             members_request_locks: Default::default(),
             typing_notice_times: Default::default(),
             event_handlers: Default::default(),
             event_handler_data: Default::default(),
             notification_handlers: Default::default(),
+            #[cfg(feature = "encryption")]
+            verification_violation_handlers: Default::default(),
             appservice_mode: config.appservice_mode,
             use_discovery_response: config.use_discovery_response,
             sync_beat: event_listener::Event::new(),
+            #[cfg(feature = "encryption")]
+            verification_beat: event_listener::Event::new(),
         });
 
         Ok(Self { inner })
@@ -495,6 +539,43 @@ impl Client {
         self.inner.base_client.store()
     }
 
+    /// Change the passphrase that is used to encrypt the state and crypto
+    /// stores.
+    ///
+    /// Passing `None` as the old passphrase encrypts previously unencrypted
+    /// stores, passing `None` as the new passphrase removes the encryption of
+    /// the stores. If the process is interrupted the client needs to be
+    /// created using the new passphrase, which finishes the change.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the stores.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the stores from
+    /// now on.
+    #[cfg(feature = "sled_state_store")]
+    pub async fn change_store_passphrase(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+    ) -> Result<()> {
+        Ok(self.inner.base_client.change_store_passphrase(old_passphrase, new_passphrase).await?)
+    }
+
+    /// Re-encrypt all the data in the state and crypto stores under freshly
+    /// generated keys.
+    ///
+    /// This should be used if the keys of the stores might have been
+    /// compromised, the stores stay protected by the same passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that protects the stores.
+    #[cfg(feature = "sled_state_store")]
+    pub async fn rekey_store(&self, passphrase: &str) -> Result<()> {
+        Ok(self.inner.base_client.rekey_store(passphrase).await?)
+    }
+
     /// Sets the mxc avatar url of the client's owner. The avatar gets unset if
     /// `url` is `None`.
     pub async fn set_avatar_url(&self, url: Option<&MxcUri>) -> Result<()> {
@@ -1619,6 +1700,78 @@ impl Client {
         Ok(self.inner.http_client.upload(request, Some(request_config)).await?)
     }
 
+    /// Upload some media to the server, streaming it out of the given reader.
+    ///
+    /// Unlike [`upload()`](#method.upload) this never holds the whole media in
+    /// memory and doesn't block while reading it, which makes it suitable for
+    /// large files.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `reader` - An `AsyncRead` implementor that will be used to fetch the
+    /// raw bytes of the media.
+    ///
+    /// * `content_length` - The number of bytes the reader will produce.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # use mime;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver)?;
+    /// let data = b"It's a secret to everybody".to_vec();
+    /// let length = data.len() as u64;
+    ///
+    /// let response = client
+    ///     .upload_stream(&mime::TEXT_PLAIN, futures::io::Cursor::new(data), length)
+    ///     .await?;
+    ///
+    /// println!("Secret URI: {}", response.content_uri);
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub async fn upload_stream<R>(
+        &self,
+        content_type: &Mime,
+        reader: R,
+        content_length: u64,
+    ) -> Result<create_content::Response>
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+    {
+        self.upload_body(content_type, reader_stream(reader, |_| ()), content_length).await
+    }
+
+    /// Upload the media that the streaming `body` produces.
+    pub(crate) async fn upload_body(
+        &self,
+        content_type: &Mime,
+        body: BodyStream,
+        content_length: u64,
+    ) -> Result<create_content::Response> {
+        let timeout = std::cmp::max(
+            Duration::from_secs(content_length / DEFAULT_UPLOAD_SPEED),
+            MIN_UPLOAD_REQUEST_TIMEOUT,
+        );
+
+        let request = assign!(create_content::Request::new(&[]), {
+            content_type: Some(content_type.essence_str()),
+        });
+
+        let request_config = self.inner.http_client.request_config.timeout(timeout);
+        Ok(self
+            .inner
+            .http_client
+            .upload_stream(request, body, content_length, Some(request_config))
+            .await?)
+    }
+
     /// Send an arbitrary request to the server, without updating client state.
     ///
     /// **Warning:** Because this method *does not* update the client state, it
@@ -1886,6 +2039,8 @@ impl Client {
         };
 
         self.inner.sync_beat.notify(usize::MAX);
+        #[cfg(feature = "encryption")]
+        self.inner.verification_beat.notify(usize::MAX);
 
         Ok(response)
     }
@@ -2301,13 +2456,17 @@ impl Client {
 
     /// Upload the file to be read from `reader` and construct an attachment
     /// message with `body` and the specified `content_type`.
-    pub(crate) async fn prepare_attachment_message<R: Read>(
+    pub(crate) async fn prepare_attachment_message<R>(
         &self,
         body: &str,
         content_type: &Mime,
-        reader: &mut R,
-    ) -> Result<ruma::events::room::message::MessageType> {
-        let response = self.upload(content_type, reader).await?;
+        reader: R,
+        content_length: u64,
+    ) -> Result<ruma::events::room::message::MessageType>
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+    {
+        let response = self.upload_stream(content_type, reader, content_length).await?;
 
         let url = response.content_uri;
 
@@ -3661,4 +3820,368 @@ pub(crate) mod test {
 
         matches::assert_matches!(encryption_event, AnySyncStateEvent::RoomEncryption(_));
     }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn room_key_sharing_strategy_is_persisted() {
+        use crate::encryption::ShareStrategy;
+
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let response =
+            EventBuilder::default().add_state_event(EventsJson::Member).build_sync_response();
+        client.process_sync(response).await.unwrap();
+
+        let room = client.get_joined_room(room_id).unwrap();
+        assert_eq!(room.room_key_sharing_strategy(), ShareStrategy::AllDevices);
+
+        room.set_room_key_sharing_strategy(ShareStrategy::VerifiedUsers).await.unwrap();
+        assert_eq!(room.room_key_sharing_strategy(), ShareStrategy::VerifiedUsers);
+
+        let room_info = client
+            .store()
+            .get_room_infos()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|r| *r.room_id == *room_id)
+            .unwrap();
+        assert_eq!(room_info.room_key_sharing_strategy, Some(ShareStrategy::VerifiedUsers));
+
+        // Syncing doesn't reset the strategy.
+        let response =
+            EventBuilder::default().add_state_event(EventsJson::Member).build_sync_response();
+        client.process_sync(response).await.unwrap();
+        assert_eq!(room.room_key_sharing_strategy(), ShareStrategy::VerifiedUsers);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn dehydrated_device_is_created() {
+        let client = logged_in_client().await;
+
+        let m = mock("PUT", "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device")
+            .match_body(Matcher::PartialJson(json!({
+                "initial_device_display_name": "Dehydrated",
+                "device_keys": { "user_id": "@example:localhost" },
+                "device_data": { "algorithm": "org.matrix.msc2697.v1.olm.libolm_pickle" },
+            })))
+            .with_status(200)
+            .with_body(r#"{ "device_id": "DEHYDRATED" }"#)
+            .create();
+
+        let device_id = client
+            .dehydrated_devices()
+            .create(b"It's a secret to everybody", "Dehydrated")
+            .await
+            .unwrap();
+
+        assert_eq!(device_id, device_id!("DEHYDRATED"));
+        m.assert();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn rehydrating_a_dehydrated_device() {
+        let client = logged_in_client().await;
+        let pickle_key = b"It's a secret to everybody";
+
+        let device = client
+            .olm_machine()
+            .await
+            .unwrap()
+            .create_dehydrated_device(pickle_key, "Dehydrated")
+            .await;
+        let request = device.request();
+
+        let _m = mock("GET", "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "device_id": request.device_id,
+                    "device_data": request.device_data,
+                })
+                .to_string(),
+            )
+            .create();
+
+        let events_path = format!(
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{}/events",
+            request.device_id
+        );
+        let events = mock("POST", events_path.as_str())
+            .with_status(200)
+            .with_body(r#"{ "events": [] }"#)
+            .expect(1)
+            .create();
+
+        // There's no claim endpoint mocked, rehydrating must not try to claim
+        // the dehydrated device.
+        let result = client.dehydrated_devices().rehydrate(pickle_key).await.unwrap().unwrap();
+
+        assert_eq!(result.imported_count, 0);
+        events.assert();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn rehydrating_without_a_dehydrated_device() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device")
+            .with_status(404)
+            .with_body(r#"{ "errcode": "M_NOT_FOUND", "error": "No dehydrated device" }"#)
+            .create();
+
+        let result = client.dehydrated_devices().rehydrate(b"It's a secret to everybody").await;
+
+        assert!(result.unwrap().is_none());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn undecryptable_events_are_tracked() {
+        use std::{
+            future,
+            sync::{Arc, Mutex},
+        };
+
+        use matrix_sdk_base::deserialized_responses::{UnableToDecryptInfo, UnableToDecryptReason};
+        use ruma::events::room::encrypted::SyncRoomEncryptedEvent;
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost").to_owned(),
+            device_id: device_id!("DEVICEID").to_owned(),
+        };
+        let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new()
+            .request_config(RequestConfig::new().disable_retry())
+            .retry_decryption();
+        let client = Client::new_with_config(homeserver, config).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        let handler_info = Arc::new(Mutex::new(None));
+
+        client
+            .register_event_handler({
+                let handler_info = handler_info.clone();
+                move |_: SyncRoomEncryptedEvent, info: Option<UnableToDecryptInfo>| {
+                    *handler_info.lock().unwrap() = info;
+                    future::ready(())
+                }
+            })
+            .await;
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let response = EventBuilder::default()
+            .add_state_event(EventsJson::Member)
+            .add_custom_joined_event(
+                room_id,
+                json!({
+                    "content": {
+                        "algorithm": "m.megolm.v1.aes-sha2",
+                        "ciphertext": "AwgAEpABhetEzzZzyYrxtEVUtlJnZtJcURBlQUQJ9irVeklCTs06LwgTMQj61PMUS4Vy\
+                                       YOX+PD67+hhU40/8olOww+Ud0m2afjMjC3wFX+4fFfSkoWPVHEmRVucfcdSF1RSB4EmK\
+                                       PIP4eo1X6x8kCIMewBvxl2sI9j4VNvDvAN7M3zkLJfFLOFHbBviI4FN7hSFHFeM739Zg\
+                                       iwxEs3hIkUXEiAfrobzaMEM/zY7SDrTdyffZndgJo7CZOVhoV6vuaOhmAy4X2t4UnbuV\
+                                       JGJjKfV57NAhp8W+9oT7ugwO",
+                        "device_id": "KIUVQQSDTM",
+                        "sender_key": "LvryVyCCaCjyRbo/vVoOGnqjVCMX9xNnKmF5vzCkqBc",
+                        "session_id": "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA"
+                    },
+                    "event_id": "$15824234321034vtUqO:localhost",
+                    "origin_server_ts": 1582423432345u64,
+                    "sender": "@alice:localhost",
+                    "type": "m.room.encrypted",
+                    "unsigned": {
+                        "age": 1234
+                    }
+                }),
+            )
+            .build_sync_response();
+
+        client.process_sync(response).await.unwrap();
+
+        assert_eq!(client.inner.undecryptable_events.len(), 1);
+        let events = client
+            .inner
+            .undecryptable_events
+            .get(&(room_id.to_owned(), "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA".to_owned()))
+            .unwrap()
+            .events
+            .clone();
+        assert_eq!(events.len(), 1);
+
+        let info = events[0].unable_to_decrypt_info.as_ref().unwrap();
+        assert_eq!(info.reason, UnableToDecryptReason::MissingSession);
+        assert_eq!(info.session_id, "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA");
+        assert!(!info.key_request_pending);
+
+        // Event handlers get the info as well.
+        let handler_info = handler_info.lock().unwrap().take().unwrap();
+        assert_eq!(handler_info.reason, UnableToDecryptReason::MissingSession);
+        assert_eq!(handler_info.session_id, info.session_id);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn undecryptable_events_are_retried() {
+        use std::{
+            future, iter,
+            sync::{Arc, Mutex},
+        };
+
+        use matrix_sdk_base::crypto::{encrypt_key_export, EncryptionSettings, OlmMachine};
+        use ruma::events::{
+            room::{
+                encrypted::EncryptedEventScheme,
+                message::{MessageType, RoomMessageEventContent, SyncRoomMessageEvent},
+            },
+            AnyMessageEventContent,
+        };
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost").to_owned(),
+            device_id: device_id!("DEVICEID").to_owned(),
+        };
+        let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new()
+            .request_config(RequestConfig::new().disable_retry())
+            .retry_decryption();
+        let client = Client::new_with_config(homeserver, config).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        let decrypted_messages = Arc::new(Mutex::new(Vec::new()));
+
+        client
+            .register_event_handler({
+                let decrypted_messages = decrypted_messages.clone();
+                move |event: SyncRoomMessageEvent| {
+                    decrypted_messages.lock().unwrap().push(event);
+                    future::ready(())
+                }
+            })
+            .await;
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let alice = OlmMachine::new(user_id!("@alice:localhost"), device_id!("ALICEDEVICE"));
+        alice
+            .share_group_session(room_id, iter::empty(), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let content = alice
+            .encrypt(
+                room_id,
+                AnyMessageEventContent::RoomMessage(RoomMessageEventContent::text_plain(
+                    "It's a secret to everybody",
+                )),
+            )
+            .await
+            .unwrap();
+
+        let session_id = match &content.scheme {
+            EncryptedEventScheme::MegolmV1AesSha2(c) => c.session_id.clone(),
+            _ => panic!("Invalid encryption scheme"),
+        };
+
+        let response = EventBuilder::default()
+            .add_state_event(EventsJson::Member)
+            .add_custom_joined_event(
+                room_id,
+                json!({
+                    "content": content,
+                    "event_id": "$15824234321034vtUqO:localhost",
+                    "origin_server_ts": 1582423432345u64,
+                    "sender": "@alice:localhost",
+                    "type": "m.room.encrypted",
+                }),
+            )
+            .build_sync_response();
+
+        client.process_sync(response).await.unwrap();
+
+        assert!(decrypted_messages.lock().unwrap().is_empty());
+        assert!(client
+            .inner
+            .undecryptable_events
+            .contains_key(&(room_id.to_owned(), session_id.clone())));
+
+        // A sync that doesn't bring us the room key doesn't retry the event.
+        client.process_sync(EventBuilder::default().build_sync_response()).await.unwrap();
+        assert!(decrypted_messages.lock().unwrap().is_empty());
+
+        // Import the room key from a key export, the next sync retries the
+        // event.
+        let exported_keys = alice.export_keys(|_| true).await.unwrap();
+        let export = encrypt_key_export(&exported_keys, "1234", 1).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.txt");
+        std::fs::write(&path, export).unwrap();
+
+        let result = client.import_keys(path, "1234").await.unwrap();
+        assert_eq!(result.imported_count, 1);
+        assert!(client.inner.restored_room_keys.contains(&(room_id.to_owned(), session_id)));
+
+        client.process_sync(EventBuilder::default().build_sync_response()).await.unwrap();
+
+        let decrypted_messages = decrypted_messages.lock().unwrap();
+        assert_eq!(decrypted_messages.len(), 1);
+        matches::assert_matches!(
+            &decrypted_messages[0].content.msgtype,
+            MessageType::Text(c) if c.body == "It's a secret to everybody"
+        );
+        assert!(client.inner.undecryptable_events.is_empty());
+        assert!(client.inner.restored_room_keys.is_empty());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn backup_check() {
+        use crate::encryption::backups::BackupState;
+
+        let client = logged_in_client().await;
+
+        let m = mock("GET", "/_matrix/client/r0/room_keys/version")
+            .with_status(404)
+            .with_body(
+                json!({
+                    "errcode": "M_NOT_FOUND",
+                    "error": "No current backup version"
+                })
+                .to_string(),
+            )
+            .create();
+
+        assert_eq!(client.backups().check().await.unwrap(), BackupState::NoBackup);
+        assert!(!client.backups().enabled().await);
+        drop(m);
+
+        let _m = mock("GET", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+                    "auth_data": {
+                        "public_key": "hdx0/eoKYPoGm7HKEEIBpeUXMMXd1Mi0uxv8utXbYUA",
+                        "signatures": {}
+                    },
+                    "count": 0,
+                    "etag": "0",
+                    "version": "1"
+                })
+                .to_string(),
+            )
+            .create();
+
+        assert_eq!(
+            client.backups().check().await.unwrap(),
+            BackupState::Untrusted { version: "1".to_owned() }
+        );
+        assert!(!client.backups().enabled().await);
+    }
 }
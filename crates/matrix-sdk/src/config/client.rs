@@ -50,6 +50,8 @@ pub struct ClientConfig {
     pub(crate) client: Option<Arc<dyn HttpSend>>,
     pub(crate) appservice_mode: bool,
     pub(crate) use_discovery_response: bool,
+    #[cfg(feature = "encryption")]
+    pub(crate) retry_decryption_enabled: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -188,10 +190,66 @@ impl ClientConfig {
         self
     }
 
+    /// Pin the master key of user identities the first time we see them.
+    ///
+    /// Pinned identities are considered to be trusted on first use, which
+    /// allows bots to only share room keys with trusted devices without a
+    /// human doing interactive verifications. See
+    /// [`OlmMachine::set_trust_on_first_use()`] for more info.
+    ///
+    /// [`OlmMachine::set_trust_on_first_use()`]: matrix_sdk_base::crypto::OlmMachine::set_trust_on_first_use
+    #[cfg(feature = "encryption")]
+    pub fn trust_on_first_use(mut self) -> Self {
+        self.base_config = self.base_config.trust_on_first_use();
+        self
+    }
+
+    /// Set the strategy that decides which devices of the room members should
+    /// receive our room keys.
+    ///
+    /// This is used for all rooms that don't have a strategy set using
+    /// [`Joined::set_room_key_sharing_strategy()`].
+    ///
+    /// [`Joined::set_room_key_sharing_strategy()`]: crate::room::Joined::set_room_key_sharing_strategy
+    #[cfg(feature = "encryption")]
+    pub fn room_key_sharing_strategy(
+        mut self,
+        strategy: matrix_sdk_base::crypto::ShareStrategy,
+    ) -> Self {
+        self.base_config = self.base_config.room_key_sharing_strategy(strategy);
+        self
+    }
+
+    /// Set the policy that decides when the room keys of rooms that we left
+    /// get deleted.
+    ///
+    /// By default room keys are never deleted.
+    #[cfg(feature = "encryption")]
+    pub fn room_key_retention_policy(
+        mut self,
+        policy: matrix_sdk_base::RoomKeyRetentionPolicy,
+    ) -> Self {
+        self.base_config = self.base_config.room_key_retention_policy(policy);
+        self
+    }
+
     /// Update the client's homeserver URL with the discovery information
     /// present in the login response, if any.
     pub fn use_discovery_response(mut self) -> Self {
         self.use_discovery_response = true;
         self
     }
+
+    /// Keep track of room events that couldn't be decrypted and pass them to
+    /// the event handlers again once their room key arrives.
+    ///
+    /// The missing room keys are requested from our other devices and, if
+    /// we have the recovery key, restored from the server-side key backup.
+    /// Event handlers might thus receive an `m.room.encrypted` event and,
+    /// some syncs later, the decrypted version of the same event.
+    #[cfg(feature = "encryption")]
+    pub fn retry_decryption(mut self) -> Self {
+        self.retry_decryption_enabled = true;
+        self
+    }
 }
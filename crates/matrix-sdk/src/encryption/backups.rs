@@ -0,0 +1,273 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server-side key backups.
+//!
+//! The [`Backups`] type discovers the current backup version on the
+//! homeserver, checks if we trust it and enables or disables the backing up of
+//! our room keys accordingly.
+//!
+//! The check runs automatically once the [`Client`] starts sending out its
+//! end-to-end encryption requests, and again if the homeserver tells us that
+//! the backup we were uploading room keys to was replaced or deleted.
+
+use matrix_sdk_base::crypto::backups::MegolmV1BackupKey;
+use ruma::api::{
+    client::{error::ErrorKind, r0::backup::get_latest_backup_info},
+    error::{FromHttpResponseError, ServerError},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{error::HttpError, Client, Error, Result};
+
+/// The only backup algorithm we support.
+const MEGOLM_V1_BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+/// The state of the server-side key backup, as seen by this client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackupState {
+    /// There is no backup on the homeserver.
+    NoBackup,
+    /// There is a backup on the homeserver, but it uses an unsupported
+    /// algorithm or we don't trust it, our room keys aren't backed up to it.
+    Untrusted {
+        /// The version of the backup.
+        version: String,
+    },
+    /// Our room keys are being backed up to the backup with the given version.
+    Enabled {
+        /// The version of the backup.
+        version: String,
+    },
+}
+
+/// Information about the current backup on the homeserver.
+#[derive(Clone, Debug)]
+pub struct BackupInfo {
+    /// The version of the backup.
+    pub version: String,
+    /// The algorithm the backup uses, e.g.
+    /// `m.megolm_backup.v1.curve25519-aes-sha2`.
+    pub algorithm: String,
+    /// The algorithm dependent data of the backup, for the megolm v1 algorithm
+    /// this contains the public key and the signatures of the backup.
+    pub auth_data: Value,
+    /// The number of room keys stored in the backup.
+    pub count: u64,
+    /// An opaque string representing the stored room keys.
+    pub etag: String,
+}
+
+#[derive(Deserialize)]
+struct MegolmV1AuthData {
+    public_key: String,
+}
+
+/// Access to the server-side key backup of our account.
+///
+/// This can be obtained using [`Client::backups()`].
+#[derive(Clone, Debug)]
+pub struct Backups {
+    pub(crate) client: Client,
+}
+
+impl Backups {
+    /// Fetch information about the current backup from the homeserver.
+    ///
+    /// Returns `None` if there is no backup on the homeserver.
+    pub async fn fetch_info(&self) -> Result<Option<BackupInfo>> {
+        let request = get_latest_backup_info::Request::new();
+
+        let response = match self.client.send(request, None).await {
+            Ok(r) => r,
+            Err(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+                if matches!(e.kind, ErrorKind::NotFound) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut algorithm = response.algorithm.deserialize_as::<Value>()?;
+
+        Ok(Some(BackupInfo {
+            version: response.version,
+            algorithm: algorithm
+                .get("algorithm")
+                .and_then(|a| a.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+            auth_data: algorithm.get_mut("auth_data").map(Value::take).unwrap_or_default(),
+            count: response.count.into(),
+            etag: response.etag,
+        }))
+    }
+
+    /// Are our room keys currently being backed up.
+    pub async fn enabled(&self) -> bool {
+        if let Some(olm) = self.client.olm_machine().await {
+            olm.backup_machine().enabled().await
+        } else {
+            false
+        }
+    }
+
+    /// Check the current backup on the homeserver and enable or disable the
+    /// backing up of our room keys to match it.
+    ///
+    /// Room keys are backed up if the backup uses a supported algorithm and
+    /// either has a valid signature from one of our verified devices or our
+    /// cross-signing identity, or it belongs to the recovery key we have
+    /// stored.
+    ///
+    /// If the backup version changed since the last check, all our room keys
+    /// will be backed up again.
+    pub async fn check(&self) -> Result<BackupState> {
+        let olm = self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let backup_machine = olm.backup_machine();
+
+        let info = if let Some(info) = self.fetch_info().await? {
+            info
+        } else {
+            if backup_machine.enabled().await {
+                info!("The server-side key backup was deleted, disabling backups");
+                backup_machine.disable_backup().await?;
+            }
+
+            return Ok(BackupState::NoBackup);
+        };
+
+        let untrusted = || async {
+            if backup_machine.enabled().await {
+                backup_machine.disable_backup().await?;
+            }
+
+            Ok::<_, Error>(BackupState::Untrusted { version: info.version.clone() })
+        };
+
+        if info.algorithm != MEGOLM_V1_BACKUP_ALGORITHM {
+            warn!(
+                version = info.version.as_str(),
+                algorithm = info.algorithm.as_str(),
+                "The server-side key backup uses an unsupported algorithm"
+            );
+
+            return untrusted().await;
+        }
+
+        let public_key = match serde_json::from_value::<MegolmV1AuthData>(info.auth_data.clone()) {
+            Ok(a) => a.public_key,
+            Err(e) => {
+                warn!(
+                    version = info.version.as_str(),
+                    error =? e,
+                    "The server-side key backup contains invalid auth data"
+                );
+
+                return untrusted().await;
+            }
+        };
+
+        let backup_keys = backup_machine.get_backup_keys().await?;
+        let recovery_key =
+            backup_keys.recovery_key.filter(|k| k.megolm_v1_public_key().to_base64() == public_key);
+
+        let trusted =
+            recovery_key.is_some() || backup_machine.verify_backup(info.auth_data.clone()).await?;
+
+        if !trusted {
+            warn!(version = info.version.as_str(), "The server-side key backup isn't trusted");
+            return untrusted().await;
+        }
+
+        if backup_machine.backup_version().await.as_deref() == Some(info.version.as_str()) {
+            return Ok(BackupState::Enabled { version: info.version });
+        }
+
+        let key = match MegolmV1BackupKey::from_base64(&public_key) {
+            Ok(k) => k,
+            Err(e) => {
+                warn!(
+                    version = info.version.as_str(),
+                    error =? e,
+                    "The server-side key backup contains an invalid public key"
+                );
+
+                return untrusted().await;
+            }
+        };
+
+        if backup_keys.backup_version.as_deref() != Some(info.version.as_str()) {
+            // This is a new backup, disabling resets the backed up state of our
+            // room keys so they all get uploaded to the new backup.
+            backup_machine.disable_backup().await?;
+            backup_machine.save_recovery_key(recovery_key, Some(info.version.clone())).await?;
+        }
+
+        info!(version = info.version.as_str(), "Enabling the server-side key backup");
+
+        key.set_version(info.version.clone());
+        backup_machine.enable_backup_v1(key).await?;
+
+        Ok(BackupState::Enabled { version: info.version })
+    }
+
+    /// Handle an error we received while uploading room keys to the backup.
+    ///
+    /// Returns `true` if the error means that the backup we were uploading to
+    /// was replaced or deleted. The current backup on the homeserver is
+    /// checked again to find out, which enables backups for the new backup if
+    /// we trust it.
+    pub(crate) async fn handle_upload_error(&self, error: &HttpError) -> bool {
+        let kind = match error {
+            HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))) => &e.kind,
+            _ => return false,
+        };
+
+        // A deleted backup is reported using a `M_NOT_FOUND` error, but the
+        // error might have other causes as well, so the current backup gets
+        // checked before we decide that our backup is gone.
+        if !matches!(kind, ErrorKind::WrongRoomKeysVersion { .. } | ErrorKind::NotFound) {
+            return false;
+        }
+
+        let olm = if let Some(olm) = self.client.olm_machine().await {
+            olm
+        } else {
+            return false;
+        };
+
+        let our_version = olm.backup_machine().backup_version().await;
+
+        let changed = match self.check().await {
+            Ok(BackupState::Enabled { version }) => Some(version) != our_version,
+            Ok(_) => true,
+            Err(e) => {
+                warn!(error =? e, "Couldn't check the server-side key backup");
+                false
+            }
+        };
+
+        if changed {
+            info!(
+                old_version = our_version.as_deref(),
+                "The server-side key backup was replaced or deleted"
+            );
+        }
+
+        changed
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dehydrated devices.
+//!
+//! The [`DehydratedDevices`] type stores a [dehydrated device] on the
+//! homeserver, so other devices can keep sending us room keys while all of our
+//! devices are offline, and rehydrates it on a new login to import the room
+//! keys it received in the meantime.
+//!
+//! The endpoints this uses aren't part of the spec yet, the homeserver needs to
+//! support [MSC3814].
+//!
+//! [dehydrated device]: matrix_sdk_base::crypto::dehydrated_devices
+//! [MSC3814]: https://github.com/matrix-org/matrix-doc/pull/3814
+
+use matrix_sdk_base::crypto::RoomKeyImportResult;
+use ruma::{
+    api::{
+        client::error::ErrorKind,
+        error::{FromHttpResponseError, ServerError},
+    },
+    DeviceId,
+};
+use tracing::info;
+
+use crate::{error::HttpError, Client, Error, Result};
+
+/// Access to the dehydrated device of our account.
+///
+/// This can be obtained using [`Client::dehydrated_devices()`].
+#[derive(Clone, Debug)]
+pub struct DehydratedDevices {
+    pub(crate) client: Client,
+}
+
+impl DehydratedDevices {
+    /// Create a new dehydrated device and store it on the homeserver.
+    ///
+    /// This replaces the dehydrated device that was previously stored on the
+    /// homeserver, if any.
+    ///
+    /// Returns the device id of the new dehydrated device.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that is used to encrypt the dehydrated device,
+    /// the same key needs to be given to [`rehydrate()`](Self::rehydrate).
+    ///
+    /// * `display_name` - The display name the dehydrated device should get.
+    pub async fn create(&self, pickle_key: &[u8], display_name: &str) -> Result<Box<DeviceId>> {
+        let olm = self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let device = olm.create_dehydrated_device(pickle_key, display_name).await;
+        let device_request = device.request().clone();
+
+        let request = upload_dehydrated_device::Request {
+            device_id: device_request.device_id,
+            device_data: device_request.device_data,
+            initial_device_display_name: device_request.initial_device_display_name,
+            device_keys: device_request.device_keys,
+            one_time_keys: device_request.one_time_keys,
+        };
+        let device_id = self.client.send(request, None).await?.device_id;
+
+        info!(device_id = device_id.as_str(), "Stored a new dehydrated device");
+
+        Ok(device_id)
+    }
+
+    /// Rehydrate the dehydrated device that is stored on the homeserver and
+    /// import the room keys it received.
+    ///
+    /// Returns `None` if there is no dehydrated device on the homeserver.
+    ///
+    /// The dehydrated device stays on the homeserver, a new one should be
+    /// created using [`create()`](Self::create) afterwards so the room keys
+    /// that were already imported aren't fetched again.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that was used to create the dehydrated device.
+    pub async fn rehydrate(&self, pickle_key: &[u8]) -> Result<Option<RoomKeyImportResult>> {
+        let olm = self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let device = match self.client.send(get_dehydrated_device::Request {}, None).await {
+            Ok(r) => r,
+            Err(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+                if matches!(e.kind, ErrorKind::NotFound) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let device_id = device.device_id;
+        let mut to_device_events = Vec::new();
+        let mut next_batch = None;
+
+        loop {
+            let request =
+                get_dehydrated_device_events::Request { device_id: device_id.clone(), next_batch };
+            let response = self.client.send(request, None).await?;
+
+            if response.events.is_empty() {
+                break;
+            }
+
+            to_device_events.extend(response.events);
+
+            if response.next_batch.is_none() {
+                break;
+            }
+
+            next_batch = response.next_batch;
+        }
+
+        let result = olm
+            .rehydrate_device(pickle_key, &device_id, device.device_data, to_device_events)
+            .await?;
+
+        Ok(Some(result))
+    }
+
+    /// Delete the dehydrated device that is stored on the homeserver.
+    ///
+    /// Returns the device id of the deleted dehydrated device, or `None` if
+    /// there was no dehydrated device on the homeserver.
+    pub async fn delete(&self) -> Result<Option<Box<DeviceId>>> {
+        match self.client.send(delete_dehydrated_device::Request {}, None).await {
+            Ok(r) => {
+                info!(device_id = r.device_id.as_str(), "Deleted the dehydrated device");
+                Ok(Some(r.device_id))
+            }
+            Err(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+                if matches!(e.kind, ErrorKind::NotFound) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+mod upload_dehydrated_device {
+    use std::collections::BTreeMap;
+
+    use matrix_sdk_base::crypto::dehydrated_devices::DehydratedDeviceData;
+    use ruma::{
+        api::ruma_api,
+        encryption::{DeviceKeys, OneTimeKey},
+        serde::Raw,
+        DeviceId, DeviceKeyId,
+    };
+
+    ruma_api! {
+        metadata: {
+            description: "Store a dehydrated device and its keys on the homeserver.",
+            method: PUT,
+            name: "upload_dehydrated_device",
+            path: "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            pub device_id: Box<DeviceId>,
+            pub device_data: DehydratedDeviceData,
+            pub initial_device_display_name: String,
+            pub device_keys: Raw<DeviceKeys>,
+
+            #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+            pub one_time_keys: BTreeMap<Box<DeviceKeyId>, Raw<OneTimeKey>>,
+        }
+
+        response: {
+            pub device_id: Box<DeviceId>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+mod get_dehydrated_device {
+    use matrix_sdk_base::crypto::dehydrated_devices::DehydratedDeviceData;
+    use ruma::{api::ruma_api, DeviceId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the dehydrated device that is stored on the homeserver.",
+            method: GET,
+            name: "get_dehydrated_device",
+            path: "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {}
+
+        response: {
+            pub device_id: Box<DeviceId>,
+            pub device_data: DehydratedDeviceData,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+mod delete_dehydrated_device {
+    use ruma::{api::ruma_api, DeviceId};
+
+    ruma_api! {
+        metadata: {
+            description: "Delete the dehydrated device that is stored on the homeserver.",
+            method: DELETE,
+            name: "delete_dehydrated_device",
+            path: "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {}
+
+        response: {
+            pub device_id: Box<DeviceId>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+mod get_dehydrated_device_events {
+    use ruma::{api::ruma_api, events::AnyToDeviceEvent, serde::Raw, DeviceId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the to-device events that were sent to a dehydrated device.",
+            method: POST,
+            name: "get_dehydrated_device_events",
+            path: "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/:device_id/events",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub device_id: Box<DeviceId>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        response: {
+            #[serde(default)]
+            pub events: Vec<Raw<AnyToDeviceEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
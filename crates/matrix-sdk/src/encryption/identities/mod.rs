@@ -91,7 +91,9 @@ mod devices;
 mod users;
 
 pub use devices::{Device, UserDevices};
-pub use matrix_sdk_base::crypto::MasterPubkey;
+pub use matrix_sdk_base::crypto::{
+    IdentityTrustState, MasterPubkey, RoomTrustReport, ShieldState, UserTrustReport,
+};
 pub use users::UserIdentity;
 
 /// Error for the manual verification step, when we manually sign users or
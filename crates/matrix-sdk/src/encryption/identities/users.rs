@@ -16,7 +16,8 @@ use std::sync::Arc;
 
 use matrix_sdk_base::{
     crypto::{
-        MasterPubkey, OwnUserIdentity as InnerOwnUserIdentity, UserIdentity as InnerUserIdentity,
+        store::CryptoStoreError, MasterPubkey, OwnUserIdentity as InnerOwnUserIdentity,
+        UserIdentity as InnerUserIdentity,
     },
     locks::RwLock,
 };
@@ -28,7 +29,7 @@ use ruma::{
     UserId,
 };
 
-use super::{ManualVerifyError, RequestVerificationError};
+use super::{IdentityTrustState, ManualVerifyError, RequestVerificationError};
 use crate::{encryption::verification::VerificationRequest, room::Joined, Client};
 
 /// A struct representing a E2EE capable identity of a user.
@@ -348,6 +349,86 @@ impl UserIdentity {
         }
     }
 
+    /// Get the trust state of the user identity.
+    ///
+    /// Besides being verified, a user identity can be [pinned] if trust on
+    /// first use is enabled, see [`ClientConfig::trust_on_first_use()`].
+    ///
+    /// [pinned]: IdentityTrustState::Pinned
+    /// [`ClientConfig::trust_on_first_use()`]: crate::config::ClientConfig::trust_on_first_use
+    pub fn trust_state(&self) -> IdentityTrustState {
+        match &self.inner {
+            UserIdentities::Own(i) => i.inner.trust_state(),
+            UserIdentities::Other(i) => i.inner.trust_state(),
+        }
+    }
+
+    /// Has the user identity changed since we last verified it.
+    ///
+    /// This is the case if we verified the user identity in the past, but the
+    /// Master key of the user changed and the new identity isn't verified.
+    /// Such a change might mean that the account of the user was taken over,
+    /// so it should be surfaced to the user.
+    ///
+    /// The violation is resolved once the new identity gets verified, e.g.
+    /// using [`UserIdentity::request_verification()`], or once the change gets
+    /// acknowledged using [`UserIdentity::withdraw_verification()`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::{Client, ruma::UserId};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # let alice = Box::<UserId>::try_from("@alice:example.org").unwrap();
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let user = client.get_user_identity(&alice).await?;
+    ///
+    /// if let Some(user) = user {
+    ///     if user.has_verification_violation() {
+    ///         println!("The identity of {} changed since we verified it", user.user_id());
+    ///     }
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub fn has_verification_violation(&self) -> bool {
+        match &self.inner {
+            UserIdentities::Own(i) => i.inner.has_verification_violation(),
+            UserIdentities::Other(i) => i.inner.has_verification_violation(),
+        }
+    }
+
+    /// Acknowledge that the user identity changed since we last verified it.
+    ///
+    /// This forgets that we verified the user identity in the past, which
+    /// resolves the [verification violation].
+    ///
+    /// [verification violation]: UserIdentity::has_verification_violation
+    pub async fn withdraw_verification(&self) -> Result<(), CryptoStoreError> {
+        match &self.inner {
+            UserIdentities::Own(i) => i.inner.withdraw_verification().await,
+            UserIdentities::Other(i) => i.inner.withdraw_verification().await,
+        }
+    }
+
+    /// Pin the current Master key of the user identity.
+    ///
+    /// This accepts a Master key that doesn't match the one we pinned when we
+    /// first saw the identity, without verifying the identity. Afterwards the
+    /// [trust state] of the identity will be [pinned].
+    ///
+    /// [trust state]: UserIdentity::trust_state
+    /// [pinned]: IdentityTrustState::Pinned
+    pub async fn pin_current_master_key(&self) -> Result<(), CryptoStoreError> {
+        match &self.inner {
+            UserIdentities::Own(i) => i.inner.pin_current_master_key().await,
+            UserIdentities::Other(i) => i.inner.pin_current_master_key().await,
+        }
+    }
+
     /// Get the public part of the Master key of this user identity.
     ///
     /// The public part of the Master key is usually used to uniquely identify
@@ -461,16 +542,7 @@ impl OtherUserIdentity {
         methods: Option<Vec<VerificationMethod>>,
     ) -> Result<VerificationRequest, RequestVerificationError> {
         let content = self.inner.verification_request_content(methods.clone()).await;
-
-        let room = if let Some(room) = self.direct_message_room.read().await.as_ref() {
-            room.clone()
-        } else if let Some(room) =
-            self.client.create_dm_room(self.inner.user_id().to_owned()).await?
-        {
-            room
-        } else {
-            return Err(RequestVerificationError::RoomCreation(self.inner.user_id().to_owned()));
-        };
+        let room = self.direct_message_room().await?;
 
         let response = room
             .send(RoomMessageEventContent::new(MessageType::VerificationRequest(content)), None)
@@ -482,6 +554,35 @@ impl OtherUserIdentity {
         Ok(VerificationRequest { inner: verification, client: self.client.clone() })
     }
 
+    /// Get the DM room we share with the user, creating a new one if we don't
+    /// share one yet.
+    ///
+    /// The room is remembered, so further verification requests end up in the
+    /// same room.
+    async fn direct_message_room(&self) -> Result<Joined, RequestVerificationError> {
+        let mut direct_message_room = self.direct_message_room.write().await;
+
+        if let Some(room) = direct_message_room.as_ref() {
+            return Ok(room.clone());
+        }
+
+        let user_id = self.inner.user_id();
+
+        // The DM might have been created, or marked as one in `m.direct`,
+        // since this identity was fetched.
+        let room = if let Some(room) = self.client.get_dm_room(user_id) {
+            room
+        } else if let Some(room) = self.client.create_dm_room(user_id.to_owned()).await? {
+            room
+        } else {
+            return Err(RequestVerificationError::RoomCreation(user_id.to_owned()));
+        };
+
+        *direct_message_room = Some(room.clone());
+
+        Ok(room)
+    }
+
     async fn verify(&self) -> Result<(), ManualVerifyError> {
         let request = self.inner.verify().await?;
         self.client.send(request, None).await?;
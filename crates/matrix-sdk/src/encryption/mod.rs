@@ -245,28 +245,47 @@
 //! [spec]: https://spec.matrix.org/unstable/client-server-api/#relationship-between-access-tokens-and-devices
 //! [device keys]: https://spec.matrix.org/unstable/client-server-api/#device-keys
 
+pub mod backups;
+pub mod dehydrated_devices;
 pub mod identities;
 pub mod verification;
 use std::{
-    collections::{BTreeMap, HashSet},
-    io::{Read,Write},
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    future::Future,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
     path::PathBuf,
     result::Result as StdResult, iter,
+    sync::{atomic::Ordering, Arc, Mutex as StdMutex},
 };
 
-use futures_util::stream::{self, StreamExt};
-pub use matrix_sdk_base::crypto::{MediaEncryptionInfo, LocalTrust, RoomKeyImportResult};
+use dashmap::mapref::entry::Entry;
+use futures_util::{
+    io::AsyncRead,
+    stream::{self, StreamExt},
+};
+pub use matrix_sdk_base::crypto::{
+    backups::RecoveryKey, secret_storage::SecretStorageKey, LocalTrust, MediaEncryptionInfo,
+    RoomKeyImportResult, ShareStrategy,
+};
+pub use matrix_sdk_base::RoomKeyRetentionPolicy;
 use matrix_sdk_base::{
     crypto::{
-        store::CryptoStoreError, CrossSigningStatus, OutgoingRequest, RoomMessageRequest,
-        ToDeviceRequest,
+        secret_storage, store::CryptoStoreError, AsyncAttachmentEncryptor, CrossSigningStatus,
+        OutgoingRequest, RoomMessageRequest, ToDeviceRequest,
     },
-    deserialized_responses::RoomEvent,
+    deserialized_responses::{RoomEvent, SyncRoomEvent},
+};
+use matrix_sdk_common::{
+    executor::spawn,
+    instant::{Duration, Instant},
+    uuid::Uuid,
 };
-use matrix_sdk_common::{instant::Duration, uuid::Uuid};
 use ruma::{
     api::client::r0::{
-        backup::add_backup_keys::Response as KeysBackupResponse,
+        backup::{
+            add_backup_keys::Response as KeysBackupResponse, get_backup_key_session,
+            get_backup_key_sessions, get_backup_keys, RoomKeyBackup,
+        },
         keys::{get_keys, upload_keys, upload_signing_keys::Request as UploadSigningKeysRequest},
         message::send_message_event,
         to_device::send_event_to_device::{
@@ -275,22 +294,94 @@ use ruma::{
         uiaa::AuthData,
     },
     assign,
-    events::{AnyMessageEvent, AnyRoomEvent, AnySyncMessageEvent, EventType},
+    events::{
+        room::encrypted::EncryptedEventScheme, AnyMessageEvent, AnyRoomEvent, AnySyncMessageEvent,
+        AnySyncRoomEvent, AnyToDeviceEvent, EventType,
+    },
     serde::Raw,
-    DeviceId, UserId,
+    DeviceId, RoomId, UserId,
 };
 use tracing::{debug, instrument, trace, warn};
 
 use crate::{
     encryption::{
+        backups::Backups,
+        dehydrated_devices::DehydratedDevices,
         identities::{Device, UserDevices},
         verification::{SasVerification, Verification, VerificationRequest},
     },
     error::{HttpError, HttpResult, RoomKeyImportError},
+    http_client::reader_stream,
     room, Client, Error, Result,
 };
 
+/// The events that were encrypted using a room key we don't have yet.
+#[derive(Debug)]
+pub(crate) struct UndecryptableEvents {
+    /// When we first failed to decrypt an event using this room key.
+    pub(crate) first_seen: Instant,
+    /// The events, oldest first.
+    pub(crate) events: VecDeque<SyncRoomEvent>,
+}
+
 impl Client {
+    /// Get the server-side key backup of our account.
+    ///
+    /// See the [`backups`] module for more info.
+    #[cfg(feature = "encryption")]
+    pub fn backups(&self) -> Backups {
+        Backups { client: self.clone() }
+    }
+
+    /// Get the dehydrated device of our account.
+    ///
+    /// See the [`dehydrated_devices`] module for more info.
+    #[cfg(feature = "encryption")]
+    pub fn dehydrated_devices(&self) -> DehydratedDevices {
+        DehydratedDevices { client: self.clone() }
+    }
+
+    /// Register a handler that is called when the identity of a user changes
+    /// after we verified it.
+    ///
+    /// The handler is called once we receive the new identity from the
+    /// homeserver. Such a change might mean that the account of the user was
+    /// taken over, see [`UserIdentity::has_verification_violation()`] for
+    /// more info.
+    ///
+    /// [`UserIdentity::has_verification_violation()`]: crate::encryption::identities::UserIdentity::has_verification_violation
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, encryption::identities::UserIdentity};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// client
+    ///     .register_verification_violation_handler(|identity: UserIdentity, _| async move {
+    ///         println!("The identity of {} changed since we verified it", identity.user_id());
+    ///     })
+    ///     .await;
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub async fn register_verification_violation_handler<H, Fut>(&self, handler: H) -> &Self
+    where
+        H: Fn(crate::encryption::identities::UserIdentity, Client) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.inner
+            .verification_violation_handlers
+            .write()
+            .await
+            .push(Box::new(move |identity, client| Box::pin((handler)(identity, client))));
+
+        self
+    }
+
     /// Get the public ed25519 key of our own device. This is usually what is
     /// called the fingerprint of the device.
     #[cfg(feature = "encryption")]
@@ -320,6 +411,31 @@ impl Client {
         self.olm_machine().await.map(|o| o.tracked_users()).unwrap_or_default()
     }
 
+    /// Remove old Olm sessions from the crypto store.
+    ///
+    /// Only the `max_sessions_per_sender` most recently used Olm sessions are
+    /// kept for every device we share sessions with. Sessions with devices
+    /// that were deleted are removed as well.
+    ///
+    /// Returns the number of sessions that were removed.
+    #[cfg(feature = "encryption")]
+    pub async fn prune_olm_sessions(&self, max_sessions_per_sender: usize) -> Result<usize> {
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        Ok(olm.prune_olm_sessions(max_sessions_per_sender).await?)
+    }
+
+    /// Remove the outgoing room key and secret requests that are older than
+    /// the given maximal age.
+    ///
+    /// Requests that were already sent out get cancelled with the next sync.
+    ///
+    /// Returns the number of requests that were removed.
+    #[cfg(feature = "encryption")]
+    pub async fn expire_secret_requests(&self, max_age: Duration) -> Result<usize> {
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        Ok(olm.expire_secret_requests(max_age).await?)
+    }
+
     /// Get a verification object with the given flow id.
     #[cfg(feature = "encryption")]
     pub async fn get_verification(&self, user_id: &UserId, flow_id: &str) -> Option<Verification> {
@@ -608,20 +724,24 @@ impl Client {
         predicate: impl FnMut(&matrix_sdk_base::crypto::olm::InboundGroupSession) -> bool,
     ) -> Result<()> {
         let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
-
-        let keys = olm.export_keys(predicate).await?;
         let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
 
-        let encrypt = move || -> Result<()> {
-            let export: String =
-                matrix_sdk_base::crypto::encrypt_key_export(&keys, &passphrase, 500_000)?;
-            let mut file = std::fs::File::create(path)?;
-            file.write_all(&export.into_bytes())?;
-            Ok(())
+        // Deriving the export key from the passphrase is expensive, create the
+        // file and the writer on a blocking thread.
+        let create_writer = move || -> Result<_> {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            Ok(matrix_sdk_base::crypto::KeyExportWriter::new(file, &passphrase, 500_000)?)
         };
 
-        let task = tokio::task::spawn_blocking(encrypt);
-        task.await.expect("Task join error")
+        let task = tokio::task::spawn_blocking(create_writer);
+        let mut writer = task.await.expect("Task join error")?;
+
+        // The room keys are encrypted and written out in batches while they
+        // are loaded from the store, so they are never all held in memory.
+        olm.export_keys_streaming(&mut writer, predicate).await?;
+        writer.finish()?;
+
+        Ok(())
     }
 
     /// Import E2EE keys from the given file path.
@@ -669,16 +789,275 @@ impl Client {
     ) -> StdResult<RoomKeyImportResult, RoomKeyImportError> {
         let olm = self.olm_machine().await.ok_or(RoomKeyImportError::StoreClosed)?;
         let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+        let runtime = tokio::runtime::Handle::current();
 
-        let decrypt = move || {
+        // Deriving the export key, checking the MAC and reading and decrypting
+        // the room keys is expensive and blocking, do all of it on a blocking
+        // thread.
+        let import = move || -> StdResult<_, RoomKeyImportError> {
             let file = std::fs::File::open(path)?;
-            matrix_sdk_base::crypto::decrypt_key_export(file, &passphrase)
+            let reader = matrix_sdk_base::crypto::KeyExportReader::new(file, &passphrase)?;
+
+            Ok(runtime.block_on(olm.import_keys_streaming(reader, false, |_| {}))?)
         };
 
-        let task = tokio::task::spawn_blocking(decrypt);
-        let import = task.await.expect("Task join error")?;
+        let task = tokio::task::spawn_blocking(import);
+        let result = task.await.expect("Task join error")?;
+
+        self.remember_restored_room_keys(&result);
+
+        Ok(result)
+    }
+
+    /// Restore all the room keys that are stored in the server-side key
+    /// backup.
+    ///
+    /// The room keys will be downloaded from the homeserver, decrypted using
+    /// the given recovery key and imported into our crypto store. Room keys
+    /// that can't be decrypted with the recovery key will be skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key that belongs to the backup.
+    ///
+    /// * `version` - The version of the backup that should be restored.
+    ///
+    /// * `progress_listener` - A closure that will be called every time a
+    /// room key has been processed. The first argument is the number of
+    /// processed room keys, the second one the total number of room keys
+    /// in the backup.
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, encryption::RecoveryKey};
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver)?;
+    /// # let user_input = "";
+    /// let recovery_key = RecoveryKey::from_base58(user_input)?;
+    ///
+    /// let result = client
+    ///     .restore_backup(&recovery_key, "1", |current, total| {
+    ///         println!("Restored {} out of {} room keys", current, total)
+    ///     })
+    ///     .await?;
+    ///
+    /// println!(
+    ///     "Imported {} room keys out of {}",
+    ///     result.imported_count, result.total_count
+    /// );
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub async fn restore_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        version: &str,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let request = get_backup_keys::Request::new(version);
+        let response = self.send(request, None).await?;
+
+        self.import_backup(recovery_key, response.rooms, progress_listener).await
+    }
+
+    /// Restore the room keys of a single room from the server-side key
+    /// backup.
+    ///
+    /// This behaves like [`Client::restore_backup()`] but only downloads and
+    /// imports the room keys that belong to the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key that belongs to the backup.
+    ///
+    /// * `version` - The version of the backup that should be restored.
+    ///
+    /// * `room_id` - The id of the room for which the room keys should be
+    /// restored.
+    ///
+    /// * `progress_listener` - A closure that will be called every time a
+    /// room key has been processed.
+    #[cfg(feature = "encryption")]
+    pub async fn restore_room_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        version: &str,
+        room_id: &RoomId,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let request = get_backup_key_sessions::Request::new(version, room_id);
+        let response = self.send(request, None).await?;
+
+        let backup = BTreeMap::from([(room_id.to_owned(), RoomKeyBackup::new(response.sessions))]);
+
+        self.import_backup(recovery_key, backup, progress_listener).await
+    }
+
+    /// Restore a single room key from the server-side key backup.
+    ///
+    /// This is useful if we received an event that we can't decrypt, the room
+    /// key might be available in the backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key that belongs to the backup.
+    ///
+    /// * `version` - The version of the backup that should be restored.
+    ///
+    /// * `room_id` - The id of the room the room key belongs to.
+    ///
+    /// * `session_id` - The unique id of the room key.
+    #[cfg(feature = "encryption")]
+    pub async fn restore_backup_session(
+        &self,
+        recovery_key: &RecoveryKey,
+        version: &str,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<RoomKeyImportResult> {
+        let request = get_backup_key_session::Request::new(version, room_id, session_id);
+        let response = self.send(request, None).await?;
+
+        let sessions = BTreeMap::from([(session_id.to_owned(), response.key_data)]);
+        let backup = BTreeMap::from([(room_id.to_owned(), RoomKeyBackup::new(sessions))]);
+
+        self.import_backup(recovery_key, backup, |_, _| {}).await
+    }
+
+    #[cfg(feature = "encryption")]
+    async fn import_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        backup: BTreeMap<Box<RoomId>, RoomKeyBackup>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let result =
+            olm.backup_machine().import_backup(recovery_key, backup, progress_listener).await?;
+
+        self.remember_restored_room_keys(&result);
+
+        Ok(result)
+    }
+
+    /// Remember the imported room keys that belong to undecryptable events,
+    /// so the events are retried after the next sync.
+    #[cfg(feature = "encryption")]
+    fn remember_restored_room_keys(&self, result: &RoomKeyImportResult) {
+        if !self.inner.retry_decryption_enabled {
+            return;
+        }
+
+        for (room_id, room_keys) in &result.keys {
+            for session_id in room_keys.values().flatten() {
+                let key = (room_id.clone(), session_id.clone());
+
+                if self.inner.undecryptable_events.contains_key(&key) {
+                    self.inner.restored_room_keys.insert(key);
+                }
+            }
+        }
+    }
+
+    /// Create a new secret storage key and put our private cross signing keys
+    /// and the recovery key of the server-side key backup into the secret
+    /// storage.
+    ///
+    /// The new key will be marked as the default secret storage key. The
+    /// returned key should be presented to the user, it can be displayed using
+    /// its `Display` implementation.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - An optional passphrase the secret storage key should
+    /// be derived from. A random key will be created if this is `None`.
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver)?;
+    /// let key = client.create_secret_storage(Some("secret-passphrase")).await?;
+    ///
+    /// println!("Your secret storage key is {}", key);
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub async fn create_secret_storage(
+        &self,
+        passphrase: Option<&str>,
+    ) -> Result<SecretStorageKey> {
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let key = match passphrase {
+            Some(passphrase) => SecretStorageKey::new_from_passphrase(passphrase),
+            None => SecretStorageKey::new(),
+        };
+
+        let secrets = olm.export_secrets_to_secret_storage(&key).await;
+
+        self.send_raw_account_data(&key.event_type(), key.event_content()).await?;
+
+        for (secret_name, content) in &secrets {
+            self.send_raw_account_data(secret_name, content).await?;
+        }
+
+        self.send_raw_account_data(
+            secret_storage::DEFAULT_KEY_EVENT_TYPE,
+            &key.default_key_content(),
+        )
+        .await?;
+
+        Ok(key)
+    }
+
+    /// Restore our private cross signing keys and the recovery key of the
+    /// server-side key backup from the secret storage.
+    ///
+    /// The secrets will be decrypted using the default secret storage key.
+    /// Our public cross signing keys need to be known, so this should be
+    /// called after the initial sync.
+    ///
+    /// Returns the status of our private cross signing keys after the import.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase_or_key` - Either the passphrase the default secret
+    /// storage key was derived from or the base58 encoded key itself.
+    #[cfg(feature = "encryption")]
+    pub async fn import_from_secret_storage(
+        &self,
+        passphrase_or_key: &str,
+    ) -> Result<CrossSigningStatus> {
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let default_key: secret_storage::DefaultKeyEventContent = self
+            .get_raw_account_data(secret_storage::DEFAULT_KEY_EVENT_TYPE)
+            .await?
+            .ok_or(secret_storage::SecretStorageError::MissingDefaultKey)?;
+
+        let key_id = default_key.key;
+        let content = self
+            .get_raw_account_data(&secret_storage::key_event_type(&key_id))
+            .await?
+            .ok_or_else(|| secret_storage::SecretStorageError::MissingKey(key_id.to_owned()))?;
+
+        let key = SecretStorageKey::from_passphrase_or_key(passphrase_or_key, &key_id, content)?;
 
-        Ok(olm.import_keys(import, false, |_, _| {}).await?)
+        let mut secrets = BTreeMap::new();
+
+        for secret_name in secret_storage::supported_secrets() {
+            if let Some(content) = self.get_raw_account_data(secret_name.as_ref()).await? {
+                secrets.insert(secret_name.as_ref().to_owned(), content);
+            }
+        }
+
+        Ok(olm.import_secrets_from_secret_storage(&key, &secrets).await?)
     }
 
     /// Tries to decrypt a `AnyRoomEvent`. Returns unencrypted room event when
@@ -690,6 +1069,8 @@ impl Client {
     ) -> serde_json::Result<RoomEvent> {
         use ruma::serde::JsonObject;
 
+        let mut unable_to_decrypt_info = None;
+
         if let Some(machine) = self.olm_machine().await {
             if let AnyRoomEvent::Message(event) = event {
                 if let AnyMessageEvent::RoomEncrypted(_) = event {
@@ -698,16 +1079,33 @@ impl Client {
                     let event = event.clone().into();
 
                     if let AnySyncMessageEvent::RoomEncrypted(e) = event {
-                        if let Ok(decrypted) = machine.decrypt_room_event(&e, room_id).await {
-                            let mut full_event = decrypted.event.deserialize_as::<JsonObject>()?;
-                            full_event.insert("room_id".to_owned(), serde_json::to_value(room_id)?);
-
-                            let event =
-                                Raw::from_json(serde_json::value::to_raw_value(&full_event)?);
-                            let encryption_info = decrypted.encryption_info;
-
-                            // Return decrypted room event
-                            return Ok(RoomEvent { event, encryption_info });
+                        match machine.decrypt_room_event(&e, room_id).await {
+                            Ok(decrypted) => {
+                                let mut full_event =
+                                    decrypted.event.deserialize_as::<JsonObject>()?;
+                                full_event
+                                    .insert("room_id".to_owned(), serde_json::to_value(room_id)?);
+
+                                let event =
+                                    Raw::from_json(serde_json::value::to_raw_value(&full_event)?);
+                                let encryption_info = decrypted.encryption_info;
+
+                                // Return decrypted room event
+                                return Ok(RoomEvent {
+                                    event,
+                                    encryption_info,
+                                    unable_to_decrypt_info: None,
+                                });
+                            }
+                            Err(error) => {
+                                unable_to_decrypt_info = machine
+                                    .unable_to_decrypt_info(&e, room_id, &error)
+                                    .await
+                                    .unwrap_or_else(|e| {
+                                        warn!(error =? e, "Couldn't get the decryption failure info");
+                                        None
+                                    });
+                            }
                         }
                     }
                 }
@@ -715,7 +1113,238 @@ impl Client {
         }
 
         // Fallback to still-encrypted room event
-        Ok(RoomEvent { event: Raw::new(event)?, encryption_info: None })
+        Ok(RoomEvent { event: Raw::new(event)?, encryption_info: None, unable_to_decrypt_info })
+    }
+
+    /// The maximum number of room keys we remember undecryptable events for.
+    #[cfg(feature = "encryption")]
+    const MAX_UNDECRYPTABLE_SESSIONS: usize = 100;
+
+    /// The maximum number of undecryptable events we remember per room key.
+    #[cfg(feature = "encryption")]
+    const MAX_UNDECRYPTABLE_EVENTS_PER_SESSION: usize = 50;
+
+    /// How long we wait for the room key of an undecryptable event before we
+    /// forget the event.
+    #[cfg(feature = "encryption")]
+    const UNDECRYPTABLE_EVENTS_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+    /// Remember the events of a room timeline that we couldn't decrypt.
+    ///
+    /// The `OlmMachine` already requested the missing room keys from our
+    /// other devices when it failed to decrypt the events, we additionally try
+    /// to restore the room keys from the server-side key backup in the
+    /// background.
+    ///
+    /// The events will be decrypted and passed to the event handlers again
+    /// once the room key arrives, see `Client::retry_decrypting_events()`.
+    /// Only a limited number of events is remembered, and only for a limited
+    /// amount of time.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn track_undecryptable_events(&self, room_id: &RoomId, events: &[SyncRoomEvent]) {
+        for event in events {
+            // Successfully decrypted events don't deserialize as encrypted
+            // events anymore.
+            let session_id = match event.event.deserialize() {
+                Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(e))) => {
+                    match e.content.scheme {
+                        EncryptedEventScheme::MegolmV1AesSha2(c) => c.session_id,
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            let key = (room_id.to_owned(), session_id);
+
+            match self.inner.undecryptable_events.entry(key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let events = &mut entry.get_mut().events;
+                    events.push_back(event.clone());
+
+                    if events.len() > Self::MAX_UNDECRYPTABLE_EVENTS_PER_SESSION {
+                        events.pop_front();
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(UndecryptableEvents {
+                        first_seen: Instant::now(),
+                        events: VecDeque::from([event.clone()]),
+                    });
+
+                    trace!(
+                        room_id = room_id.as_str(),
+                        session_id = key.1.as_str(),
+                        "Tracking a new undecryptable event"
+                    );
+
+                    let client = self.clone();
+
+                    spawn(async move {
+                        client.restore_room_key_from_backup(&key.0, &key.1).await;
+                    });
+                }
+            }
+        }
+
+        self.prune_undecryptable_events();
+    }
+
+    /// Forget the undecryptable events that waited too long for their room
+    /// key, and the oldest ones if we remember too many of them.
+    #[cfg(feature = "encryption")]
+    fn prune_undecryptable_events(&self) {
+        let events = &self.inner.undecryptable_events;
+
+        events.retain(|_, e| e.first_seen.elapsed() < Self::UNDECRYPTABLE_EVENTS_MAX_AGE);
+
+        while events.len() > Self::MAX_UNDECRYPTABLE_SESSIONS {
+            let oldest = events.iter().min_by_key(|e| e.first_seen).map(|e| e.key().clone());
+
+            if let Some(key) = oldest {
+                events.remove(&key);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Try to fetch a single room key from the server-side key backup, if we
+    /// have the recovery key for the current backup.
+    ///
+    /// Successfully restored room keys are remembered by
+    /// `Client::import_backup()`, so the events that were encrypted using them
+    /// are retried after the next sync.
+    #[cfg(feature = "encryption")]
+    async fn restore_room_key_from_backup(&self, room_id: &RoomId, session_id: &str) {
+        let olm = if let Some(olm) = self.olm_machine().await { olm } else { return };
+
+        let backup_keys = match olm.backup_machine().get_backup_keys().await {
+            Ok(k) => k,
+            Err(e) => {
+                warn!(error =? e, "Couldn't load the backup keys");
+                return;
+            }
+        };
+
+        if let (Some(recovery_key), Some(version)) =
+            (backup_keys.recovery_key, backup_keys.backup_version)
+        {
+            if let Err(e) =
+                self.restore_backup_session(&recovery_key, &version, room_id, session_id).await
+            {
+                debug!(
+                    room_id = room_id.as_str(),
+                    session_id,
+                    error =? e,
+                    "Couldn't restore a room key from the backup"
+                );
+            }
+        }
+    }
+
+    /// Collect the room keys that arrived since the last time we retried to
+    /// decrypt the undecryptable events.
+    ///
+    /// These are the room keys we received as to-device events in the given
+    /// sync response and the room keys we restored from the server-side key
+    /// backup.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn received_room_keys(
+        &self,
+        to_device_events: &[Raw<AnyToDeviceEvent>],
+    ) -> BTreeSet<(Box<RoomId>, String)> {
+        let mut room_keys: BTreeSet<_> = to_device_events
+            .iter()
+            .filter_map(|e| match e.deserialize() {
+                Ok(AnyToDeviceEvent::RoomKey(e)) => Some((e.content.room_id, e.content.session_id)),
+                Ok(AnyToDeviceEvent::ForwardedRoomKey(e)) => {
+                    Some((e.content.room_id, e.content.session_id))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let restored: Vec<_> =
+            self.inner.restored_room_keys.iter().map(|k| k.key().clone()).collect();
+
+        for key in restored {
+            self.inner.restored_room_keys.remove(&key);
+            room_keys.insert(key);
+        }
+
+        room_keys
+    }
+
+    /// Try to decrypt the events we previously failed to decrypt using the
+    /// room keys that arrived in the meantime.
+    ///
+    /// Events that can now be decrypted are passed to the event handlers
+    /// again and are forgotten afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_keys` - The room id and session id pairs of the room keys that
+    /// arrived since the last retry.
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn retry_decrypting_events(
+        &self,
+        room_keys: BTreeSet<(Box<RoomId>, String)>,
+    ) -> Result<()> {
+        self.prune_undecryptable_events();
+
+        let olm = if let Some(olm) = self.olm_machine().await { olm } else { return Ok(()) };
+
+        for key in room_keys {
+            let (room_id, pending) = match self.inner.undecryptable_events.remove(&key) {
+                Some(((room_id, _), pending)) => (room_id, pending),
+                None => continue,
+            };
+
+            let mut decrypted = Vec::new();
+            let mut undecryptable = VecDeque::new();
+
+            for event in pending.events {
+                // All the events share the same room key, if the first one
+                // can't be decrypted, the rest can't be decrypted either.
+                if !undecryptable.is_empty() && decrypted.is_empty() {
+                    undecryptable.push_back(event);
+                    continue;
+                }
+
+                let result = match event.event.deserialize() {
+                    Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(e))) => {
+                        olm.decrypt_room_event(&e, &room_id).await.ok()
+                    }
+                    _ => None,
+                };
+
+                match result {
+                    Some(e) => decrypted.push(e),
+                    None => undecryptable.push_back(event),
+                }
+            }
+
+            if !undecryptable.is_empty() {
+                self.inner.undecryptable_events.insert(
+                    key,
+                    UndecryptableEvents { first_seen: pending.first_seen, events: undecryptable },
+                );
+            }
+
+            if !decrypted.is_empty() {
+                debug!(
+                    room_id = room_id.as_str(),
+                    count = decrypted.len(),
+                    "Decrypted previously undecryptable events"
+                );
+
+                let room = self.get_room(&room_id);
+                self.handle_sync_timeline_events(&room, &decrypted).await?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Query the server for users device keys.
@@ -733,26 +1362,75 @@ impl Client {
         let request = assign!(get_keys::Request::new(), { device_keys });
 
         let response = self.send(request, None).await?;
+
+        // Only new verification violations should be reported, remember which
+        // identities already had one.
+        let mut known_violations = BTreeSet::new();
+
+        for user_id in response.master_keys.keys() {
+            if let Some(identity) = self.get_user_identity(user_id).await? {
+                if identity.has_verification_violation() {
+                    known_violations.insert(user_id);
+                }
+            }
+        }
+
         self.mark_request_as_sent(request_id, &response).await?;
 
+        let mut violations = Vec::new();
+
+        for user_id in response.master_keys.keys().filter(|u| !known_violations.contains(u)) {
+            if let Some(identity) = self.get_user_identity(user_id).await? {
+                if identity.has_verification_violation() {
+                    violations.push(identity);
+                }
+            }
+        }
+
+        let mut futures = Vec::new();
+
+        for handler in &*self.inner.verification_violation_handlers.read().await {
+            futures.extend(violations.iter().map(|i| (handler)(i.clone(), self.clone())));
+        }
+
+        // Run the handlers with the lock no longer being held, in order.
+        for fut in futures {
+            fut.await;
+        }
+
         Ok(response)
     }
 
     /// Encrypt and upload the file to be read from `reader` and construct an
     /// attachment message with `body` and the specified `content_type`.
     #[cfg(feature = "encryption")]
-    pub(crate) async fn prepare_encrypted_attachment_message<R: Read>(
+    pub(crate) async fn prepare_encrypted_attachment_message<R>(
         &self,
         body: &str,
         content_type: &mime::Mime,
-        reader: &mut R,
-    ) -> Result<ruma::events::room::message::MessageType> {
-        let mut reader = matrix_sdk_base::crypto::AttachmentEncryptor::new(reader);
+        reader: R,
+        content_length: u64,
+    ) -> Result<ruma::events::room::message::MessageType>
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+    {
+        // The encryptor is moved into the upload body, it hands back the
+        // encryption info once all the data has been encrypted.
+        let encryption_info = Arc::new(StdMutex::new(None));
+
+        let upload_body = {
+            let encryption_info = encryption_info.clone();
+            reader_stream(AsyncAttachmentEncryptor::new(reader), move |encryptor| {
+                *encryption_info.lock().unwrap() = Some(encryptor.finish());
+            })
+        };
 
-        let response = self.upload(content_type, &mut reader).await?;
+        let response = self.upload_body(content_type, upload_body, content_length).await?;
 
         let file: ruma::events::room::EncryptedFile = {
-            let keys = reader.finish();
+            let keys = encryption_info.lock().unwrap().take().ok_or_else(|| {
+                IoError::new(IoErrorKind::UnexpectedEof, "The attachment wasn't fully uploaded")
+            })?;
             ruma::events::room::EncryptedFileInit {
                 url: response.content_uri,
                 key: keys.web_key,
@@ -796,23 +1474,80 @@ impl Client {
         Ok(self.send(request, None).await?)
     }
 
+    #[cfg(feature = "encryption")]
+    async fn send_raw_account_data(
+        &self,
+        event_type: &str,
+        content: &impl serde::Serialize,
+    ) -> Result<ruma::api::client::r0::config::set_global_account_data::Response> {
+        let own_user =
+            self.user_id().await.ok_or_else(|| Error::from(HttpError::AuthenticationRequired))?;
+        let data = serde_json::value::to_raw_value(content)?;
+
+        let request = ruma::api::client::r0::config::set_global_account_data::Request::new(
+            &data, event_type, &own_user,
+        );
+
+        Ok(self.send(request, None).await?)
+    }
+
+    /// Fetch the content of a global account data event from the server,
+    /// returns `None` if the event doesn't exist.
+    #[cfg(feature = "encryption")]
+    async fn get_raw_account_data<T: serde::de::DeserializeOwned>(
+        &self,
+        event_type: &str,
+    ) -> Result<Option<T>> {
+        use ruma::api::{
+            client::{error::ErrorKind, r0::config::get_global_account_data},
+            error::{FromHttpResponseError, ServerError},
+        };
+
+        let own_user =
+            self.user_id().await.ok_or_else(|| Error::from(HttpError::AuthenticationRequired))?;
+
+        let request = get_global_account_data::Request::new(&own_user, event_type);
+
+        match self.send(request, None).await {
+            Ok(response) => Ok(Some(response.account_data.deserialize_as()?)),
+            Err(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+                if matches!(e.kind, ErrorKind::NotFound) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
     #[cfg(feature = "encryption")]
     pub(crate) async fn create_dm_room(&self, user_id: Box<UserId>) -> Result<Option<room::Joined>> {
         use ruma::{
             api::client::r0::room::create_room::RoomPreset,
-            events::AnyGlobalAccountDataEventContent,
+            events::{
+                room::encryption::RoomEncryptionEventContent, AnyGlobalAccountDataEventContent,
+                InitialStateEvent,
+            },
+            EventEncryptionAlgorithm,
         };
 
         const SYNC_WAIT_TIME: Duration = Duration::from_secs(3);
 
         // First we create the DM room, where we invite the user and tell the
-        // invitee that the room should be a DM.
+        // invitee that the room should be a DM. The room is encrypted from the
+        // start, so the verification events we send into it are encrypted as
+        // well.
         let invite = &[user_id.clone()];
+        let initial_state = &[InitialStateEvent {
+            content: RoomEncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2),
+            state_key: "".to_owned(),
+        }
+        .to_raw_any()];
 
         let request = assign!(
             ruma::api::client::r0::room::create_room::Request::new(),
             {
                 invite,
+                initial_state,
                 is_direct: true,
                 preset: Some(RoomPreset::TrustedPrivateChat),
             }
@@ -949,6 +1684,8 @@ impl Client {
             }
         }
 
+        self.inner.verification_beat.notify(usize::MAX);
+
         Ok(())
     }
 
@@ -992,8 +1729,16 @@ impl Client {
                 self.mark_request_as_sent(r.request_id(), &response).await?;
             }
             OutgoingRequests::KeysBackup(request) => {
-                let response = self.send_backup_request(request).await?;
-                self.mark_request_as_sent(r.request_id(), &response).await?;
+                match self.send_backup_request(request).await {
+                    Ok(response) => self.mark_request_as_sent(r.request_id(), &response).await?,
+                    Err(e) => {
+                        // If the backup was replaced or deleted our room keys will
+                        // be uploaded to the new backup, if we trust it.
+                        if !self.backups().handle_upload_error(&e).await {
+                            return Err(e.into());
+                        }
+                    }
+                }
             }
         }
 
@@ -1003,13 +1748,13 @@ impl Client {
     async fn send_backup_request(
         &self,
         request: &matrix_sdk_base::crypto::KeysBackupRequest,
-    ) -> Result<KeysBackupResponse> {
+    ) -> HttpResult<KeysBackupResponse> {
         let request = ruma::api::client::r0::backup::add_backup_keys::Request::new(
             &request.version,
             request.rooms.to_owned(),
         );
 
-        Ok(self.send(request, None).await?)
+        self.send(request, None).await
     }
 
     pub(crate) async fn send_outgoing_requests(&self) -> Result<()> {
@@ -1035,6 +1780,27 @@ impl Client {
             })
             .await;
 
+        // Check the server-side key backup once, so we resume backing up our
+        // room keys after a restart.
+        if !self.inner.backups_checked.swap(true, Ordering::SeqCst) {
+            if let Err(e) = self.backups().check().await {
+                warn!(error =? e, "Couldn't check the server-side key backup");
+                self.inner.backups_checked.store(false, Ordering::SeqCst);
+            }
+        }
+
+        if let Some(olm) = self.olm_machine().await {
+            match olm.backup_machine().backup().await {
+                Ok(Some(request)) => {
+                    if let Err(e) = self.send_outgoing_request(request).await {
+                        warn!(error =? e, "Error when backing up room keys");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!(error =? e, "Couldn't create a room key backup request"),
+            }
+        }
+
         Ok(())
     }
 }
@@ -29,6 +29,27 @@
 //!   authentication
 //! string.
 //! * [`QrVerification`] - Interactive verification using QR codes.
+//!
+//! Every verification object exposes a `changes()` stream yielding a
+//! [`VerificationState`] each time the verification makes progress, so there
+//! is no need to poll the verification objects or to listen for the raw
+//! verification events.
+//!
+//! Verifying our own devices happens over to-device messages, while other
+//! users are verified in a DM room we share with them. If we don't share a DM
+//! with the user, an encrypted one gets created when the verification is
+//! requested. The in-room verification events are picked up from the sync
+//! responses, so both kinds of flows are driven the same way.
+
+use std::{future::Future, mem};
+
+use futures_core::stream::Stream;
+use futures_util::future;
+#[cfg(feature = "qrcode")]
+use matrix_sdk_base::crypto::QrVerification as BaseQrVerification;
+use matrix_sdk_base::crypto::{Sas as BaseSas, Verification as BaseVerification};
+
+use crate::Client;
 
 #[cfg(feature = "qrcode")]
 mod qrcode;
@@ -37,12 +58,126 @@ mod sas;
 
 #[cfg(feature = "qrcode")]
 pub use matrix_sdk_base::crypto::{matrix_qrcode::QrVerificationData, ScanError};
-pub use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, Emoji};
+pub use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, Emoji, SasMethod};
 #[cfg(feature = "qrcode")]
 pub use qrcode::QrVerification;
 pub use requests::VerificationRequest;
 pub use sas::SasVerification;
 
+/// The state of a verification request or of a verification flow.
+///
+/// The states are yielded by the `changes()` streams of the verification
+/// objects, e.g. [`VerificationRequest::changes()`].
+#[derive(Debug, Clone)]
+pub enum VerificationState {
+    /// The verification was requested, but the other side didn't accept the
+    /// request yet.
+    Requested,
+    /// Both sides accepted the request, a verification flow can be started.
+    Ready,
+    /// A verification flow was started.
+    Started,
+    /// The keys were exchanged, the short auth string can be presented to the
+    /// user or the QR code was scanned, and the user needs to confirm the
+    /// verification.
+    KeysExchanged,
+    /// We confirmed the verification and are waiting for the other side to do
+    /// the same.
+    Confirmed,
+    /// The verification finished successfully.
+    Done,
+    /// The verification request was accepted by another one of our devices,
+    /// which will carry out the verification.
+    Passive,
+    /// The verification was cancelled.
+    Cancelled(CancelInfo),
+}
+
+impl VerificationState {
+    fn is_final(&self) -> bool {
+        matches!(
+            self,
+            VerificationState::Done | VerificationState::Passive | VerificationState::Cancelled(_)
+        )
+    }
+
+    fn from_sas(sas: &BaseSas) -> Self {
+        if let Some(info) = sas.cancel_info() {
+            VerificationState::Cancelled(info)
+        } else if sas.is_done() {
+            VerificationState::Done
+        } else if sas.have_we_confirmed() {
+            VerificationState::Confirmed
+        } else if sas.can_be_presented() {
+            VerificationState::KeysExchanged
+        } else {
+            VerificationState::Started
+        }
+    }
+
+    #[cfg(feature = "qrcode")]
+    fn from_qr(qr: &BaseQrVerification) -> Self {
+        if let Some(info) = qr.cancel_info() {
+            VerificationState::Cancelled(info)
+        } else if qr.is_done() {
+            VerificationState::Done
+        } else if qr.has_been_confirmed() {
+            VerificationState::Confirmed
+        } else if qr.has_been_scanned() || qr.reciprocated() {
+            VerificationState::KeysExchanged
+        } else {
+            VerificationState::Started
+        }
+    }
+
+    fn from_verification(verification: &BaseVerification) -> Self {
+        match verification {
+            BaseVerification::SasV1(s) => Self::from_sas(s),
+            #[cfg(feature = "qrcode")]
+            BaseVerification::QrV1(q) => Self::from_qr(q),
+        }
+    }
+}
+
+/// Create a stream that yields the state returned by `state` every time the
+/// state changes, the stream ends once a final state has been yielded.
+///
+/// The state is checked after every sync, since that's when we receive the
+/// events of the other side, and after we send out a verification event.
+fn state_stream<F, Fut>(client: Client, mut state: F) -> impl Stream<Item = VerificationState>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = VerificationState>,
+{
+    async_stream::stream! {
+        let mut last_state: Option<VerificationState> = None;
+
+        loop {
+            // Start listening before we check the state, otherwise we might
+            // miss a change that happens in between.
+            let listener = client.inner.verification_beat.listen();
+            let current_state = state().await;
+
+            let changed = last_state
+                .as_ref()
+                .map_or(true, |s| mem::discriminant(s) != mem::discriminant(&current_state));
+
+            if changed {
+                let is_final = current_state.is_final();
+                last_state = Some(current_state.clone());
+
+                yield current_state;
+
+                if is_final {
+                    break;
+                }
+            }
+
+            listener.await;
+        }
+    }
+}
+
 /// An enum over the different verification types the SDK supports.
 #[derive(Debug, Clone)]
 pub enum Verification {
@@ -138,6 +273,22 @@ impl Verification {
             Verification::QrV1(q) => q.we_started(),
         }
     }
+
+    /// Get a stream of the state changes of this verification flow.
+    ///
+    /// The current state is yielded right away, the stream ends once the
+    /// verification is done or cancelled.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        let (client, verification): (Client, BaseVerification) = match self {
+            Verification::SasV1(s) => (s.client.clone(), s.inner.clone().into()),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(q) => (q.client.clone(), q.inner.clone().into()),
+        };
+
+        state_stream(client, move || {
+            future::ready(VerificationState::from_verification(&verification))
+        })
+    }
 }
 
 impl From<SasVerification> for Verification {
@@ -152,3 +303,248 @@ impl From<QrVerification> for Verification {
         Self::QrV1(qr)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use futures_util::{pin_mut, StreamExt};
+    use matrix_sdk_base::crypto::{
+        OlmMachine, OutgoingRequests, OutgoingVerificationRequest, Sas as BaseSas, ToDeviceRequest,
+        Verification as BaseVerification,
+    };
+    use matrix_sdk_common::uuid::Uuid;
+    use ruma::{
+        api::client::r0::{
+            keys::get_keys,
+            sync::sync_events::{DeviceLists, ToDevice},
+            to_device::send_event_to_device::Response as ToDeviceResponse,
+        },
+        device_id,
+        events::{key::verification::cancel::CancelCode, AnyToDeviceEvent},
+        serde::Raw,
+        user_id, DeviceId, UserId,
+    };
+    use serde_json::json;
+    use url::Url;
+
+    use super::{SasVerification, VerificationState};
+    use crate::Client;
+
+    fn alice_id() -> &'static UserId {
+        user_id!("@alice:example.org")
+    }
+
+    fn alice_device_id() -> &'static DeviceId {
+        device_id!("ALICEDEVICE")
+    }
+
+    fn bob_id() -> &'static UserId {
+        user_id!("@bob:example.org")
+    }
+
+    fn bob_device_id() -> &'static DeviceId {
+        device_id!("BOBDEVICE")
+    }
+
+    fn client() -> Client {
+        Client::new(Url::parse("http://localhost").unwrap()).unwrap()
+    }
+
+    fn to_device_events(sender: &UserId, request: &ToDeviceRequest) -> Vec<Raw<AnyToDeviceEvent>> {
+        request
+            .messages
+            .values()
+            .flat_map(|m| m.values())
+            .map(|content| {
+                serde_json::from_value(json!({
+                    "sender": sender,
+                    "type": request.event_type,
+                    "content": content,
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn verification_request(request: OutgoingVerificationRequest) -> ToDeviceRequest {
+        match request {
+            OutgoingVerificationRequest::ToDevice(r) => r,
+            OutgoingVerificationRequest::InRoom(_) => panic!("Unexpected in-room request"),
+        }
+    }
+
+    async fn receive(machine: &OlmMachine, events: Vec<Raw<AnyToDeviceEvent>>) {
+        let mut to_device = ToDevice::new();
+        to_device.events = events;
+
+        machine
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), None)
+            .await
+            .unwrap();
+    }
+
+    /// Pass the to-device requests the sender queued up to the recipient.
+    async fn deliver(sender: &OlmMachine, recipient: &OlmMachine) {
+        let mut events = Vec::new();
+
+        for request in sender.outgoing_requests().await.unwrap() {
+            if let OutgoingRequests::ToDeviceRequest(r) = request.request() {
+                events.extend(to_device_events(sender.user_id(), r));
+                sender
+                    .mark_request_as_sent(request.request_id(), &ToDeviceResponse::new())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        receive(recipient, events).await;
+    }
+
+    /// Let the machine know about the device of the other machine.
+    async fn exchange_device_keys(machine: &OlmMachine, other: &OlmMachine) {
+        let device_keys = other
+            .outgoing_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r.request() {
+                OutgoingRequests::KeysUpload(r) => r.device_keys.clone(),
+                _ => None,
+            })
+            .unwrap();
+
+        let mut response = get_keys::Response::new();
+        response.device_keys.insert(
+            other.user_id().to_owned(),
+            BTreeMap::from([(other.device_id().to_owned(), device_keys)]),
+        );
+
+        machine.mark_request_as_sent(&Uuid::new_v4(), &response).await.unwrap();
+    }
+
+    /// Let Bob start a SAS verification with Alice.
+    ///
+    /// Returns the machines of Alice and Bob, the SAS verification of Alice and
+    /// the SAS verification of Bob.
+    async fn start_sas(client: &Client) -> (OlmMachine, OlmMachine, SasVerification, BaseSas) {
+        let alice = OlmMachine::new(alice_id(), alice_device_id());
+        let bob = OlmMachine::new(bob_id(), bob_device_id());
+
+        exchange_device_keys(&alice, &bob).await;
+        exchange_device_keys(&bob, &alice).await;
+
+        let device = bob.get_device(alice_id(), alice_device_id()).await.unwrap().unwrap();
+        let (bob_sas, request) = device.start_verification().await.unwrap();
+        receive(&alice, to_device_events(bob_id(), &request)).await;
+
+        let alice_sas = match alice.get_verification(bob_id(), bob_sas.flow_id().as_str()) {
+            Some(BaseVerification::SasV1(s)) => s,
+            _ => panic!("Alice should have received the SAS verification"),
+        };
+
+        (alice, bob, SasVerification { inner: alice_sas, client: client.clone() }, bob_sas)
+    }
+
+    /// Wake up the state streams, like a sync would.
+    fn notify(client: &Client) {
+        client.inner.verification_beat.notify(usize::MAX);
+    }
+
+    #[tokio::test]
+    async fn sas_state_changes() {
+        let client = client();
+        let (alice, bob, sas, bob_sas) = start_sas(&client).await;
+
+        let changes = sas.changes();
+        pin_mut!(changes);
+
+        assert!(matches!(changes.next().await, Some(VerificationState::Started)));
+
+        // Alice accepts, Bob answers with his key and Alice with hers.
+        let request = verification_request(sas.inner.accept().unwrap());
+        receive(&bob, to_device_events(alice_id(), &request)).await;
+        deliver(&bob, &alice).await;
+        deliver(&alice, &bob).await;
+
+        notify(&client);
+        assert!(matches!(changes.next().await, Some(VerificationState::KeysExchanged)));
+        assert_eq!(sas.emoji(), bob_sas.emoji());
+
+        let request = verification_request(sas.inner.confirm().await.unwrap().0.unwrap());
+        receive(&bob, to_device_events(alice_id(), &request)).await;
+
+        notify(&client);
+        assert!(matches!(changes.next().await, Some(VerificationState::Confirmed)));
+
+        let request = verification_request(bob_sas.confirm().await.unwrap().0.unwrap());
+        receive(&alice, to_device_events(bob_id(), &request)).await;
+
+        notify(&client);
+        assert!(matches!(changes.next().await, Some(VerificationState::Done)));
+
+        // The stream ends once the verification reached a final state.
+        assert!(changes.next().await.is_none());
+        assert!(sas.is_done());
+    }
+
+    #[tokio::test]
+    async fn sas_cancellation() {
+        let client = client();
+        let (alice, _, sas, bob_sas) = start_sas(&client).await;
+
+        let changes = sas.changes();
+        pin_mut!(changes);
+
+        assert!(matches!(changes.next().await, Some(VerificationState::Started)));
+
+        let request = verification_request(bob_sas.cancel().unwrap());
+        receive(&alice, to_device_events(bob_id(), &request)).await;
+
+        notify(&client);
+
+        match changes.next().await {
+            Some(VerificationState::Cancelled(info)) => {
+                assert_eq!(info.cancel_code(), &CancelCode::User);
+                assert!(!info.cancelled_by_us());
+            }
+            state => panic!("The verification should have been cancelled, got {:?}", state),
+        }
+
+        assert!(changes.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sas_timeout() {
+        let client = client();
+        let (alice, bob, sas, bob_sas) = start_sas(&client).await;
+
+        let changes = sas.changes();
+        pin_mut!(changes);
+
+        assert!(matches!(changes.next().await, Some(VerificationState::Started)));
+
+        let request = verification_request(sas.inner.accept().unwrap());
+        receive(&bob, to_device_events(alice_id(), &request)).await;
+        deliver(&bob, &alice).await;
+        deliver(&alice, &bob).await;
+
+        notify(&client);
+        assert!(matches!(changes.next().await, Some(VerificationState::KeysExchanged)));
+
+        // Bob's side gave up waiting for Alice to confirm.
+        let request = verification_request(bob_sas.cancel_with_code(CancelCode::Timeout).unwrap());
+        receive(&alice, to_device_events(bob_id(), &request)).await;
+
+        notify(&client);
+
+        match changes.next().await {
+            Some(VerificationState::Cancelled(info)) => {
+                assert_eq!(info.cancel_code(), &CancelCode::Timeout);
+            }
+            state => panic!("The verification should have timed out, got {:?}", state),
+        }
+
+        assert!(changes.next().await.is_none());
+    }
+}
@@ -12,12 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use futures_core::stream::Stream;
+use futures_util::future;
 use matrix_sdk_base::crypto::{
     matrix_qrcode::{qrcode::QrCode, EncodingError},
     CancelInfo, QrVerification as BaseQrVerification,
 };
 use ruma::UserId;
 
+use super::{state_stream, VerificationState};
 use crate::{Client, Result};
 
 /// An object controlling QR code style key verification flows.
@@ -101,4 +104,13 @@ impl QrVerification {
 
         Ok(())
     }
+
+    /// Get a stream of the state changes of this verification flow.
+    ///
+    /// The current state is yielded right away, the stream ends once the
+    /// verification is done or cancelled.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        let qr = self.inner.clone();
+        state_stream(self.client.clone(), move || future::ready(VerificationState::from_qr(&qr)))
+    }
 }
@@ -12,10 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use futures_core::stream::Stream;
 use matrix_sdk_base::crypto::{CancelInfo, VerificationRequest as BaseVerificationRequest};
 use ruma::events::key::verification::VerificationMethod;
 
-use super::SasVerification;
+use super::{state_stream, SasMethod, SasVerification, VerificationState};
 #[cfg(feature = "qrcode")]
 use super::{QrVerification, QrVerificationData};
 use crate::{Client, Result};
@@ -102,6 +103,7 @@ impl VerificationRequest {
     pub async fn accept(&self) -> Result<()> {
         if let Some(request) = self.inner.accept() {
             self.client.send_verification_request(request).await?;
+            self.inner.save().await?;
         }
 
         Ok(())
@@ -116,6 +118,7 @@ impl VerificationRequest {
     pub async fn accept_with_methods(&self, methods: Vec<VerificationMethod>) -> Result<()> {
         if let Some(request) = self.inner.accept_with_methods(methods) {
             self.client.send_verification_request(request).await?;
+            self.inner.save().await?;
         }
 
         Ok(())
@@ -124,11 +127,12 @@ impl VerificationRequest {
     /// Generate a QR code
     #[cfg(feature = "qrcode")]
     pub async fn generate_qr_code(&self) -> Result<Option<QrVerification>> {
-        Ok(self
-            .inner
-            .generate_qr_code()
-            .await?
-            .map(|qr| QrVerification { inner: qr, client: self.client.clone() }))
+        Ok(self.inner.generate_qr_code().await?.map(|qr| {
+            // Nothing gets sent out, but the request moved on to a
+            // verification flow.
+            self.client.inner.verification_beat.notify(usize::MAX);
+            QrVerification { inner: qr, client: self.client.clone() }
+        }))
     }
 
     /// Start a QR code verification by providing a scanned QR code for this
@@ -163,6 +167,24 @@ impl VerificationRequest {
         }
     }
 
+    /// Transition from this verification request into a SAS verification flow
+    /// that only offers the given short auth string methods.
+    ///
+    /// The decimal method is always offered, since the spec requires every
+    /// client to support it.
+    pub async fn start_sas_with_methods(
+        &self,
+        methods: &[SasMethod],
+    ) -> Result<Option<SasVerification>> {
+        if let Some((sas, request)) = self.inner.start_sas_with_methods(methods).await? {
+            self.client.send_verification_request(request).await?;
+
+            Ok(Some(SasVerification { inner: sas, client: self.client.clone() }))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Cancel the verification request
     pub async fn cancel(&self) -> Result<()> {
         if let Some(request) = self.inner.cancel() {
@@ -171,4 +193,75 @@ impl VerificationRequest {
 
         Ok(())
     }
+
+    /// Get a stream of the state changes of this verification request.
+    ///
+    /// The current state is yielded right away. Once a verification flow is
+    /// started from this request, the stream follows the state of the
+    /// verification flow, which can be fetched using
+    /// [`Client::get_verification()`] with the flow id of this request. The
+    /// stream ends once the verification is done or cancelled, or once
+    /// another one of our devices accepted the request.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::{Client, ruma::UserId};
+    /// # use matrix_sdk::encryption::verification::VerificationState;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use url::Url;
+    /// # let alice = Box::<UserId>::try_from("@alice:example.org").unwrap();
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let request = client.get_verification_request(&alice, "$some_id").await;
+    ///
+    /// if let Some(request) = request {
+    ///     let mut changes = Box::pin(request.changes());
+    ///
+    ///     while let Some(state) = changes.next().await {
+    ///         match state {
+    ///             VerificationState::Ready => {
+    ///                 request.start_sas().await?;
+    ///             }
+    ///             VerificationState::Done => println!("Successfully verified"),
+    ///             VerificationState::Cancelled(info) => {
+    ///                 println!("The verification was cancelled: {}", info.reason())
+    ///             }
+    ///             _ => (),
+    ///         }
+    ///     }
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        let request = self.inner.clone();
+        let client = self.client.clone();
+
+        state_stream(self.client.clone(), move || {
+            let request = request.clone();
+            let client = client.clone();
+
+            async move {
+                let verification = client.olm_machine().await.and_then(|o| {
+                    o.get_verification(request.other_user(), request.flow_id().as_str())
+                });
+
+                if let Some(info) = request.cancel_info() {
+                    VerificationState::Cancelled(info)
+                } else if let Some(verification) = verification {
+                    VerificationState::from_verification(&verification)
+                } else if request.is_passive() {
+                    VerificationState::Passive
+                } else if request.is_done() {
+                    VerificationState::Done
+                } else if request.is_ready() {
+                    VerificationState::Ready
+                } else {
+                    VerificationState::Requested
+                }
+            }
+        })
+    }
 }
@@ -12,9 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, ReadOnlyDevice, Sas as BaseSas};
+use futures_core::stream::Stream;
+use futures_util::future;
+use matrix_sdk_base::crypto::{
+    AcceptSettings, CancelInfo, ReadOnlyDevice, Sas as BaseSas, SasMethod,
+};
 use ruma::UserId;
 
+use super::{state_stream, VerificationState};
 use crate::{error::Result, Client};
 
 /// An object controlling the short auth string verification flow.
@@ -154,6 +159,12 @@ impl SasVerification {
         self.inner.supports_emoji()
     }
 
+    /// Get the short auth string method that should be used to present the
+    /// short auth string to the user, `None` if it wasn't negotiated yet.
+    pub fn sas_method(&self) -> Option<SasMethod> {
+        self.inner.sas_method()
+    }
+
     /// Is the verification process done.
     pub fn is_done(&self) -> bool {
         self.inner.is_done()
@@ -205,4 +216,42 @@ impl SasVerification {
     pub fn other_user_id(&self) -> &UserId {
         self.inner.other_user_id()
     }
+
+    /// Get a stream of the state changes of this verification flow.
+    ///
+    /// The current state is yielded right away, the stream ends once the
+    /// verification is done or cancelled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::{Client, ruma::UserId};
+    /// # use matrix_sdk::encryption::verification::VerificationState;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use url::Url;
+    /// # let alice = Box::<UserId>::try_from("@alice:example.org").unwrap();
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let sas = client
+    ///     .get_verification(&alice, "$some_id")
+    ///     .await
+    ///     .and_then(|v| v.sas());
+    ///
+    /// if let Some(sas) = sas {
+    ///     let mut changes = Box::pin(sas.changes());
+    ///
+    ///     while let Some(state) = changes.next().await {
+    ///         if let VerificationState::KeysExchanged = state {
+    ///             println!("Do the emoji match? {:?}", sas.emoji());
+    ///         }
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        let sas = self.inner.clone();
+        state_stream(self.client.clone(), move || future::ready(VerificationState::from_sas(&sas)))
+    }
 }
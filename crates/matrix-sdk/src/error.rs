@@ -21,7 +21,8 @@ use http::StatusCode;
 use matrix_sdk_base::crypto::ScanError;
 #[cfg(feature = "encryption")]
 use matrix_sdk_base::crypto::{
-    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError,
+    dehydrated_devices::DehydrationError, secret_storage::SecretStorageError, CryptoStoreError,
+    DecryptorError, KeyExportError, MegolmError, OlmError,
 };
 use matrix_sdk_base::{Error as SdkBaseError, StoreError};
 use reqwest::Error as ReqwestError;
@@ -99,6 +100,10 @@ pub enum HttpError {
     /// Tried to send a request without `user_id` in the `Session`
     #[error("missing user_id in session")]
     UserIdRequired,
+
+    /// Reading the body of a streaming request failed.
+    #[error(transparent)]
+    Io(#[from] IoError),
 }
 
 /// Internal representation of errors.
@@ -141,6 +146,21 @@ pub enum Error {
     #[error(transparent)]
     DecryptorError(#[from] DecryptorError),
 
+    /// An error occurred while accessing the secret storage.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    SecretStorage(#[from] SecretStorageError),
+
+    /// An error occurred while exporting or importing room keys.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    KeyExport(#[from] KeyExportError),
+
+    /// An error occurred while creating or rehydrating a dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    Dehydration(#[from] DehydrationError),
+
     /// An error occurred in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
@@ -34,7 +34,7 @@
 use std::any::TypeId;
 use std::{borrow::Cow, fmt, future::Future, ops::Deref};
 
-use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncRoomEvent};
+use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncRoomEvent, UnableToDecryptInfo};
 use ruma::{events::AnySyncStateEvent, serde::Raw};
 use serde::Deserialize;
 use serde_json::value::RawValue as RawJsonValue;
@@ -121,6 +121,7 @@ pub struct EventHandlerData<'a> {
     pub room: Option<room::Room>,
     pub raw: &'a RawJsonValue,
     pub encryption_info: Option<&'a EncryptionInfo>,
+    pub unable_to_decrypt_info: Option<&'a UnableToDecryptInfo>,
 }
 
 /// Context for an event handler.
@@ -179,6 +180,14 @@ impl EventHandlerContext for Option<EncryptionInfo> {
     }
 }
 
+/// Information on why an encrypted event couldn't be decrypted, `None` if the
+/// event isn't an undecryptable encrypted event.
+impl EventHandlerContext for Option<UnableToDecryptInfo> {
+    fn from_data(data: &EventHandlerData<'_>) -> Option<Self> {
+        Some(data.unable_to_decrypt_info.cloned())
+    }
+}
+
 /// A custom value registered with
 /// [`.register_event_handler_context`][Client::register_event_handler_context].
 #[derive(Debug)]
@@ -251,7 +260,7 @@ impl Client {
         self.handle_sync_events_wrapped_with(
             room,
             events,
-            |ev| (ev, None),
+            |ev| (ev, None, None),
             |raw| Ok((kind, raw.deserialize_as::<ExtractType>()?.event_type)),
         )
         .await
@@ -272,7 +281,7 @@ impl Client {
         self.handle_sync_events_wrapped_with(
             room,
             state_events,
-            |ev| (ev, None),
+            |ev| (ev, None, None),
             |raw| {
                 let StateEventDetails { event_type, unsigned } = raw.deserialize_as()?;
                 let redacted = unsigned.and_then(|u| u.redacted_because).is_some();
@@ -298,7 +307,7 @@ impl Client {
         self.handle_sync_events_wrapped_with(
             room,
             timeline_events,
-            |e| (&e.event, e.encryption_info.as_ref()),
+            |e| (&e.event, e.encryption_info.as_ref(), e.unable_to_decrypt_info.as_ref()),
             |raw| {
                 let TimelineEventDetails { event_type, state_key, unsigned } =
                     raw.deserialize_as()?;
@@ -319,11 +328,17 @@ impl Client {
         &self,
         room: &Option<room::Room>,
         list: &'a [U],
-        get_event_details: impl Fn(&'a U) -> (&'a Raw<T>, Option<&'a EncryptionInfo>),
+        get_event_details: impl Fn(
+            &'a U,
+        ) -> (
+            &'a Raw<T>,
+            Option<&'a EncryptionInfo>,
+            Option<&'a UnableToDecryptInfo>,
+        ),
         get_id: impl Fn(&Raw<T>) -> serde_json::Result<(EventKind, Cow<'_, str>)>,
     ) -> serde_json::Result<()> {
         for x in list {
-            let (raw_event, encryption_info) = get_event_details(x);
+            let (raw_event, encryption_info, unable_to_decrypt_info) = get_event_details(x);
             let (ev_kind, ev_type) = get_id(raw_event)?;
             let event_handler_id = (ev_kind, &*ev_type);
 
@@ -340,6 +355,7 @@ impl Client {
                         room: room.clone(),
                         raw: raw_event.json(),
                         encryption_info,
+                        unable_to_decrypt_info,
                     };
                     (handler)(data)
                 })
@@ -12,10 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{convert::TryFrom, fmt::Debug, sync::Arc};
+use std::{convert::TryFrom, fmt::Debug, mem, pin::Pin, sync::Arc};
 
 use bytes::{Bytes, BytesMut};
-use http::Response as HttpResponse;
+use futures_core::stream::Stream;
+use futures_util::{
+    io::{AsyncRead, AsyncReadExt},
+    StreamExt,
+};
+use http::{
+    header::{HeaderValue, CONTENT_LENGTH},
+    Response as HttpResponse,
+};
 use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
 use reqwest::{Client, Response};
 use ruma::api::{
@@ -31,6 +39,13 @@ use crate::{
     Session,
 };
 
+/// The size of the chunks a streaming request body is read in.
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A request body that is streamed out chunk by chunk instead of being held in
+/// memory as a whole, used to upload large files.
+pub type BodyStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync>>;
+
 /// Abstraction around the http layer. The allows implementors to use different
 /// http libraries.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -90,6 +105,78 @@ pub trait HttpSend: AsyncTraitDeps {
         request: http::Request<Bytes>,
         config: RequestConfig,
     ) -> Result<http::Response<Bytes>, HttpError>;
+
+    /// Send a request whose body is streamed out instead of being held in
+    /// memory.
+    ///
+    /// This is used to upload media. The default implementation collects the
+    /// whole body and forwards the request to
+    /// [`send_request()`](#tymethod.send_request), implementors that are able
+    /// to stream out request bodies should override it.
+    ///
+    /// Since the body can only be read once, the request can't be retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The http request with a streaming body. The
+    ///   `Content-Length` header of the request is always set.
+    ///
+    /// * `request_config` - The config used for this request.
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<BodyStream>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let (parts, mut body) = request.into_parts();
+        let mut buffer = BytesMut::new();
+
+        while let Some(chunk) = body.next().await {
+            buffer.extend_from_slice(&chunk?);
+        }
+
+        self.send_request(http::Request::from_parts(parts, buffer.freeze()), config).await
+    }
+}
+
+/// Turn the given reader into a [`BodyStream`].
+///
+/// The `on_eof` callback receives the reader after all of its data has been
+/// read, but before the last chunk is handed out. This ensures that the
+/// callback ran once the receiver of the stream got all of the data, even if
+/// it doesn't poll the stream for its end.
+pub(crate) fn reader_stream<R, F>(reader: R, on_eof: F) -> BodyStream
+where
+    R: AsyncRead + Send + Sync + Unpin + 'static,
+    F: FnOnce(R) + Send + Sync + 'static,
+{
+    async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Bytes> {
+        let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+        let read_bytes = reader.read(&mut buffer).await?;
+        buffer.truncate(read_bytes);
+
+        Ok(buffer.into())
+    }
+
+    Box::pin(async_stream::try_stream! {
+        let mut reader = reader;
+        let mut chunk = read_chunk(&mut reader).await?;
+
+        while !chunk.is_empty() {
+            let next = read_chunk(&mut reader).await?;
+
+            if next.is_empty() {
+                break;
+            }
+
+            yield mem::replace(&mut chunk, next);
+        }
+
+        on_eof(reader);
+
+        if !chunk.is_empty() {
+            yield chunk;
+        }
+    })
 }
 
 #[derive(Clone, Debug)]
@@ -121,15 +208,24 @@ impl HttpClient {
             None => self.request_config,
         };
 
-        let request = if !self.request_config.assert_identity {
-            self.try_into_http_request(request, session, config).await?
-        } else {
-            self.try_into_http_request_with_identity_assertion(request, session, config).await?
-        };
+        let request = self.build_http_request(request, session, config).await?;
 
         self.inner.send_request(request, config).await
     }
 
+    async fn build_http_request<Request: OutgoingRequest>(
+        &self,
+        request: Request,
+        session: Arc<RwLock<Option<Session>>>,
+        config: RequestConfig,
+    ) -> Result<http::Request<Bytes>, HttpError> {
+        if !self.request_config.assert_identity {
+            self.try_into_http_request(request, session, config).await
+        } else {
+            self.try_into_http_request_with_identity_assertion(request, session, config).await
+        }
+    }
+
     async fn try_into_http_request<Request: OutgoingRequest>(
         &self,
         request: Request,
@@ -209,6 +305,29 @@ impl HttpClient {
         Ok(create_content::Response::try_from_http_response(response)?)
     }
 
+    /// Upload media, replacing the body of the given request with the
+    /// streaming `body` which needs to produce exactly `content_length` bytes.
+    pub async fn upload_stream(
+        &self,
+        request: create_content::Request<'_>,
+        body: BodyStream,
+        content_length: u64,
+        config: Option<RequestConfig>,
+    ) -> Result<create_content::Response, HttpError> {
+        let config = config.unwrap_or(self.request_config);
+
+        let (mut parts, _) =
+            self.build_http_request(request, self.session.clone(), config).await?.into_parts();
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(content_length));
+
+        let response = self
+            .inner
+            .send_streaming_request(http::Request::from_parts(parts, body), config)
+            .await?;
+
+        Ok(create_content::Response::try_from_http_response(response)?)
+    }
+
     pub async fn send<Request>(
         &self,
         request: Request,
@@ -234,8 +353,6 @@ pub(crate) fn client_with_config(config: &ClientConfig) -> Result<Client, HttpEr
 
     #[cfg(not(target_arch = "wasm32"))]
     let http_client = {
-        use http::HeaderValue;
-
         let http_client = if config.disable_ssl_verification {
             http_client.danger_accept_invalid_certs(true)
         } else {
@@ -367,4 +484,18 @@ impl HttpSend for Client {
     ) -> Result<http::Response<Bytes>, HttpError> {
         send_request(self, request, config).await
     }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<BodyStream>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let mut request = reqwest::Request::try_from(request.map(reqwest::Body::wrap_stream))?;
+        *request.timeout_mut() = Some(config.timeout);
+
+        let response = self.execute(request).await?;
+
+        Ok(response_to_http_response(response).await?)
+    }
 }
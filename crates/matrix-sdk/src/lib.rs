@@ -61,7 +61,7 @@ pub mod encryption;
 
 pub use client::{Client, LoopCtrl};
 pub use error::{Error, HttpError, HttpResult, Result};
-pub use http_client::HttpSend;
+pub use http_client::{BodyStream, HttpSend};
 pub use room_member::RoomMember;
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -13,6 +13,8 @@ use ruma::{
     UserId,
 };
 
+#[cfg(feature = "encryption")]
+use crate::{encryption::identities::RoomTrustReport, Error};
 use crate::{
     error::HttpResult,
     media::{MediaFormat, MediaRequest, MediaType},
@@ -163,7 +165,11 @@ impl Common {
         return Ok(self.client.decrypt_room_event(&event).await?);
 
         #[cfg(not(feature = "encryption"))]
-        return Ok(RoomEvent { event: Raw::new(&event)?, encryption_info: None });
+        return Ok(RoomEvent {
+            event: Raw::new(&event)?,
+            encryption_info: None,
+            unable_to_decrypt_info: None,
+        });
     }
 
     pub(crate) async fn request_members(&self) -> Result<Option<MembersResponse>> {
@@ -386,17 +392,41 @@ impl Common {
     /// Returns true if all devices in the room are verified, otherwise false.
     #[cfg(feature = "encryption")]
     pub async fn contains_only_verified_devices(&self) -> Result<bool> {
-        let user_ids = self.client.store().get_user_ids(self.room_id()).await?;
-
-        for user_id in user_ids {
-            let devices = self.client.get_user_devices(&user_id).await?;
-            let any_unverified = devices.devices().any(|d| !d.verified());
+        Ok(self.trust_report().await?.contains_only_verified_devices())
+    }
 
-            if any_unverified {
-                return Ok(false);
-            }
-        }
+    /// Get a summary of the trust state of all the members of this room and
+    /// of their devices.
+    ///
+    /// The summaries of the members are cached and only get recalculated once
+    /// the identity or the devices of a member change, which makes this
+    /// suitable to decide which shield should be displayed for the room.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::room_id};
+    /// # use matrix_sdk::encryption::identities::ShieldState;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver)?;
+    /// # let room_id = room_id!("!test:localhost");
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     match room.trust_report().await?.shield_state() {
+    ///         ShieldState::Green => println!("All devices are verified"),
+    ///         ShieldState::Grey => println!("Some devices aren't verified"),
+    ///         ShieldState::Red => println!("Some devices can't be trusted"),
+    ///     }
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub async fn trust_report(&self) -> Result<RoomTrustReport> {
+        let olm = self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let user_ids = self.client.store().get_user_ids(self.room_id()).await?;
 
-        Ok(true)
+        Ok(olm.room_trust_report(user_ids.iter().map(|u| &**u)).await?)
     }
 }
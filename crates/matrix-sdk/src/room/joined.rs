@@ -2,6 +2,9 @@
 use std::sync::Arc;
 use std::{io::Read, ops::Deref};
 
+use futures_util::io::{AsyncRead, Cursor};
+#[cfg(feature = "encryption")]
+use matrix_sdk_base::crypto::ShareStrategy;
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::locks::Mutex;
 use matrix_sdk_common::{
@@ -302,6 +305,51 @@ impl Joined {
         Ok(())
     }
 
+    /// Get the strategy that decides which devices of the room members receive
+    /// our room keys for this room.
+    #[cfg(feature = "encryption")]
+    pub fn room_key_sharing_strategy(&self) -> ShareStrategy {
+        self.client.base_client().room_key_sharing_strategy(self.inner.room_id())
+    }
+
+    /// Set the strategy that decides which devices of the room members receive
+    /// our room keys for this room.
+    ///
+    /// This overrides the strategy that was set using
+    /// [`ClientConfig::room_key_sharing_strategy()`] for this room, the
+    /// strategy is persisted in the state store.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::room_id};
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver)?;
+    /// use matrix_sdk::encryption::ShareStrategy;
+    ///
+    /// let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+    ///
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     // Sending messages to this room will now fail if any of the room
+    ///     // members has an unverified device.
+    ///     room.set_room_key_sharing_strategy(ShareStrategy::ErrorOnUnverifiedDevices).await?;
+    /// }
+    /// # Result::<_, matrix_sdk::Error>::Ok(()) });
+    /// ```
+    ///
+    /// [`ClientConfig::room_key_sharing_strategy()`]: crate::config::ClientConfig::room_key_sharing_strategy
+    #[cfg(feature = "encryption")]
+    pub async fn set_room_key_sharing_strategy(&self, strategy: ShareStrategy) -> Result<()> {
+        Ok(self
+            .client
+            .base_client()
+            .set_room_key_sharing_strategy(self.inner.room_id(), strategy)
+            .await?)
+    }
+
     /// Share a group session for the given room.
     ///
     /// This will create Olm sessions with all the users/device pairs in the
@@ -383,6 +431,11 @@ impl Joined {
     /// **Note**: If you just want to send a custom JSON payload to a room, you
     /// can use the [`Joined::send_raw()`] method for that.
     ///
+    /// If the room is encrypted and its room key sharing strategy is
+    /// [`ShareStrategy::ErrorOnUnverifiedDevices`], this fails with an
+    /// [`OlmError::UnverifiedDevices`] error listing the unverified devices of
+    /// the room members, no message is sent in that case.
+    ///
     /// # Arguments
     ///
     /// * `content` - The content of the message event.
@@ -460,6 +513,8 @@ impl Joined {
     /// [`SyncMessageEvent`]: ruma::events::SyncMessageEvent
     /// [`Unsigned`]: ruma::events::Unsigned
     /// [`transaction_id`]: ruma::events::Unsigned#structfield.transaction_id
+    /// [`ShareStrategy::ErrorOnUnverifiedDevices`]: matrix_sdk_base::crypto::ShareStrategy::ErrorOnUnverifiedDevices
+    /// [`OlmError::UnverifiedDevices`]: matrix_sdk_base::crypto::OlmError::UnverifiedDevices
     pub async fn send(
         &self,
         content: impl MessageEventContent,
@@ -594,6 +649,10 @@ impl Joined {
     /// If the room is encrypted and the encryption feature is enabled the
     /// upload will be encrypted.
     ///
+    /// The whole attachment is read into memory before it's uploaded, use
+    /// [`send_attachment_stream()`](#method.send_attachment_stream) for large
+    /// files.
+    ///
     /// This is a convenience method that calls the
     /// [`Client::upload()`](#Client::method.upload) and afterwards the
     /// [`send()`](#method.send).
@@ -644,15 +703,89 @@ impl Joined {
         reader: &mut R,
         txn_id: Option<Uuid>,
     ) -> Result<send_message_event::Response> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let content_length = data.len() as u64;
+
+        self.send_attachment_stream(body, content_type, Cursor::new(data), content_length, txn_id)
+            .await
+    }
+
+    /// Send an attachment to this room, streaming it out of the given reader.
+    ///
+    /// Unlike [`send_attachment()`](#method.send_attachment) this never holds
+    /// the whole attachment in memory and doesn't block while reading it. If
+    /// the room is encrypted and the encryption feature is enabled the
+    /// attachment will be encrypted while it's being uploaded.
+    ///
+    /// # Arguments
+    /// * `body` - A textual representation of the media that is going to be
+    /// uploaded. Usually the file name.
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `reader` - An `AsyncRead` implementor that will be used to fetch the
+    /// raw bytes of the media.
+    ///
+    /// * `content_length` - The number of bytes the reader will produce.
+    ///
+    /// * `txn_id` - A unique `Uuid` that can be attached to a `MessageEvent`
+    /// held in its unsigned field as `transaction_id`. If not given one is
+    /// created for the message.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::room_id};
+    /// # use url::Url;
+    /// # use mime;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver)?;
+    /// # let room_id = room_id!("!test:localhost");
+    /// let data = b"It's a secret to everybody".to_vec();
+    /// let length = data.len() as u64;
+    ///
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     room.send_attachment_stream(
+    ///         "secret.txt",
+    ///         &mime::TEXT_PLAIN,
+    ///         futures::io::Cursor::new(data),
+    ///         length,
+    ///         None,
+    ///     ).await?;
+    /// }
+    /// # Result::<_, matrix_sdk::Error>::Ok(()) });
+    /// ```
+    pub async fn send_attachment_stream<R>(
+        &self,
+        body: &str,
+        content_type: &Mime,
+        reader: R,
+        content_length: u64,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response>
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+    {
         #[cfg(feature = "encryption")]
         let content = if self.is_encrypted() {
-            self.client.prepare_encrypted_attachment_message(body, content_type, reader).await?
+            self.client
+                .prepare_encrypted_attachment_message(body, content_type, reader, content_length)
+                .await?
         } else {
-            self.client.prepare_attachment_message(body, content_type, reader).await?
+            self.client
+                .prepare_attachment_message(body, content_type, reader, content_length)
+                .await?
         };
 
         #[cfg(not(feature = "encryption"))]
-        let content = self.client.prepare_attachment_message(body, content_type, reader).await?;
+        let content = self
+            .client
+            .prepare_attachment_message(body, content_type, reader, content_length)
+            .await?;
 
         self.send(RoomMessageEventContent::new(content), txn_id).await
     }
@@ -39,10 +39,16 @@ impl Left {
     /// Forget this room.
     ///
     /// This communicates to the homeserver that it should forget the room.
+    ///
+    /// Depending on the configured room key retention policy, this also
+    /// deletes the room keys of the room.
     pub async fn forget(&self) -> Result<()> {
         let request = forget_room::Request::new(self.inner.room_id());
         let _response = self.client.send(request, None).await?;
 
+        #[cfg(feature = "encryption")]
+        self.client.base_client().receive_forgotten_room(self.inner.room_id()).await?;
+
         Ok(())
     }
 }
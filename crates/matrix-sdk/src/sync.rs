@@ -48,6 +48,11 @@ impl Client {
                 .await?;
             self.handle_sync_state_events(&room, &state.events).await?;
             self.handle_sync_timeline_events(&room, &timeline.events).await?;
+
+            #[cfg(feature = "encryption")]
+            if self.inner.retry_decryption_enabled {
+                self.track_undecryptable_events(room_id, &timeline.events);
+            }
         }
 
         for (room_id, room_info) in &rooms.leave {
@@ -63,6 +68,11 @@ impl Client {
                 .await?;
             self.handle_sync_state_events(&room, &state.events).await?;
             self.handle_sync_timeline_events(&room, &timeline.events).await?;
+
+            #[cfg(feature = "encryption")]
+            if self.inner.retry_decryption_enabled {
+                self.track_undecryptable_events(room_id, &timeline.events);
+            }
         }
 
         for (room_id, room_info) in &rooms.invite {
@@ -81,6 +91,14 @@ impl Client {
             .await?;
         }
 
+        // Room keys might have arrived with this sync, either as to-device
+        // events or by restoring them from the backup.
+        #[cfg(feature = "encryption")]
+        if self.inner.retry_decryption_enabled {
+            let room_keys = self.received_room_keys(&response.to_device.events);
+            self.retry_decrypting_events(room_keys).await?;
+        }
+
         // Construct notification event handler futures
         let mut futures = Vec::new();
         for handler in &*self.notification_handlers().await {